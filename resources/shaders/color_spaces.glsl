@@ -1,6 +1,24 @@
-const float minWavelength = 380.0;
-const float maxWavelength = 720.0;
-const float rangeWavelengths = maxWavelength - minWavelength;
+// rendered wavelength range, set from the scene's spectral settings by the renderer
+layout(constant_id = 0) const float minWavelength = 380.0;
+layout(constant_id = 1) const float maxWavelength = 720.0;
+#define rangeWavelengths (maxWavelength - minWavelength)
+
+// xyz to output color space matrix including chromatic adaptation, set by the renderer
+// given row by row, defaults to linear sRGB
+layout(constant_id = 4) const float xyzToOutput00 = 3.240969941904523;
+layout(constant_id = 5) const float xyzToOutput01 = -1.537383177570094;
+layout(constant_id = 6) const float xyzToOutput02 = -0.498610760293003;
+layout(constant_id = 7) const float xyzToOutput10 = -0.969243636280880;
+layout(constant_id = 8) const float xyzToOutput11 = 1.875967501507721;
+layout(constant_id = 9) const float xyzToOutput12 = 0.041555057407176;
+layout(constant_id = 10) const float xyzToOutput20 = 0.055630079696994;
+layout(constant_id = 11) const float xyzToOutput21 = -0.203976958888977;
+layout(constant_id = 12) const float xyzToOutput22 = 1.056971514242879;
+
+// wavelength range covered by the spectra texture, the D65 table and the rgb to spectrum table
+const float SPECTRA_MIN_WAVELENGTH = 380.0;
+const float SPECTRA_MAX_WAVELENGTH = 720.0;
+const float SPECTRA_RANGE_WAVELENGTHS = SPECTRA_MAX_WAVELENGTH - SPECTRA_MIN_WAVELENGTH;
 
 // Piecewise Gaussian
 float G(float x, float mu, float T1, float T2) {
@@ -23,7 +41,7 @@ const float D65_5nm[69] = float[69](49.9755,52.3118,54.6482,68.7015,82.7549,87.1
 );
 // wavelengthToD65 from PLTFalcor
 float wavelengthToD65(float lambda) {
-    const float wavelengthResize = (lambda - minWavelength) * 0.2f;
+    const float wavelengthResize = (lambda - SPECTRA_MIN_WAVELENGTH) * 0.2f;
     const int wavelength_t = int(ceil(wavelengthResize)+.5f);
     const int wavelength_b = int(floor(wavelengthResize)+.5f);
     const float weight = wavelengthResize - wavelength_b;
@@ -49,65 +67,26 @@ vec3 XYZtoSRGB_linear(vec3 c) {
     return transpose(M) * c;
 }
 
-// spectrumToRgb adapted from PLTFalcor
-vec3 spectrumToRgb(float wavelength) {
+// xyz of a wavelength relative to D65, so a flat spectrum has the D65 white and Y = 1
+// keep in sync with RENDER_WHITE in color.rs
+vec3 spectrumToXyz(float wavelength) {
     vec3 xyz = wavelengthToXYZ(wavelength) * wavelengthToD65(wavelength);
 
     const float Y_D65 = 10.5670762f;
-    return XYZtoSRGB_linear(xyz) / Y_D65;
+    return xyz / Y_D65;
 }
 
-const float white_sd[11] = float[11](1,1,0.9999,0.9993,0.9992,0.9998,1,1,1,1,0);
-const float cyan_sd[11] = float[11](0.9710,0.9426,1.0007,1.0007,1.0007,1.0007,0.1564,0,0,0,0);
-const float magenta_sd[11] = float[11](1,1,0.968, 0.22295,0,0.0458,0.8369,1,1,0.9959,0);
-const float yellow_sd[11] = float[11](0.0001,0,0.1088,0.6651,1,1,0.9996,0.9586,0.9685,0.9840,0);
-const float red_sd[11] = float[11](0.1012,0.0515,0,0,0,0,0.8325,1.0149,1.0149,1.014,0);
-const float green_sd[11] = float[11](0,0,0.0273,0.7937,1,0.9418,0.1719,0,0,0.0025,0);
-const float blue_sd[11] = float[11](1,1,0.8916,0.3323,0,0,0.0003,0.0369,0.0483,0.0496,0);
-
-// adapted from rgbToSpectrum from PLTFalcor
-float rgb_to_spectrum(vec3 rgb, float wavelength) {
-    float sd = 0.0;
-    const int b = int(clamp((wavelength - minWavelength) / rangeWavelengths, 0.0, 1.0) * 10.0);
-    const float white_s   = white_sd[b];
-    const float cyan_s    = cyan_sd[b];
-    const float magenta_s = magenta_sd[b];
-    const float yellow_s  = yellow_sd[b];
-    const float red_s     = red_sd[b];
-    const float green_s   = green_sd[b];
-    const float blue_s    = blue_sd[b];
-    const float red = rgb.x;
-    const float green = rgb.y;
-    const float blue = rgb.z;
-    if (red <= green && red <= blue) {
-        sd += white_s * red;
-        if (green <= blue) {
-            sd += cyan_s * (green - red);
-            sd += blue_s * (blue - green);
-        } else {
-            sd += cyan_s * (blue - red);
-            sd += green_s * (green - blue);
-        }
-    } else if (green <= red && green <= blue) {
-        sd += white_s * green;
-        if (red <= blue) {
-            sd += magenta_s * (red - green);
-            sd += blue_s * (blue - red);
-        } else {
-            sd += magenta_s * (blue - green);
-            sd += red_s * (red - blue);
-        }
-    } else {
-        sd += white_s * blue;
-        if (red <= green) {
-            sd += yellow_s * (red - blue);
-            sd += green_s * (green - red);
-        } else {
-            sd += yellow_s * (green - blue);
-            sd += red_s * (red - green);
-        }
-    }
-    return sd;
+// spectrumToRgb adapted from PLTFalcor
+vec3 spectrumToRgb(float wavelength) {
+    return XYZtoSRGB_linear(spectrumToXyz(wavelength));
+}
+
+vec3 XYZtoOutput(vec3 c) {
+    return vec3(
+        dot(vec3(xyzToOutput00, xyzToOutput01, xyzToOutput02), c),
+        dot(vec3(xyzToOutput10, xyzToOutput11, xyzToOutput12), c),
+        dot(vec3(xyzToOutput20, xyzToOutput21, xyzToOutput22), c)
+    );
 }
 
 //By Björn Ottosson
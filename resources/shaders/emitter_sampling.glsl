@@ -1,20 +1,21 @@
 #include "color_spaces.glsl"
+#include "spectra.glsl"
 
 struct EmitterSample {
     vec3 position;
     vec3 direction;
     vec3 normal;
-    vec3 radiance;
+    vec4 radiance;
     float pdf;
 };
 
-EmitterSample sample_light(vec3 hit_pos, inout uint seed, inout float wavelength) {
+EmitterSample sample_light(vec3 hit_pos, inout uint seed, vec4 wavelengths) {
     EmitterSample result;
     // default values
     result.position = vec3(1, 0, 0);
     result.direction = vec3(1, 0, 0);
     result.normal = vec3(1, 0, 0);
-    result.radiance = vec3(0);
+    result.radiance = vec4(0);
     result.pdf = 1;
 
     // pick a random emitter
@@ -25,7 +26,7 @@ EmitterSample sample_light(vec3 hit_pos, inout uint seed, inout float wavelength
         result.position = light.position;
         result.direction = normalize(light.position - hit_pos);
         result.normal = -result.direction;
-        result.radiance = vec3(rgb_to_spectrum(light.color, wavelength));
+        result.radiance = light_spectrum(light, wavelengths);
         result.pdf = 1.0 / lights.num_lights;
     } else if (light.type == EMITTER_TYPE_AREA) {
         // sample random point on triangle
@@ -54,14 +55,10 @@ EmitterSample sample_light(vec3 hit_pos, inout uint seed, inout float wavelength
             visible = true;
         }
 
-        float wavelength_u = (wavelength - minWavelength) / rangeWavelengths;
-        float wavelength_v = (float(light.spectra_i) + 0.5) / float(textureSize(SpectraTexture, 0).y);
-        float spectral_radiance = texture(SpectraTexture, vec2(wavelength_u, wavelength_v)).r;
-
         if (visible) {
             result.pdf = 1.0 / lights.num_lights / area;
             result.normal = normal;
-            result.radiance = vec3(spectral_radiance * light.color[0]);
+            result.radiance = light_spectrum(light, wavelengths);
         }
     } else if (light.type == EMITTER_TYPE_DIRECTIONAL) {
         vec3 light_dir = normalize(light.data[0]);
@@ -79,7 +76,7 @@ EmitterSample sample_light(vec3 hit_pos, inout uint seed, inout float wavelength
         result.position = emitter_pos;
         result.direction = dir_to_light;
         result.normal = light_dir;
-        result.radiance = in_beam ? vec3(rgb_to_spectrum(light.color, wavelength)) : vec3(0);
+        result.radiance = in_beam ? light_spectrum(light, wavelengths) : vec4(0);
         result.pdf = 1.0 / lights.num_lights;
     }
 
@@ -20,6 +20,9 @@ const uint EMITTER_TYPE_POINT = 0;
 const uint EMITTER_TYPE_AREA = 1;
 const uint EMITTER_TYPE_DIRECTIONAL = 2;
 
+// spectra_i of point/directional lights without a spectrum - keep in sync with raytrace.rs
+const uint NO_SPECTRUM = 0xFFFFFFFFu;
+
 #extension GL_EXT_scalar_block_layout : enable
 
 layout(scalar, set = 0, binding = 3) readonly buffer Vertices {
@@ -38,3 +41,6 @@ layout(scalar, set = 0, binding = 5) readonly buffer InstanceOffsets {
 #define BRDF_PARAMS_BINDING 6
 
 layout(set = 0, binding = 7) uniform sampler2D SpectraTexture;
+
+// sigmoid polynomial coefficients for uplifting rgb, see RgbToSpectrumTable in spectral.rs
+layout(set = 0, binding = 8) uniform sampler2D RgbToSpectrumTable;
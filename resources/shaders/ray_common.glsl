@@ -3,13 +3,19 @@ const float PI = 3.1415926535897932384626433832795;
 struct RayPayload {
     // inputs
     uint seed;
-    float wavelength;
+    // hero wavelength in x, the other lanes are only traced with hero wavelength sampling
+    vec4 wavelengths;
 
     // outputs
     bool is_hit;
+    // gl_InstanceID of the hit, used for the instance aov
+    uint instance_i;
     bool is_emitter;
     bool is_specular;
-    vec3 rad;
+    // the sampled direction depends on the hero wavelength, so the other lanes must be terminated
+    bool is_dispersive;
+    // spectral radiance per wavelength, or an rgb color for the simple raygen shader
+    vec4 rad;
     vec3 hit_pos;
     vec3 hit_normal;
     vec3 hit_geo_normal;
@@ -24,7 +30,7 @@ struct RayPayload {
     vec3 emitter_brdf_vals;
     vec3 emitter_normal;
 
-    float brdf_val;
-    float emitter_brdf_val;
+    vec4 brdf_val;
+    vec4 emitter_brdf_val;
     float emitter_type;
 };
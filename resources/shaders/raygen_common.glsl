@@ -1,9 +1,24 @@
+// linear radiance, tonemapped for display by tonemap.comp
 layout(set = 0, binding = 0) writeonly uniform image2D image;
 layout(set = 0, binding = 1, rgba32f) uniform image2D accum_image;
 layout(set = 0, binding = 2) uniform accelerationStructureEXT tlas;
+// per pixel wavelength bins for multispectral output, laid out as [y][x][bin]
+layout(std430, set = 0, binding = 9) buffer SpectralBins { float spectral_bins[]; };
+// arbitrary output variables, only written when AOVS is enabled
+// albedo and normal are accumulated like the beauty image, depth and instance are from frame 0
+const uint AOV_ALBEDO = 0;
+const uint AOV_NORMAL = 1;
+const uint AOV_DEPTH_INSTANCE = 2;
+layout(set = 0, binding = 10, rgba32f) uniform image2D aov_images[3];
+// per pixel luminance moments over the frames that were actually sampled
+// r is the sum, g the sum of squares and b the number of sampled frames
+layout(set = 0, binding = 11, rgba32f) uniform image2D moment_image;
 layout(push_constant) uniform Constants {
     mat4 view_inverse;
     mat4 proj_inverse;
     uvec2 seed_offset;
     uint frame;
+    // the launch covers one tile of the full image, starting at tile_offset
+    uvec2 tile_offset;
+    uvec2 image_size;
 };
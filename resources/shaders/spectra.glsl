@@ -0,0 +1,70 @@
+// requires hit_common.glsl and color_spaces.glsl to be included first
+
+vec4 sample_spectrum(uint spectra_i, vec4 wavelengths) {
+    vec4 wavelengths_u = (wavelengths - SPECTRA_MIN_WAVELENGTH) / SPECTRA_RANGE_WAVELENGTHS;
+    float wavelength_v = (float(spectra_i) + 0.5) / float(textureSize(SpectraTexture, 0).y);
+    vec4 result;
+    for (int i = 0; i < 4; i++) {
+        result[i] = texture(SpectraTexture, vec2(wavelengths_u[i], wavelength_v)).r;
+    }
+    return result;
+}
+
+float sigmoid(float x) {
+    if (isinf(x)) {
+        return x > 0 ? 1.0 : 0.0;
+    }
+    return 0.5 + x / (2.0 * sqrt(1.0 + x * x));
+}
+
+float inverse_smoothstep(float x) {
+    return 0.5 - sin(asin(1.0 - 2.0 * x) / 3.0);
+}
+
+// sigmoid polynomial coefficients for an rgb color in [0, 1]
+// mirrors RgbToSpectrumTable::coeffs in spectral.rs
+vec3 rgb_to_spectrum_coeffs(vec3 rgb) {
+    rgb = clamp(rgb, 0.0, 1.0);
+
+    // grey spectra are constant, so invert the sigmoid directly
+    if (rgb.r == rgb.g && rgb.g == rgb.b) {
+        return vec3(0, 0, (rgb.r - 0.5) / sqrt(rgb.r * (1.0 - rgb.r)));
+    }
+
+    // table is laid out as rows of [max component][z][y] with x along each row
+    const ivec2 size = textureSize(RgbToSpectrumTable, 0);
+    const int res = size.x;
+    const int l = rgb.r >= rgb.g ? (rgb.r >= rgb.b ? 0 : 2) : (rgb.g >= rgb.b ? 1 : 2);
+    const float z = rgb[l];
+    const float x = rgb[(l + 1) % 3] / z * (res - 1);
+    const float y = rgb[(l + 2) % 3] / z * (res - 1);
+    const float zc = inverse_smoothstep(inverse_smoothstep(z)) * (res - 1);
+    const int zi = min(int(zc), res - 2);
+
+    // x and y are filtered by the sampler, z is interpolated by hand
+    const float u = (x + 0.5) / res;
+    const float v = (float((l * res + zi) * res) + y + 0.5) / size.y;
+    const vec3 c0 = texture(RgbToSpectrumTable, vec2(u, v)).xyz;
+    const vec3 c1 = texture(RgbToSpectrumTable, vec2(u, v + float(res) / size.y)).xyz;
+    return mix(c0, c1, zc - zi);
+}
+
+// smooth spectrum whose color under D65 matches the given rgb, evaluated at each wavelength
+// colors brighter than 1 (emitters) are uplifted at a lower brightness and scaled back up
+vec4 rgb_to_spectrum(vec3 rgb, vec4 wavelengths) {
+    const float m = max(rgb.r, max(rgb.g, rgb.b));
+    const float scale = m > 1.0 ? 2.0 * m : 1.0;
+    const vec3 c = rgb_to_spectrum_coeffs(rgb / scale);
+    const vec4 t = (wavelengths - SPECTRA_MIN_WAVELENGTH) / SPECTRA_RANGE_WAVELENGTHS;
+    const vec4 x = (c.x * t + c.y) * t + c.z;
+    return scale * vec4(sigmoid(x[0]), sigmoid(x[1]), sigmoid(x[2]), sigmoid(x[3]));
+}
+
+// spectral radiance of a light at the given wavelengths
+// lights with a spectrum are scaled by the first color channel, the rest are uplifted from rgb
+vec4 light_spectrum(Light light, vec4 wavelengths) {
+    if (light.spectra_i == NO_SPECTRUM) {
+        return rgb_to_spectrum(light.color, wavelengths);
+    }
+    return sample_spectrum(light.spectra_i, wavelengths) * light.color[0];
+}
@@ -0,0 +1,220 @@
+// renders a scene without presenting and reports how long it took
+
+use std::{cell::RefCell, time::Instant};
+
+use anyhow::{bail, Result};
+use gpu_allocator::vulkan::Allocator;
+use serde::Serialize;
+
+use crate::{render::Renderer, scene::scenes::mesh::MeshScene, window::WindowData};
+
+/// Used when neither the scene nor the command line sets a resolution
+pub const DEFAULT_SIZE: (u32, u32) = (1280, 720);
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    // names that end up in the report
+    pub scene: String,
+    pub renderer: String,
+    pub device: String,
+    pub frames: u32,
+    // rendered before the timed frames, so pipeline warmup and clock ramp up don't count
+    pub warmup_frames: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct TimeStats {
+    pub mean: f64,
+    pub min: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl TimeStats {
+    /// Nearest rank percentiles, None without samples
+    pub fn from_samples(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+        Some(Self {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            min: sorted[0],
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MemoryReport {
+    pub allocated_bytes: u64,
+    pub reserved_bytes: u64,
+}
+
+/// Everything in milliseconds
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub scene: String,
+    pub renderer: String,
+    pub device: String,
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+    pub samples_per_frame: u32,
+    // wall time of submitting a frame and waiting for it
+    pub frame_time_ms: TimeStats,
+    // time the device spent tracing, None if the renderer can't measure it
+    pub trace_time_ms: Option<TimeStats>,
+    pub blas_build_ms: Option<f64>,
+    pub tlas_build_ms: Option<f64>,
+    pub samples_per_second: f64,
+    // device memory, None for the CPU renderer
+    pub memory: Option<MemoryReport>,
+}
+
+/// Ingests the scene and renders the warmup and timed frames
+pub fn run<R: Renderer<MeshScene, WindowData>>(
+    renderer: &mut R,
+    mut scene: MeshScene,
+    options: BenchOptions,
+    allocator: Option<&RefCell<Allocator>>,
+) -> Result<BenchReport> {
+    if options.frames == 0 {
+        bail!("need at least one frame");
+    }
+    let (width, height) = scene.output.resolution.unwrap_or(DEFAULT_SIZE);
+    scene.output.resolution = Some((width, height));
+    scene.camera.handle_resize(width, height);
+    // every pixel has to be traced every frame for the numbers to be comparable
+    scene.sampling.adaptive_threshold = None;
+    renderer.ingest_scene(&scene, (width, height))?;
+
+    for _ in 0..options.warmup_frames {
+        renderer.render_offscreen(&[])?;
+    }
+
+    let mut frame_times = Vec::with_capacity(options.frames as usize);
+    let mut trace_times = Vec::with_capacity(options.frames as usize);
+    for _ in 0..options.frames {
+        let start = Instant::now();
+        renderer.render_offscreen(&[])?;
+        frame_times.push(start.elapsed().as_secs_f64() * 1000.0);
+        if let Some(trace_time) = renderer.last_trace_time()? {
+            trace_times.push(trace_time.as_secs_f64() * 1000.0);
+        }
+    }
+
+    // the trace times are only used if every frame has one
+    let trace_time_ms = if trace_times.len() == frame_times.len() {
+        TimeStats::from_samples(&trace_times)
+    } else {
+        None
+    };
+    let total_seconds = frame_times.iter().sum::<f64>() / 1000.0;
+    // lowered by a target spp, the same way the renderers do it
+    let samples_per_frame = scene.sampling.frame_samples();
+    let samples = width as f64 * height as f64 * samples_per_frame as f64 * options.frames as f64;
+    let build_times = renderer.accel_build_times();
+    let memory = allocator.map(|allocator| {
+        let report = allocator.borrow().generate_report();
+        MemoryReport {
+            allocated_bytes: report.total_allocated_bytes,
+            reserved_bytes: report.total_reserved_bytes,
+        }
+    });
+
+    Ok(BenchReport {
+        scene: options.scene,
+        renderer: options.renderer,
+        device: options.device,
+        width,
+        height,
+        frames: options.frames,
+        samples_per_frame,
+        frame_time_ms: TimeStats::from_samples(&frame_times).unwrap(),
+        trace_time_ms,
+        blas_build_ms: build_times.map(|times| times.bottom_level.as_secs_f64() * 1000.0),
+        tlas_build_ms: build_times.map(|times| times.top_level.as_secs_f64() * 1000.0),
+        samples_per_second: samples / total_seconds,
+        memory,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::render::renderers::CpuRenderer;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        assert_eq!(TimeStats::from_samples(&[]), None);
+
+        let samples: Vec<_> = (1..=100).rev().map(f64::from).collect();
+        let stats = TimeStats::from_samples(&samples).unwrap();
+        assert_eq!(stats.mean, 50.5);
+        assert_eq!((stats.min, stats.max), (1.0, 100.0));
+        assert_eq!((stats.p50, stats.p90, stats.p99), (50.0, 90.0, 99.0));
+
+        let stats = TimeStats::from_samples(&[3.0]).unwrap();
+        assert_eq!(
+            (stats.min, stats.p50, stats.p99, stats.max),
+            (3.0, 3.0, 3.0, 3.0)
+        );
+    }
+
+    #[test]
+    fn cpu_bench() {
+        let file = File::open("resources/scenes/cubes.toml").unwrap();
+        let mut scene = MeshScene::load_from(file).unwrap();
+        scene.output.resolution = Some((8, 6));
+        scene.sampling.samples_per_frame = 1;
+        let options = BenchOptions {
+            scene: "cubes.toml".to_string(),
+            renderer: "cpu".to_string(),
+            device: "cpu".to_string(),
+            frames: 3,
+            warmup_frames: 1,
+        };
+
+        let report = run(&mut CpuRenderer::headless(), scene, options, None).unwrap();
+        assert_eq!((report.width, report.height, report.frames), (8, 6, 3));
+        assert!(report.trace_time_ms.is_some());
+        assert!(report.samples_per_second > 0.0);
+        assert!(report.memory.is_none());
+        assert_eq!(report.samples_per_frame, 1);
+    }
+
+    #[test]
+    fn bench_counts_the_samples_traced_for_spp() {
+        let file = File::open("resources/scenes/cubes.toml").unwrap();
+        let mut scene = MeshScene::load_from(file).unwrap();
+        scene.output.resolution = Some((8, 6));
+        // two frames of 3 rather than 4 and 2
+        scene.sampling.samples_per_frame = 4;
+        scene.sampling.spp = Some(6);
+        let options = BenchOptions {
+            scene: "cubes.toml".to_string(),
+            renderer: "cpu".to_string(),
+            device: "cpu".to_string(),
+            frames: 2,
+            warmup_frames: 0,
+        };
+
+        let mut renderer = CpuRenderer::headless();
+        let report = run(&mut renderer, scene, options, None).unwrap();
+        assert_eq!(report.samples_per_frame, 3);
+        assert_eq!(renderer.samples_per_pixel(), 6);
+    }
+}
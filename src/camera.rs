@@ -5,8 +5,6 @@ use std::fmt::Debug;
 use glam::{Mat3, Mat4, Vec3};
 use winit::keyboard::KeyCode;
 
-use crate::window::WindowData;
-
 #[derive(Debug, Copy, Clone)]
 enum Direction {
     None = 0,
@@ -19,6 +17,8 @@ enum Direction {
     Escape = 0x40,
 }
 
+type KeyMovement = (Direction, Box<dyn Fn(&Vec3) -> Vec3>);
+
 pub struct Camera {
     // matrix from world space to camera space
     view: Mat4,
@@ -30,7 +30,7 @@ pub struct Camera {
     position: Vec3,
     direction: Vec3,
 
-    key_movements: BTreeMap<KeyCode, (Direction, Box<dyn Fn(&Vec3) -> Vec3>)>,
+    key_movements: BTreeMap<KeyCode, KeyMovement>,
     movement_direction: u32,
     updated_view: bool,
 
@@ -52,9 +52,8 @@ impl Debug for Camera {
 impl Camera {
     const SPEED: f32 = 5f32;
 
-    pub fn new(view: Mat4, fov: f32) -> Camera {
-        let mut key_movements: BTreeMap<KeyCode, (Direction, Box<dyn Fn(&Vec3) -> Vec3>)> =
-            BTreeMap::new();
+    pub fn new(view: Mat4, fov: f32, aspect_ratio: f32) -> Camera {
+        let mut key_movements: BTreeMap<KeyCode, KeyMovement> = BTreeMap::new();
 
         key_movements.insert(
             KeyCode::KeyW,
@@ -98,12 +97,7 @@ impl Camera {
         );
 
         let fov_radians = fov * PI / 180f32;
-        let mut perspective = Mat4::perspective_lh(
-            fov_radians,
-            WindowData::DEFAULT_WIDTH as f32 / WindowData::DEFAULT_HEIGHT as f32,
-            0.1f32,
-            1000f32,
-        );
+        let mut perspective = Mat4::perspective_lh(fov_radians, aspect_ratio, 0.1f32, 1000f32);
         perspective.y_axis = -perspective.y_axis;
 
         Camera {
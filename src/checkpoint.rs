@@ -0,0 +1,168 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+const MAGIC: &[u8; 4] = b"KGCP";
+const VERSION: u32 = 1;
+
+/// Everything needed to continue an accumulation where it left off
+///
+/// The buffers hold the raw sums the renderer accumulates, in the renderer's own layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    // hash of the scene, camera and settings the accumulation belongs to
+    pub scene_hash: u64,
+    pub size: (u32, u32),
+    pub frame: u32,
+    // base seed the per frame seeds are derived from
+    pub seed: u64,
+    pub accumulation: Vec<f32>,
+    pub moments: Vec<f32>,
+    pub aovs: Vec<Vec<f32>>,
+    pub spectral_bins: Vec<f32>,
+}
+
+impl Checkpoint {
+    /// Writes next to `path` first and renames, so a crash mid write keeps the previous checkpoint
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.scene_hash.to_le_bytes())?;
+        writer.write_all(&self.size.0.to_le_bytes())?;
+        writer.write_all(&self.size.1.to_le_bytes())?;
+        writer.write_all(&self.frame.to_le_bytes())?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        write_floats(&mut writer, &self.accumulation)?;
+        write_floats(&mut writer, &self.moments)?;
+        writer.write_all(&(self.aovs.len() as u32).to_le_bytes())?;
+        for aov in &self.aovs {
+            write_floats(&mut writer, aov)?;
+        }
+        write_floats(&mut writer, &self.spectral_bins)?;
+        writer.into_inner()?.sync_all()?;
+
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open checkpoint {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("{} is not a checkpoint", path.display());
+        }
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            bail!("unsupported checkpoint version {version}, expected {VERSION}");
+        }
+
+        let scene_hash = read_u64(&mut reader)?;
+        let size = (read_u32(&mut reader)?, read_u32(&mut reader)?);
+        let frame = read_u32(&mut reader)?;
+        let seed = read_u64(&mut reader)?;
+        let accumulation = read_floats(&mut reader)?;
+        let moments = read_floats(&mut reader)?;
+        let aovs = (0..read_u32(&mut reader)?)
+            .map(|_| read_floats(&mut reader))
+            .collect::<Result<_>>()?;
+        let spectral_bins = read_floats(&mut reader)?;
+
+        Ok(Self {
+            scene_hash,
+            size,
+            frame,
+            seed,
+            accumulation,
+            moments,
+            aovs,
+            spectral_bins,
+        })
+    }
+}
+
+/// FNV-1a, unlike `DefaultHasher` it is stable across builds so checkpoints stay valid
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn write_floats(writer: &mut impl Write, data: &[f32]) -> Result<()> {
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    for x in data {
+        writer.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_floats(reader: &mut impl Read) -> Result<Vec<f32>> {
+    let len = read_u64(reader)? as usize;
+    let mut bytes = Vec::new();
+    reader
+        .take(len as u64 * 4)
+        .read_to_end(&mut bytes)
+        .context("failed to read checkpoint")?;
+    if bytes.len() != len * 4 {
+        bail!("checkpoint is truncated");
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_bytes, Checkpoint};
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let checkpoint = Checkpoint {
+            scene_hash: hash_bytes(b"scene"),
+            size: (2, 1),
+            frame: 7,
+            seed: 42,
+            accumulation: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0],
+            moments: vec![0.5; 8],
+            aovs: vec![vec![0.25; 8], vec![-1.0; 8]],
+            spectral_bins: Vec::new(),
+        };
+
+        let path = std::env::temp_dir().join(format!("kg-checkpoint-{}.ckpt", std::process::id()));
+        checkpoint.write(&path).unwrap();
+        assert_eq!(Checkpoint::read(&path).unwrap(), checkpoint);
+
+        // cut off partway through the accumulation
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..40]).unwrap();
+        assert!(Checkpoint::read(&path).is_err());
+
+        std::fs::write(&path, b"not a checkpoint").unwrap();
+        assert!(Checkpoint::read(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_ne!(hash_bytes(b"scene"), hash_bytes(b"scenf"));
+    }
+}
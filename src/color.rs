@@ -0,0 +1,196 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use glam::{DMat3, DVec3};
+
+/// CIE xy chromaticity coordinates
+pub type Chromaticity = [f64; 2];
+
+pub const D50: Chromaticity = [0.34567, 0.35850];
+pub const D55: Chromaticity = [0.33242, 0.34743];
+// the ACES white point
+pub const D60: Chromaticity = [0.32168, 0.33767];
+pub const D65: Chromaticity = [0.31270, 0.32900];
+pub const E: Chromaticity = [1.0 / 3.0, 1.0 / 3.0];
+
+// spectra are converted to XYZ relative to D65, so a flat spectrum ends up at the D65 white
+// keep in sync with spectrumToXyz in color_spaces.glsl
+pub const RENDER_WHITE: Chromaticity = D65;
+
+// cone response matrix of the Bradford chromatic adaptation transform
+const BRADFORD: DMat3 = DMat3::from_cols(
+    DVec3::new(0.8951, -0.7502, 0.0389),
+    DVec3::new(0.2664, 1.7135, -0.0685),
+    DVec3::new(-0.1614, 0.0367, 1.0296),
+);
+
+/// Colour space of the rendered output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    // linear Rec.709 primaries
+    Srgb,
+    Rec2020,
+    DisplayP3,
+    // ACES AP1
+    AcesCg,
+    // ACES AP0
+    Aces2065_1,
+    Xyz,
+}
+
+impl FromStr for ColorSpace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "srgb" | "rec709" => ColorSpace::Srgb,
+            "rec2020" => ColorSpace::Rec2020,
+            "display_p3" | "p3" => ColorSpace::DisplayP3,
+            "acescg" => ColorSpace::AcesCg,
+            "aces2065_1" | "aces" => ColorSpace::Aces2065_1,
+            "xyz" => ColorSpace::Xyz,
+            _ => bail!(
+                "color space must be one of \"srgb\", \"rec2020\", \"display_p3\", \"acescg\", \"aces2065_1\" or \"xyz\""
+            ),
+        })
+    }
+}
+
+impl ColorSpace {
+    /// Red, green and blue primaries followed by the white point
+    pub fn chromaticities(self) -> [Chromaticity; 4] {
+        match self {
+            ColorSpace::Srgb => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06], D65],
+            ColorSpace::Rec2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046], D65],
+            ColorSpace::DisplayP3 => [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060], D65],
+            ColorSpace::AcesCg => [[0.713, 0.293], [0.165, 0.830], [0.128, 0.044], D60],
+            ColorSpace::Aces2065_1 => [[0.7347, 0.2653], [0.0, 1.0], [0.0001, -0.0770], D60],
+            // primaries that make RGB equal to XYZ
+            ColorSpace::Xyz => [[1.0, 0.0], [0.0, 1.0], [0.0, 0.0], E],
+        }
+    }
+
+    /// White point the render is adapted to unless the scene picks one
+    pub fn default_white(self) -> Chromaticity {
+        match self {
+            // raw XYZ stays relative to the render white
+            ColorSpace::Xyz => RENDER_WHITE,
+            _ => self.chromaticities()[3],
+        }
+    }
+
+    pub fn rgb_to_xyz(self) -> DMat3 {
+        // the xyz primaries lie on y = 0 so they can't go through xy_to_xyz
+        if self == ColorSpace::Xyz {
+            return DMat3::IDENTITY;
+        }
+
+        let [r, g, b, w] = self.chromaticities();
+        let primaries = DMat3::from_cols(xy_to_xyz(r), xy_to_xyz(g), xy_to_xyz(b));
+        // scale the primaries so that rgb (1, 1, 1) lands on the white point
+        let scale = primaries.inverse() * xy_to_xyz(w);
+        primaries * DMat3::from_diagonal(scale)
+    }
+
+    /// Converts XYZ from the renderer into this space, adapting the render white to `white`
+    pub fn render_xyz_to_rgb(self, white: Chromaticity) -> DMat3 {
+        self.rgb_to_xyz().inverse() * bradford(RENDER_WHITE, white)
+    }
+
+    /// Converts this space adapted to `white` into linear Rec.709 adapted to D65
+    ///
+    /// The ACES and AgX tonemappers are fitted to Rec.709 input and go through this.
+    pub fn to_rec709(self, white: Chromaticity) -> DMat3 {
+        ColorSpace::Srgb.rgb_to_xyz().inverse() * bradford(white, D65) * self.rgb_to_xyz()
+    }
+}
+
+/// Parses a named white point, one of d50, d55, d60, d65 or e
+pub fn parse_white_point(name: &str) -> Result<Chromaticity> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "d50" => D50,
+        "d55" => D55,
+        "d60" => D60,
+        "d65" => D65,
+        "e" => E,
+        _ => bail!("white point must be one of \"d50\", \"d55\", \"d60\", \"d65\" or \"e\""),
+    })
+}
+
+// XYZ with Y = 1 of a chromaticity
+fn xy_to_xyz([x, y]: Chromaticity) -> DVec3 {
+    DVec3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// Bradford chromatic adaptation from the `src` white to the `dst` white
+pub fn bradford(src: Chromaticity, dst: Chromaticity) -> DMat3 {
+    let src_cone = BRADFORD * xy_to_xyz(src);
+    let dst_cone = BRADFORD * xy_to_xyz(dst);
+    BRADFORD.inverse() * DMat3::from_diagonal(dst_cone / src_cone) * BRADFORD
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{DMat3, DVec3};
+
+    use super::{bradford, ColorSpace, D50, D60, D65};
+
+    fn assert_close(a: DMat3, b: DMat3, tolerance: f64) {
+        let (a, b) = (a.to_cols_array(), b.to_cols_array());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < tolerance, "{a:?} != {b:?}");
+        }
+    }
+
+    #[test]
+    fn color_space_matrices() {
+        // the usual sRGB to XYZ matrix
+        let srgb_to_xyz = DMat3::from_cols(
+            DVec3::new(0.4124564, 0.2126729, 0.0193339),
+            DVec3::new(0.3575761, 0.7151522, 0.1191920),
+            DVec3::new(0.1804375, 0.0721750, 0.9503041),
+        );
+        assert_close(ColorSpace::Srgb.rgb_to_xyz(), srgb_to_xyz, 5e-4);
+
+        // the D65 to D50 Bradford matrix from Lindbloom
+        let d65_to_d50 = DMat3::from_cols(
+            DVec3::new(1.0478112, 0.0295424, -0.0092345),
+            DVec3::new(0.0228866, 0.9904844, 0.0150436),
+            DVec3::new(-0.0501270, -0.0170491, 0.7521316),
+        );
+        assert_close(bradford(D65, D50), d65_to_d50, 5e-4);
+
+        assert_close(
+            ColorSpace::Xyz.render_xyz_to_rgb(D65),
+            DMat3::IDENTITY,
+            1e-12,
+        );
+
+        // the render white maps to rgb white in every space with its default white
+        for space in [
+            ColorSpace::Srgb,
+            ColorSpace::Rec2020,
+            ColorSpace::DisplayP3,
+            ColorSpace::AcesCg,
+            ColorSpace::Aces2065_1,
+        ] {
+            let white = DVec3::new(0.3127 / 0.329, 1.0, (1.0 - 0.3127 - 0.329) / 0.329);
+            let rgb = space.render_xyz_to_rgb(space.default_white()) * white;
+            assert!(
+                (rgb - DVec3::ONE).abs().max_element() < 1e-6,
+                "{space:?}: {rgb}"
+            );
+
+            // and stays white on the way to the tonemappers, whatever the output white
+            for white in [space.default_white(), D50, D60] {
+                let to_rec709 = space.to_rec709(white) * space.render_xyz_to_rgb(white);
+                let rgb = to_rec709 * DVec3::new(0.3127 / 0.329, 1.0, 0.3583 / 0.329);
+                assert!(
+                    (rgb - DVec3::ONE).abs().max_element() < 1e-6,
+                    "{space:?}: {rgb}"
+                );
+            }
+        }
+        assert_close(ColorSpace::Srgb.to_rec709(D65), DMat3::IDENTITY, 1e-12);
+    }
+}
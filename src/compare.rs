@@ -0,0 +1,467 @@
+use std::{f32::consts::PI, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use glam::{Mat3, Vec3};
+use image::DynamicImage;
+
+use crate::{
+    color::ColorSpace,
+    output::{read_pfm, ImageFormat},
+};
+
+// keeps dark pixels from dominating the relative error
+const RELATIVE_EPSILON: f32 = 1e-2;
+
+/// Pixels per degree of a 0.7 m wide 4K monitor seen from 0.7 m, the FLIP default
+pub const DEFAULT_PIXELS_PER_DEGREE: f32 = 0.7 * 3840.0 / 0.7 * PI / 180.0;
+
+// FLIP parameters, see "FLIP: A Difference Evaluator for Alternating Images" (Andersson et al.)
+const FLIP_QC: f32 = 0.7;
+const FLIP_QF: f32 = 0.5;
+const FLIP_PC: f32 = 0.4;
+const FLIP_PT: f32 = 0.95;
+// peak to trough width of the edge detector in degrees
+const FLIP_FEATURE_WIDTH: f32 = 0.082;
+// contrast sensitivity of the achromatic and the two opponent channels, as (a1, b1, a2, b2) of
+// a sum of two gaussians
+const FLIP_CSF: [[f32; 4]; 3] = [
+    [1.0, 0.0047, 0.0, 1e-5],
+    [1.0, 0.0053, 0.0, 1e-5],
+    [34.1, 0.04, 13.5, 0.025],
+];
+
+// stops of the magma colour map, sRGB encoded
+const MAGMA: [[f32; 3]; 9] = [
+    [0.001, 0.000, 0.014],
+    [0.079, 0.054, 0.212],
+    [0.232, 0.060, 0.438],
+    [0.390, 0.100, 0.502],
+    [0.550, 0.161, 0.506],
+    [0.716, 0.215, 0.475],
+    [0.869, 0.288, 0.409],
+    [0.968, 0.440, 0.360],
+    [0.987, 0.991, 0.750],
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metrics {
+    pub mse: f32,
+    pub relative_mse: f32,
+    /// in dB against a peak of 1, infinite for identical images
+    pub psnr: f32,
+    pub ssim: f32,
+    pub flip: f32,
+}
+
+pub struct Comparison {
+    pub metrics: Metrics,
+    /// per pixel FLIP error in [0, 1], top row first
+    pub flip_map: Vec<f32>,
+}
+
+/// Reads an image as linear RGBA, 8 and 16 bit images are assumed to be sRGB encoded
+pub fn read_image(path: &Path) -> Result<((u32, u32), Vec<f32>)> {
+    if ImageFormat::from_path(path, false) == ImageFormat::Pfm {
+        return read_pfm(path);
+    }
+
+    let image = image::open(path).map_err(|e| anyhow!("couldn't read {}: {e}", path.display()))?;
+    // float images hold the linear accumulation, everything else went through the display curve
+    let linear = matches!(
+        image,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    );
+    let size = (image.width(), image.height());
+    let mut pixels = image.into_rgba32f().into_raw();
+    if !linear {
+        for pixel in pixels.chunks_mut(4) {
+            pixel[..3].iter_mut().for_each(|c| *c = decode_srgb(*c));
+        }
+    }
+    Ok((size, pixels))
+}
+
+/// Compares linear RGBA images, MSE and PSNR look at the linear values, SSIM and FLIP at the
+/// values clamped to [0, 1] as they would be shown on an sRGB display
+pub fn compare(
+    (width, height): (u32, u32),
+    reference: &[f32],
+    test: &[f32],
+    pixels_per_degree: f32,
+) -> Result<Comparison> {
+    let size = (width as usize, height as usize);
+    if reference.len() != size.0 * size.1 * 4 || test.len() != reference.len() {
+        bail!("images need to be {width}x{height} RGBA");
+    }
+
+    let mse = reference
+        .chunks(4)
+        .zip(test.chunks(4))
+        .flat_map(|(a, b)| (0..3).map(move |c| (a[c] - b[c]).powi(2)))
+        .sum::<f32>()
+        / (size.0 * size.1 * 3) as f32;
+    let flip_map = flip(size, reference, test, pixels_per_degree);
+    let flip = flip_map.iter().sum::<f32>() / flip_map.len() as f32;
+
+    Ok(Comparison {
+        metrics: Metrics {
+            mse,
+            relative_mse: relative_mse(test, reference),
+            psnr: -10.0 * mse.log10(),
+            ssim: ssim(size, reference, test),
+            flip,
+        },
+        flip_map,
+    })
+}
+
+/// Mean of the squared error over the squared reference, over the RGB channels
+pub fn relative_mse(test: &[f32], reference: &[f32]) -> f32 {
+    let squared_error: f32 = test
+        .chunks(4)
+        .zip(reference.chunks(4))
+        .flat_map(|(a, b)| {
+            (0..3).map(move |c| (a[c] - b[c]).powi(2) / (b[c] * b[c] + RELATIVE_EPSILON))
+        })
+        .sum();
+    squared_error / (reference.len() / 4 * 3) as f32
+}
+
+/// Maps errors in [0, 1] to linear RGBA in the magma colour map
+pub fn false_color(errors: &[f32]) -> Vec<f32> {
+    let mut pixels = Vec::with_capacity(errors.len() * 4);
+    for &error in errors {
+        let t = error.clamp(0.0, 1.0) * (MAGMA.len() - 1) as f32;
+        let i = (t as usize).min(MAGMA.len() - 2);
+        let color = Vec3::from(MAGMA[i]).lerp(Vec3::from(MAGMA[i + 1]), t - i as f32);
+        pixels.extend(color.to_array().map(decode_srgb));
+        pixels.push(1.0);
+    }
+    pixels
+}
+
+fn decode_srgb(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn encode_srgb(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// channel c of RGBA pixels
+fn plane(pixels: &[f32], c: usize, f: impl Fn(f32) -> f32) -> Vec<f32> {
+    pixels.chunks(4).map(|p| f(p[c])).collect()
+}
+
+// correlates a channel with `kx` along rows and `ky` along columns, repeating the edge pixels,
+// both kernels are centered and of odd length
+fn convolve((width, height): (usize, usize), plane: &[f32], kx: &[f32], ky: &[f32]) -> Vec<f32> {
+    let sample = |i: isize, len: usize| i.clamp(0, len as isize - 1) as usize;
+    let (rx, ry) = ((kx.len() / 2) as isize, (ky.len() / 2) as isize);
+
+    let mut rows = vec![0.0; plane.len()];
+    for y in 0..height {
+        let row = &plane[y * width..(y + 1) * width];
+        for x in 0..width {
+            rows[y * width + x] = kx
+                .iter()
+                .enumerate()
+                .map(|(i, k)| k * row[sample(x as isize + i as isize - rx, width)])
+                .sum();
+        }
+    }
+
+    let mut out = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            out[y * width + x] = ky
+                .iter()
+                .enumerate()
+                .map(|(i, k)| k * rows[sample(y as isize + i as isize - ry, height) * width + x])
+                .sum();
+        }
+    }
+    out
+}
+
+// mean SSIM of the display encoded RGB channels, with the usual 11x11 gaussian window
+fn ssim(size: (usize, usize), reference: &[f32], test: &[f32]) -> f32 {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    let window: Vec<f32> = (-5..=5)
+        .map(|x: i32| (-(x * x) as f32 / (2.0 * 1.5 * 1.5)).exp())
+        .collect();
+    let total: f32 = window.iter().sum();
+    let window: Vec<f32> = window.iter().map(|w| w / total).collect();
+    let blur = |plane: &[f32]| convolve(size, plane, &window, &window);
+
+    let mut sum = 0.0;
+    for c in 0..3 {
+        let a = plane(reference, c, encode_srgb);
+        let b = plane(test, c, encode_srgb);
+        let product =
+            |x: &[f32], y: &[f32]| -> Vec<f32> { x.iter().zip(y).map(|(x, y)| x * y).collect() };
+        let (mean_a, mean_b) = (blur(&a), blur(&b));
+        let (aa, bb, ab) = (
+            blur(&product(&a, &a)),
+            blur(&product(&b, &b)),
+            blur(&product(&a, &b)),
+        );
+        for i in 0..a.len() {
+            let (ma, mb) = (mean_a[i], mean_b[i]);
+            let (va, vb, cov) = (aa[i] - ma * ma, bb[i] - mb * mb, ab[i] - ma * mb);
+            sum += (2.0 * ma * mb + C1) * (2.0 * cov + C2)
+                / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+        }
+    }
+    sum / (3 * size.0 * size.1) as f32
+}
+
+// LDR-FLIP of the clamped linear values, per pixel
+fn flip(size: (usize, usize), reference: &[f32], test: &[f32], pixels_per_degree: f32) -> Vec<f32> {
+    let rgb_to_xyz = ColorSpace::Srgb.rgb_to_xyz().as_mat3();
+    let xyz_to_rgb = rgb_to_xyz.inverse();
+    let white = rgb_to_xyz * Vec3::ONE;
+
+    let to_ycxcz = |pixels: &[f32]| -> [Vec<f32>; 3] {
+        let xyz: Vec<Vec3> = pixels
+            .chunks(4)
+            .map(|p| rgb_to_xyz * Vec3::new(p[0], p[1], p[2]).clamp(Vec3::ZERO, Vec3::ONE) / white)
+            .collect();
+        [
+            xyz.iter().map(|v| 116.0 * v.y - 16.0).collect(),
+            xyz.iter().map(|v| 500.0 * (v.x - v.y)).collect(),
+            xyz.iter().map(|v| 200.0 * (v.y - v.z)).collect(),
+        ]
+    };
+    let (reference, test) = (to_ycxcz(reference), to_ycxcz(test));
+
+    // color pipeline, blur by the contrast sensitivity and compare in a Hunt adjusted Lab
+    let filters = FLIP_CSF.map(|csf| spatial_filter(csf, pixels_per_degree));
+    let perceived = |ycxcz: &[Vec<f32>; 3]| -> Vec<Vec3> {
+        let [y, cx, cz] = [0, 1, 2].map(|c| {
+            filters[c]
+                .iter()
+                .map(|(weight, kernel)| (weight, convolve(size, &ycxcz[c], kernel, kernel)))
+                .fold(vec![0.0; ycxcz[c].len()], |mut sum, (weight, filtered)| {
+                    sum.iter_mut()
+                        .zip(filtered)
+                        .for_each(|(s, f)| *s += weight * f);
+                    sum
+                })
+        });
+        (0..y.len())
+            .map(|i| {
+                let luminance = (y[i] + 16.0) / 116.0;
+                let xyz = Vec3::new(
+                    luminance + cx[i] / 500.0,
+                    luminance,
+                    luminance - cz[i] / 200.0,
+                );
+                let rgb = (xyz_to_rgb * (xyz * white)).clamp(Vec3::ZERO, Vec3::ONE);
+                hunt_lab(rgb_to_xyz, white, rgb)
+            })
+            .collect()
+    };
+    let (reference_lab, test_lab) = (perceived(&reference), perceived(&test));
+    let max_error = hyab(
+        hunt_lab(rgb_to_xyz, white, Vec3::Y),
+        hunt_lab(rgb_to_xyz, white, Vec3::Z),
+    )
+    .powf(FLIP_QC);
+
+    // feature pipeline, edges and points in the achromatic channel
+    let features = |ycxcz: &[Vec<f32>; 3]| {
+        let luminance: Vec<f32> = ycxcz[0].iter().map(|y| (y + 16.0) / 116.0).collect();
+        let [edge, point] = feature_filters(pixels_per_degree);
+        let magnitude = |(derivative, gaussian): &(Vec<f32>, Vec<f32>)| -> Vec<f32> {
+            let dx = convolve(size, &luminance, derivative, gaussian);
+            let dy = convolve(size, &luminance, gaussian, derivative);
+            dx.iter().zip(dy).map(|(x, y)| x.hypot(y)).collect()
+        };
+        (magnitude(&edge), magnitude(&point))
+    };
+    let ((reference_edges, reference_points), (test_edges, test_points)) =
+        (features(&reference), features(&test));
+
+    (0..reference_lab.len())
+        .map(|i| {
+            let color = hyab(reference_lab[i], test_lab[i]).powf(FLIP_QC);
+            let low = FLIP_PC * max_error;
+            let color = if color < low {
+                FLIP_PT / low * color
+            } else {
+                FLIP_PT + (color - low) / (max_error - low) * (1.0 - FLIP_PT)
+            };
+            let feature = (reference_edges[i] - test_edges[i])
+                .abs()
+                .max((reference_points[i] - test_points[i]).abs());
+            let feature = (feature / 2f32.sqrt()).powf(FLIP_QF).clamp(0.0, 1.0);
+            color.powf(1.0 - feature)
+        })
+        .collect()
+}
+
+// the contrast sensitivity filter as weighted separable gaussians, applied along both axes
+fn spatial_filter([a1, b1, a2, b2]: [f32; 4], pixels_per_degree: f32) -> Vec<(f32, Vec<f32>)> {
+    // wide enough for the widest gaussian of any channel, like the reference implementation
+    let widest = FLIP_CSF
+        .iter()
+        .flatten()
+        .skip(1)
+        .step_by(2)
+        .fold(0.0f32, |a, &b| a.max(b));
+    let radius = (3.0 * (widest / (2.0 * PI * PI)).sqrt() * pixels_per_degree).ceil() as i32;
+
+    let terms: Vec<_> = [(a1, b1), (a2, b2)]
+        .into_iter()
+        .filter(|&(a, _)| a > 0.0)
+        .map(|(a, b)| {
+            let kernel: Vec<f32> = (-radius..=radius)
+                .map(|x| (-PI * PI * (x as f32 / pixels_per_degree).powi(2) / b).exp())
+                .collect();
+            (a * (PI / b).sqrt(), kernel)
+        })
+        .collect();
+    // normalize the 2D filter to sum to one
+    let total: f32 = terms
+        .iter()
+        .map(|(weight, kernel)| weight * kernel.iter().sum::<f32>().powi(2))
+        .sum();
+    terms
+        .into_iter()
+        .map(|(weight, kernel)| (weight / total, kernel))
+        .collect()
+}
+
+// (derivative, gaussian) pairs of the edge and point detectors, the derivative goes along the
+// axis the feature is detected on
+fn feature_filters(pixels_per_degree: f32) -> [(Vec<f32>, Vec<f32>); 2] {
+    let sd = 0.5 * FLIP_FEATURE_WIDTH * pixels_per_degree;
+    let radius = (3.0 * sd).ceil() as i32;
+    let gaussian: Vec<f32> = (-radius..=radius)
+        .map(|x| (-(x * x) as f32 / (2.0 * sd * sd)).exp())
+        .collect();
+    let total: f32 = gaussian.iter().sum();
+    let gaussian: Vec<f32> = gaussian.iter().map(|g| g / total).collect();
+
+    // positive weights sum to 1 and negative weights to -1
+    let normalize = |kernel: Vec<f32>| -> Vec<f32> {
+        let positive: f32 = kernel.iter().filter(|&&k| k > 0.0).sum();
+        let negative: f32 = -kernel.iter().filter(|&&k| k < 0.0).sum::<f32>();
+        kernel
+            .iter()
+            .map(|&k| if k < 0.0 { k / negative } else { k / positive })
+            .collect()
+    };
+    let first = (-radius..=radius)
+        .zip(&gaussian)
+        .map(|(x, g)| -(x as f32) * g)
+        .collect();
+    let second = (-radius..=radius)
+        .zip(&gaussian)
+        .map(|(x, g)| ((x * x) as f32 / (sd * sd) - 1.0) * g)
+        .collect();
+    [
+        (normalize(first), gaussian.clone()),
+        (normalize(second), gaussian),
+    ]
+}
+
+// CIELAB with the chroma scaled by the lightness
+fn hunt_lab(rgb_to_xyz: Mat3, white: Vec3, rgb: Vec3) -> Vec3 {
+    let f = |t: f32| {
+        const DELTA: f32 = 6.0 / 29.0;
+        if t > DELTA.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+        }
+    };
+    let xyz = rgb_to_xyz * rgb / white;
+    let (fx, fy, fz) = (f(xyz.x), f(xyz.y), f(xyz.z));
+    let l = 116.0 * fy - 16.0;
+    Vec3::new(
+        l,
+        0.01 * l * 500.0 * (fx - fy),
+        0.01 * l * 200.0 * (fy - fz),
+    )
+}
+
+fn hyab(a: Vec3, b: Vec3) -> f32 {
+    let d = a - b;
+    d.x.abs() + d.y.hypot(d.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, false_color, Metrics, DEFAULT_PIXELS_PER_DEGREE};
+
+    const SIZE: usize = 32;
+
+    // squares big enough to survive the FLIP blur
+    fn checkerboard(dark: f32, bright: f32) -> Vec<f32> {
+        (0..SIZE * SIZE)
+            .flat_map(|i| {
+                let v = if (i % SIZE / 8 + i / SIZE / 8).is_multiple_of(2) {
+                    dark
+                } else {
+                    bright
+                };
+                [v, v, v, 1.0]
+            })
+            .collect()
+    }
+
+    fn metrics(reference: &[f32], test: &[f32]) -> Metrics {
+        let size = (SIZE as u32, SIZE as u32);
+        compare(size, reference, test, DEFAULT_PIXELS_PER_DEGREE)
+            .unwrap()
+            .metrics
+    }
+
+    #[test]
+    fn identical_images() {
+        let image = checkerboard(0.1, 0.8);
+        let metrics = metrics(&image, &image);
+        assert_eq!(metrics.mse, 0.0);
+        assert_eq!(metrics.relative_mse, 0.0);
+        assert_eq!(metrics.psnr, f32::INFINITY);
+        assert!((metrics.ssim - 1.0).abs() < 1e-4, "{metrics:?}");
+        assert!(metrics.flip < 1e-4, "{metrics:?}");
+    }
+
+    #[test]
+    fn errors_grow_with_the_difference() {
+        let reference = checkerboard(0.1, 0.8);
+        let close = metrics(&reference, &checkerboard(0.12, 0.8));
+        let far = metrics(&reference, &checkerboard(0.8, 0.1));
+        assert!(close.mse < far.mse);
+        assert!(close.relative_mse < far.relative_mse);
+        assert!(close.psnr > far.psnr);
+        assert!(close.ssim > far.ssim, "{close:?} {far:?}");
+        assert!(close.flip < far.flip, "{close:?} {far:?}");
+
+        // white against black is past the point where errors are compressed
+        let white = [1.0; SIZE * SIZE * 4];
+        let black = [0.0, 0.0, 0.0, 1.0].repeat(SIZE * SIZE);
+        let metrics = metrics(&white, &black);
+        assert!(metrics.flip > 0.95, "{metrics:?}");
+    }
+
+    #[test]
+    fn false_color_ends() {
+        let pixels = false_color(&[0.0, 1.0]);
+        assert!(pixels[..3].iter().all(|&c| c < 0.01));
+        assert!(pixels[4..7].iter().all(|&c| c > 0.5));
+    }
+}
@@ -1,15 +1,65 @@
 use anyhow::Result;
-use std::ffi::{c_void, CStr};
+use std::ffi::{c_void, CStr, CString};
 
 use ash::{
     ext,
     vk::{
-        self, DebugUtilsMessageSeverityFlagsEXT, DebugUtilsMessageTypeFlagsEXT,
+        self, DebugUtilsLabelEXT, DebugUtilsMessageSeverityFlagsEXT, DebugUtilsMessageTypeFlagsEXT,
         DebugUtilsMessengerCallbackDataEXT, DebugUtilsMessengerCreateInfoEXT,
-        DebugUtilsMessengerEXT,
+        DebugUtilsMessengerEXT, DebugUtilsObjectNameInfoEXT, Handle,
     },
+    Device, Instance,
 };
-use log::{debug, error, info, warn};
+use log::{error, info, log_enabled, trace, warn, Level};
+
+// log target of shader debugPrintfEXT output, e.g. RUST_LOG=shader=info
+const SHADER_LOG_TARGET: &str = "shader";
+
+/// Validation layer features, independent of the build profile
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DebugOptions {
+    pub validation: bool,
+    // instruments shaders to catch out of bounds accesses and the like
+    pub gpu_assisted: bool,
+    // routes debugPrintfEXT output from shaders to the log
+    pub debug_printf: bool,
+}
+
+impl DebugOptions {
+    /// Whether the validation layer and debug messenger are needed
+    pub fn enabled(&self) -> bool {
+        self.validation || self.gpu_assisted || self.debug_printf
+    }
+
+    pub fn validation_features(&self) -> Vec<vk::ValidationFeatureEnableEXT> {
+        let mut features = vec![
+            vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION,
+            vk::ValidationFeatureEnableEXT::BEST_PRACTICES,
+        ];
+        if self.gpu_assisted {
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT);
+        }
+        if self.debug_printf {
+            features.push(vk::ValidationFeatureEnableEXT::DEBUG_PRINTF);
+        }
+        features
+    }
+}
+
+/// The severities the log would show, the rest aren't asked for
+pub fn message_severity() -> DebugUtilsMessageSeverityFlagsEXT {
+    let mut severity =
+        DebugUtilsMessageSeverityFlagsEXT::ERROR | DebugUtilsMessageSeverityFlagsEXT::WARNING;
+    // debug printf output comes in as info
+    if log_enabled!(Level::Info) || log_enabled!(target: SHADER_LOG_TARGET, Level::Info) {
+        severity |= DebugUtilsMessageSeverityFlagsEXT::INFO;
+    }
+    if log_enabled!(Level::Trace) {
+        severity |= DebugUtilsMessageSeverityFlagsEXT::VERBOSE;
+    }
+    severity
+}
 
 pub struct DebugUtilsData {
     loader: ext::debug_utils::Instance,
@@ -25,6 +75,13 @@ impl DebugUtilsData {
 
         Ok(Self { loader, messenger })
     }
+
+    /// Object names and command buffer labels for a device of this instance
+    pub fn names(&self, instance: &Instance, device: &Device) -> DebugNames {
+        DebugNames {
+            device: Some(ext::debug_utils::Device::new(instance, device)),
+        }
+    }
 }
 
 impl Drop for DebugUtilsData {
@@ -36,6 +93,45 @@ impl Drop for DebugUtilsData {
     }
 }
 
+/// Names objects and labels command buffers so validation messages and captures show them,
+/// does nothing without debug utils
+#[derive(Clone, Default)]
+pub struct DebugNames {
+    device: Option<ext::debug_utils::Device>,
+}
+
+impl DebugNames {
+    pub fn name<H: Handle>(&self, handle: H, name: &str) {
+        let Some(device) = &self.device else {
+            return;
+        };
+        // names only help debugging, one that can't be passed on is dropped
+        let Ok(name) = CString::new(name) else {
+            return;
+        };
+        let name_info = DebugUtilsObjectNameInfoEXT::default()
+            .object_handle(handle)
+            .object_name(&name);
+        if let Err(e) = unsafe { device.set_debug_utils_object_name(&name_info) } {
+            warn!("failed to name {:?}: {e}", H::TYPE);
+        }
+    }
+
+    /// Opens a region of the command buffer, closed by end_label
+    pub unsafe fn begin_label(&self, command_buffer: vk::CommandBuffer, name: &CStr) {
+        if let Some(device) = &self.device {
+            let label = DebugUtilsLabelEXT::default().label_name(name);
+            device.cmd_begin_debug_utils_label(command_buffer, &label);
+        }
+    }
+
+    pub unsafe fn end_label(&self, command_buffer: vk::CommandBuffer) {
+        if let Some(device) = &self.device {
+            device.cmd_end_debug_utils_label(command_buffer);
+        }
+    }
+}
+
 pub unsafe extern "system" fn debug_callback(
     severity: DebugUtilsMessageSeverityFlagsEXT,
     msg_type: DebugUtilsMessageTypeFlagsEXT,
@@ -48,6 +144,16 @@ pub unsafe extern "system" fn debug_callback(
     }
 
     let message = CStr::from_ptr(callback_data.p_message).to_string_lossy();
+    let message_id = if callback_data.p_message_id_name.is_null() {
+        Default::default()
+    } else {
+        CStr::from_ptr(callback_data.p_message_id_name).to_string_lossy()
+    };
+
+    if message_id.contains("DEBUG-PRINTF") {
+        info!(target: SHADER_LOG_TARGET, "{}", debug_printf_output(&message));
+        return vk::FALSE;
+    }
 
     // go in order of priority
     if severity.contains(DebugUtilsMessageSeverityFlagsEXT::ERROR) {
@@ -57,10 +163,48 @@ pub unsafe extern "system" fn debug_callback(
     } else if severity.contains(DebugUtilsMessageSeverityFlagsEXT::INFO) {
         info!("({:?}) {}", msg_type, message);
     } else if severity.contains(DebugUtilsMessageSeverityFlagsEXT::VERBOSE) {
-        debug!("({:?}) {}", msg_type, message);
+        trace!("({:?}) {}", msg_type, message);
     } else {
         info!("(UNKNOWN_SEVERITY) ({:?}) {}", msg_type, message);
     }
 
     vk::FALSE
 }
+
+// the printed text of a debug printf message, shaders print their own pixel
+//
+// layers that add the shader source location put the text on the last line
+fn debug_printf_output(message: &str) -> &str {
+    message
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or_default()
+        .trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_printf_messages() {
+        let verbose =
+            "Validation Information: [ WARNING-DEBUG-PRINTF ] | MessageID = 0x76589099 | \
+            vkQueueSubmit(): Command buffer (0x5555) Pipeline (0x66) Shader Module (0x77) \
+            Stage = Raygen. Launch ID (x, y, z) = (12, 34, 0)\n\
+            Debug shader printf message generated at line 368\n\
+            \n\
+            368:         debugPrintfEXT(\"(%u, %u) non-finite sample %v3f\", ...);\n\
+            (12, 34) non-finite sample nan, 0.50, 1.00\n";
+        assert_eq!(
+            debug_printf_output(verbose),
+            "(12, 34) non-finite sample nan, 0.50, 1.00"
+        );
+
+        assert_eq!(
+            debug_printf_output("(0, 1) non-finite sample inf, 0, 0"),
+            "(0, 1) non-finite sample inf, 0, 0"
+        );
+    }
+}
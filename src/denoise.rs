@@ -0,0 +1,177 @@
+use glam::Vec3;
+
+use crate::scene::scenes::mesh::DenoiseSettings;
+
+// B3 spline weights for offsets 0, 1 and 2, keep in sync with denoise.comp
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// misses and emitters have no albedo, so they aren't demodulated
+const MIN_ALBEDO: f32 = 1e-3;
+
+/// Denoises linear RGBA pixels with an edge avoiding à-trous wavelet filter
+///
+/// `albedo` and `normal` are RGB guides with one entry per pixel. The albedo is divided out before
+/// filtering so texture detail isn't blurred away, and multiplied back in afterwards.
+/// This is the CPU counterpart of denoise.comp.
+pub fn denoise(
+    (width, height): (u32, u32),
+    pixels: &[f32],
+    albedo: &[f32],
+    normal: &[f32],
+    settings: &DenoiseSettings,
+) -> Vec<f32> {
+    let (width, height) = (width as usize, height as usize);
+    let rgb =
+        |data: &[f32], stride: usize, i: usize| Vec3::from_slice(&data[i * stride..i * stride + 3]);
+    let albedo: Vec<_> = (0..width * height).map(|i| rgb(albedo, 3, i)).collect();
+    let normal: Vec<_> = (0..width * height).map(|i| rgb(normal, 3, i)).collect();
+    let demodulation: Vec<_> = albedo
+        .iter()
+        .map(|a| Vec3::select(a.cmpgt(Vec3::splat(MIN_ALBEDO)), *a, Vec3::ONE))
+        .collect();
+
+    let mut current: Vec<_> = (0..width * height)
+        .map(|i| rgb(pixels, 4, i) / demodulation[i])
+        .collect();
+    let mut next = current.clone();
+
+    for iteration in 0..settings.iterations {
+        let step = 1 << iteration;
+        // later iterations see a smoother image, so the color falloff tightens
+        let color_phi = settings.color_phi / step as f32;
+
+        for y in 0..height {
+            for x in 0..width {
+                let p = y * width + x;
+                let mut sum = Vec3::ZERO;
+                let mut weight_sum = 0.0;
+
+                for dy in -2..=2isize {
+                    for dx in -2..=2isize {
+                        let qx = x as isize + dx * step;
+                        let qy = y as isize + dy * step;
+                        if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+
+                        let weight = KERNEL[dx.unsigned_abs()]
+                            * KERNEL[dy.unsigned_abs()]
+                            * edge_weight(current[p], current[q], color_phi)
+                            * edge_weight(normal[p], normal[q], settings.normal_phi)
+                            * edge_weight(albedo[p], albedo[q], settings.albedo_phi);
+                        sum += current[q] * weight;
+                        weight_sum += weight;
+                    }
+                }
+
+                // the center tap always has a weight, so this never divides by zero
+                next[p] = sum / weight_sum;
+            }
+        }
+
+        std::mem::swap(&mut current, &mut next);
+    }
+
+    current
+        .iter()
+        .zip(&demodulation)
+        .zip(pixels.chunks(4))
+        .flat_map(|((color, demodulation), pixel)| {
+            let color = *color * *demodulation;
+            [color.x, color.y, color.z, pixel[3]]
+        })
+        .collect()
+}
+
+fn edge_weight(a: Vec3, b: Vec3, phi: f32) -> f32 {
+    (-a.distance_squared(b) / (phi * phi)).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scene::scenes::mesh::DenoiseSettings;
+
+    use super::denoise;
+
+    const SIZE: usize = 32;
+
+    // deterministic noise in [-0.5, 0.5)
+    fn noise(i: usize) -> f32 {
+        (i.wrapping_mul(2654435761) % 1000) as f32 / 1000.0 - 0.5
+    }
+
+    fn variance(values: impl Iterator<Item = f32> + Clone) -> f32 {
+        let n = values.clone().count() as f32;
+        let mean = values.clone().sum::<f32>() / n;
+        values.map(|v| (v - mean) * (v - mean)).sum::<f32>() / n
+    }
+
+    #[test]
+    fn smooths_flat_regions() {
+        let pixels: Vec<f32> = (0..SIZE * SIZE)
+            .flat_map(|i| [0.5 + 0.4 * noise(i); 3].into_iter().chain([1.0]))
+            .collect();
+        let albedo = vec![0.5; SIZE * SIZE * 3];
+        let normal: Vec<f32> = [0.0, 0.0, 1.0].repeat(SIZE * SIZE);
+
+        let settings = DenoiseSettings::default();
+        let out = denoise(
+            (SIZE as u32, SIZE as u32),
+            &pixels,
+            &albedo,
+            &normal,
+            &settings,
+        );
+
+        let before = variance(pixels.iter().step_by(4).copied());
+        let after = variance(out.iter().step_by(4).copied());
+        assert!(
+            after < before / 10.0,
+            "variance went from {before} to {after}"
+        );
+        assert!(out.iter().skip(3).step_by(4).all(|&a| a == 1.0));
+    }
+
+    #[test]
+    fn keeps_guide_edges() {
+        // the left half is dark and faces x, the right half is bright and faces y
+        let left = |i: usize| i % SIZE < SIZE / 2;
+        let albedo: Vec<f32> = (0..SIZE * SIZE)
+            .flat_map(|i| [if left(i) { 0.2 } else { 0.8 }; 3])
+            .collect();
+        let normal: Vec<f32> = (0..SIZE * SIZE)
+            .flat_map(|i| {
+                if left(i) {
+                    [1.0, 0.0, 0.0]
+                } else {
+                    [0.0, 1.0, 0.0]
+                }
+            })
+            .collect();
+        let pixels: Vec<f32> = (0..SIZE * SIZE)
+            .flat_map(|i| {
+                [albedo[i * 3] * (1.0 + 0.4 * noise(i)); 3]
+                    .into_iter()
+                    .chain([1.0])
+            })
+            .collect();
+
+        let settings = DenoiseSettings::default();
+        let out = denoise(
+            (SIZE as u32, SIZE as u32),
+            &pixels,
+            &albedo,
+            &normal,
+            &settings,
+        );
+
+        // the columns on either side of the edge keep their own brightness
+        for y in 0..SIZE {
+            let dark = out[(y * SIZE + SIZE / 2 - 1) * 4];
+            let bright = out[(y * SIZE + SIZE / 2) * 4];
+            assert!((dark - 0.2).abs() < 0.05, "{dark}");
+            assert!((bright - 0.8).abs() < 0.1, "{bright}");
+        }
+    }
+}
@@ -3,16 +3,16 @@ use std::{
     ptr,
 };
 
-use ash::vk::{self, StructureType};
+use ash::vk::{self, ExtendsPhysicalDeviceFeatures2, StructureType, TaggedStructure};
 
 macro_rules! vk_features {
     (
         $first_struct:ty {
-            $($base_feature:ident),* $(,)?
+            $($(#[$base_marker:ident])? $base_feature:ident),* $(,)?
         }
         $(,
             $feature_struct:ty {
-                $($feature:ident),* $(,)?
+                $($(#[$marker:ident])? $feature:ident),* $(,)?
             }
         )*
         $(,)?
@@ -54,22 +54,29 @@ macro_rules! vk_features {
             }
 
             unsafe {
-                let mut features = Vec::new();
-                features.push(EnabledFeatures::new(
+                // only pushed to when there are extension structs
+                #[allow(unused_mut)]
+                let mut features = vec![EnabledFeatures::new(
                     vk::PhysicalDeviceFeatures2::STRUCTURE_TYPE,
                     Layout::new::<vk::PhysicalDeviceFeatures2>(),
-                    vec![$(
-                        offset_of!(vk::PhysicalDeviceFeatures2, features) + offset_of!($first_struct, $base_feature)
-                    ),*],
-                ));
+                    stringify!($first_struct),
+                    vec![$((
+                        offset_of!(vk::PhysicalDeviceFeatures2, features) + offset_of!($first_struct, $base_feature),
+                        stringify!($base_feature),
+                        false $(|| $crate::features::markers::$base_marker)?,
+                    )),*],
+                )];
 
                 $(
                     features.push(EnabledFeatures::new(
                         <$feature_struct as TaggedStructure>::STRUCTURE_TYPE,
                         Layout::new::<$feature_struct>(),
-                        vec![$(
-                            offset_of!($feature_struct, $feature)
-                        ),*]
+                        stringify!($feature_struct),
+                        vec![$((
+                            offset_of!($feature_struct, $feature),
+                            stringify!($feature),
+                            false $(|| $crate::features::markers::$marker)?,
+                        )),*]
                     ));
                 )*
 
@@ -81,6 +88,15 @@ macro_rules! vk_features {
 
 pub(crate) use vk_features;
 
+/// Markers for fields in `vk_features!`, e.g. `#[optional] ray_traversal_primitive_culling`
+///
+/// Unknown markers don't compile.
+#[allow(non_upper_case_globals)]
+pub mod markers {
+    // enabled if the device supports it, otherwise left out without making the device unsuitable
+    pub const optional: bool = true;
+}
+
 #[derive(Debug)]
 pub struct VkFeatureGuard<'a> {
     // static lifetime because this lives for as long as the struct does
@@ -88,13 +104,15 @@ pub struct VkFeatureGuard<'a> {
     parent: &'a VkFeatures,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EnabledFeatures {
     s_type: StructureType,
     layout: Layout,
+    // as written in the macro, used to name missing features
+    struct_name: &'static str,
 
-    // field offsets of enabled features
-    offsets: Vec<usize>,
+    // field offsets and names of enabled features, and whether they are optional
+    fields: Vec<(usize, &'static str, bool)>,
 }
 
 #[derive(Debug)]
@@ -106,13 +124,28 @@ impl EnabledFeatures {
     /// Create a new `EnabledFeatures`
     ///
     /// This should never be manually called - use the `vk_features!` macro instead.
-    pub unsafe fn new(s_type: StructureType, layout: Layout, offsets: Vec<usize>) -> Self {
+    pub unsafe fn new(
+        s_type: StructureType,
+        layout: Layout,
+        struct_name: &'static str,
+        fields: Vec<(usize, &'static str, bool)>,
+    ) -> Self {
         Self {
             s_type,
             layout,
-            offsets,
+            struct_name,
+            fields,
         }
     }
+
+    // struct name without the module path
+    fn short_name(&self) -> &'static str {
+        self.struct_name
+            .rsplit("::")
+            .next()
+            .unwrap_or(self.struct_name)
+            .trim()
+    }
 }
 
 impl VkFeatures {
@@ -130,11 +163,16 @@ impl VkFeatures {
 
 impl<'a> VkFeatureGuard<'a> {
     fn new(parent: &'a VkFeatures) -> Self {
+        Self::build(parent, |_, _| true)
+    }
+
+    // `set(i, j)` says whether field `j` of struct `i` is set to true
+    fn build(parent: &'a VkFeatures, set: impl Fn(usize, usize) -> bool) -> Self {
         // loop through each layout and create p_next linked list
         let mut head: *mut vk::PhysicalDeviceFeatures2 = ptr::null_mut();
         let mut curr = ptr::addr_of_mut!(head) as *mut *mut vk::BaseOutStructure;
 
-        for feature in &parent.features {
+        for (i, feature) in parent.features.iter().enumerate() {
             unsafe {
                 let layout = feature.layout;
                 let mem = alloc_zeroed(layout);
@@ -142,9 +180,11 @@ impl<'a> VkFeatureGuard<'a> {
                     alloc::handle_alloc_error(layout);
                 }
 
-                for &offset in &feature.offsets {
-                    let feature_ptr = mem.add(offset) as *mut vk::Bool32;
-                    feature_ptr.write(vk::TRUE);
+                for (j, &(offset, _, _)) in feature.fields.iter().enumerate() {
+                    if set(i, j) {
+                        let feature_ptr = mem.add(offset) as *mut vk::Bool32;
+                        feature_ptr.write(vk::TRUE);
+                    }
                 }
 
                 let struct_ptr = mem as *mut vk::BaseOutStructure;
@@ -161,49 +201,123 @@ impl<'a> VkFeatureGuard<'a> {
         VkFeatureGuard { head, parent }
     }
 
-    /// Gets an immutable reference to the underlying `PhysicalDeviceFeatures2` struct
-    pub fn get(&self) -> &vk::PhysicalDeviceFeatures2<'_> {
-        unsafe { &*self.head }
+    // the value of every field in the chain, indexed like the fields of the parent
+    fn values(&self) -> Vec<Vec<bool>> {
+        let mut values = Vec::new();
+        let mut curr = self.head as *mut vk::BaseOutStructure;
+        let mut all_features = self.parent.features.iter();
+        while !curr.is_null() {
+            let features = all_features.next().unwrap();
+
+            values.push(
+                features
+                    .fields
+                    .iter()
+                    .map(|&(offset, _, _)| {
+                        let feature_ptr = unsafe { curr.byte_add(offset) } as *mut vk::Bool32;
+                        unsafe { feature_ptr.read() == vk::TRUE }
+                    })
+                    .collect(),
+            );
+
+            curr = unsafe { (*curr).p_next };
+        }
+
+        // we should have gone through all features while iterating
+        assert!(all_features.next().is_none());
+        values
     }
 
-    /// Returns `true` if all features are supported, false otherwise
-    pub fn supported(&self, instance: &ash::Instance, device: vk::PhysicalDevice) -> bool {
-        // create copy of features list
+    // which of the listed fields the device supports, indexed like `values`
+    fn supported_values(
+        &self,
+        instance: &ash::Instance,
+        device: vk::PhysicalDevice,
+    ) -> Vec<Vec<bool>> {
         // this copy will be mutated, which breaks the invariant,
         // so we must make sure the user never sees it
-        let copy = self.clone();
+        let copy = Self::new(self.parent);
 
         // populate feature list with supported features
         unsafe { instance.get_physical_device_features2(device, &mut *copy.head) };
+        copy.values()
+    }
 
-        // check if all requested features are in the list
-        let mut curr = copy.head as *mut vk::BaseOutStructure;
-        let mut all_features = self.parent.features.iter();
-        while !curr.is_null() {
-            let features = all_features.next().unwrap();
+    /// Gets an immutable reference to the underlying `PhysicalDeviceFeatures2` struct
+    pub fn get(&self) -> &vk::PhysicalDeviceFeatures2<'_> {
+        unsafe { &*self.head }
+    }
 
-            for &offset in &features.offsets {
-                let feature_ptr = unsafe { curr.byte_add(offset) } as *mut vk::Bool32;
-                let supported = unsafe { feature_ptr.read() };
-                if supported == vk::FALSE {
-                    return false;
+    /// The struct of type `T` in the chain, None if no feature of it was listed
+    ///
+    /// Lists from `for_device` have exactly the features the device was created with set.
+    pub fn enabled<T: TaggedStructure + ExtendsPhysicalDeviceFeatures2>(&self) -> Option<&T> {
+        let mut curr = self.head as *const vk::BaseOutStructure;
+        while !curr.is_null() {
+            unsafe {
+                if (*curr).s_type == T::STRUCTURE_TYPE {
+                    return Some(&*(curr as *const T));
                 }
+                curr = (*curr).p_next;
             }
+        }
+        None
+    }
 
-            curr = unsafe { (*curr).p_next };
+    /// Names of the required features the device doesn't support, as `Struct::field`
+    pub fn missing(&self, instance: &ash::Instance, device: vk::PhysicalDevice) -> Vec<String> {
+        let set = self.values();
+        let supported = self.supported_values(instance, device);
+
+        let mut missing = Vec::new();
+        for (i, features) in self.parent.features.iter().enumerate() {
+            for (j, &(_, name, optional)) in features.fields.iter().enumerate() {
+                if set[i][j] && !optional && !supported[i][j] {
+                    missing.push(format!("{}::{name}", features.short_name()));
+                }
+            }
         }
+        missing
+    }
 
-        // we should have gone through all features while iterating
-        assert!(all_features.next().is_none());
-        true
+    /// The list to create `device` with, optional features it doesn't support are left out
+    pub fn for_device(&self, instance: &ash::Instance, device: vk::PhysicalDevice) -> Self {
+        let set = self.values();
+        let supported = self.supported_values(instance, device);
+        let features = &self.parent.features;
+        Self::build(self.parent, |i, j| {
+            let optional = features[i].fields[j].2;
+            set[i][j] && (supported[i][j] || !optional)
+        })
+    }
+
+    /// Every feature in either list, required if either requires it
+    ///
+    /// Merges the lists as they were declared, anything left out by `for_device` is back in.
+    pub fn merge(&self, other: &VkFeatureGuard<'_>) -> VkFeatures {
+        let mut features = self.parent.features.clone();
+        for theirs in &other.parent.features {
+            let Some(ours) = features.iter_mut().find(|f| f.s_type == theirs.s_type) else {
+                features.push(theirs.clone());
+                continue;
+            };
+            for &(offset, name, optional) in &theirs.fields {
+                match ours.fields.iter_mut().find(|f| f.0 == offset) {
+                    Some(field) => field.2 &= optional,
+                    None => ours.fields.push((offset, name, optional)),
+                }
+            }
+        }
+        VkFeatures { features }
     }
 }
 
 impl Clone for VkFeatureGuard<'_> {
     fn clone(&self) -> Self {
-        // initializing new from scratch is fine since we have invariant that user cannot modify anything in the chain
-        // therefore all features in the set (and only those features) should be set in this clone
-        Self::new(self.parent)
+        // the user can't modify the chain, so it only differs from the parent by what `for_device`
+        // left out
+        let set = self.values();
+        Self::build(self.parent, |i, j| set[i][j])
     }
 }
 
@@ -228,6 +342,8 @@ impl Drop for VkFeatureGuard<'_> {
 
 #[cfg(test)]
 mod tests {
+    use ash::vk;
+
     #[test]
     fn test_vkfeatures() {
         let features = vk_features! {
@@ -249,4 +365,79 @@ mod tests {
         println!("{:?}", features_to_pass_to_func);
         println!("{:?}", cloned);
     }
+
+    #[test]
+    fn feature_names() {
+        let features = vk_features! {
+            vk::PhysicalDeviceFeatures {
+                alpha_to_one,
+            },
+            ash::vk::PhysicalDeviceVulkan12Features {
+                buffer_device_address,
+            },
+        };
+
+        let names: Vec<_> = features
+            .features
+            .iter()
+            .flat_map(|f| f.fields.iter().map(|&(_, name, _)| (f.short_name(), name)))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("PhysicalDeviceFeatures", "alpha_to_one"),
+                ("PhysicalDeviceVulkan12Features", "buffer_device_address"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_lists() {
+        let renderer = vk_features! {
+            vk::PhysicalDeviceFeatures {
+                #[optional] shader_int64,
+            },
+            vk::PhysicalDeviceVulkan12Features {
+                buffer_device_address,
+                #[optional] timeline_semaphore,
+            },
+        };
+        let window = vk_features! {
+            vk::PhysicalDeviceFeatures {
+                shader_int64,
+                alpha_to_one,
+            },
+            vk::PhysicalDeviceVulkan13Features {
+                #[optional] synchronization2,
+            },
+        };
+
+        // required on either side makes it required
+        let merged = renderer.get_list().merge(&window.get_list());
+        let fields: Vec<_> = merged
+            .features
+            .iter()
+            .flat_map(|f| f.fields.iter().map(|&(_, name, optional)| (name, optional)))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("shader_int64", false),
+                ("alpha_to_one", false),
+                ("buffer_device_address", false),
+                ("timeline_semaphore", true),
+                ("synchronization2", true),
+            ]
+        );
+
+        let list = merged.get_list().clone();
+        assert_eq!(list.get().features.shader_int64, vk::TRUE);
+        let vulkan13 = list
+            .enabled::<vk::PhysicalDeviceVulkan13Features>()
+            .unwrap();
+        assert_eq!(vulkan13.synchronization2, vk::TRUE);
+        assert!(list
+            .enabled::<vk::PhysicalDeviceVulkan11Features>()
+            .is_none());
+    }
 }
@@ -0,0 +1,269 @@
+// golden image tests, every bundled scene is rendered on the CPU and compared to a stored reference
+//
+// the references live in resources/golden, rerun with KG_UPDATE_GOLDEN=1 to rewrite them after a
+// change that is meant to alter the images. failures write the render and a diff to target/golden
+//
+// the raytracing renderer is checked against the same references on lavapipe. that test needs the
+// shaders built with make and a software Vulkan device, so it is ignored by default, run it with
+// cargo test -- --ignored
+
+use std::{
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    color::ColorSpace,
+    compare::{read_image, relative_mse},
+    debug::DebugNames,
+    headless::HeadlessContext,
+    output::{write_display_image, write_linear_image, ImageFormat},
+    render::{
+        renderers::{CpuRenderer, RaytraceRenderer},
+        Renderer,
+    },
+    scene::scenes::mesh::MeshScene,
+    utils::DeviceSelector,
+    window::WindowData,
+};
+
+const SCENES_DIR: &str = "resources/scenes";
+const GOLDEN_DIR: &str = "resources/golden";
+const DIFF_DIR: &str = "target/golden";
+const UPDATE_VAR: &str = "KG_UPDATE_GOLDEN";
+// lavapipe reports itself as llvmpipe
+const SOFTWARE_DEVICE: &str = "llvmpipe";
+
+// scenes whose meshes aren't checked in
+const SKIPPED: &[&str] = &["ajax-point.toml"];
+
+const SIZE: (u32, u32) = (32, 24);
+const SEED: u64 = 0x6b67;
+const SAMPLES_PER_FRAME: u32 = 4;
+const FRAMES: u32 = 2;
+
+// keeps dark pixels from dominating the relative error
+const EPSILON: f32 = 1e-2;
+// a pixel differs when any channel is off by more than this, relative to the reference
+const PIXEL_TOLERANCE: f32 = 0.05;
+// share of pixels that may differ, floating point differences between platforms can change a
+// few paths and the noise makes those pixels differ entirely
+const MAX_DIFFERING_PIXELS: f32 = 0.02;
+const MAX_RELATIVE_MSE: f32 = 1e-3;
+
+#[derive(Debug)]
+struct Difference {
+    relative_mse: f32,
+    // share of pixels further apart than PIXEL_TOLERANCE
+    differing_pixels: f32,
+    // per pixel error relative to PIXEL_TOLERANCE, RGBA
+    diff_image: Vec<f32>,
+}
+
+impl Difference {
+    fn passes(&self) -> bool {
+        // written so NaNs fail
+        self.relative_mse <= MAX_RELATIVE_MSE && self.differing_pixels <= MAX_DIFFERING_PIXELS
+    }
+}
+
+fn compare(render: &[f32], reference: &[f32]) -> Difference {
+    let pixels = reference.len() / 4;
+    let mut differing = 0;
+    let mut diff_image = Vec::with_capacity(reference.len());
+    for (a, b) in render.chunks(4).zip(reference.chunks(4)) {
+        let mut max_error = 0.0f32;
+        for c in 0..3 {
+            let d = a[c] - b[c];
+            // NaN sticks
+            let error = d.abs() / (b[c].abs() + EPSILON);
+            max_error = if error.is_nan() {
+                error
+            } else {
+                max_error.max(error)
+            };
+        }
+        if max_error.is_nan() || max_error > PIXEL_TOLERANCE {
+            differing += 1;
+        }
+        // black where equal, red past the tolerance, NaNs show up white
+        let shade = max_error / PIXEL_TOLERANCE;
+        diff_image.extend(if shade.is_nan() {
+            [1.0; 4]
+        } else {
+            [
+                shade.min(1.0),
+                (shade - 1.0).clamp(0.0, 1.0) * 0.25,
+                0.0,
+                1.0,
+            ]
+        });
+    }
+
+    Difference {
+        relative_mse: relative_mse(render, reference),
+        differing_pixels: differing as f32 / pixels as f32,
+        diff_image,
+    }
+}
+
+fn render<R: Renderer<MeshScene, WindowData>>(
+    renderer: &mut R,
+    scene_path: &Path,
+) -> Result<(Vec<f32>, ColorSpace)> {
+    let mut scene = MeshScene::load_from(File::open(scene_path)?)?;
+    scene.output.resolution = Some(SIZE);
+    scene.sampling.samples_per_frame = SAMPLES_PER_FRAME;
+    scene.render.seed = Some(SEED);
+
+    renderer.ingest_scene(&scene, SIZE)?;
+    for _ in 0..FRAMES {
+        renderer.render_offscreen(&[])?;
+    }
+    Ok((renderer.read_back_linear()?, scene.output.color_space))
+}
+
+fn render_cpu(scene_path: &Path) -> Result<(Vec<f32>, ColorSpace)> {
+    render(&mut CpuRenderer::headless(), scene_path)
+}
+
+// a fresh renderer for every scene, dropped before the context
+fn render_raytrace(context: &HeadlessContext, scene_path: &Path) -> Result<(Vec<f32>, ColorSpace)> {
+    let mut renderer = RaytraceRenderer::new(
+        &context.vk_lib,
+        &context.instance,
+        &context.device,
+        context.physical_device,
+        &context.queue_family_info,
+        context.allocator().clone(),
+        DebugNames::default(),
+    )?;
+    render(&mut renderer, scene_path)
+}
+
+fn read_reference(path: &Path) -> Result<Vec<f32>> {
+    let (size, pixels) = read_image(path)?;
+    if size != SIZE {
+        return Err(anyhow!(
+            "{} is {}x{}, expected {}x{}",
+            path.display(),
+            size.0,
+            size.1,
+            SIZE.0,
+            SIZE.1
+        ));
+    }
+    Ok(pixels)
+}
+
+fn scene_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<_> = fs::read_dir(SCENES_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .filter(|path| !SKIPPED.iter().any(|skipped| path.ends_with(skipped)))
+        .collect();
+    paths.sort();
+    paths
+}
+
+// checks one scene, Err describes the failure
+//
+// `renderer` names the failure outputs, only the CPU renderer writes the references
+fn check_scene(
+    scene_path: &Path,
+    renderer: &str,
+    render: impl Fn(&Path) -> Result<(Vec<f32>, ColorSpace)>,
+    update: bool,
+) -> Result<()> {
+    let name = scene_path.file_stem().unwrap().to_string_lossy();
+    let reference_path = Path::new(GOLDEN_DIR).join(format!("{name}.exr"));
+    let (pixels, color_space) = render(scene_path)?;
+    let exr = ImageFormat::Exr { half: false };
+
+    if update {
+        fs::create_dir_all(GOLDEN_DIR)?;
+        return write_linear_image(&reference_path, SIZE, exr, color_space, &pixels, &[]);
+    }
+
+    let reference = read_reference(&reference_path)
+        .map_err(|e| anyhow!("{e}, run with {UPDATE_VAR}=1 to create it"))?;
+    let difference = compare(&pixels, &reference);
+    if difference.passes() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(DIFF_DIR)?;
+    let render_path = Path::new(DIFF_DIR).join(format!("{name}-{renderer}.exr"));
+    let diff_path = Path::new(DIFF_DIR).join(format!("{name}-{renderer}-diff.png"));
+    write_linear_image(&render_path, SIZE, exr, color_space, &pixels, &[])?;
+    write_display_image(&diff_path, SIZE, ColorSpace::Srgb, &difference.diff_image)?;
+    Err(anyhow!(
+        "relative mse {:.2e} (max {MAX_RELATIVE_MSE:.0e}), {:.1}% of pixels differ (max {:.1}%), \
+         see {} and {}",
+        difference.relative_mse,
+        difference.differing_pixels * 100.0,
+        MAX_DIFFERING_PIXELS * 100.0,
+        render_path.display(),
+        diff_path.display()
+    ))
+}
+
+// checks every scene, panicking with all the failures
+fn check_scenes(
+    renderer: &str,
+    render: impl Fn(&Path) -> Result<(Vec<f32>, ColorSpace)>,
+    update: bool,
+) {
+    let failures: Vec<_> = scene_paths()
+        .iter()
+        .filter_map(|path| {
+            check_scene(path, renderer, &render, update)
+                .err()
+                .map(|e| format!("{}: {e}", path.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn bundled_scenes_match_references() {
+    let update = env::var_os(UPDATE_VAR).is_some();
+    check_scenes("cpu", render_cpu, update);
+}
+
+#[test]
+#[ignore = "needs lavapipe and the shaders built with make"]
+fn raytraced_scenes_match_references() {
+    let selector = DeviceSelector::Name(SOFTWARE_DEVICE.to_string());
+    let context = HeadlessContext::new::<RaytraceRenderer>(Some(&selector))
+        .unwrap_or_else(|e| panic!("no usable software Vulkan device: {e}"));
+    check_scenes("raytrace", |path| render_raytrace(&context, path), false);
+}
+
+#[test]
+fn metric_tolerates_small_differences() {
+    let reference = [0.5, 0.25, 0.0, 1.0].repeat(100);
+    assert!(compare(&reference, &reference).passes());
+    assert_eq!(compare(&reference, &reference).relative_mse, 0.0);
+
+    // slightly off everywhere
+    let close: Vec<_> = reference.iter().map(|x| x * 1.01).collect();
+    assert!(compare(&close, &reference).passes());
+
+    // one pixel completely off is noise
+    let mut one_off = reference.clone();
+    one_off[0] = 0.6;
+    assert!(compare(&one_off, &reference).passes());
+
+    // a brighter image isn't
+    let brighter: Vec<_> = reference.iter().map(|x| x * 1.5).collect();
+    assert!(!compare(&brighter, &reference).passes());
+
+    let mut nan = reference.clone();
+    nan[4] = f32::NAN;
+    assert!(!compare(&nan, &reference).passes());
+}
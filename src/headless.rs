@@ -0,0 +1,161 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void},
+    ptr,
+    rc::Rc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ash::{vk, Device, Entry, Instance};
+use gpu_allocator::vulkan::{Allocator, AllocatorCreateDesc};
+
+use crate::{
+    defer::Defer,
+    render::Renderer,
+    scene::scenes::mesh::MeshScene,
+    utils::{
+        choose_physical_device, device_name, missing_device_extensions,
+        missing_instance_extensions, query_queue_families, DeviceSelector, QueueFamilyInfo,
+        SuitabilityReport,
+    },
+    window::WindowData,
+    APPLICATION_NAME,
+};
+
+/// Instance, device and allocator for rendering without a window
+///
+/// Renderers created from it have to be dropped before it.
+pub struct HeadlessContext {
+    allocator: Option<Rc<RefCell<Allocator>>>,
+    pub device: Device,
+    pub physical_device: vk::PhysicalDevice,
+    pub queue_family_info: QueueFamilyInfo,
+    pub instance: Instance,
+    pub vk_lib: Entry,
+}
+
+impl HeadlessContext {
+    /// Picks a device the way the windowed app does, minus the presenting
+    pub fn new<R: Renderer<MeshScene, WindowData>>(
+        selector: Option<&DeviceSelector>,
+    ) -> Result<Self> {
+        let vk_lib = load_vulkan()?;
+        let instance = create_instance(&vk_lib, R::required_instance_extensions())?
+            .defer(|x| unsafe { x.destroy_instance(None) });
+        let (physical_device, queue_family_info, device) =
+            Self::create_device::<R>(&instance, &vk_lib, selector)?;
+        let device = device.defer(|x| unsafe { x.destroy_device(None) });
+
+        let allocator = Allocator::new(&AllocatorCreateDesc {
+            instance: (*instance).clone(),
+            device: (*device).clone(),
+            physical_device,
+            debug_settings: Default::default(),
+            buffer_device_address: true,
+            allocation_sizes: Default::default(),
+        })?;
+
+        Ok(Self {
+            allocator: Some(Rc::new(RefCell::new(allocator))),
+            device: device.undefer(),
+            physical_device,
+            queue_family_info,
+            instance: instance.undefer(),
+            vk_lib,
+        })
+    }
+
+    pub fn allocator(&self) -> &Rc<RefCell<Allocator>> {
+        self.allocator.as_ref().unwrap()
+    }
+
+    pub fn device_name(&self) -> String {
+        device_name(&self.instance, self.physical_device)
+    }
+
+    fn create_device<R: Renderer<MeshScene, WindowData>>(
+        instance: &Instance,
+        vk_lib: &Entry,
+        selector: Option<&DeviceSelector>,
+    ) -> Result<(vk::PhysicalDevice, QueueFamilyInfo, Device)> {
+        let extensions = R::required_device_extensions();
+        let physical_device = choose_physical_device(
+            instance,
+            selector,
+            |device| check_device::<R>(vk_lib, instance, device),
+            |device| R::score_device(instance, device),
+        )?;
+        let queue_family_info = query_queue_families(vk_lib, instance, physical_device, None)?;
+        let features = R::required_features().for_device(instance, physical_device);
+
+        let queue_info = R::get_queue_info(&queue_family_info);
+        let create_info = vk::DeviceCreateInfo {
+            p_next: features.get() as *const _ as *const c_void,
+            queue_create_info_count: queue_info.len() as u32,
+            p_queue_create_infos: queue_info.as_ptr(),
+            enabled_extension_count: extensions.len() as u32,
+            pp_enabled_extension_names: extensions.as_ptr(),
+            p_enabled_features: ptr::null(),
+            ..Default::default()
+        };
+        let device = unsafe { instance.create_device(physical_device, &create_info, None) }?;
+
+        Ok((physical_device, queue_family_info, device))
+    }
+}
+
+pub fn load_vulkan() -> Result<Entry> {
+    // the loading error already includes its source, so it isn't kept as one
+    unsafe { Entry::load() }.map_err(|e| anyhow!("failed to load the Vulkan library: {e}"))
+}
+
+pub fn create_instance(vk_lib: &Entry, extensions: &[*const c_char]) -> Result<Instance> {
+    let missing = missing_instance_extensions(vk_lib, extensions)?;
+    if !missing.is_empty() {
+        bail!("missing instance extensions {}", missing.join(", "));
+    }
+
+    let app_info = vk::ApplicationInfo {
+        p_application_name: APPLICATION_NAME.as_ptr() as *const c_char,
+        api_version: vk::make_api_version(0, 1, 3, 0),
+        ..Default::default()
+    };
+    let create_info = vk::InstanceCreateInfo {
+        p_application_info: &app_info,
+        enabled_extension_count: extensions.len() as u32,
+        pp_enabled_extension_names: extensions.as_ptr(),
+        ..Default::default()
+    };
+
+    unsafe { Ok(vk_lib.create_instance(&create_info, None)?) }
+}
+
+/// What keeps the renderer from using the device without presenting
+pub fn check_device<R: Renderer<MeshScene, WindowData>>(
+    vk_lib: &Entry,
+    instance: &Instance,
+    device: vk::PhysicalDevice,
+) -> Result<SuitabilityReport> {
+    let queue_family_info = query_queue_families(vk_lib, instance, device, None)?;
+    Ok(SuitabilityReport {
+        missing_device_extensions: missing_device_extensions(
+            instance,
+            device,
+            R::required_device_extensions(),
+        )?,
+        missing_features: R::required_features().missing(instance, device),
+        missing_queue_families: queue_family_info.missing(R::required_queue_families()),
+        ..Default::default()
+    })
+}
+
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        // the allocator frees its memory through the device
+        drop(self.allocator.take());
+        unsafe {
+            self.device.destroy_device(None);
+            self.instance.destroy_instance(None);
+        }
+    }
+}
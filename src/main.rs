@@ -1,15 +1,14 @@
 use std::cell::RefCell;
 use std::ffi::{c_char, c_void, CStr};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ash::vk::{
-    DebugUtilsMessageSeverityFlagsEXT, DebugUtilsMessageTypeFlagsEXT,
-    DebugUtilsMessengerCreateInfoEXT, EXT_DEBUG_UTILS_NAME,
+    DebugUtilsMessageTypeFlagsEXT, DebugUtilsMessengerCreateInfoEXT, EXT_DEBUG_UTILS_NAME,
 };
 use ash::{ext, khr, Device};
 use ash::{
@@ -17,17 +16,25 @@ use ash::{
     Entry, Instance,
 };
 
-use clap::Parser;
-use debug::DebugUtilsData;
+use clap::{CommandFactory, Parser, Subcommand};
+use color::ColorSpace;
+use debug::{DebugNames, DebugOptions, DebugUtilsData};
 use defer::Defer;
 use env_logger::Builder;
+use features::VkFeatures;
 use gpu_allocator::vulkan::{Allocator, AllocatorCreateDesc};
-use log::{debug, info, warn, LevelFilter};
-use render::renderers::RaytraceRenderer;
+use log::{debug, error, info, warn, LevelFilter};
+use output::ImageFormat;
+use render::renderers::{CpuRenderer, RaytraceRenderer};
 use render::Renderer;
-use scene::scenes::mesh::{MeshScene, MeshSceneUpdate};
+use scene::scenes::mesh::{Aov, MeshScene, MeshSceneUpdate, SamplingSettings, Tonemap};
 use scene::Scene;
-use utils::{query_queue_families, QueueFamilyInfo};
+use tiles::{tile_grid, tile_writer, TileWriter};
+use utils::{
+    choose_physical_device, device_name, missing_device_extensions, missing_instance_extensions,
+    pick_physical_device, query_queue_families, DeviceSelector, QueueFamily, QueueFamilyInfo,
+    SuitabilityReport,
+};
 use window::WindowData;
 use winit::application::ApplicationHandler;
 use winit::dpi::PhysicalSize;
@@ -37,12 +44,25 @@ use winit::keyboard::PhysicalKey;
 use winit::raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 use winit::window::{CursorGrabMode, WindowAttributes, WindowId};
 
+mod bench;
 mod camera;
+mod checkpoint;
+mod color;
+mod compare;
 mod debug;
 mod defer;
+mod denoise;
 mod features;
+#[cfg(test)]
+mod golden;
+mod headless;
+mod output;
 mod render;
+mod sampling;
 mod scene;
+mod spectral;
+mod tiles;
+mod tonemap;
 mod utils;
 mod window;
 
@@ -56,6 +76,142 @@ const DEBUG_MODE: bool = false;
 
 const APPLICATION_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "\0");
 
+// reading back the accumulation stalls the gpu, so the noise threshold is only checked this often
+const NOISE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// tile size for captures beyond the device image limit that don't pick one
+const DEFAULT_TILE_SIZE: u32 = 2048;
+
+/// What gets written to disk and when
+struct CaptureOptions {
+    // frame to capture at, sampling limits in the scene also end a capture
+    frame: Option<u32>,
+    output_path: String,
+    output_format: ImageFormat,
+    spectral_output_path: Option<String>,
+    checkpoint_path: Option<String>,
+    checkpoint_interval: Duration,
+    resume_path: Option<String>,
+    tile_size: Option<u32>,
+}
+
+impl CaptureOptions {
+    /// Whether rendering stops at a limit and saves, rather than being interactive
+    fn has_limit(&self, sampling: &SamplingSettings) -> bool {
+        self.frame.is_some() || sampling.has_limit()
+    }
+}
+
+/// Progress through a capture that is traced one tile at a time
+struct TiledCapture {
+    image_size: (u32, u32),
+    tile_size: u32,
+    offsets: Vec<(u32, u32)>,
+    // index of the tile being traced
+    current: usize,
+    // whether the renderer has been told about the current tile
+    started: bool,
+    // seconds spent on the finished tiles
+    elapsed: f32,
+    writer: Option<Box<dyn TileWriter>>,
+}
+
+impl TiledCapture {
+    /// Share of a whole capture time limit left for the current tile
+    fn tile_time_limit(&self, time_limit: f32) -> f32 {
+        let remaining_tiles = (self.offsets.len() - self.current) as f32;
+        (time_limit - self.elapsed).max(0.0) / remaining_tiles
+    }
+}
+
+/// Which limit a capture reached, if any
+fn reached_limit<R: Renderer<MeshScene, WindowData>>(
+    renderer: &R,
+    sampling: &SamplingSettings,
+    capture_frame: Option<u32>,
+    frame_count: u32,
+    elapsed: f32,
+    tiles: Option<&TiledCapture>,
+    last_noise_check: &mut Option<Instant>,
+) -> Result<Option<&'static str>> {
+    if capture_frame.is_some_and(|frame| frame_count >= frame) {
+        return Ok(Some("capture frame"));
+    }
+    if sampling
+        .spp
+        .is_some_and(|spp| renderer.samples_per_pixel() >= spp)
+    {
+        return Ok(Some("sample count"));
+    }
+    // the time limit covers the whole capture, so tiles split it between them
+    let time_limit = sampling
+        .time_limit
+        .map(|limit| tiles.map_or(limit, |tiles| tiles.tile_time_limit(limit)));
+    if time_limit.is_some_and(|limit| elapsed >= limit) {
+        return Ok(Some("time limit"));
+    }
+    if let Some(threshold) = sampling.noise_threshold {
+        if last_noise_check.is_none_or(|t| t.elapsed() >= NOISE_CHECK_INTERVAL) {
+            *last_noise_check = Some(Instant::now());
+            if renderer
+                .estimate_noise()?
+                .is_some_and(|noise| noise <= threshold)
+            {
+                return Ok(Some("noise threshold"));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Splits the capture into tiles when asked to, or when it is too large for one image
+fn plan_tiles(
+    scene: &MeshScene,
+    capture: &CaptureOptions,
+    max_size: u32,
+) -> Result<Option<TiledCapture>> {
+    let Some(image_size) = scene.output.resolution else {
+        return Ok(None);
+    };
+    let tile_size = match capture.tile_size.or(scene.output.tile_size) {
+        Some(tile_size) => tile_size.min(max_size),
+        None if image_size.0.max(image_size.1) > max_size => DEFAULT_TILE_SIZE.min(max_size),
+        None => return Ok(None),
+    };
+
+    if !capture.has_limit(&scene.sampling) {
+        bail!("tiled renders need a capture frame or a sampling limit");
+    }
+    if !scene.output.aovs.is_empty() {
+        bail!("aovs are not supported in tiled renders");
+    }
+    if scene.denoise.save {
+        bail!("denoising is not supported in tiled renders");
+    }
+    if capture.spectral_output_path.is_some() {
+        bail!("spectral output is not supported in tiled renders");
+    }
+    if capture.checkpoint_path.is_some() || capture.resume_path.is_some() {
+        bail!("checkpoints are not supported in tiled renders");
+    }
+
+    let writer = tile_writer(
+        Path::new(&capture.output_path),
+        capture.output_format,
+        &scene.output,
+        image_size,
+    )?;
+    Ok(Some(TiledCapture {
+        image_size,
+        tile_size,
+        offsets: tile_grid(image_size, tile_size),
+        current: 0,
+        started: false,
+        elapsed: 0.0,
+        writer: Some(writer),
+    }))
+}
+
 struct MeshApp<R> {
     // WARNING: ORDER MATTERS HERE!!!
     // fields are dropped from top to bottom (not bottom to top like C++)
@@ -69,11 +225,18 @@ struct MeshApp<R> {
     instance: Instance,
     vk_lib: Entry,
     scene: MeshScene,
-    pending_resize: Option<(u32, u32)>,
+    window_size: (u32, u32),
+    // size the renderer was last told to render at
+    render_size: Option<(u32, u32)>,
     prev_instant: Option<Instant>,
     frame_count: u32,
-    capture_frame: Option<u32>,
-    output_path: String,
+    render_start: Option<Instant>,
+    last_noise_check: Option<Instant>,
+    last_checkpoint: Option<Instant>,
+    capture: CaptureOptions,
+    tiles: Option<TiledCapture>,
+    // None picks the best suitable device
+    device_selector: Option<DeviceSelector>,
 }
 
 impl<R> MeshApp<R>
@@ -82,23 +245,22 @@ where
 {
     pub fn new(
         event_loop: &EventLoop<()>,
-        scene: MeshScene,
-        debug_mode: bool,
-        capture_frame: Option<u32>,
-        output_path: String,
+        mut scene: MeshScene,
+        debug: DebugOptions,
+        capture: CaptureOptions,
+        device_selector: Option<DeviceSelector>,
     ) -> Result<Self> {
         let vk_lib = unsafe { Entry::load().expect("failed to load Vulkan library") };
 
-        let enable_vk_debug = debug_mode && Self::is_vk_debug_supported(&vk_lib)?;
-        if debug_mode && !enable_vk_debug {
-            warn!("running in debug mode, but validation layer/debug_utils extension are not found/supported");
+        let enable_vk_debug = debug.enabled() && Self::is_vk_debug_supported(&vk_lib)?;
+        if debug.enabled() && !enable_vk_debug {
+            warn!("validation was asked for, but validation layer/debug_utils extension are not found/supported");
         }
+        // the shaders only print when someone is listening
+        scene.render.debug_printf = enable_vk_debug && debug.debug_printf;
 
         let mut debug_utils_info = enable_vk_debug.then(|| DebugUtilsMessengerCreateInfoEXT {
-            message_severity: DebugUtilsMessageSeverityFlagsEXT::ERROR
-                | DebugUtilsMessageSeverityFlagsEXT::WARNING
-                | DebugUtilsMessageSeverityFlagsEXT::INFO
-                | DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
+            message_severity: debug::message_severity(),
             message_type: DebugUtilsMessageTypeFlagsEXT::GENERAL
                 | DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
                 | DebugUtilsMessageTypeFlagsEXT::VALIDATION,
@@ -107,11 +269,7 @@ where
             ..Default::default()
         });
 
-        let validation_feature_enable = [
-            // vk::ValidationFeatureEnableEXT::DEBUG_PRINTF,
-            vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION,
-            vk::ValidationFeatureEnableEXT::BEST_PRACTICES,
-        ];
+        let validation_feature_enable = debug.validation_features();
         let mut validation_features = enable_vk_debug.then(|| vk::ValidationFeaturesEXT {
             enabled_validation_feature_count: validation_feature_enable.len() as u32,
             p_enabled_validation_features: validation_feature_enable.as_ptr(),
@@ -143,14 +301,93 @@ where
             instance: instance.undefer(),
             vk_lib,
             scene,
-            pending_resize: None,
+            window_size: (WindowData::DEFAULT_WIDTH, WindowData::DEFAULT_HEIGHT),
+            render_size: None,
             prev_instant: None,
             frame_count: 0,
-            capture_frame,
-            output_path,
+            render_start: None,
+            last_noise_check: None,
+            last_checkpoint: None,
+            capture,
+            tiles: None,
+            device_selector,
         })
     }
 
+    /// Whether the app renders until a limit is reached and saves, rather than being interactive
+    fn is_capturing(&self) -> bool {
+        self.capture.has_limit(&self.scene.sampling)
+    }
+
+    /// The scene resolution if it has one, otherwise the window size
+    ///
+    /// The interactive view is scaled by the render scale, captures always use the full size.
+    fn target_render_size(&self) -> (u32, u32) {
+        if let Some(tiles) = &self.tiles {
+            return (tiles.tile_size, tiles.tile_size);
+        }
+        let (width, height) = self.scene.output.resolution.unwrap_or(self.window_size);
+        if self.is_capturing() {
+            return (width, height);
+        }
+        let scale = self.scene.output.render_scale;
+        (
+            ((width as f32 * scale).round() as u32).max(1),
+            ((height as f32 * scale).round() as u32).max(1),
+        )
+    }
+
+    /// Which limit the render reached, if any
+    fn reached_limit(&mut self) -> Result<Option<&'static str>> {
+        reached_limit(
+            self.renderer.as_ref().unwrap(),
+            &self.scene.sampling,
+            self.capture.frame,
+            self.frame_count,
+            self.render_start.map_or(0.0, |t| t.elapsed().as_secs_f32()),
+            self.tiles.as_ref(),
+            &mut self.last_noise_check,
+        )
+    }
+
+    /// Hands the finished tile to the writer and moves on, returns whether every tile is done
+    fn finish_tile(&mut self) -> Result<bool> {
+        let pixels = self.renderer.as_ref().unwrap().read_back_linear()?;
+        let tiles = self.tiles.as_mut().unwrap();
+        let offset = tiles.offsets[tiles.current];
+        tiles
+            .writer
+            .as_mut()
+            .unwrap()
+            .write_tile(offset, tiles.tile_size, &pixels)?;
+
+        tiles.current += 1;
+        tiles.started = false;
+        tiles.elapsed += self.render_start.map_or(0.0, |t| t.elapsed().as_secs_f32());
+        self.frame_count = 0;
+        self.render_start = None;
+        self.last_noise_check = None;
+        if tiles.current < tiles.offsets.len() {
+            return Ok(false);
+        }
+
+        tiles.writer.take().unwrap().finish()?;
+        Ok(true)
+    }
+
+    fn save_checkpoint(&mut self) {
+        let Some(checkpoint_path) = &self.capture.checkpoint_path else {
+            return;
+        };
+        self.renderer
+            .as_ref()
+            .unwrap()
+            .save_checkpoint(checkpoint_path)
+            .expect("failed to save checkpoint");
+        self.last_checkpoint = Some(Instant::now());
+        println!("\nSaved checkpoint to {}", checkpoint_path);
+    }
+
     fn is_vk_debug_supported(vk_lib: &Entry) -> Result<bool> {
         let available_layers = unsafe { vk_lib.enumerate_instance_layer_properties()? };
         let supported_extensions = unsafe { vk_lib.enumerate_instance_extension_properties(None)? };
@@ -168,6 +405,7 @@ where
     }
 
     fn get_layers_and_extensions(
+        vk_lib: &Entry,
         event_loop: &EventLoop<()>,
         use_debug_layers: bool,
     ) -> Result<(Vec<*const c_char>, Vec<*const c_char>)> {
@@ -184,12 +422,14 @@ where
         let required_extensions = ash_window::enumerate_required_extensions(raw_display_handle)?;
         let required_renderer_extensions = R::required_instance_extensions();
 
-        // could check if extensions are supported to print exactly the extensions that arent supported
-        // but eh, im lazy
-
         extensions.extend_from_slice(required_extensions);
         extensions.extend_from_slice(required_renderer_extensions);
 
+        let missing = missing_instance_extensions(vk_lib, &extensions)?;
+        if !missing.is_empty() {
+            bail!("missing instance extensions {}", missing.join(", "));
+        }
+
         Ok((layers, extensions))
     }
 
@@ -200,7 +440,7 @@ where
         validation_features: Option<&mut vk::ValidationFeaturesEXT>,
     ) -> Result<Instance> {
         let (layers, extensions) =
-            Self::get_layers_and_extensions(event_loop, debug_utils_info.is_some())?;
+            Self::get_layers_and_extensions(vk_lib, event_loop, debug_utils_info.is_some())?;
 
         let app_info = vk::ApplicationInfo {
             p_application_name: APPLICATION_NAME.as_ptr() as *const c_char,
@@ -234,65 +474,36 @@ where
         unsafe { Ok(vk_lib.create_instance(&create_info, None)?) }
     }
 
-    fn is_device_suitable(
+    // the renderer's features along with the window's
+    fn required_features() -> VkFeatures {
+        R::required_features().merge(&WindowData::required_features())
+    }
+
+    /// What keeps the device from rendering and presenting to the window
+    fn check_device(
         &self,
         device: vk::PhysicalDevice,
         surface: vk::SurfaceKHR,
-    ) -> Result<bool> {
-        // check compatibility of device with window and renderer
-        let required_renderer_extensions = R::required_device_extensions();
-        let required_window_extensions = WindowData::required_device_extensions();
-        let required_extensions =
-            [required_renderer_extensions, required_window_extensions].concat();
-        let required_features = R::required_features();
-
-        let supported_extensions = unsafe {
-            self.instance
-                .enumerate_device_extension_properties(device)?
-        };
-
-        // check that all required extensions and features are supported (i.e. required is a subset of supported)
-        for ext in required_extensions {
-            let ext_name = unsafe { CStr::from_ptr(ext) };
-            if !supported_extensions
-                .iter()
-                .any(|x| x.extension_name_as_c_str().unwrap() == ext_name)
-            {
-                return Ok(false);
-            }
-        }
-
-        if !required_features.supported(&self.instance, device) {
-            return Ok(false);
-        }
-
-        if !WindowData::is_device_suitable(&self.vk_lib, &self.instance, device, surface)? {
-            return Ok(false);
-        }
-
+    ) -> Result<SuitabilityReport> {
+        // check compatibility of device with renderer first, then with the window
+        let mut report = headless::check_device::<R>(&self.vk_lib, &self.instance, device)?;
+        report.missing_features = Self::required_features()
+            .get_list()
+            .missing(&self.instance, device);
+
+        let window_extensions = WindowData::required_device_extensions();
+        let missing = missing_device_extensions(&self.instance, device, window_extensions)?;
+        report.missing_device_extensions.extend(missing);
+        report.unsupported_surface =
+            !WindowData::is_device_suitable(&self.vk_lib, &self.instance, device, surface)?;
+
+        // the window needs to present, the renderer only says what it renders with
         let queue_family_info =
-            utils::query_queue_families(&self.vk_lib, &self.instance, device, surface)?;
-        Ok(R::has_required_queue_families(&queue_family_info))
-    }
-
-    fn pick_physical_device(
-        &self,
-        devices: impl Iterator<Item = vk::PhysicalDevice>,
-    ) -> Option<vk::PhysicalDevice> {
-        // could make a smarter device scoring system, but let's just take either the first discrete GPU device
-        // or the first device that works if there is no discrete GPU
-        // in the future could expand this to have the renderer score devices based on what would be best for it
-        let mut devices = devices.peekable();
-        let first = devices.peek().cloned();
-
-        for device in devices {
-            let properties = unsafe { self.instance.get_physical_device_properties(device) };
-            if properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU {
-                return Some(device);
-            }
+            query_queue_families(&self.vk_lib, &self.instance, device, Some(surface))?;
+        if queue_family_info.present_index.is_none() {
+            report.missing_queue_families.push(QueueFamily::Present);
         }
-
-        first
+        Ok(report)
     }
 
     fn create_device(
@@ -305,7 +516,10 @@ where
             WindowData::required_device_extensions(),
         ]
         .concat();
-        let enabled_features = R::required_features();
+        let features = Self::required_features();
+        let enabled_features = features
+            .get_list()
+            .for_device(&self.instance, physical_device);
 
         let queue_info = R::get_queue_info(queue_family_info);
 
@@ -362,7 +576,7 @@ where
                         .with_title("kubgrupp"),
                 )
                 .unwrap();
-            if self.capture_frame.is_none() {
+            if !self.is_capturing() {
                 window
                     .set_cursor_grab(CursorGrabMode::Confined)
                     .or_else(|_e| window.set_cursor_grab(CursorGrabMode::Locked))
@@ -388,24 +602,19 @@ where
             // surface created - now we pick physical device
             // we start by checking if the device works for the application
             // we then let the renderer pick the optimal device out of this selection
-            let devices = unsafe {
-                self.instance
-                    .enumerate_physical_devices()
-                    .expect("failed to enumerate physical devices")
+            let physical_device = match choose_physical_device(
+                &self.instance,
+                self.device_selector.as_ref(),
+                |device| self.check_device(device, *surface),
+                |device| R::score_device(&self.instance, device),
+            ) {
+                Ok(physical_device) => physical_device,
+                Err(e) => {
+                    error!("{e:#}");
+                    event_loop.exit();
+                    return;
+                }
             };
-
-            let valid_devices = devices.into_iter().filter(|device| {
-                // skip and log if check function returns Err
-                self.is_device_suitable(*device, *surface)
-                    .unwrap_or_else(|e| {
-                        warn!("failed to check if device was suitable: {}", e);
-                        false
-                    })
-            });
-
-            let physical_device = self
-                .pick_physical_device(valid_devices)
-                .expect("failed to find compatible physical device");
             let physical_device_properties = unsafe {
                 self.instance
                     .get_physical_device_properties(physical_device)
@@ -416,9 +625,13 @@ where
             );
             self.physical_device = Some(physical_device);
 
-            let queue_family_info =
-                query_queue_families(&self.vk_lib, &self.instance, physical_device, *surface)
-                    .expect("failed to find queue family info");
+            let queue_family_info = query_queue_families(
+                &self.vk_lib,
+                &self.instance,
+                physical_device,
+                Some(*surface),
+            )
+            .expect("failed to find queue family info");
             let device = self
                 .create_device(physical_device, &queue_family_info)
                 .expect("failed to create device");
@@ -449,6 +662,11 @@ where
             );
             surface.undefer();
 
+            let debug_names = self
+                .debug_data
+                .as_ref()
+                .map(|debug_data| debug_data.names(&self.instance, &device))
+                .unwrap_or_default();
             self.renderer = Some(
                 R::new(
                     &self.vk_lib,
@@ -457,17 +675,43 @@ where
                     physical_device,
                     &queue_family_info,
                     self.allocator.as_mut().unwrap().clone(),
+                    debug_names,
                 )
                 .expect("failed to create renderer"),
             );
 
+            // tiles are planned first so the renderer only ever allocates a tile
+            let max_size = self.renderer.as_ref().unwrap().max_image_size();
+            self.tiles =
+                plan_tiles(&self.scene, &self.capture, max_size).expect("cannot render in tiles");
+            if let Some(tiles) = &self.tiles {
+                println!(
+                    "Rendering {}x{} in {} tiles of {}x{}",
+                    tiles.image_size.0,
+                    tiles.image_size.1,
+                    tiles.offsets.len(),
+                    tiles.tile_size,
+                    tiles.tile_size
+                );
+            }
+
             // this is where we load the initial scene into the renderer
             // future updates come through the event loop through the render function
+            let size = self.target_render_size();
             self.renderer
                 .as_mut()
                 .unwrap()
-                .ingest_scene(&self.scene)
+                .ingest_scene(&self.scene, size)
                 .expect("failed to ingest scene");
+
+            if let Some(resume_path) = &self.capture.resume_path {
+                println!("Resuming from {}", resume_path);
+                self.renderer
+                    .as_mut()
+                    .unwrap()
+                    .resume_from(resume_path)
+                    .expect("failed to load checkpoint");
+            }
         }
     }
 
@@ -488,7 +732,7 @@ where
                 is_synthetic: _is_synthetic,
             } => {
                 if let PhysicalKey::Code(key_code) = input_event.physical_key {
-                    if self.capture_frame.is_none() {
+                    if !self.is_capturing() {
                         self.scene
                             .camera
                             .handle_key_input(key_code, input_event.state.is_pressed());
@@ -496,7 +740,7 @@ where
                 }
             }
             WindowEvent::Resized(PhysicalSize { width, height }) => {
-                self.pending_resize = Some((width, height));
+                self.window_size = (width, height);
             }
             WindowEvent::RedrawRequested => {
                 let dt: f32;
@@ -506,8 +750,10 @@ where
                     dt = 0f32;
                 }
                 self.prev_instant = Some(Instant::now());
+                self.render_start.get_or_insert_with(Instant::now);
+                self.last_checkpoint.get_or_insert_with(Instant::now);
 
-                if self.capture_frame.is_none() {
+                if !self.is_capturing() {
                     self.scene.camera.handle_movement(dt);
                 }
 
@@ -517,15 +763,27 @@ where
                     updates.push(MeshSceneUpdate::NewView(new_view));
                 }
 
-                if let Some((w, h)) = self.pending_resize {
-                    self.scene.camera.handle_resize(w, h);
+                let (w, h) = self.target_render_size();
+                if self.render_size != Some((w, h)) {
+                    // tiles are cut from the projection of the whole image
+                    let (aspect_w, aspect_h) =
+                        self.tiles.as_ref().map_or((w, h), |tiles| tiles.image_size);
+                    self.scene.camera.handle_resize(aspect_w, aspect_h);
                     updates.push(MeshSceneUpdate::NewSize((
                         w,
                         h,
                         self.scene.camera.perspective(),
                     )));
 
-                    self.pending_resize = None;
+                    self.render_size = Some((w, h));
+                }
+
+                if let Some(tiles) = self.tiles.as_mut().filter(|tiles| !tiles.started) {
+                    updates.push(MeshSceneUpdate::Tile {
+                        offset: tiles.offsets[tiles.current],
+                        image_size: tiles.image_size,
+                    });
+                    tiles.started = true;
                 }
 
                 self.renderer
@@ -535,20 +793,55 @@ where
                     .expect("failed to render to target");
 
                 self.frame_count += 1;
-                print!("\rFrame: {}    ", self.frame_count);
+                let spp = self.renderer.as_ref().unwrap().samples_per_pixel();
+                print!("\rFrame: {} ({} spp)    ", self.frame_count, spp);
                 std::io::Write::flush(&mut std::io::stdout()).ok();
 
-                if let Some(target_frame) = self.capture_frame {
-                    if self.frame_count >= target_frame {
+                if self
+                    .last_checkpoint
+                    .is_some_and(|t| t.elapsed() >= self.capture.checkpoint_interval)
+                {
+                    self.save_checkpoint();
+                }
+
+                if self.is_capturing() {
+                    if let Some(limit) = self.reached_limit().expect("failed to check limits") {
+                        let render_time = self.render_start.unwrap().elapsed().as_secs_f32();
+                        println!();
+                        println!("Reached {limit}: {spp} spp in {render_time:.2}s");
+                        if let Some(tiles) = &self.tiles {
+                            println!(
+                                "Finished tile {}/{}",
+                                tiles.current + 1,
+                                tiles.offsets.len()
+                            );
+                            if self.finish_tile().expect("failed to write tile") {
+                                println!("Wrote {}", self.capture.output_path);
+                                event_loop.exit();
+                                return;
+                            }
+                            self.window.as_ref().unwrap().request_redraw();
+                            return;
+                        }
                         println!(
                             "Capturing frame {} to {}",
-                            self.frame_count, self.output_path
+                            self.frame_count, self.capture.output_path
                         );
                         self.renderer
                             .as_mut()
                             .unwrap()
-                            .save_image(&self.output_path)
+                            .save_image(&self.capture.output_path, self.capture.output_format)
                             .expect("failed to save image");
+                        if let Some(spectral_output_path) = &self.capture.spectral_output_path {
+                            println!("Writing spectral image to {}", spectral_output_path);
+                            self.renderer
+                                .as_ref()
+                                .unwrap()
+                                .save_spectral_image(spectral_output_path)
+                                .expect("failed to save spectral image");
+                        }
+                        // lets the render be extended later
+                        self.save_checkpoint();
                         event_loop.exit();
                         return;
                     }
@@ -566,7 +859,7 @@ where
         _device_id: DeviceId,
         event: DeviceEvent,
     ) {
-        if self.capture_frame.is_none() {
+        if !self.is_capturing() {
             if let DeviceEvent::MouseMotion { delta: (dx, dy) } = event {
                 let (sx, sy) = self.window.as_ref().unwrap().get_size();
                 self.scene
@@ -578,16 +871,502 @@ where
 }
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[arg(short, long)]
-    scene_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required = true)]
+    scene_file: Option<String>,
 
     #[arg(short = 'c', long)]
     capture_frame: Option<u32>,
 
+    /// captured image, .exr, .hdr and .pfm store the linear accumulation
     #[arg(short = 'o', long, default_value = "output.png")]
     output: String,
+
+    /// write half floats instead of full floats to .exr outputs
+    #[arg(long)]
+    half: bool,
+
+    /// exposure in stops, overrides output.exposure in the scene
+    #[arg(long, allow_negative_numbers = true)]
+    exposure: Option<f32>,
+
+    /// clamp, reinhard, aces (or filmic) or agx, overrides output.tonemap in the scene
+    #[arg(long)]
+    tonemap: Option<Tonemap>,
+
+    /// srgb, rec2020, display_p3, acescg, aces2065_1 or xyz, overrides output.color_space in the scene
+    #[arg(long)]
+    color_space: Option<ColorSpace>,
+
+    /// albedo, normal, depth, instance or brdf, can be repeated, overrides output.aovs in the scene
+    ///
+    /// written as extra channels of .exr captures, or as separate .exr files next to other formats
+    #[arg(long = "aov")]
+    aovs: Vec<Aov>,
+
+    /// denoise the captured image on the CPU, same as denoise.save in the scene
+    #[arg(long)]
+    denoise: bool,
+
+    /// denoise the interactive view on the GPU, same as denoise.preview in the scene
+    #[arg(long)]
+    denoise_preview: bool,
+
+    /// stop and save after this many samples per pixel, overrides sampling.spp in the scene
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    spp: Option<u32>,
+
+    /// stop and save after this many seconds, overrides sampling.time_limit in the scene
+    #[arg(long, value_parser = parse_positive_f32)]
+    time_limit: Option<f32>,
+
+    /// stop and save once the estimated relative error drops below this, e.g. 0.01,
+    /// overrides sampling.noise_threshold in the scene
+    #[arg(long, value_parser = parse_positive_f32)]
+    noise_threshold: Option<f32>,
+
+    /// stop sampling pixels whose 95% confidence interval is within this fraction of their value,
+    /// e.g. 0.05, overrides sampling.adaptive_threshold in the scene
+    #[arg(long, value_parser = parse_positive_f32)]
+    adaptive_threshold: Option<f32>,
+
+    /// multispectral image written alongside the capture (.exr, or .raw/.envi for ENVI)
+    ///
+    /// needs a capture frame or one of the sampling limits
+    #[arg(long)]
+    spectral_output: Option<String>,
+
+    /// periodically save the accumulation here so the render can be resumed after a crash
+    #[arg(long)]
+    checkpoint: Option<String>,
+
+    /// minutes between checkpoints
+    #[arg(long, default_value_t = 10.0, value_parser = parse_positive_f32)]
+    checkpoint_interval: f32,
+
+    /// continue the accumulation in a checkpoint, the scene, camera and size must match
+    #[arg(long)]
+    resume: Option<String>,
+
+    /// render width, overrides output.resolution in the scene, the window only shows a preview
+    #[arg(long, requires = "height", value_parser = clap::value_parser!(u32).range(1..))]
+    width: Option<u32>,
+
+    /// render height, overrides output.resolution in the scene
+    #[arg(long, requires = "width", value_parser = clap::value_parser!(u32).range(1..))]
+    height: Option<u32>,
+
+    /// trace captures in square tiles of this size and stitch them on the host, overrides
+    /// output.tile_size in the scene, captures beyond the device image limit are always tiled,
+    /// 2048 at a time by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    tile_size: Option<u32>,
+
+    /// scale of the interactive view, e.g. 0.5 to preview at half resolution,
+    /// overrides output.render_scale in the scene
+    #[arg(long, value_parser = parse_positive_f32)]
+    render_scale: Option<f32>,
+
+    /// base seed of the random numbers, the same seed renders the same image on the same device,
+    /// overrides render.seed in the scene
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// number of wavelength bins in the multispectral image
+    #[arg(long, default_value_t = 31, value_parser = clap::value_parser!(u32).range(1..))]
+    spectral_bins: u32,
+
+    /// render with the CPU path tracer instead of hardware ray tracing, slow but needs no ray
+    /// tracing support
+    ///
+    /// captures are rendered without a window or a GPU, the GPU is only used to show the
+    /// interactive view
+    #[arg(long)]
+    cpu: bool,
+
+    /// device to use, an index from `kg devices` or part of its name, otherwise the best
+    /// suitable device is picked
+    #[arg(long)]
+    device: Option<DeviceSelector>,
+
+    /// enable the Vulkan validation layer, always on in debug builds
+    #[arg(long)]
+    validation: bool,
+
+    /// enable GPU assisted validation, which checks shader memory accesses, implies --validation
+    #[arg(long)]
+    gpu_assisted: bool,
+
+    /// log the debugPrintfEXT output of shaders with the pixel it came from, implies --validation
+    ///
+    /// path.rgen prints non-finite samples, shown at the info level or with RUST_LOG=shader=info
+    #[arg(long)]
+    debug_printf: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare two images with MSE, relative MSE, PSNR, SSIM and FLIP
+    ///
+    /// .exr, .hdr and .pfm images are compared as they are, other formats are assumed to be sRGB
+    /// encoded. SSIM and FLIP see the images clamped to [0, 1] as shown on an sRGB display.
+    Compare {
+        reference: PathBuf,
+        test: PathBuf,
+
+        /// write the per pixel FLIP error here as a false colour 8 bit image
+        #[arg(long)]
+        diff: Option<PathBuf>,
+
+        /// pixels per degree of visual angle for FLIP, the default is a 4K monitor at 0.7 m
+        #[arg(long, default_value_t = compare::DEFAULT_PIXELS_PER_DEGREE, value_parser = parse_positive_f32)]
+        pixels_per_degree: f32,
+    },
+    /// Render a scene without a window and report frame times as JSON
+    ///
+    /// GPU frames report the time spent tracing from timestamp queries next to the wall time,
+    /// along with the acceleration structure build times and the allocated device memory.
+    Bench {
+        /// scene in resources/scenes
+        scene_file: String,
+
+        /// number of timed frames
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+        frames: u32,
+
+        /// frames rendered before timing starts
+        #[arg(long, default_value_t = 5)]
+        warmup: u32,
+
+        /// render width, overrides output.resolution in the scene, 1280x720 if neither is set
+        #[arg(long, requires = "height", value_parser = clap::value_parser!(u32).range(1..))]
+        width: Option<u32>,
+
+        /// render height, overrides output.resolution in the scene
+        #[arg(long, requires = "width", value_parser = clap::value_parser!(u32).range(1..))]
+        height: Option<u32>,
+
+        /// samples per pixel traced every frame, overrides sampling.samples_per_frame in the scene
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        samples_per_frame: Option<u32>,
+
+        /// base seed of the random numbers, overrides render.seed in the scene
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// write the report here instead of to stdout
+        #[arg(short = 'o', long)]
+        output: Option<PathBuf>,
+
+        /// benchmark the CPU path tracer instead of hardware ray tracing
+        #[arg(long)]
+        cpu: bool,
+
+        /// device to use, an index from `kg devices` or part of its name
+        #[arg(long)]
+        device: Option<DeviceSelector>,
+    },
+    /// List the Vulkan devices and whether the renderer can use them
+    ///
+    /// Presenting isn't checked since there is no window.
+    Devices {
+        /// check the requirements of the CPU path tracer instead of hardware ray tracing
+        #[arg(long)]
+        cpu: bool,
+    },
+}
+
+fn compare_images(
+    reference_path: &Path,
+    test_path: &Path,
+    diff_path: Option<&Path>,
+    pixels_per_degree: f32,
+) -> Result<()> {
+    let (size, reference) = compare::read_image(reference_path)?;
+    let (test_size, test) = compare::read_image(test_path)?;
+    if size != test_size {
+        bail!(
+            "{} is {}x{} but {} is {}x{}",
+            reference_path.display(),
+            size.0,
+            size.1,
+            test_path.display(),
+            test_size.0,
+            test_size.1
+        );
+    }
+
+    let comparison = compare::compare(size, &reference, &test, pixels_per_degree)?;
+    let metrics = comparison.metrics;
+    println!("mse       {:.6e}", metrics.mse);
+    println!("rel. mse  {:.6e}", metrics.relative_mse);
+    println!("psnr      {:.2} dB", metrics.psnr);
+    println!("ssim      {:.6}", metrics.ssim);
+    println!("flip      {:.6}", metrics.flip);
+
+    if let Some(diff_path) = diff_path {
+        let pixels = compare::false_color(&comparison.flip_map);
+        output::write_display_image(diff_path, size, ColorSpace::Srgb, &pixels)?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn bench_scene(
+    scene_file: &str,
+    frames: u32,
+    warmup_frames: u32,
+    resolution: Option<(u32, u32)>,
+    samples_per_frame: Option<u32>,
+    seed: Option<u64>,
+    output: Option<&Path>,
+    cpu: bool,
+    device: Option<&DeviceSelector>,
+) -> Result<()> {
+    let path = Path::new("resources/scenes/").join(scene_file);
+    let mut scene = MeshScene::load_from(File::open(&path)?)?;
+    if resolution.is_some() {
+        scene.output.resolution = resolution;
+    }
+    if let Some(samples_per_frame) = samples_per_frame {
+        scene.sampling.samples_per_frame = samples_per_frame;
+    }
+    if let Some(seed) = seed {
+        scene.render.seed = Some(seed);
+    }
+    let mut options = bench::BenchOptions {
+        scene: scene_file.to_string(),
+        renderer: String::new(),
+        device: String::new(),
+        frames,
+        warmup_frames,
+    };
+
+    let report = if cpu {
+        options.renderer = "cpu".to_string();
+        options.device = "cpu".to_string();
+        bench::run(&mut CpuRenderer::headless(), scene, options, None)?
+    } else {
+        let context = headless::HeadlessContext::new::<RaytraceRenderer>(device)?;
+        options.renderer = "raytrace".to_string();
+        options.device = context.device_name();
+        info!("Benchmarking on {}", options.device);
+        let mut renderer = RaytraceRenderer::new(
+            &context.vk_lib,
+            &context.instance,
+            &context.device,
+            context.physical_device,
+            &context.queue_family_info,
+            context.allocator().clone(),
+            DebugNames::default(),
+        )?;
+        bench::run(&mut renderer, scene, options, Some(context.allocator()))?
+        // the renderer drops before the context
+    };
+
+    let json = serde_json::to_string_pretty(&report)?;
+    match output {
+        Some(path) => std::fs::write(path, json + "\n")?,
+        None => println!("{json}"),
+    }
+    Ok(())
+}
+
+/// Renders a capture with no window or Vulkan instance, for renderers that don't need a device
+fn capture_headless<R: Renderer<MeshScene, WindowData>>(
+    renderer: &mut R,
+    mut scene: MeshScene,
+    capture: &CaptureOptions,
+) -> Result<()> {
+    let image_size = scene
+        .output
+        .resolution
+        .unwrap_or((WindowData::DEFAULT_WIDTH, WindowData::DEFAULT_HEIGHT));
+    scene.camera.handle_resize(image_size.0, image_size.1);
+    let mut tiles = plan_tiles(&scene, capture, renderer.max_image_size())?;
+    let size = tiles
+        .as_ref()
+        .map_or(image_size, |tiles| (tiles.tile_size, tiles.tile_size));
+    if let Some(tiles) = &tiles {
+        println!(
+            "Rendering {}x{} in {} tiles of {}x{}",
+            image_size.0,
+            image_size.1,
+            tiles.offsets.len(),
+            tiles.tile_size,
+            tiles.tile_size
+        );
+    }
+
+    renderer.ingest_scene(&scene, size)?;
+    if let Some(resume_path) = &capture.resume_path {
+        println!("Resuming from {}", resume_path);
+        renderer.resume_from(resume_path)?;
+    }
+
+    let mut last_checkpoint = Instant::now();
+    loop {
+        // tiles are cut from the projection of the whole image
+        let mut updates = vec![MeshSceneUpdate::NewSize((
+            size.0,
+            size.1,
+            scene.camera.perspective(),
+        ))];
+        if let Some(tiles) = &tiles {
+            updates.push(MeshSceneUpdate::Tile {
+                offset: tiles.offsets[tiles.current],
+                image_size,
+            });
+        }
+
+        let render_start = Instant::now();
+        let mut last_noise_check = None;
+        let mut frame_count = 0;
+        let limit = loop {
+            renderer.render_offscreen(&updates)?;
+            updates.clear();
+
+            frame_count += 1;
+            let spp = renderer.samples_per_pixel();
+            print!("\rFrame: {} ({} spp)    ", frame_count, spp);
+            std::io::Write::flush(&mut std::io::stdout()).ok();
+
+            if let Some(checkpoint_path) = &capture.checkpoint_path {
+                if last_checkpoint.elapsed() >= capture.checkpoint_interval {
+                    renderer.save_checkpoint(checkpoint_path)?;
+                    last_checkpoint = Instant::now();
+                    println!("\nSaved checkpoint to {}", checkpoint_path);
+                }
+            }
+
+            if let Some(limit) = reached_limit(
+                renderer,
+                &scene.sampling,
+                capture.frame,
+                frame_count,
+                render_start.elapsed().as_secs_f32(),
+                tiles.as_ref(),
+                &mut last_noise_check,
+            )? {
+                break limit;
+            }
+        };
+        let spp = renderer.samples_per_pixel();
+        let render_time = render_start.elapsed().as_secs_f32();
+        println!();
+        println!("Reached {limit}: {spp} spp in {render_time:.2}s");
+
+        let Some(tiles) = tiles.as_mut() else {
+            break;
+        };
+        println!(
+            "Finished tile {}/{}",
+            tiles.current + 1,
+            tiles.offsets.len()
+        );
+        let pixels = renderer.read_back_linear()?;
+        let offset = tiles.offsets[tiles.current];
+        tiles
+            .writer
+            .as_mut()
+            .unwrap()
+            .write_tile(offset, tiles.tile_size, &pixels)?;
+        tiles.current += 1;
+        tiles.elapsed += render_time;
+        if tiles.current == tiles.offsets.len() {
+            tiles.writer.take().unwrap().finish()?;
+            println!("Wrote {}", capture.output_path);
+            return Ok(());
+        }
+    }
+
+    println!("Capturing to {}", capture.output_path);
+    renderer.save_image(&capture.output_path, capture.output_format)?;
+    if let Some(spectral_output_path) = &capture.spectral_output_path {
+        println!("Writing spectral image to {}", spectral_output_path);
+        renderer.save_spectral_image(spectral_output_path)?;
+    }
+    // lets the render be extended later
+    if let Some(checkpoint_path) = &capture.checkpoint_path {
+        renderer.save_checkpoint(checkpoint_path)?;
+        println!("Saved checkpoint to {}", checkpoint_path);
+    }
+    Ok(())
+}
+
+fn list_devices<R: Renderer<MeshScene, WindowData>>() -> Result<()> {
+    let vk_lib = headless::load_vulkan()?;
+    // the devices are listed even if the renderer's instance extensions are missing
+    let extensions = R::required_instance_extensions();
+    let missing = missing_instance_extensions(&vk_lib, extensions)?;
+    let supported: Vec<_> = extensions
+        .iter()
+        .copied()
+        .filter(|&ext| {
+            let name = unsafe { CStr::from_ptr(ext) }.to_string_lossy();
+            !missing.iter().any(|missing| *missing == name)
+        })
+        .collect();
+    let instance = headless::create_instance(&vk_lib, &supported)?
+        .defer(|x| unsafe { x.destroy_instance(None) });
+    let devices = unsafe { instance.enumerate_physical_devices()? };
+    if devices.is_empty() {
+        bail!("no Vulkan devices found");
+    }
+
+    let ray_tracing_extensions = RaytraceRenderer::required_device_extensions();
+    let mut suitable = Vec::new();
+    for (i, &device) in devices.iter().enumerate() {
+        let properties = unsafe { instance.get_physical_device_properties(device) };
+        let version = properties.api_version;
+        println!(
+            "{i}: {} ({:?}, Vulkan {}.{}.{})",
+            device_name(&instance, device),
+            properties.device_type,
+            vk::api_version_major(version),
+            vk::api_version_minor(version),
+            vk::api_version_patch(version)
+        );
+
+        let ray_tracing =
+            missing_device_extensions(&instance, device, ray_tracing_extensions)?.is_empty();
+        println!("   ray tracing: {}", if ray_tracing { "yes" } else { "no" });
+        let mut report = headless::check_device::<R>(&vk_lib, &instance, device)?;
+        report.missing_instance_extensions = missing.clone();
+        if report.is_suitable() {
+            println!("   suitable, score {}", R::score_device(&instance, device));
+            suitable.push(device);
+        } else {
+            println!("   unsuitable: {report}");
+        }
+    }
+
+    let best = pick_physical_device(suitable.into_iter(), |device| {
+        R::score_device(&instance, device)
+    });
+    match best.and_then(|best| devices.iter().position(|&device| device == best)) {
+        Some(i) => println!("\ndevice {i} is used unless --device picks another"),
+        None => println!("\nno device is suitable"),
+    }
+    Ok(())
+}
+
+fn parse_positive_f32(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if x > 0.0 => Ok(x),
+        Ok(_) => Err("must be positive".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 fn main() {
@@ -598,18 +1377,150 @@ fn main() {
 
     let args = Args::parse();
 
-    let event_loop = EventLoop::new().unwrap();
+    if let Some(command) = args.command {
+        let result = match command {
+            Command::Compare {
+                reference,
+                test,
+                diff,
+                pixels_per_degree,
+            } => compare_images(&reference, &test, diff.as_deref(), pixels_per_degree),
+            Command::Bench {
+                scene_file,
+                frames,
+                warmup,
+                width,
+                height,
+                samples_per_frame,
+                seed,
+                output,
+                cpu,
+                device,
+            } => bench_scene(
+                &scene_file,
+                frames,
+                warmup,
+                width.zip(height),
+                samples_per_frame,
+                seed,
+                output.as_deref(),
+                cpu,
+                device.as_ref(),
+            ),
+            Command::Devices { cpu: true } => list_devices::<CpuRenderer>(),
+            Command::Devices { cpu: false } => list_devices::<RaytraceRenderer>(),
+        };
+        if let Err(e) = result {
+            eprintln!("error: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let path = Path::new("resources/scenes/").join(&args.scene_file);
+    // required unless there's a subcommand
+    let scene_file = args.scene_file.unwrap();
+    let path = Path::new("resources/scenes/").join(scene_file);
     let file = File::open(path).expect("scene file does not exist");
-    let scene = MeshScene::load_from(file).expect("scene could not be loaded");
-    let mut app: MeshApp<RaytraceRenderer> = MeshApp::new(
-        &event_loop,
-        scene,
-        DEBUG_MODE,
-        args.capture_frame,
-        args.output,
-    )
-    .unwrap();
-    event_loop.run_app(&mut app).unwrap();
+    let mut scene = MeshScene::load_from(file).expect("scene could not be loaded");
+    if let Some(exposure) = args.exposure {
+        scene.output.exposure = exposure;
+    }
+    if let Some(tonemap) = args.tonemap {
+        scene.output.tonemap = tonemap;
+    }
+    if let Some(color_space) = args.color_space {
+        scene.output.color_space = color_space;
+    }
+    if !args.aovs.is_empty() {
+        // repeats are dropped like in the scene file
+        scene.output.aovs.clear();
+        for aov in args.aovs {
+            if !scene.output.aovs.contains(&aov) {
+                scene.output.aovs.push(aov);
+            }
+        }
+    }
+    if let (Some(width), Some(height)) = (args.width, args.height) {
+        scene.output.resolution = Some((width, height));
+        scene.camera.handle_resize(width, height);
+    }
+    if let Some(render_scale) = args.render_scale {
+        scene.output.render_scale = render_scale;
+    }
+    if let Some(seed) = args.seed {
+        scene.render.seed = Some(seed);
+    }
+    // picked here so it can be printed, a render can then be reproduced with --seed
+    let seed = *scene.render.seed.get_or_insert_with(rand::random);
+    info!("Base seed: {seed}");
+    scene.denoise.save |= args.denoise;
+    scene.denoise.preview |= args.denoise_preview;
+    if let Some(spp) = args.spp {
+        scene.sampling.spp = Some(spp);
+    }
+    if let Some(time_limit) = args.time_limit {
+        scene.sampling.time_limit = Some(time_limit);
+    }
+    if let Some(noise_threshold) = args.noise_threshold {
+        scene.sampling.noise_threshold = Some(noise_threshold);
+    }
+    if let Some(adaptive_threshold) = args.adaptive_threshold {
+        scene.sampling.adaptive_threshold = Some(adaptive_threshold);
+    }
+    if args.spectral_output.is_some() {
+        if args.capture_frame.is_none() && !scene.sampling.has_limit() {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--spectral-output needs --capture-frame, --spp, --time-limit or --noise-threshold",
+                )
+                .exit();
+        }
+        if let Some(spectral_output) = &args.spectral_output {
+            if output::envi_header_path(Path::new(spectral_output)) == Path::new(&args.output) {
+                Args::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "the ENVI header of --spectral-output would overwrite --output",
+                    )
+                    .exit();
+            }
+        }
+        scene.spectral.bins = args.spectral_bins;
+    }
+    let debug = DebugOptions {
+        validation: args.validation || DEBUG_MODE,
+        gpu_assisted: args.gpu_assisted,
+        debug_printf: args.debug_printf,
+    };
+    let output_format = ImageFormat::from_path(Path::new(&args.output), args.half);
+    let capture = CaptureOptions {
+        frame: args.capture_frame,
+        output_path: args.output,
+        output_format,
+        spectral_output_path: args.spectral_output,
+        checkpoint_path: args.checkpoint,
+        checkpoint_interval: Duration::from_secs_f32(args.checkpoint_interval * 60.0),
+        resume_path: args.resume,
+        tile_size: args.tile_size,
+    };
+    // cpu captures need neither a window nor a device
+    if args.cpu && capture.has_limit(&scene.sampling) {
+        if let Err(e) = capture_headless(&mut CpuRenderer::headless(), scene, &capture) {
+            eprintln!("error: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let event_loop = EventLoop::new().unwrap();
+    if args.cpu {
+        let mut app: MeshApp<CpuRenderer> =
+            MeshApp::new(&event_loop, scene, debug, capture, args.device).unwrap();
+        event_loop.run_app(&mut app).unwrap();
+    } else {
+        let mut app: MeshApp<RaytraceRenderer> =
+            MeshApp::new(&event_loop, scene, debug, capture, args.device).unwrap();
+        event_loop.run_app(&mut app).unwrap();
+    }
 }
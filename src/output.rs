@@ -0,0 +1,623 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use exr::{
+    meta::attribute::Chromaticities,
+    prelude::{
+        f16, AnyChannel, AnyChannels, AttributeValue, Encoding, FlatSamples, Image, Layer,
+        LayerAttributes, SmallVec, Text, Vec2, WritableImage,
+    },
+};
+use image::{codecs::hdr::HdrEncoder, ImageBuffer, Rgb, Rgba};
+use log::warn;
+
+use crate::{color::ColorSpace, scene::scenes::mesh::Aov};
+
+/// Image format of a capture, picked from the extension of the output path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// tonemapped 8 bit output in any format the image crate can encode
+    Ldr,
+    Exr {
+        half: bool,
+    },
+    /// Radiance RGBE
+    Hdr,
+    Pfm,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path, half: bool) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("exr") => Self::Exr { half },
+            Some("hdr") => Self::Hdr,
+            Some("pfm") => Self::Pfm,
+            _ => Self::Ldr,
+        }
+    }
+
+    /// Whether the format stores the linear accumulation rather than the displayed image
+    pub fn is_linear(self) -> bool {
+        self != Self::Ldr
+    }
+}
+
+/// Extra image written alongside the beauty image, such as albedo or depth
+pub struct AovImage {
+    pub name: &'static str,
+    pub channels: &'static [&'static str],
+    // [pixel][channel], top row first
+    pub data: Vec<f32>,
+}
+
+/// Picks the aovs a scene asks for, in its order, out of a renderer's aov buffers
+///
+/// `albedo` and `normal` hold RGB averages, `depth_instance` holds RGBA pixels with the depth and
+/// the tlas instance. `instance_hit_groups` turns instances into brdf ids.
+pub fn collect_aovs(
+    aovs: &[Aov],
+    albedo: &[f32],
+    normal: &[f32],
+    depth_instance: &[f32],
+    instance_hit_groups: &[u32],
+) -> Vec<AovImage> {
+    aovs.iter()
+        .map(|&aov| {
+            let (channels, data): (&[_], _) = match aov {
+                Aov::Albedo => (&["R", "G", "B"], albedo.to_vec()),
+                Aov::Normal => (&["X", "Y", "Z"], normal.to_vec()),
+                Aov::Depth => (&["Z"], depth_instance.chunks(4).map(|p| p[0]).collect()),
+                Aov::Instance => (&["id"], depth_instance.chunks(4).map(|p| p[1]).collect()),
+                // misses keep -1
+                Aov::Brdf => (
+                    &["id"],
+                    depth_instance
+                        .chunks(4)
+                        .map(|p| match instance_hit_groups.get(p[1] as usize) {
+                            Some(&hit_group) if p[1] >= 0.0 => hit_group as f32,
+                            _ => -1.0,
+                        })
+                        .collect(),
+                ),
+            };
+            AovImage {
+                name: aov.name(),
+                channels,
+                data,
+            }
+        })
+        .collect()
+}
+
+/// Writes linear RGBA pixels, stored top row first, without any tonemapping or encoding
+///
+/// Alpha is dropped since every format here only stores RGB.
+/// EXR files are tagged with the chromaticities of `color_space` and get `aovs` as extra layers,
+/// other formats get them as separate files, see [write_aov_files].
+pub fn write_linear_image(
+    path: &Path,
+    (width, height): (u32, u32),
+    format: ImageFormat,
+    color_space: ColorSpace,
+    pixels: &[f32],
+    aovs: &[AovImage],
+) -> Result<()> {
+    let (width, height) = (width as usize, height as usize);
+    if pixels.len() != width * height * 4 {
+        bail!(
+            "expected {} pixel components, got {}",
+            width * height * 4,
+            pixels.len()
+        );
+    }
+    let rgb = |x: usize, y: usize| {
+        let i = (y * width + x) * 4;
+        (pixels[i], pixels[i + 1], pixels[i + 2])
+    };
+
+    match format {
+        ImageFormat::Exr { half } => {
+            let planes = band_planes(pixels, 4);
+            let samples = |plane: &Vec<f32>| {
+                if half {
+                    FlatSamples::F16(plane.iter().copied().map(f16::from_f32).collect())
+                } else {
+                    FlatSamples::F32(plane.clone())
+                }
+            };
+            let mut channels: SmallVec<[_; 4]> = ["R", "G", "B"]
+                .into_iter()
+                .zip(&planes)
+                .map(|(name, plane)| AnyChannel::new(name, samples(plane)))
+                .collect();
+            // layers in a single part file are channel name prefixes, like albedo.R
+            for aov in aovs {
+                channels.extend(aov_channels(aov, Some(aov.name)));
+            }
+            let layer = Layer::new(
+                (width, height),
+                LayerAttributes::default(),
+                Encoding::FAST_LOSSLESS,
+                AnyChannels::sort(channels),
+            );
+            write_exr(path, color_space, layer)?;
+
+            // the aovs are already in the file
+            return Ok(());
+        }
+        ImageFormat::Hdr => {
+            let data: Vec<_> = pixels.chunks(4).map(|p| Rgb([p[0], p[1], p[2]])).collect();
+            let file = BufWriter::new(File::create(path)?);
+            HdrEncoder::new(file).encode(&data, width, height)?;
+        }
+        ImageFormat::Pfm => write_pfm(path, (width, height), rgb)?,
+        ImageFormat::Ldr => bail!("{} is not a linear image format", path.display()),
+    }
+
+    write_aov_files(path, (width as u32, height as u32), aovs)
+}
+
+/// Writes every aov to its own EXR file next to `path`, named `<stem>.<aov>.exr`
+///
+/// The aovs are in the render color space but the files aren't tagged with it, since normals,
+/// depth and ids aren't colors.
+pub fn write_aov_files(path: &Path, (width, height): (u32, u32), aovs: &[AovImage]) -> Result<()> {
+    let size = (width as usize, height as usize);
+    for aov in aovs {
+        let layer = Layer::new(
+            size,
+            LayerAttributes::default(),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(aov_channels(aov, None)),
+        );
+        Image::from_layer(layer)
+            .write()
+            .to_file(aov_path(path, aov.name))?;
+    }
+
+    Ok(())
+}
+
+fn aov_path(path: &Path, name: &str) -> PathBuf {
+    path.with_extension(format!("{name}.exr"))
+}
+
+// aovs are always stored as full floats, half floats can't hold large ids exactly
+fn aov_channels(aov: &AovImage, prefix: Option<&str>) -> SmallVec<[AnyChannel<FlatSamples>; 4]> {
+    aov.channels
+        .iter()
+        .zip(band_planes(&aov.data, aov.channels.len()))
+        .map(|(&name, plane)| {
+            let name = match prefix {
+                Some(prefix) => format!("{prefix}.{name}"),
+                None => name.to_string(),
+            };
+            AnyChannel::new(name.as_str(), FlatSamples::F32(plane))
+        })
+        .collect()
+}
+
+/// Writes tonemapped RGBA pixels in [0, 1] with the sRGB transfer curve at 8 bits
+///
+/// PNG files are tagged with `color_space`, other formats are written by the image crate untagged.
+pub fn write_display_image(
+    path: &Path,
+    (width, height): (u32, u32),
+    color_space: ColorSpace,
+    pixels: &[f32],
+) -> Result<()> {
+    let to_srgb = |v: f32| -> u8 {
+        let c = v.clamp(0.0, 1.0);
+        let srgb = if c <= 0.0031308 {
+            12.92 * c
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (srgb * 255.0) as u8
+    };
+
+    let mut img_data = Vec::with_capacity(pixels.len());
+    for pixel in pixels.chunks(4) {
+        img_data.push(to_srgb(pixel[0]));
+        img_data.push(to_srgb(pixel[1]));
+        img_data.push(to_srgb(pixel[2]));
+        img_data.push((pixel[3].clamp(0.0, 1.0) * 255.0) as u8);
+    }
+
+    let is_png = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    if is_png {
+        return write_png(path, (width, height), color_space, &img_data);
+    }
+
+    let img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, img_data)
+        .ok_or_else(|| anyhow!("failed to create image buffer"))?;
+    img.save(path)?;
+
+    Ok(())
+}
+
+fn write_png(
+    path: &Path,
+    (width, height): (u32, u32),
+    color_space: ColorSpace,
+    data: &[u8],
+) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let [red, green, blue, white] = color_space.chromaticities();
+    if color_space == ColorSpace::Srgb {
+        encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    } else if [red, green, blue].iter().any(|&[x, y]| x < 0.0 || y <= 0.0) {
+        // cHRM can only hold real primaries, which rules out ACES AP0 and XYZ
+        warn!("{color_space:?} primaries can't be stored in a png, writing it untagged");
+    } else {
+        let xy = |[x, y]: [f64; 2]| (x as f32, y as f32);
+        encoder.set_source_chromaticities(png::SourceChromaticities::new(
+            xy(white),
+            xy(red),
+            xy(green),
+            xy(blue),
+        ));
+        // no gAMA, the pixels use the sRGB curve and a pure power law would misdescribe it
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+fn write_exr(
+    path: &Path,
+    color_space: ColorSpace,
+    layer: Layer<AnyChannels<FlatSamples>>,
+) -> Result<()> {
+    let mut image = Image::from_layer(layer);
+
+    let [red, green, blue, white] = color_space
+        .chromaticities()
+        .map(|[x, y]| Vec2(x as f32, y as f32));
+    image.attributes.chromaticities = Some(Chromaticities {
+        red,
+        green,
+        blue,
+        white,
+    });
+
+    image.write().to_file(path)?;
+
+    Ok(())
+}
+
+fn write_pfm(
+    path: &Path,
+    (width, height): (usize, usize),
+    rgb: impl Fn(usize, usize) -> (f32, f32, f32),
+) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    // a negative scale marks the data as little endian
+    write!(file, "PF\n{width} {height}\n-1.0\n")?;
+    // pfm rows go from the bottom of the image to the top
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let (r, g, b) = rgb(x, y);
+            for c in [r, g, b] {
+                file.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Reads a grey or RGB .pfm into RGBA pixels, top row first
+pub fn read_pfm(path: &Path) -> Result<((u32, u32), Vec<f32>)> {
+    let data = fs::read(path)?;
+    // three header lines, the last whitespace is followed directly by the samples
+    let mut fields = data.splitn(5, |b| b.is_ascii_whitespace());
+    let mut field = || -> Result<&str> {
+        let bytes = fields
+            .next()
+            .ok_or_else(|| anyhow!("{} has a truncated header", path.display()))?;
+        Ok(std::str::from_utf8(bytes)?)
+    };
+    let channels = match field()? {
+        "PF" => 3,
+        "Pf" => 1,
+        magic => bail!("{} isn't a pfm, starts with {magic:?}", path.display()),
+    };
+    let width: usize = field()?.parse()?;
+    let height: usize = field()?.parse()?;
+    let little_endian = field()?.parse::<f32>()? < 0.0;
+    let samples = fields.next().unwrap_or_default();
+    if samples.len() < width * height * channels * 4 {
+        bail!("{} is missing pixels", path.display());
+    }
+
+    let mut pixels = vec![1.0; width * height * 4];
+    for (i, bytes) in samples
+        .chunks_exact(4)
+        .take(width * height * channels)
+        .enumerate()
+    {
+        let bytes = bytes.try_into().unwrap();
+        let value = if little_endian {
+            f32::from_le_bytes(bytes)
+        } else {
+            f32::from_be_bytes(bytes)
+        };
+        let (pixel, channel) = (i / channels, i % channels);
+        // pfm rows go from the bottom of the image to the top
+        let (x, y) = (pixel % width, height - 1 - pixel / width);
+        let offset = (y * width + x) * 4;
+        if channels == 1 {
+            pixels[offset..offset + 3].fill(value);
+        } else {
+            pixels[offset + channel] = value;
+        }
+    }
+
+    Ok(((width as u32, height as u32), pixels))
+}
+
+/// Writes a multispectral image with one band per wavelength bin
+///
+/// `data` is laid out as `[y][x][band]` and `wavelengths` holds the center of each band in nm.
+/// `.exr` writes an OpenEXR image using the spectral channel naming of Fichet et al. 2021,
+/// `.raw` or `.envi` writes the raw data to `path` with an ENVI `.hdr` header next to it.
+pub fn write_multispectral(
+    path: &Path,
+    (width, height): (u32, u32),
+    wavelengths: &[f32],
+    data: &[f32],
+) -> Result<()> {
+    let pixels = width as usize * height as usize;
+    if data.len() != pixels * wavelengths.len() {
+        bail!(
+            "expected {} spectral samples, got {}",
+            pixels * wavelengths.len(),
+            data.len()
+        );
+    }
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("exr") => write_spectral_exr(path, (width, height), wavelengths, data),
+        Some("raw" | "envi") => write_envi(path, (width, height), wavelengths, data),
+        _ => bail!(
+            "unsupported multispectral output {}, expected .exr, .raw or .envi",
+            path.display()
+        ),
+    }
+}
+
+/// The ENVI header written next to a `.raw` or `.envi` multispectral output
+pub fn envi_header_path(path: &Path) -> PathBuf {
+    path.with_extension("hdr")
+}
+
+// splits [pixel][band] data into one plane per band
+fn band_planes(data: &[f32], bands: usize) -> Vec<Vec<f32>> {
+    (0..bands)
+        .map(|band| data.iter().skip(band).step_by(bands).copied().collect())
+        .collect()
+}
+
+fn write_spectral_exr(
+    path: &Path,
+    (width, height): (u32, u32),
+    wavelengths: &[f32],
+    data: &[f32],
+) -> Result<()> {
+    let channels: SmallVec<[_; 4]> = band_planes(data, wavelengths.len())
+        .into_iter()
+        .zip(wavelengths)
+        .map(|(plane, wavelength)| {
+            // the spectral layout uses a comma as the decimal separator
+            let name = format!("S0.{}nm", format!("{wavelength:.6}").replace('.', ","));
+            AnyChannel::new(name.as_str(), FlatSamples::F32(plane))
+        })
+        .collect();
+
+    let mut attributes = LayerAttributes::default();
+    attributes.other.insert(
+        Text::from("spectralLayoutVersion"),
+        AttributeValue::Text(Text::from("1.0")),
+    );
+
+    let layer = Layer::new(
+        (width as usize, height as usize),
+        attributes,
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer).write().to_file(path)?;
+
+    Ok(())
+}
+
+// ENVI images are a headerless raw file described by a text header with the same name
+fn write_envi(
+    path: &Path,
+    (width, height): (u32, u32),
+    wavelengths: &[f32],
+    data: &[f32],
+) -> Result<()> {
+    let header_path = envi_header_path(path);
+
+    let wavelength_list = wavelengths
+        .iter()
+        .map(|w| w.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut header = BufWriter::new(File::create(&header_path)?);
+    writeln!(header, "ENVI")?;
+    writeln!(header, "description = {{kg multispectral render}}")?;
+    writeln!(header, "samples = {width}")?;
+    writeln!(header, "lines = {height}")?;
+    writeln!(header, "bands = {}", wavelengths.len())?;
+    writeln!(header, "header offset = 0")?;
+    writeln!(header, "file type = ENVI Standard")?;
+    // 32 bit float, little endian, band sequential
+    writeln!(header, "data type = 4")?;
+    writeln!(header, "interleave = bsq")?;
+    writeln!(header, "byte order = 0")?;
+    writeln!(header, "wavelength units = Nanometers")?;
+    writeln!(header, "wavelength = {{{wavelength_list}}}")?;
+    header.flush()?;
+
+    let mut raw = BufWriter::new(File::create(path)?);
+    for plane in band_planes(data, wavelengths.len()) {
+        for value in plane {
+            raw.write_all(&value.to_le_bytes())?;
+        }
+    }
+    raw.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::color::ColorSpace;
+
+    use exr::prelude::read_all_flat_layers_from_file;
+
+    use super::{
+        read_pfm, write_linear_image, write_multispectral, write_png, AovImage, ImageFormat,
+    };
+
+    #[test]
+    fn envi_is_band_sequential() {
+        let dir = std::env::temp_dir().join(format!("kg-envi-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // 2x1 image with 3 bands, stored as [pixel][band]
+        let data = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        write_multispectral(&dir.join("out.raw"), (2, 1), &[400.0, 500.0, 600.0], &data).unwrap();
+
+        let header = fs::read_to_string(dir.join("out.hdr")).unwrap();
+        assert!(header.contains("bands = 3"));
+        assert!(header.contains("wavelength = {400, 500, 600}"));
+
+        let raw: Vec<f32> = fs::read(dir.join("out.raw"))
+            .unwrap()
+            .chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(raw, [1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+
+        // .hdr is Radiance, never ENVI
+        assert!(write_multispectral(&dir.join("out.hdr"), (2, 1), &[400.0], &data[..2]).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn png_color_tags() {
+        let dir = std::env::temp_dir().join(format!("kg-png-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.png");
+
+        let read_info = |color_space| {
+            write_png(&path, (1, 1), color_space, &[0, 0, 0, 255]).unwrap();
+            let decoder =
+                png::Decoder::new(std::io::BufReader::new(fs::File::open(&path).unwrap()));
+            let reader = decoder.read_info().unwrap();
+            let info = reader.info();
+            (info.gama_chunk, info.chrm_chunk)
+        };
+
+        // the sRGB curve is only ever described by the sRGB chunk, never by gAMA
+        let (gamma, chromaticities) = read_info(ColorSpace::Rec2020);
+        assert!(gamma.is_none());
+        assert_eq!(chromaticities.unwrap().blue.0.into_value(), 0.131);
+        assert_eq!(read_info(ColorSpace::Xyz), (None, None));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pfm_is_bottom_up() {
+        let dir = std::env::temp_dir().join(format!("kg-pfm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.pfm");
+
+        let format = ImageFormat::from_path(&path, false);
+        assert_eq!(format, ImageFormat::Pfm);
+
+        // 1x2 image, top pixel first
+        let pixels = [1.0, 2.0, 3.0, 1.0, 4.0, 5.0, 6.0, 1.0];
+        write_linear_image(&path, (1, 2), format, ColorSpace::Srgb, &pixels, &[]).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let data: Vec<f32> = bytes[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(data, [4.0, 5.0, 6.0, 1.0, 2.0, 3.0]);
+
+        let (size, read) = read_pfm(&path).unwrap();
+        assert_eq!(size, (1, 2));
+        assert_eq!(read, pixels);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn aov_layers_and_files() {
+        let dir = std::env::temp_dir().join(format!("kg-aov-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let pixels = [0.5; 8];
+        let aovs = [AovImage {
+            name: "depth",
+            channels: &["Z"],
+            data: vec![1.0, f32::INFINITY],
+        }];
+
+        // exr outputs get the aovs as prefixed channels
+        let path = dir.join("out.exr");
+        let format = ImageFormat::from_path(&path, true);
+        write_linear_image(&path, (2, 1), format, ColorSpace::Srgb, &pixels, &aovs).unwrap();
+        let image = read_all_flat_layers_from_file(&path).unwrap();
+        let channels = &image.layer_data[0].channel_data.list;
+        let names: Vec<_> = channels.iter().map(|c| c.name.to_string()).collect();
+        assert_eq!(names, ["B", "G", "R", "depth.Z"]);
+        let depth: Vec<_> = channels[3].sample_data.values_as_f32().collect();
+        assert_eq!(depth, [1.0, f32::INFINITY]);
+
+        // everything else gets separate files
+        let path = dir.join("out.pfm");
+        write_linear_image(
+            &path,
+            (2, 1),
+            ImageFormat::Pfm,
+            ColorSpace::Srgb,
+            &pixels,
+            &aovs,
+        )
+        .unwrap();
+        assert!(dir.join("out.depth.exr").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
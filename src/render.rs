@@ -1,6 +1,12 @@
-use std::{cell::RefCell, ffi::c_char, path::Path, rc::Rc};
+use std::{cell::RefCell, ffi::c_char, path::Path, rc::Rc, time::Duration};
 
-use crate::{features::VkFeatureGuard, scene::Scene, utils::QueueFamilyInfo};
+use crate::{
+    debug::DebugNames,
+    features::VkFeatureGuard,
+    output::ImageFormat,
+    scene::Scene,
+    utils::{QueueFamily, QueueFamilyInfo},
+};
 use ash::{vk, Device, Entry, Instance};
 use gpu_allocator::vulkan::Allocator;
 
@@ -8,6 +14,13 @@ pub mod renderers;
 
 // Device should be initialized outside the renderer, but renderer takes device for construction
 
+/// Device time spent building the acceleration structures of a scene
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccelBuildTimes {
+    pub bottom_level: Duration,
+    pub top_level: Duration,
+}
+
 pub trait Renderer<S, Target>
 where
     S: Scene,
@@ -20,17 +33,51 @@ where
         physical_device: vk::PhysicalDevice,
         queue_family_info: &QueueFamilyInfo,
         allocator: Rc<RefCell<Allocator>>,
+        // for naming the renderer's objects, does nothing without debug utils
+        debug_names: DebugNames,
     ) -> anyhow::Result<Self>;
 
-    fn ingest_scene(&mut self, scene: &S) -> anyhow::Result<()>;
+    // images are allocated at `size`, the size of the first render or tile
+    fn ingest_scene(&mut self, scene: &S, size: (u32, u32)) -> anyhow::Result<()>;
     fn render_to(&mut self, updates: &[S::Update], target: &mut Target) -> anyhow::Result<()>;
+    // renders a frame into the accumulation without presenting it and waits for it to finish
+    fn render_offscreen(&mut self, updates: &[S::Update]) -> anyhow::Result<()>;
+
+    fn save_image<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> anyhow::Result<()>;
+    fn save_spectral_image<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()>;
+    fn save_checkpoint<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()>;
+    // the checkpoint is checked against the scene and applied on the next render
+    fn resume_from<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()>;
+    // linear RGBA pixels of the current render, before any denoising or tonemapping
+    fn read_back_linear(&self) -> anyhow::Result<Vec<f32>>;
+    // largest render width or height, larger images have to be tiled
+    fn max_image_size(&self) -> u32;
+
+    // samples per pixel accumulated since the view last changed
+    fn samples_per_pixel(&self) -> u32;
+    // relative error of the accumulation, None until there is enough to compare
+    fn estimate_noise(&self) -> anyhow::Result<Option<f32>>;
 
-    fn save_image<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()>;
+    // time spent tracing the last offscreen frame, None if the device can't measure it
+    fn last_trace_time(&self) -> anyhow::Result<Option<Duration>>;
+    // None if the renderer doesn't build bottom and top level structures
+    fn accel_build_times(&self) -> Option<AccelBuildTimes>;
 
     fn required_instance_extensions() -> &'static [*const c_char];
     fn required_device_extensions() -> &'static [*const c_char];
     fn required_features() -> VkFeatureGuard<'static>;
 
-    fn has_required_queue_families(queue_family_info: &QueueFamilyInfo) -> bool;
+    fn required_queue_families() -> &'static [QueueFamily];
+    // ranks suitable devices, the highest score is used unless one is picked on the command line
+    fn score_device(instance: &Instance, physical_device: vk::PhysicalDevice) -> u32 {
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        match properties.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        }
+    }
     fn get_queue_info(queue_family_info: &QueueFamilyInfo) -> Vec<vk::DeviceQueueCreateInfo<'_>>;
 }
@@ -1,3 +1,5 @@
+mod cpu;
 mod raytrace;
 
+pub use cpu::CpuRenderer;
 pub use raytrace::RaytraceRenderer;
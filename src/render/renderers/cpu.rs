@@ -0,0 +1,620 @@
+use std::{
+    cell::RefCell,
+    ffi::c_char,
+    path::Path,
+    rc::Rc,
+    sync::LazyLock,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use ash::{vk, Device, Entry, Instance};
+use glam::{Mat3, Mat4};
+use gpu_allocator::vulkan::Allocator;
+
+use crate::{
+    checkpoint::{hash_bytes, Checkpoint},
+    debug::DebugNames,
+    denoise::denoise,
+    features::{vk_features, VkFeatureGuard, VkFeatures},
+    output::{
+        collect_aovs, write_aov_files, write_display_image, write_linear_image,
+        write_multispectral, AovImage, ImageFormat,
+    },
+    render::{AccelBuildTimes, Renderer},
+    sampling::{estimate_noise, frame_seed, visible_size},
+    scene::{
+        scenes::mesh::{
+            DenoiseSettings, MeshScene, MeshSceneUpdate, OutputSettings, SamplingSettings,
+            SpectralSettings,
+        },
+        Scene,
+    },
+    spectral::RGB_TO_SPECTRUM_TABLE,
+    tonemap::tonemap,
+    utils::{QueueFamily, QueueFamilyInfo},
+    window::WindowData,
+};
+
+use integrator::{Integrator, Launch, PixelBuffers};
+use present::Presenter;
+use shading::CpuScene;
+
+mod bvh;
+mod integrator;
+mod present;
+mod shading;
+
+// indices into the aov buffers, same layout as the aov images of the ray traced renderer
+const AOV_ALBEDO: usize = 0;
+const AOV_NORMAL: usize = 1;
+const AOV_DEPTH_INSTANCE: usize = 2;
+const AOV_IMAGE_COUNT: usize = 3;
+
+/// Path tracer running on the CPU, the reference the shaders are checked against
+///
+/// Builds its own bvh over the same scene and reimplements every shader, so it renders the same
+/// image as [RaytraceRenderer](super::RaytraceRenderer) up to noise, without any ray tracing
+/// hardware. Vulkan is only used to show the result in a window.
+pub struct CpuRenderer {
+    // None when rendering headless
+    presenter: Option<Presenter>,
+    scene: Option<CpuScene>,
+    integrator: Integrator,
+    view_inverse: Mat4,
+    proj_inverse: Mat4,
+    // render size, the size of the tile when rendering tiles
+    size: (u32, u32),
+    tile_offset: (u32, u32),
+    image_size: (u32, u32),
+    xyz_to_output: Mat3,
+    // RGBA pixels, top row first
+    image: Vec<f32>,
+    accumulation: Vec<f32>,
+    moments: Vec<f32>,
+    // albedo, normal and depth with instance, indexed by the AOV_* constants
+    aovs: Vec<Vec<f32>>,
+    // per pixel wavelength bins, [y][x][bin]
+    spectral_bins: Vec<f32>,
+    instance_hit_groups: Vec<u32>,
+    spectral_settings: SpectralSettings,
+    output_settings: OutputSettings,
+    denoise_settings: DenoiseSettings,
+    sampling_settings: SamplingSettings,
+    current_frame: u32,
+    seed: u64,
+    scene_hash: u64,
+    pending_checkpoint: Option<Checkpoint>,
+    max_image_size: u32,
+    last_trace_time: Option<Duration>,
+}
+
+impl CpuRenderer {
+    /// A renderer that never presents, for rendering without a window or GPU
+    pub fn headless() -> Self {
+        CpuRenderer {
+            presenter: None,
+            scene: None,
+            integrator: Integrator::Path,
+            view_inverse: Mat4::IDENTITY,
+            proj_inverse: Mat4::IDENTITY,
+            size: (0, 0),
+            tile_offset: (0, 0),
+            image_size: (0, 0),
+            xyz_to_output: Mat3::IDENTITY,
+            image: Vec::new(),
+            accumulation: Vec::new(),
+            moments: Vec::new(),
+            aovs: Vec::new(),
+            spectral_bins: Vec::new(),
+            instance_hit_groups: Vec::new(),
+            spectral_settings: Default::default(),
+            output_settings: Default::default(),
+            denoise_settings: Default::default(),
+            sampling_settings: Default::default(),
+            current_frame: 0,
+            seed: 0,
+            scene_hash: 0,
+            pending_checkpoint: None,
+            max_image_size: u32::MAX,
+            last_trace_time: None,
+        }
+    }
+
+    // applies the updates and renders one frame into the accumulation
+    fn trace_frame(&mut self, updates: &[MeshSceneUpdate]) -> anyhow::Result<()> {
+        for update in updates {
+            match update {
+                MeshSceneUpdate::NewView(view) => {
+                    self.view_inverse = view.inverse();
+                    self.current_frame = 0;
+                }
+                MeshSceneUpdate::NewSize((width, height, projection)) => {
+                    self.resize((*width, *height));
+                    self.proj_inverse = projection.inverse();
+                    self.tile_offset = (0, 0);
+                    self.image_size = (*width, *height);
+                    self.current_frame = 0;
+                }
+                MeshSceneUpdate::Tile { offset, image_size } => {
+                    self.tile_offset = *offset;
+                    self.image_size = *image_size;
+                    self.current_frame = 0;
+                }
+            }
+        }
+
+        if let Some(checkpoint) = self.pending_checkpoint.take() {
+            self.restore_checkpoint(checkpoint)?;
+        }
+
+        let launch = Launch {
+            scene: self
+                .scene
+                .as_ref()
+                .ok_or_else(|| anyhow!("no scene to render"))?,
+            integrator: self.integrator,
+            view_inverse: self.view_inverse,
+            proj_inverse: self.proj_inverse,
+            seed_offset: frame_seed(self.seed, self.current_frame),
+            frame: self.current_frame,
+            tile_offset: self.tile_offset,
+            image_size: self.image_size,
+            spectral: self.spectral_settings,
+            samples_per_frame: self.sampling_settings.frame_samples(),
+            adaptive_threshold: self.sampling_settings.adaptive_threshold.unwrap_or(0.0),
+            xyz_to_output: self.xyz_to_output,
+        };
+
+        let (width, height) = (self.size.0 as usize, self.size.1 as usize);
+        let bins = self.spectral_settings.bins as usize;
+        let (albedo, normal, depth_instance) = match self.aovs.as_mut_slice() {
+            [albedo, normal, depth_instance] => (
+                split_rows(albedo, height),
+                split_rows(normal, height),
+                split_rows(depth_instance, height),
+            ),
+            _ => (
+                split_rows(&mut [], height),
+                split_rows(&mut [], height),
+                split_rows(&mut [], height),
+            ),
+        };
+        let rows = split_rows(&mut self.image, height)
+            .into_iter()
+            .zip(split_rows(&mut self.accumulation, height))
+            .zip(split_rows(&mut self.moments, height))
+            .zip(albedo.into_iter().zip(normal).zip(depth_instance))
+            .zip(split_rows(&mut self.spectral_bins, height))
+            .enumerate()
+            .map(
+                |(
+                    y,
+                    ((((image, accumulation), moments), ((albedo, normal), depth_instance)), bins),
+                )| {
+                    (
+                        y as u32,
+                        PixelBuffers {
+                            image,
+                            accumulation,
+                            moments,
+                            albedo,
+                            normal,
+                            depth_instance,
+                            bins,
+                        },
+                    )
+                },
+            );
+
+        // rows are handed out one at a time, so threads stay busy however uneven the scene is
+        let rows = Mutex::new(rows);
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let start = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    let Some((y, mut row)) = rows.lock().unwrap().next() else {
+                        break;
+                    };
+                    for x in 0..width {
+                        launch.shade_pixel((x as u32, y), row.pixel(x, bins));
+                    }
+                });
+            }
+        });
+        self.last_trace_time = Some(start.elapsed());
+
+        self.current_frame += 1;
+        Ok(())
+    }
+
+    fn resize(&mut self, (width, height): (u32, u32)) {
+        let pixels = (width * height) as usize;
+        self.size = (width, height);
+        self.image = vec![0.0; 4 * pixels];
+        self.accumulation = vec![0.0; 4 * pixels];
+        self.moments = vec![0.0; 4 * pixels];
+        self.aovs = if self.aovs_enabled() {
+            vec![vec![0.0; 4 * pixels]; AOV_IMAGE_COUNT]
+        } else {
+            Vec::new()
+        };
+        self.spectral_bins = vec![0.0; pixels * self.spectral_settings.bins as usize];
+    }
+
+    /// Whether the path tracer writes aovs, the denoiser needs them even if none are saved
+    fn aovs_enabled(&self) -> bool {
+        !self.output_settings.aovs.is_empty() || self.denoise_settings.enabled()
+    }
+
+    /// Identifies the scene, camera and sampling a checkpoint was rendered with
+    ///
+    /// Hashes the same bytes as the ray traced renderer, so either can resume the other's checkpoints.
+    fn checkpoint_hash(&self) -> u64 {
+        let mut bytes = self.scene_hash.to_le_bytes().to_vec();
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.view_inverse.to_cols_array()));
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.proj_inverse.to_cols_array()));
+        bytes.extend_from_slice(bytemuck::cast_slice(&[
+            self.tile_offset.0,
+            self.tile_offset.1,
+            self.image_size.0,
+            self.image_size.1,
+        ]));
+        bytes.extend_from_slice(&self.sampling_settings.frame_samples().to_le_bytes());
+        hash_bytes(&bytes)
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: Checkpoint) -> anyhow::Result<()> {
+        let size = self.size;
+        if checkpoint.size != size {
+            bail!(
+                "checkpoint is {}x{} but the render is {}x{}",
+                checkpoint.size.0,
+                checkpoint.size.1,
+                size.0,
+                size.1
+            );
+        }
+        if checkpoint.scene_hash != self.checkpoint_hash() {
+            bail!("checkpoint was rendered from a different scene, camera or sample count");
+        }
+        if checkpoint.aovs.len() != self.aovs.len() {
+            bail!(
+                "checkpoint has {} aovs, expected {}",
+                checkpoint.aovs.len(),
+                self.aovs.len()
+            );
+        }
+        if checkpoint.spectral_bins.len() != self.spectral_bins.len() {
+            bail!("checkpoint has a different number of spectral bins");
+        }
+
+        self.accumulation = checkpoint.accumulation;
+        self.moments = checkpoint.moments;
+        self.aovs = checkpoint.aovs;
+        self.spectral_bins = checkpoint.spectral_bins;
+        self.current_frame = checkpoint.frame;
+        self.seed = checkpoint.seed;
+        Ok(())
+    }
+
+    /// RGB average of an aov that holds a sum of per frame averages
+    fn accumulated_aov(&self, index: usize) -> Vec<f32> {
+        let frames = self.current_frame.max(1) as f32;
+        self.aovs[index]
+            .chunks(4)
+            .flat_map(|p| [p[0] / frames, p[1] / frames, p[2] / frames])
+            .collect()
+    }
+
+    /// The enabled aovs in the order the scene lists them
+    fn collect_aovs(&self) -> Vec<AovImage> {
+        let aovs = &self.output_settings.aovs;
+        if aovs.is_empty() {
+            return Vec::new();
+        }
+
+        collect_aovs(
+            aovs,
+            &self.accumulated_aov(AOV_ALBEDO),
+            &self.accumulated_aov(AOV_NORMAL),
+            &self.aovs[AOV_DEPTH_INSTANCE],
+            &self.instance_hit_groups,
+        )
+    }
+
+    fn denoised_image(&self) -> Vec<f32> {
+        denoise(
+            self.size,
+            &self.image,
+            &self.accumulated_aov(AOV_ALBEDO),
+            &self.accumulated_aov(AOV_NORMAL),
+            &self.denoise_settings,
+        )
+    }
+}
+
+// splits a buffer into one slice per row, buffers that are disabled give empty rows
+fn split_rows(buffer: &mut [f32], height: usize) -> Vec<&mut [f32]> {
+    if buffer.is_empty() || height == 0 {
+        return (0..height).map(|_| Default::default()).collect();
+    }
+    let row_len = buffer.len() / height;
+    buffer.chunks_mut(row_len).collect()
+}
+
+impl Renderer<MeshScene, WindowData> for CpuRenderer {
+    fn new(
+        _vk_lib: &Entry,
+        instance: &Instance,
+        device: &Device,
+        physical_device: vk::PhysicalDevice,
+        queue_family_info: &QueueFamilyInfo,
+        allocator: Rc<RefCell<Allocator>>,
+        debug_names: DebugNames,
+    ) -> anyhow::Result<Self> {
+        let queue_index = queue_family_info
+            .compute_index
+            .ok_or(anyhow!("no compute"))?;
+        let limits = unsafe { instance.get_physical_device_properties(physical_device) }.limits;
+
+        Ok(CpuRenderer {
+            presenter: Some(Presenter::new(
+                device,
+                queue_index,
+                limits,
+                allocator,
+                debug_names,
+            )?),
+            // the presenter uploads the whole render as one image
+            max_image_size: limits.max_image_dimension2_d,
+            ..Self::headless()
+        })
+    }
+
+    fn ingest_scene(&mut self, scene: &MeshScene, size: (u32, u32)) -> anyhow::Result<()> {
+        self.scene = Some(CpuScene::new(scene, &RGB_TO_SPECTRUM_TABLE)?);
+        self.integrator = Integrator::from_raygen(scene.raygen_shader.file())?;
+
+        self.output_settings = scene.output.clone();
+        self.denoise_settings = scene.denoise;
+        self.sampling_settings = scene.sampling;
+        self.seed = scene.render.seed.unwrap_or_else(rand::random);
+        self.spectral_settings = scene.spectral;
+        self.scene_hash = scene.source_hash;
+
+        let output = &scene.output;
+        let white = output
+            .white_point
+            .unwrap_or(output.color_space.default_white());
+        self.xyz_to_output = output.color_space.render_xyz_to_rgb(white).as_mat3();
+
+        // same order as the instances of the bvh, objects then procedural objects
+        let procedural_hit_groups = scene
+            .procedural_objects
+            .iter()
+            .map(|proc_obj| (scene.hit_shaders.len() + proc_obj.geometry_index) as u32);
+        self.instance_hit_groups = scene
+            .objects
+            .iter()
+            .map(|object| object.brdf_i as u32)
+            .chain(procedural_hit_groups)
+            .collect();
+
+        self.view_inverse = scene.camera.view().inverse();
+        self.proj_inverse = scene.camera.perspective().inverse();
+        self.resize(size);
+        self.tile_offset = (0, 0);
+        self.image_size = size;
+        self.current_frame = 0;
+
+        Ok(())
+    }
+
+    fn render_to(
+        &mut self,
+        updates: &[<MeshScene as Scene>::Update],
+        target: &mut WindowData,
+    ) -> anyhow::Result<()> {
+        self.trace_frame(updates)?;
+
+        let pixels = if self.denoise_settings.preview {
+            self.denoised_image()
+        } else {
+            self.image.clone()
+        };
+        let pixels = tonemap(&pixels, &self.output_settings);
+        let size = self.size;
+        self.presenter
+            .as_mut()
+            .ok_or_else(|| anyhow!("a headless renderer can't present"))?
+            .present(target, size, &pixels)
+    }
+
+    fn render_offscreen(&mut self, updates: &[MeshSceneUpdate]) -> anyhow::Result<()> {
+        self.trace_frame(updates)
+    }
+
+    fn save_image<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> anyhow::Result<()> {
+        if self.image.is_empty() {
+            bail!("no image to save");
+        }
+
+        // linear formats get the untouched render, everything else gets what is displayed
+        // unless it gets denoised, which happens before tonemapping
+        let denoised =
+            self.denoise_settings.save || (self.denoise_settings.preview && !format.is_linear());
+        let pixel_data = if denoised {
+            self.denoised_image()
+        } else {
+            self.image.clone()
+        };
+        let aovs = self.collect_aovs();
+
+        let color_space = self.output_settings.color_space;
+        if format.is_linear() {
+            write_linear_image(
+                path.as_ref(),
+                self.size,
+                format,
+                color_space,
+                &pixel_data,
+                &aovs,
+            )
+        } else {
+            let pixel_data = tonemap(&pixel_data, &self.output_settings);
+            write_display_image(path.as_ref(), self.size, color_space, &pixel_data)?;
+            write_aov_files(path.as_ref(), self.size, &aovs)
+        }
+    }
+
+    fn save_spectral_image<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let bins = self.spectral_settings.bins;
+        if bins == 0 {
+            bail!("multispectral binning is not enabled for this scene");
+        }
+
+        // the bins hold a sum of per frame averages
+        let frames = self.current_frame.max(1) as f32;
+        let data: Vec<f32> = self.spectral_bins.iter().map(|x| x / frames).collect();
+
+        let SpectralSettings {
+            min_wavelength,
+            max_wavelength,
+            ..
+        } = self.spectral_settings;
+        let bin_width = (max_wavelength - min_wavelength) / bins as f32;
+        let wavelengths: Vec<f32> = (0..bins)
+            .map(|i| min_wavelength + (i as f32 + 0.5) * bin_width)
+            .collect();
+
+        write_multispectral(path.as_ref(), self.size, &wavelengths, &data)
+    }
+
+    fn save_checkpoint<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        if self.accumulation.is_empty() {
+            bail!("no accumulation to checkpoint");
+        }
+
+        Checkpoint {
+            scene_hash: self.checkpoint_hash(),
+            size: self.size,
+            frame: self.current_frame,
+            seed: self.seed,
+            accumulation: self.accumulation.clone(),
+            moments: self.moments.clone(),
+            aovs: self.aovs.clone(),
+            spectral_bins: self.spectral_bins.clone(),
+        }
+        .write(path.as_ref())
+    }
+
+    fn resume_from<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.pending_checkpoint = Some(Checkpoint::read(path.as_ref())?);
+        Ok(())
+    }
+
+    fn read_back_linear(&self) -> anyhow::Result<Vec<f32>> {
+        Ok(self.image.clone())
+    }
+
+    fn max_image_size(&self) -> u32 {
+        self.max_image_size
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.current_frame * self.sampling_settings.frame_samples()
+    }
+
+    fn estimate_noise(&self) -> anyhow::Result<Option<f32>> {
+        let visible = visible_size(self.size, self.tile_offset, self.image_size);
+        Ok(estimate_noise(
+            &self.accumulation,
+            self.size.0,
+            visible,
+            self.current_frame,
+        ))
+    }
+
+    fn last_trace_time(&self) -> anyhow::Result<Option<Duration>> {
+        Ok(self.last_trace_time)
+    }
+
+    // the scene goes into a single bvh
+    fn accel_build_times(&self) -> Option<AccelBuildTimes> {
+        None
+    }
+
+    fn required_instance_extensions() -> &'static [*const c_char] {
+        &[]
+    }
+
+    // only presents, nothing beyond what the window needs
+    fn required_device_extensions() -> &'static [*const c_char] {
+        &[]
+    }
+
+    fn required_features() -> VkFeatureGuard<'static> {
+        static FEATURES: LazyLock<VkFeatures> = LazyLock::new(|| {
+            vk_features! {
+                vk::PhysicalDeviceFeatures {},
+            }
+        });
+
+        FEATURES.get_list()
+    }
+
+    fn required_queue_families() -> &'static [QueueFamily] {
+        &[QueueFamily::Compute]
+    }
+
+    fn get_queue_info(queue_family_info: &QueueFamilyInfo) -> Vec<vk::DeviceQueueCreateInfo<'_>> {
+        let create_info = vk::DeviceQueueCreateInfo {
+            queue_family_index: queue_family_info.compute_index.unwrap(),
+            queue_count: 1,
+            p_queue_priorities: &1.0,
+            ..Default::default()
+        };
+
+        vec![create_info]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::CpuRenderer;
+    use crate::{render::Renderer, scene::scenes::mesh::MeshScene};
+
+    #[test]
+    fn renders_every_scene() {
+        for entry in fs::read_dir("resources/scenes").unwrap() {
+            let path = entry.unwrap().path();
+            // the ajax bust isn't checked in
+            if path.ends_with("ajax-point.toml") {
+                continue;
+            }
+            let mut scene = MeshScene::load_from(File::open(&path).unwrap()).unwrap();
+            scene.output.resolution = Some((16, 12));
+            scene.sampling.samples_per_frame = 1;
+
+            let mut renderer = CpuRenderer::headless();
+            renderer.ingest_scene(&scene, (16, 12)).unwrap();
+            renderer.render_offscreen(&[]).unwrap();
+            renderer.render_offscreen(&[]).unwrap();
+
+            let image = renderer.read_back_linear().unwrap();
+            assert_eq!(image.len(), 16 * 12 * 4);
+            assert!(
+                image.iter().all(|x| x.is_finite()),
+                "{} has non-finite pixels",
+                path.display()
+            );
+            assert_eq!(renderer.samples_per_pixel(), 2);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use glam::{Mat4, Vec2, Vec3};
+
+use crate::scene::scenes::mesh::Aabb;
+
+// nodes with this many primitives or fewer aren't split any further
+const MAX_LEAF_SIZE: usize = 4;
+// deep enough for any tree built by median splits
+const STACK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+    pub t_min: f32,
+    pub t_max: f32,
+}
+
+/// Object space shape of an intersection shader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProceduralShape {
+    // unit sphere at the origin, sphere.rint
+    Sphere,
+    // unit disc in the xy plane facing +z, with a hole in the middle for dvd.rint
+    Disc { inner_radius: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Primitive {
+    // world space triangle
+    Triangle {
+        vertices: [Vec3; 3],
+        instance: u32,
+        index: u32,
+    },
+    Procedural {
+        // world space bounds of the transformed aabb
+        bounds: Aabb,
+        shape: ProceduralShape,
+        world_to_object: Mat4,
+        instance: u32,
+        index: u32,
+    },
+}
+
+/// Closest intersection along a ray, like the builtins available to a closest hit shader
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub t: f32,
+    // tlas instance, objects come first followed by procedural objects
+    pub instance: usize,
+    // triangle in the mesh, or aabb in the procedural geometry
+    pub primitive: usize,
+    // weights of the second and third vertex of a triangle
+    pub barycentrics: Vec2,
+    // object space normal reported by a procedural intersection
+    pub normal: Vec3,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    bounds: Aabb,
+    // leaves hold count primitives starting at first, inner nodes have a count of 0
+    // and their second child at first, the first child always directly follows its parent
+    first: u32,
+    count: u32,
+}
+
+/// Bounding volume hierarchy over every primitive of the scene, split at the median centroid
+pub struct Bvh {
+    nodes: Vec<Node>,
+    primitives: Vec<Primitive>,
+}
+
+impl Primitive {
+    fn bounds(&self) -> Aabb {
+        match self {
+            Primitive::Triangle { vertices, .. } => Aabb {
+                min: vertices[0].min(vertices[1]).min(vertices[2]),
+                max: vertices[0].max(vertices[1]).max(vertices[2]),
+            },
+            Primitive::Procedural { bounds, .. } => *bounds,
+        }
+    }
+
+    fn centroid(&self) -> Vec3 {
+        let bounds = self.bounds();
+        (bounds.min + bounds.max) * 0.5
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        match *self {
+            Primitive::Triangle {
+                vertices: [a, b, c],
+                instance,
+                index,
+            } => {
+                // möller-trumbore
+                let edge1 = b - a;
+                let edge2 = c - a;
+                let p = ray.direction.cross(edge2);
+                let det = edge1.dot(p);
+                if det.abs() < f32::EPSILON * f32::EPSILON {
+                    return None;
+                }
+                let inv_det = 1.0 / det;
+                let s = ray.origin - a;
+                let u = s.dot(p) * inv_det;
+                if !(0.0..=1.0).contains(&u) {
+                    return None;
+                }
+                let q = s.cross(edge1);
+                let v = ray.direction.dot(q) * inv_det;
+                if v < 0.0 || u + v > 1.0 {
+                    return None;
+                }
+                let t = edge2.dot(q) * inv_det;
+                if t < ray.t_min || t > ray.t_max {
+                    return None;
+                }
+
+                Some(Hit {
+                    t,
+                    instance: instance as usize,
+                    primitive: index as usize,
+                    barycentrics: Vec2::new(u, v),
+                    normal: Vec3::ZERO,
+                })
+            }
+            Primitive::Procedural {
+                shape,
+                world_to_object,
+                instance,
+                index,
+                ..
+            } => {
+                // the direction isn't renormalized, so t is the same in both spaces
+                let origin = world_to_object.transform_point3(ray.origin);
+                let direction = world_to_object.transform_vector3(ray.direction);
+                let (t, normal) = shape.intersect(origin, direction, ray.t_min, ray.t_max)?;
+
+                Some(Hit {
+                    t,
+                    instance: instance as usize,
+                    primitive: index as usize,
+                    barycentrics: Vec2::ZERO,
+                    normal,
+                })
+            }
+        }
+    }
+}
+
+impl ProceduralShape {
+    // mirrors the intersection shaders, returns the distance and object space normal
+    fn intersect(
+        self,
+        origin: Vec3,
+        direction: Vec3,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, Vec3)> {
+        match self {
+            ProceduralShape::Sphere => {
+                let a = direction.dot(direction);
+                let b = 2.0 * origin.dot(direction);
+                let c = origin.dot(origin) - 1.0;
+                let discriminant = b * b - 4.0 * a * c;
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let sqrt_disc = discriminant.sqrt();
+                let mut t = (-b - sqrt_disc) / (2.0 * a);
+                if t < t_min || t > t_max {
+                    t = (-b + sqrt_disc) / (2.0 * a);
+                    if t < t_min || t > t_max {
+                        return None;
+                    }
+                }
+                Some((t, (origin + t * direction).normalize()))
+            }
+            ProceduralShape::Disc { inner_radius } => {
+                if direction.z.abs() < 1e-6 {
+                    return None;
+                }
+                let t = -origin.z / direction.z;
+                if t < t_min || t > t_max {
+                    return None;
+                }
+                let dist_from_center = (origin + t * direction).truncate().length();
+                if dist_from_center > 1.0 || dist_from_center < inner_radius {
+                    return None;
+                }
+                Some((t, Vec3::Z))
+            }
+        }
+    }
+}
+
+/// World space bounds of a transformed aabb
+pub fn transform_aabb(transform: Mat4, aabb: &Aabb) -> Aabb {
+    let corners = (0..8).map(|i| {
+        transform.transform_point3(Vec3::new(
+            if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+            if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+            if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+        ))
+    });
+    corners.fold(
+        Aabb {
+            min: Vec3::INFINITY,
+            max: Vec3::NEG_INFINITY,
+        },
+        |bounds, corner| Aabb {
+            min: bounds.min.min(corner),
+            max: bounds.max.max(corner),
+        },
+    )
+}
+
+// slab test, inv_direction can hold infinities for axis aligned rays
+fn hits_bounds(bounds: &Aabb, origin: Vec3, inv_direction: Vec3, t_min: f32, t_max: f32) -> bool {
+    let t0 = (bounds.min - origin) * inv_direction;
+    let t1 = (bounds.max - origin) * inv_direction;
+    let near = t0.min(t1).max_element().max(t_min);
+    let far = t0.max(t1).min_element().min(t_max);
+    near <= far
+}
+
+impl Bvh {
+    pub fn new(primitives: Vec<Primitive>) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(2 * primitives.len() / MAX_LEAF_SIZE + 1),
+            primitives,
+        };
+        if !bvh.primitives.is_empty() {
+            bvh.build(0, bvh.primitives.len());
+        }
+        bvh
+    }
+
+    fn build(&mut self, start: usize, end: usize) -> usize {
+        let primitives = &mut self.primitives[start..end];
+        let mut bounds = primitives[0].bounds();
+        let mut centroid_bounds = Aabb {
+            min: primitives[0].centroid(),
+            max: primitives[0].centroid(),
+        };
+        for primitive in primitives.iter() {
+            let (primitive_bounds, centroid) = (primitive.bounds(), primitive.centroid());
+            bounds.min = bounds.min.min(primitive_bounds.min);
+            bounds.max = bounds.max.max(primitive_bounds.max);
+            centroid_bounds.min = centroid_bounds.min.min(centroid);
+            centroid_bounds.max = centroid_bounds.max.max(centroid);
+        }
+
+        let node_i = self.nodes.len();
+        self.nodes.push(Node {
+            bounds,
+            first: start as u32,
+            count: (end - start) as u32,
+        });
+
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        // primitives with the same centroid can't be told apart by splitting
+        if end - start <= MAX_LEAF_SIZE || extent[axis] <= 0.0 {
+            return node_i;
+        }
+
+        let mid = (end - start) / 2;
+        primitives.select_nth_unstable_by(mid, |a, b| {
+            a.centroid()[axis].total_cmp(&b.centroid()[axis])
+        });
+
+        self.build(start, start + mid);
+        let second = self.build(start + mid, end);
+        self.nodes[node_i].first = second as u32;
+        self.nodes[node_i].count = 0;
+        node_i
+    }
+
+    // visits the leaves the ray passes through, nearest child first
+    // stops when visit returns true, visit can shorten the ray by lowering t_max
+    fn traverse(&self, ray: &Ray, mut visit: impl FnMut(&Primitive, &mut f32) -> bool) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_direction = ray.direction.recip();
+        let mut t_max = ray.t_max;
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_len = 1;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node = &self.nodes[stack[stack_len]];
+            if !hits_bounds(&node.bounds, ray.origin, inv_direction, ray.t_min, t_max) {
+                continue;
+            }
+
+            if node.count > 0 {
+                let first = node.first as usize;
+                for primitive in &self.primitives[first..first + node.count as usize] {
+                    if visit(primitive, &mut t_max) {
+                        return;
+                    }
+                }
+            } else {
+                let first_child = stack[stack_len] + 1;
+                let second_child = node.first as usize;
+                // the child the ray points away from is nearer, it's pushed last so it's visited first
+                let a = self.nodes[first_child].bounds;
+                let b = self.nodes[second_child].bounds;
+                let (near, far) = if ray.direction.dot(b.min + b.max - a.min - a.max) >= 0.0 {
+                    (first_child, second_child)
+                } else {
+                    (second_child, first_child)
+                };
+                stack[stack_len] = far;
+                stack[stack_len + 1] = near;
+                stack_len += 2;
+            }
+        }
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let mut closest = None;
+        self.traverse(ray, |primitive, t_max| {
+            if let Some(hit) = primitive.intersect(&Ray {
+                t_max: *t_max,
+                ..*ray
+            }) {
+                *t_max = hit.t;
+                closest = Some(hit);
+            }
+            false
+        });
+        closest
+    }
+
+    /// Whether anything is hit, like a ray traced with gl_RayFlagsTerminateOnFirstHitEXT
+    pub fn occluded(&self, ray: &Ray) -> bool {
+        let mut occluded = false;
+        self.traverse(ray, |primitive, _| {
+            occluded = primitive.intersect(ray).is_some();
+            occluded
+        });
+        occluded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Mat4, Vec3};
+
+    use super::{Bvh, Primitive, ProceduralShape, Ray};
+    use crate::scene::scenes::mesh::Aabb;
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction,
+            t_min: 1e-4,
+            t_max: 1000.0,
+        }
+    }
+
+    // a row of unit triangles facing +z at x = 0, 2, 4, ...
+    fn triangles(count: u32) -> Vec<Primitive> {
+        (0..count)
+            .map(|i| {
+                let x = 2.0 * i as f32;
+                Primitive::Triangle {
+                    vertices: [
+                        Vec3::new(x, 0.0, -(i as f32)),
+                        Vec3::new(x + 1.0, 0.0, -(i as f32)),
+                        Vec3::new(x, 1.0, -(i as f32)),
+                    ],
+                    instance: i,
+                    index: 0,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn closest_hit_matches_brute_force() {
+        let bvh = Bvh::new(triangles(50));
+        for i in 0..50 {
+            let x = 2.0 * i as f32 + 0.25;
+            let hit = bvh
+                .intersect(&ray(Vec3::new(x, 0.25, 5.0), Vec3::NEG_Z))
+                .unwrap();
+            assert_eq!(hit.instance, i);
+            assert!((hit.t - (5.0 + i as f32)).abs() < 1e-4);
+            assert!(bvh.occluded(&ray(Vec3::new(x, 0.25, 5.0), Vec3::NEG_Z)));
+        }
+        assert!(bvh
+            .intersect(&ray(Vec3::new(1.5, 0.75, 5.0), Vec3::NEG_Z))
+            .is_none());
+    }
+
+    #[test]
+    fn procedural_spheres_and_discs() {
+        let sphere =
+            Mat4::from_translation(Vec3::new(0.0, 0.0, -4.0)) * Mat4::from_scale(Vec3::splat(2.0));
+        let dvd = Mat4::from_translation(Vec3::new(10.0, 0.0, 0.0));
+        let unit = Aabb {
+            min: Vec3::splat(-1.0),
+            max: Vec3::splat(1.0),
+        };
+        let bvh = Bvh::new(vec![
+            Primitive::Procedural {
+                bounds: super::transform_aabb(sphere, &unit),
+                shape: ProceduralShape::Sphere,
+                world_to_object: sphere.inverse(),
+                instance: 0,
+                index: 0,
+            },
+            Primitive::Procedural {
+                bounds: super::transform_aabb(dvd, &unit),
+                shape: ProceduralShape::Disc {
+                    inner_radius: 1.0 / 6.0,
+                },
+                world_to_object: dvd.inverse(),
+                instance: 1,
+                index: 0,
+            },
+        ]);
+
+        let hit = bvh
+            .intersect(&ray(Vec3::new(0.0, 0.0, 5.0), Vec3::NEG_Z))
+            .unwrap();
+        assert_eq!(hit.instance, 0);
+        assert!((hit.t - 7.0).abs() < 1e-4);
+        assert!(hit.normal.abs_diff_eq(Vec3::Z, 1e-4));
+
+        let hit = bvh
+            .intersect(&ray(Vec3::new(10.5, 0.0, 5.0), Vec3::NEG_Z))
+            .unwrap();
+        assert_eq!(hit.instance, 1);
+        // through the hole in the middle
+        assert!(bvh
+            .intersect(&ray(Vec3::new(10.0, 0.0, 5.0), Vec3::NEG_Z))
+            .is_none());
+    }
+}
@@ -0,0 +1,488 @@
+use anyhow::{bail, Result};
+use glam::{Mat3, Mat4, UVec2, Vec2, Vec3, Vec4};
+
+use super::{
+    bvh::Ray,
+    shading::{spectrum_to_xyz, CpuScene, Rng},
+};
+use crate::scene::scenes::mesh::{
+    SpectralSettings, WavelengthSampling, SPECTRUM_MAX_WAVELENGTH, SPECTRUM_MIN_WAVELENGTH,
+};
+
+// everything below mirrors path.rgen and simple.rgen, keep them in sync
+
+const MAX_DEPTH: u32 = 12;
+const T_MAX: f32 = 1000.0;
+
+// adaptive sampling, see is_converged in path.rgen
+const ADAPTIVE_MIN_FRAMES: f32 = 4.0;
+const CONFIDENCE_Z: f32 = 1.96;
+const ADAPTIVE_EPSILON: f32 = 1e-2;
+
+// the CIE Y curve is approximated by a sech^2 lobe for importance sampling
+const CIE_Y_CENTER: f32 = 538.0;
+const CIE_Y_SCALE: f32 = 0.0072;
+
+fn range_wavelengths(spectral: &SpectralSettings) -> f32 {
+    spectral.max_wavelength - spectral.min_wavelength
+}
+
+// the share of the spectra range that is rendered
+fn range_scale(spectral: &SpectralSettings) -> f32 {
+    range_wavelengths(spectral) / (SPECTRUM_MAX_WAVELENGTH - SPECTRUM_MIN_WAVELENGTH)
+}
+
+fn sample_cie_y(spectral: &SpectralSettings, u: f32) -> f32 {
+    let a = (CIE_Y_SCALE * (spectral.min_wavelength - CIE_Y_CENTER)).tanh();
+    let b = (CIE_Y_SCALE * (spectral.max_wavelength - CIE_Y_CENTER)).tanh();
+    CIE_Y_CENTER + (a + (b - a) * u).atanh() / CIE_Y_SCALE
+}
+
+fn cie_y_pdf(spectral: &SpectralSettings, wavelength: f32) -> f32 {
+    let a = (CIE_Y_SCALE * (spectral.min_wavelength - CIE_Y_CENTER)).tanh();
+    let b = (CIE_Y_SCALE * (spectral.max_wavelength - CIE_Y_CENTER)).tanh();
+    let c = (CIE_Y_SCALE * (wavelength - CIE_Y_CENTER)).cosh();
+    CIE_Y_SCALE / (c * c * (b - a))
+}
+
+// wavelengths traced by sample i and the weight of each lane relative to uniform sampling over
+// the whole spectra range, so narrowing the range clips the spectrum instead of brightening it
+fn sample_wavelengths(
+    spectral: &SpectralSettings,
+    samples_per_frame: u32,
+    i: u32,
+    rng: &mut Rng,
+) -> (Vec4, Vec4) {
+    let mut u = rng.next();
+    if spectral.sampling != WavelengthSampling::Uniform {
+        u = (i as f32 + u) / samples_per_frame as f32;
+    }
+
+    let range = range_wavelengths(spectral);
+    let min = spectral.min_wavelength;
+    let scale = range_scale(spectral);
+    match spectral.sampling {
+        WavelengthSampling::CieY => {
+            let wavelength = sample_cie_y(spectral, u);
+            let weight = scale / (cie_y_pdf(spectral, wavelength) * range);
+            (Vec4::splat(wavelength), Vec4::new(weight, 0.0, 0.0, 0.0))
+        }
+        WavelengthSampling::Hero => {
+            // the other wavelengths are evenly spaced after the hero, wrapping around the range
+            let hero = u * range + min;
+            let offsets = Vec4::new(0.0, 1.0, 2.0, 3.0) * range / 4.0;
+            let wrapped =
+                (Vec4::splat(hero - min) + offsets).map(|x| x - range * (x / range).floor());
+            (wrapped + min, Vec4::splat(0.25 * scale))
+        }
+        WavelengthSampling::Uniform | WavelengthSampling::Stratified => (
+            Vec4::splat(u * range + min),
+            Vec4::new(scale, 0.0, 0.0, 0.0),
+        ),
+    }
+}
+
+/// The raygen shader a scene uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrator {
+    Path,
+    // one ray per pixel showing the radiance of whatever it hits, for the debug shaders
+    Simple,
+}
+
+impl Integrator {
+    pub fn from_raygen(file: &str) -> Result<Self> {
+        Ok(match file {
+            "path.rgen" => Integrator::Path,
+            "simple.rgen" => Integrator::Simple,
+            _ => bail!("the cpu renderer has no implementation of {file}"),
+        })
+    }
+}
+
+/// What the raygen shaders get as push and specialization constants
+pub struct Launch<'a> {
+    pub scene: &'a CpuScene,
+    pub integrator: Integrator,
+    pub view_inverse: Mat4,
+    pub proj_inverse: Mat4,
+    pub seed_offset: (u32, u32),
+    pub frame: u32,
+    pub tile_offset: (u32, u32),
+    pub image_size: (u32, u32),
+    pub spectral: SpectralSettings,
+    pub samples_per_frame: u32,
+    // 0 disables adaptive sampling
+    pub adaptive_threshold: f32,
+    pub xyz_to_output: Mat3,
+}
+
+/// The RGBA texels and wavelength bins of one pixel, aovs and bins are empty when disabled
+pub struct PixelBuffers<'a> {
+    pub image: &'a mut [f32],
+    pub accumulation: &'a mut [f32],
+    pub moments: &'a mut [f32],
+    pub albedo: &'a mut [f32],
+    pub normal: &'a mut [f32],
+    pub depth_instance: &'a mut [f32],
+    pub bins: &'a mut [f32],
+}
+
+impl PixelBuffers<'_> {
+    /// The buffers of pixel x out of the buffers of a row
+    pub fn pixel(&mut self, x: usize, bins: usize) -> PixelBuffers<'_> {
+        fn texel(row: &mut [f32], x: usize, len: usize) -> &mut [f32] {
+            if row.is_empty() {
+                row
+            } else {
+                &mut row[x * len..(x + 1) * len]
+            }
+        }
+
+        PixelBuffers {
+            image: texel(self.image, x, 4),
+            accumulation: texel(self.accumulation, x, 4),
+            moments: texel(self.moments, x, 4),
+            albedo: texel(self.albedo, x, 4),
+            normal: texel(self.normal, x, 4),
+            depth_instance: texel(self.depth_instance, x, 4),
+            bins: texel(self.bins, x, bins),
+        }
+    }
+}
+
+fn load(texel: &[f32]) -> Vec4 {
+    Vec4::from_slice(texel)
+}
+
+fn store(texel: &mut [f32], value: Vec4) {
+    value.write_to_slice(texel);
+}
+
+impl Launch<'_> {
+    /// Runs the raygen shader for a pixel of the tile
+    pub fn shade_pixel(&self, launch_pixel: (u32, u32), buffers: PixelBuffers) {
+        match self.integrator {
+            Integrator::Path => self.trace_path(launch_pixel, buffers),
+            Integrator::Simple => self.trace_simple(launch_pixel, buffers),
+        }
+    }
+
+    fn range_wavelengths(&self) -> f32 {
+        range_wavelengths(&self.spectral)
+    }
+
+    fn primary_ray(&self, pixel_center: Vec2, t_min: f32) -> Ray {
+        let d = pixel_center / UVec2::from(self.image_size).as_vec2() * 2.0 - 1.0;
+        let origin = self.view_inverse * Vec4::new(0.0, 0.0, 0.0, 1.0);
+        let target = self.proj_inverse * Vec4::new(d.x, d.y, 1.0, 1.0);
+        let direction = self.view_inverse * target.truncate().normalize().extend(0.0);
+        Ray {
+            origin: origin.truncate(),
+            direction: direction.truncate(),
+            t_min,
+            t_max: T_MAX,
+        }
+    }
+
+    fn xyz_to_output(&self, xyz: Vec3) -> Vec3 {
+        self.xyz_to_output * xyz
+    }
+
+    fn trace_simple(&self, launch_pixel: (u32, u32), buffers: PixelBuffers) {
+        let image_pixel = UVec2::from(launch_pixel) + UVec2::from(self.tile_offset);
+        let ray = self.primary_ray(image_pixel.as_vec2() + 0.5, 0.001);
+        // the debug shaders don't use random numbers or wavelengths
+        let wavelengths = Vec4::splat(self.spectral.min_wavelength);
+        let rad = self
+            .scene
+            .trace(&ray, wavelengths, &mut Rng::new(0))
+            .map_or(Vec4::ZERO, |payload| payload.rad);
+        store(buffers.image, rad.truncate().extend(1.0));
+    }
+
+    fn is_converged(&self, moments: Vec4) -> bool {
+        let n = moments.z;
+        if self.adaptive_threshold <= 0.0 || n < ADAPTIVE_MIN_FRAMES {
+            return false;
+        }
+        let mean = moments.x / n;
+        let variance = (moments.y / n - mean * mean).max(0.0) * n / (n - 1.0);
+        let half_width = CONFIDENCE_Z * (variance / n).sqrt();
+        half_width <= self.adaptive_threshold * (mean + ADAPTIVE_EPSILON)
+    }
+
+    // converged pixels add their current estimate as this frame's average
+    fn repeat_estimate(&self, buffers: PixelBuffers) {
+        let frame = self.frame as f32;
+        let mut accum = load(buffers.accumulation);
+        let mean = accum.truncate() / frame;
+        accum += mean.extend(0.0);
+        if self.frame % 2 == 1 {
+            accum.w += mean.y;
+        }
+        store(buffers.accumulation, accum);
+        let color = self.xyz_to_output(accum.truncate() / (frame + 1.0));
+        store(buffers.image, color.extend(1.0));
+
+        for aov in [buffers.albedo, buffers.normal] {
+            if !aov.is_empty() {
+                let sum = load(aov).truncate();
+                store(aov, (sum + sum / frame).extend(1.0));
+            }
+        }
+
+        for bin in buffers.bins {
+            *bin += *bin / frame;
+        }
+    }
+
+    fn trace_path(&self, launch_pixel: (u32, u32), buffers: PixelBuffers) {
+        let image_size = UVec2::from(self.image_size);
+        let image_pixel = UVec2::from(launch_pixel) + UVec2::from(self.tile_offset);
+        if image_pixel.cmpge(image_size).any() {
+            return;
+        }
+        let mut moments = if self.frame > 0 {
+            load(buffers.moments)
+        } else {
+            Vec4::ZERO
+        };
+        if self.is_converged(moments) {
+            self.repeat_estimate(buffers);
+            return;
+        }
+
+        let seed = image_pixel
+            .wrapping_add(UVec2::splat(self.frame).wrapping_mul(image_size))
+            .wrapping_add(UVec2::from(self.seed_offset));
+        let mut rng = Rng::tea(seed.x, seed.y);
+
+        let aovs = !buffers.albedo.is_empty();
+        let spectral_bins = buffers.bins.len();
+        let hero = self.spectral.sampling == WavelengthSampling::Hero;
+        let t_min = 0.0001;
+
+        // accumulated in xyz, converted to the output color space at the end
+        let mut result = Vec3::ZERO;
+        let mut albedo = Vec3::ZERO;
+        let mut normal = Vec3::ZERO;
+
+        if self.frame == 0 {
+            buffers.bins.fill(0.0);
+        }
+
+        for i in 0..self.samples_per_frame {
+            let jitter = Vec2::new(rng.next(), rng.next());
+            let (wavelengths, weights) =
+                sample_wavelengths(&self.spectral, self.samples_per_frame, i, &mut rng);
+            let mut ray = self.primary_ray(image_pixel.as_vec2() + jitter, t_min);
+
+            let mut value = Vec4::ZERO;
+            let mut throughput = Vec4::ONE;
+            let mut secondaries_terminated = false;
+
+            for depth in 0..MAX_DEPTH {
+                let payload = self.scene.trace(&ray, wavelengths, &mut rng);
+
+                if aovs && depth == 0 {
+                    // the instance and depth only come from the first sample
+                    if self.frame == 0 && i == 0 {
+                        let depth_instance = match &payload {
+                            Some(payload) => Vec4::new(
+                                ray.origin.distance(payload.hit_pos),
+                                payload.instance as f32,
+                                0.0,
+                                0.0,
+                            ),
+                            None => Vec4::new(f32::INFINITY, -1.0, 0.0, 0.0),
+                        };
+                        store(buffers.depth_instance, depth_instance);
+                    }
+                    if let Some(payload) = &payload {
+                        normal += payload.hit_normal;
+                        if !payload.is_emitter {
+                            // the sampled brdf weight averages out to the albedo of the first hit
+                            let mut albedo_val = payload.brdf_val;
+                            if payload.is_dispersive && hero {
+                                albedo_val = Vec4::new(albedo_val.x * 4.0, 0.0, 0.0, 0.0);
+                            }
+                            for lane in 0..4 {
+                                albedo += albedo_val[lane]
+                                    * weights[lane]
+                                    * spectrum_to_xyz(wavelengths[lane]);
+                            }
+                        }
+                    }
+                }
+
+                // ignore misses
+                let Some(payload) = payload else {
+                    break;
+                };
+
+                // emitter hits terminate paths
+                if payload.is_emitter {
+                    let visible = match payload.emitter_type {
+                        1.0 => true,
+                        0.0 => ray.direction.dot(payload.hit_normal).abs() >= 0.996,
+                        2.0 => (-ray.direction).dot(payload.hit_normal) >= 0.6,
+                        _ => false,
+                    };
+                    if visible {
+                        value += throughput * payload.rad;
+                    }
+                    break;
+                }
+
+                // next ray is traced in the direction of the brdf sample
+                ray.origin = payload.hit_pos;
+                ray.direction = payload.brdf_d;
+
+                // sample the emitter picked by the hit shader
+                if !payload.is_specular {
+                    let dist_vec = payload.emitter_o - payload.hit_pos;
+                    let toward_emitter = dist_vec.normalize();
+                    let emitter_dist_sq = dist_vec.dot(dist_vec);
+                    let emitter_dist = emitter_dist_sq.sqrt();
+
+                    let cos_em = payload.emitter_normal.dot(-toward_emitter);
+                    let cos_obj = payload.hit_geo_normal.dot(toward_emitter);
+                    if cos_em > 0.0 && cos_obj > 0.0 {
+                        let shadow_ray = Ray {
+                            origin: payload.hit_pos,
+                            direction: toward_emitter,
+                            t_min,
+                            t_max: emitter_dist - t_min,
+                        };
+                        if !self.scene.occluded(&shadow_ray) {
+                            let g = cos_obj * cos_em / emitter_dist_sq;
+                            value += throughput * g * payload.rad * payload.emitter_brdf_val
+                                / payload.emitter_pdf;
+                        }
+                    }
+                }
+
+                // dispersive hits only follow the hero wavelength, which makes up for the others
+                if payload.is_dispersive && hero && !secondaries_terminated {
+                    throughput = Vec4::new(throughput.x * 4.0, 0.0, 0.0, 0.0);
+                    secondaries_terminated = true;
+                }
+
+                throughput *= payload.brdf_val;
+                if throughput == Vec4::ZERO {
+                    break;
+                }
+
+                // russian roulette
+                if depth >= 3 {
+                    const CONT_PROB: f32 = 0.9;
+                    if rng.next() < CONT_PROB {
+                        throughput *= 1.0 / CONT_PROB;
+                    } else {
+                        break;
+                    }
+                }
+            }
+
+            for lane in 0..4 {
+                if weights[lane] > 0.0 {
+                    result += value[lane] * weights[lane] * spectrum_to_xyz(wavelengths[lane]);
+
+                    // without the range scale the weights are relative to uniform sampling over
+                    // the range, so scaling by the bin count gives the mean radiance over each bin
+                    if spectral_bins > 0 {
+                        let t = (wavelengths[lane] - self.spectral.min_wavelength)
+                            / self.range_wavelengths();
+                        let bin = ((t * spectral_bins as f32) as usize).min(spectral_bins - 1);
+                        buffers.bins[bin] += value[lane] * weights[lane]
+                            / range_scale(&self.spectral)
+                            * spectral_bins as f32
+                            / self.samples_per_frame as f32;
+                    }
+                }
+            }
+        }
+        let spp = self.samples_per_frame as f32;
+        result /= spp;
+
+        moments += Vec4::new(result.y, result.y * result.y, 1.0, 0.0);
+        store(buffers.moments, moments);
+
+        let mut accum = if self.frame > 0 {
+            load(buffers.accumulation)
+        } else {
+            Vec4::ZERO
+        };
+        accum += result.extend(0.0);
+        // odd frames also sum their luminance in alpha, for the noise estimate
+        if self.frame % 2 == 1 {
+            accum.w += result.y;
+        }
+        store(buffers.accumulation, accum);
+
+        let color = self.xyz_to_output(accum.truncate() / (self.frame as f32 + 1.0));
+        store(buffers.image, color.extend(1.0));
+
+        // aovs hold the sum of per frame averages
+        if aovs {
+            let (prev_albedo, prev_normal) = if self.frame > 0 {
+                (
+                    load(buffers.albedo).truncate(),
+                    load(buffers.normal).truncate(),
+                )
+            } else {
+                (Vec3::ZERO, Vec3::ZERO)
+            };
+            let albedo = prev_albedo + self.xyz_to_output(albedo / spp);
+            store(buffers.albedo, albedo.extend(1.0));
+            store(buffers.normal, (prev_normal + normal / spp).extend(1.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sample_wavelengths, spectrum_to_xyz, Rng};
+    use crate::scene::scenes::mesh::{SpectralSettings, WavelengthSampling};
+
+    // the Y of a flat spectrum seen through the wavelength weights of one frame
+    fn flat_spectrum_y(spectral: &SpectralSettings) -> f32 {
+        let samples = 4096;
+        let mut rng = Rng::new(1);
+        let mut y = 0.0;
+        for i in 0..samples {
+            let (wavelengths, weights) = sample_wavelengths(spectral, samples, i, &mut rng);
+            for lane in 0..4 {
+                y += weights[lane] * spectrum_to_xyz(wavelengths[lane]).y;
+            }
+        }
+        y / samples as f32
+    }
+
+    #[test]
+    fn narrowing_the_range_clips_the_spectrum() {
+        for sampling in [
+            WavelengthSampling::Uniform,
+            WavelengthSampling::Stratified,
+            WavelengthSampling::CieY,
+            WavelengthSampling::Hero,
+        ] {
+            let full = SpectralSettings {
+                sampling,
+                ..Default::default()
+            };
+            let narrow = SpectralSettings {
+                min_wavelength: 400.0,
+                max_wavelength: 700.0,
+                ..full
+            };
+            // Y is close to 0 at both ends, so clipping them barely changes a flat spectrum
+            let (full, narrow) = (flat_spectrum_y(&full), flat_spectrum_y(&narrow));
+            assert!(
+                (narrow / full - 1.0).abs() < 0.02,
+                "{sampling:?} {full} {narrow}"
+            );
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::Result;
+use ash::{vk, Device};
+use gpu_allocator::{vulkan::Allocator, MemoryLocation};
+
+use crate::{
+    debug::DebugNames,
+    utils::{AllocatedBuffer, AllocatedImage},
+    window::{letterbox, WindowData},
+};
+
+const COLOR_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+const COLOR_LAYERS: vk::ImageSubresourceLayers = vk::ImageSubresourceLayers {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    mip_level: 0,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+/// Uploads pixels rendered on the CPU and blits them to the window
+///
+/// Only used for the interactive view, so one frame in flight is plenty.
+pub struct Presenter {
+    allocator: Rc<RefCell<Allocator>>,
+    device: Device,
+    debug_names: DebugNames,
+    limits: vk::PhysicalDeviceLimits,
+    command_pool: vk::CommandPool,
+    queue: vk::Queue,
+    command_buffer: vk::CommandBuffer,
+    staging_buffer: Option<AllocatedBuffer>,
+    image: Option<AllocatedImage>,
+}
+
+impl Presenter {
+    pub fn new(
+        device: &Device,
+        queue_family_index: u32,
+        limits: vk::PhysicalDeviceLimits,
+        allocator: Rc<RefCell<Allocator>>,
+        debug_names: DebugNames,
+    ) -> Result<Self> {
+        let command_pool = {
+            let create_info = vk::CommandPoolCreateInfo {
+                queue_family_index,
+                flags: vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+                ..Default::default()
+            };
+            unsafe { device.create_command_pool(&create_info, None) }?
+        };
+        let queue = unsafe { device.get_device_queue(queue_family_index, 0) };
+
+        let allocate_info = vk::CommandBufferAllocateInfo {
+            command_buffer_count: 1,
+            command_pool,
+            level: vk::CommandBufferLevel::PRIMARY,
+            ..Default::default()
+        };
+        let command_buffer = unsafe { device.allocate_command_buffers(&allocate_info)?[0] };
+        debug_names.name(command_buffer, "cpu present");
+
+        Ok(Self {
+            allocator,
+            device: device.clone(),
+            debug_names,
+            limits,
+            command_pool,
+            queue,
+            command_buffer,
+            staging_buffer: None,
+            image: None,
+        })
+    }
+
+    // recreates the staging buffer and image when the render size changes
+    fn resize(&mut self, (width, height): (u32, u32)) -> Result<()> {
+        if let Some(image) = &self.image {
+            if (image.width, image.height) == (width, height) {
+                return Ok(());
+            }
+        }
+
+        unsafe {
+            self.destroy_resources();
+
+            let size = (width * height * 4) as usize * size_of::<f32>();
+            let staging_buffer = AllocatedBuffer::new(
+                &self.device,
+                &mut self.allocator.borrow_mut(),
+                size as vk::DeviceSize,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                MemoryLocation::CpuToGpu,
+                self.limits,
+            )?;
+            staging_buffer.set_name(&self.debug_names, "cpu render staging");
+            self.staging_buffer = Some(staging_buffer);
+        }
+
+        let mut image = AllocatedImage::new(
+            &self.device,
+            &mut self.allocator.borrow_mut(),
+            (width, height),
+            vk::Format::R32G32B32A32_SFLOAT,
+            vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuOnly,
+        )?;
+        image.set_name(&self.debug_names, "cpu render");
+        image.transition(
+            &self.device,
+            self.queue,
+            self.command_pool,
+            vk::ImageLayout::GENERAL,
+        )?;
+        self.image = Some(image);
+        Ok(())
+    }
+
+    /// Shows RGBA pixels, top row first, letterboxed in the window
+    pub fn present(
+        &mut self,
+        target: &mut WindowData,
+        size: (u32, u32),
+        pixels: &[f32],
+    ) -> Result<()> {
+        // the previous upload has to finish before the staging buffer is overwritten
+        unsafe { self.device.queue_wait_idle(self.queue)? };
+        self.resize(size)?;
+        self.staging_buffer.as_mut().unwrap().store(pixels)?;
+
+        let (target_image, _) = target.acquire_next_image()?;
+        self.record(target_image, target.get_size())?;
+
+        let (image_semaphore, render_semaphore) = target.get_current_semaphores();
+        let wait_stage = vk::PipelineStageFlags::TRANSFER;
+        let submit_info = vk::SubmitInfo {
+            command_buffer_count: 1,
+            p_command_buffers: &raw const self.command_buffer,
+            signal_semaphore_count: 1,
+            p_signal_semaphores: &raw const render_semaphore,
+            wait_semaphore_count: 1,
+            p_wait_semaphores: &raw const image_semaphore,
+            p_wait_dst_stage_mask: &raw const wait_stage,
+            ..Default::default()
+        };
+
+        unsafe {
+            self.device.queue_submit(
+                self.queue,
+                &[submit_info],
+                target.get_current_flight_fence(),
+            )?;
+        }
+
+        target.present(self.queue)
+    }
+
+    fn record(&self, target_image: vk::Image, target_size: (u32, u32)) -> Result<()> {
+        let image = self.image.as_ref().unwrap();
+        let command_buffer = self.command_buffer;
+
+        unsafe {
+            self.device
+                .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+            self.device
+                .begin_command_buffer(command_buffer, &vk::CommandBufferBeginInfo::default())?;
+
+            self.device.cmd_copy_buffer_to_image(
+                command_buffer,
+                self.staging_buffer.as_ref().unwrap().buffer,
+                image.image,
+                vk::ImageLayout::GENERAL,
+                &[vk::BufferImageCopy {
+                    image_subresource: COLOR_LAYERS,
+                    image_extent: vk::Extent3D {
+                        width: image.width,
+                        height: image.height,
+                        depth: 1,
+                    },
+                    ..Default::default()
+                }],
+            );
+
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[vk::MemoryBarrier {
+                    src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+                    ..Default::default()
+                }],
+                &[],
+                &[vk::ImageMemoryBarrier {
+                    src_access_mask: vk::AccessFlags::NONE,
+                    dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    old_layout: vk::ImageLayout::UNDEFINED,
+                    new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    image: target_image,
+                    subresource_range: COLOR_RANGE,
+                    ..Default::default()
+                }],
+            );
+
+            // same letterboxing as the ray traced view
+            self.device.cmd_clear_color_image(
+                command_buffer,
+                target_image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &vk::ClearColorValue::default(),
+                &[COLOR_RANGE],
+            );
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier {
+                    src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    image: target_image,
+                    subresource_range: COLOR_RANGE,
+                    ..Default::default()
+                }],
+            );
+
+            let ((x, y), (width, height)) = letterbox((image.width, image.height), target_size);
+            self.device.cmd_blit_image(
+                command_buffer,
+                image.image,
+                vk::ImageLayout::GENERAL,
+                target_image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::ImageBlit {
+                    src_subresource: COLOR_LAYERS,
+                    src_offsets: [
+                        vk::Offset3D { x: 0, y: 0, z: 0 },
+                        vk::Offset3D {
+                            x: image.width as i32,
+                            y: image.height as i32,
+                            z: 1,
+                        },
+                    ],
+                    dst_subresource: COLOR_LAYERS,
+                    dst_offsets: [
+                        vk::Offset3D {
+                            x: x as i32,
+                            y: y as i32,
+                            z: 0,
+                        },
+                        vk::Offset3D {
+                            x: (x + width) as i32,
+                            y: (y + height) as i32,
+                            z: 1,
+                        },
+                    ],
+                }],
+                vk::Filter::LINEAR,
+            );
+
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier {
+                    src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    dst_access_mask: vk::AccessFlags::NONE,
+                    old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                    image: target_image,
+                    subresource_range: COLOR_RANGE,
+                    ..Default::default()
+                }],
+            );
+
+            self.device.end_command_buffer(command_buffer)?;
+        }
+
+        Ok(())
+    }
+
+    unsafe fn destroy_resources(&mut self) {
+        if let Some(x) = self.staging_buffer.take() {
+            x.destroy(&self.device, &mut self.allocator.borrow_mut());
+        }
+        if let Some(x) = self.image.take() {
+            x.destroy(&self.device, &mut self.allocator.borrow_mut());
+        }
+    }
+}
+
+impl Drop for Presenter {
+    fn drop(&mut self) {
+        unsafe {
+            self.device
+                .queue_wait_idle(self.queue)
+                .expect("failed to wait for queue idle");
+            self.destroy_resources();
+            self.device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
@@ -0,0 +1,1108 @@
+use std::f32::consts::PI;
+
+use anyhow::{bail, Result};
+use bytemuck::Pod;
+use glam::{Mat4, Vec3, Vec4};
+
+use super::bvh::{transform_aabb, Bvh, Hit, Primitive, ProceduralShape, Ray};
+use crate::{
+    scene::scenes::mesh::{Light, MeshScene, SPECTRUM_SAMPLES},
+    spectral::{
+        eval_sigmoid_polynomial, wavelength_to_d65, wavelength_to_xyz, RgbToSpectrumTable,
+        MAX_WAVELENGTH, MIN_WAVELENGTH,
+    },
+};
+
+// luminance of the D65 table, see spectrumToXyz in color_spaces.glsl
+const Y_D65: f64 = 10.5670762;
+
+/// Random numbers of the shaders, seeded with tea and advanced with an lcg, see random.glsl
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self(seed)
+    }
+
+    /// 16 rounds of the tiny encryption algorithm over two values
+    pub fn tea(mut v0: u32, mut v1: u32) -> Self {
+        let mut s0 = 0u32;
+        for _ in 0..16 {
+            s0 = s0.wrapping_add(0x9e3779b9);
+            v0 = v0.wrapping_add(
+                (v1 << 4).wrapping_add(0xa341316c)
+                    ^ v1.wrapping_add(s0)
+                    ^ (v1 >> 5).wrapping_add(0xc8013ea4),
+            );
+            v1 = v1.wrapping_add(
+                (v0 << 4).wrapping_add(0xad90777d)
+                    ^ v0.wrapping_add(s0)
+                    ^ (v0 >> 5).wrapping_add(0x7e95761e),
+            );
+        }
+        Self(v0)
+    }
+
+    /// Uniform float in [0, 1), like rnd in random.glsl
+    pub fn next(&mut self) -> f32 {
+        self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+        (self.0 & 0x00FFFFFF) as f32 / 0x01000000 as f32
+    }
+}
+
+/// XYZ of a wavelength relative to D65, so a flat spectrum has the D65 white and Y = 1
+pub fn spectrum_to_xyz(wavelength: f32) -> Vec3 {
+    let wavelength = wavelength as f64;
+    let d65 = wavelength_to_d65(wavelength);
+    Vec3::from_array(wavelength_to_xyz(wavelength).map(|c| (c * d65 / Y_D65) as f32))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Spectrum {
+    // smooth spectrum uplifted from an rgb color, see rgb_to_spectrum in spectra.glsl
+    Rgb { coeffs: [f32; 3], scale: f32 },
+    // row of the scene's spectra data
+    Tabulated { index: usize, scale: f32 },
+}
+
+impl Spectrum {
+    fn from_rgb(table: &RgbToSpectrumTable, rgb: Vec3) -> Self {
+        // colors brighter than 1 are uplifted at a lower brightness and scaled back up
+        let m = rgb.max_element();
+        let scale = if m > 1.0 { 2.0 * m } else { 1.0 };
+        Self::Rgb {
+            coeffs: table.coeffs((rgb / scale).as_dvec3().to_array()),
+            scale,
+        }
+    }
+
+    // lights with a spectrum are scaled by the first color channel, the rest are uplifted from rgb
+    fn from_light(table: &RgbToSpectrumTable, color: Vec3, spectra_i: Option<u32>) -> Self {
+        match spectra_i {
+            Some(index) => Self::Tabulated {
+                index: index as usize,
+                scale: color.x,
+            },
+            None => Self::from_rgb(table, color),
+        }
+    }
+}
+
+// sampled like the spectra texture, linear filtering clamped to the edges
+fn sample_tabulated(samples: &[f32; SPECTRUM_SAMPLES], wavelength: f32) -> f32 {
+    let u = (wavelength - MIN_WAVELENGTH as f32) / (MAX_WAVELENGTH - MIN_WAVELENGTH) as f32;
+    let texel = u * SPECTRUM_SAMPLES as f32 - 0.5;
+    let i = texel.floor();
+    let t = texel - i;
+    let at = |i: f32| samples[(i.max(0.0) as usize).min(SPECTRUM_SAMPLES - 1)];
+    at(i) * (1.0 - t) + at(i + 1.0) * t
+}
+
+/// What the closest hit shaders write to the ray payload, see ray_common.glsl
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Payload {
+    pub instance: u32,
+    pub is_emitter: bool,
+    pub is_specular: bool,
+    // the sampled direction depends on the hero wavelength, so the other lanes must be terminated
+    pub is_dispersive: bool,
+    // spectral radiance of an emitter hit or of the sampled light
+    pub rad: Vec4,
+    pub hit_pos: Vec3,
+    pub hit_normal: Vec3,
+    pub hit_geo_normal: Vec3,
+
+    pub brdf_val: Vec4,
+    pub brdf_d: Vec3,
+
+    pub emitter_o: Vec3,
+    pub emitter_pdf: f32,
+    pub emitter_brdf_val: Vec4,
+    pub emitter_normal: Vec3,
+    pub emitter_type: f32,
+}
+
+struct EmitterSample {
+    position: Vec3,
+    direction: Vec3,
+    normal: Vec3,
+    radiance: Vec4,
+    pdf: f32,
+}
+
+// closest hit shader of an instance along with its brdf params
+#[derive(Debug, Clone, Copy)]
+enum Material {
+    // emitter.rchit, the triangles of the object are the lights starting at first_light
+    AreaEmitter {
+        first_light: usize,
+    },
+    // diffuse.rchit and spectral_diffuse.rchit
+    Diffuse {
+        albedo: Spectrum,
+    },
+    Mirror,
+    // sellmeier coefficients
+    Dielectric {
+        b: Vec3,
+        c: Vec3,
+    },
+    Microfacet {
+        albedo: Spectrum,
+        ks: f32,
+        ior: f32,
+        roughness: f32,
+    },
+    Grating {
+        period: f32,
+        height: f32,
+        towards: Vec3,
+    },
+    Flat {
+        color: Vec3,
+    },
+    Normals,
+    Checkerboard {
+        albedo_1: Vec3,
+        albedo_2: Vec3,
+        scale: f32,
+    },
+    DirectionalEmitter {
+        light: usize,
+    },
+    ProceduralDiffuse {
+        albedo: Spectrum,
+    },
+    // black, light grey and grey rings around the hole, then the underside of the disc
+    Dvd {
+        rings: [Spectrum; 3],
+        back: Spectrum,
+    },
+}
+
+impl Material {
+    // custom_index is what the shader gets as gl_InstanceCustomIndexEXT
+    fn new(
+        file: &str,
+        params: &[u8],
+        custom_index: u32,
+        table: &RgbToSpectrumTable,
+    ) -> Result<Self> {
+        let material = match file {
+            "emitter.rchit" => Material::AreaEmitter {
+                first_light: custom_index as usize,
+            },
+            "diffuse.rchit" => {
+                let albedo: [f32; 3] = read_params(file, params)?;
+                Material::Diffuse {
+                    albedo: Spectrum::from_rgb(table, Vec3::from_array(albedo)),
+                }
+            }
+            "spectral_diffuse.rchit" => {
+                let [index]: [u32; 1] = read_params(file, params)?;
+                Material::Diffuse {
+                    albedo: Spectrum::Tabulated {
+                        index: index as usize,
+                        scale: 1.0,
+                    },
+                }
+            }
+            "mirror.rchit" => {
+                read_params::<[f32; 0]>(file, params)?;
+                Material::Mirror
+            }
+            "dielectric.rchit" => {
+                let p: [f32; 6] = read_params(file, params)?;
+                Material::Dielectric {
+                    b: Vec3::from_slice(&p[0..3]),
+                    c: Vec3::from_slice(&p[3..6]),
+                }
+            }
+            "microfacet.rchit" => {
+                let p: [f32; 5] = read_params(file, params)?;
+                let albedo = Vec3::from_slice(&p[0..3]);
+                Material::Microfacet {
+                    albedo: Spectrum::from_rgb(table, albedo),
+                    ks: 1.0 - albedo.max_element(),
+                    ior: p[3],
+                    roughness: p[4],
+                }
+            }
+            "diffraction_grating.rchit" => {
+                let p: [f32; 5] = read_params(file, params)?;
+                Material::Grating {
+                    period: p[0],
+                    height: p[1],
+                    towards: Vec3::from_slice(&p[2..5]),
+                }
+            }
+            "flat.rchit" => {
+                let color: [f32; 3] = read_params(file, params)?;
+                Material::Flat {
+                    color: Vec3::from_array(color),
+                }
+            }
+            "normals.rchit" => {
+                read_params::<[f32; 0]>(file, params)?;
+                Material::Normals
+            }
+            "checkerboard.rchit" | "diffuse_checkerboard.rchit" => {
+                let p: [f32; 7] = read_params(file, params)?;
+                Material::Checkerboard {
+                    albedo_1: Vec3::from_slice(&p[0..3]),
+                    albedo_2: Vec3::from_slice(&p[3..6]),
+                    scale: p[6],
+                }
+            }
+            "directional_emitter.rchit" => Material::DirectionalEmitter {
+                light: custom_index as usize,
+            },
+            "procedural_diffuse.rchit" => Material::ProceduralDiffuse {
+                albedo: Spectrum::from_rgb(table, Vec3::new(0.8, 0.3, 0.3)),
+            },
+            "dvd.rchit" => Material::Dvd {
+                rings: [Vec3::ZERO, Vec3::splat(0.8), Vec3::splat(0.6)]
+                    .map(|albedo| Spectrum::from_rgb(table, albedo)),
+                back: Spectrum::from_rgb(table, Vec3::new(0.4, 0.1, 0.1)),
+            },
+            _ => bail!("the cpu renderer has no implementation of {file}"),
+        };
+        Ok(material)
+    }
+}
+
+fn read_params<T: Pod>(file: &str, params: &[u8]) -> Result<T> {
+    if params.len() != size_of::<T>() {
+        bail!(
+            "{file} expects {} bytes of brdf params, got {}",
+            size_of::<T>(),
+            params.len()
+        );
+    }
+    Ok(bytemuck::pod_read_unaligned(params))
+}
+
+fn intersection_shape(file: &str) -> Result<ProceduralShape> {
+    Ok(match file {
+        "sphere.rint" => ProceduralShape::Sphere,
+        "disc.rint" => ProceduralShape::Disc { inner_radius: 0.0 },
+        "dvd.rint" => ProceduralShape::Disc {
+            inner_radius: 1.0 / 6.0,
+        },
+        _ => bail!("the cpu renderer has no implementation of {file}"),
+    })
+}
+
+struct Instance {
+    object_to_world: Mat4,
+    world_to_object: Mat4,
+    // first vertex of the mesh for triangle instances
+    first_vertex: usize,
+    material: Material,
+}
+
+// interpolated hit on a triangle, in world space
+struct Surface {
+    pos: Vec3,
+    normal: Vec3,
+    face_normal: Vec3,
+}
+
+impl Surface {
+    fn flip_backface(&mut self, direction: Vec3) {
+        if direction.dot(self.face_normal) > 0.0 {
+            self.normal = -self.normal;
+            self.face_normal = -self.face_normal;
+        }
+    }
+}
+
+/// A scene ready to be traced on the CPU, mirroring the buffers bound to the ray tracing pipeline
+pub struct CpuScene {
+    bvh: Bvh,
+    instances: Vec<Instance>,
+    // object space positions and normals, three per triangle like the vertex buffer
+    vertices: Vec<(Vec3, Vec3)>,
+    lights: Vec<(Light, Spectrum)>,
+    spectra: Vec<[f32; SPECTRUM_SAMPLES]>,
+}
+
+impl CpuScene {
+    pub fn new(scene: &MeshScene, table: &RgbToSpectrumTable) -> Result<Self> {
+        let mut vertices = Vec::new();
+        let mut mesh_starts = Vec::new();
+        for model in &scene.meshes {
+            let mesh = &model.mesh;
+            mesh_starts.push(vertices.len());
+            vertices.extend(mesh.indices.iter().map(|&i| {
+                let i = 3 * i as usize;
+                (
+                    Vec3::from_slice(&mesh.positions[i..i + 3]),
+                    Vec3::from_slice(&mesh.normals[i..i + 3]),
+                )
+            }));
+        }
+
+        let mut instances = Vec::new();
+        let mut primitives = Vec::new();
+        for object in &scene.objects {
+            let Some(shader) = scene.hit_shaders.get(object.brdf_i) else {
+                bail!("object uses undefined brdf {}", object.brdf_i);
+            };
+            let first_vertex = mesh_starts[object.mesh_i];
+            let instance = instances.len() as u32;
+            let triangle_count = scene.meshes[object.mesh_i].mesh.indices.len() / 3;
+            primitives.extend((0..triangle_count).map(|index| Primitive::Triangle {
+                vertices: [0, 1, 2].map(|k| {
+                    object
+                        .transform
+                        .transform_point3(vertices[first_vertex + 3 * index + k].0)
+                }),
+                instance,
+                index: index as u32,
+            }));
+
+            instances.push(Instance {
+                object_to_world: object.transform,
+                world_to_object: object.transform.inverse(),
+                first_vertex,
+                material: Material::new(
+                    shader.file(),
+                    &object.brdf_params,
+                    object.vertex_index,
+                    table,
+                )?,
+            });
+        }
+
+        for procedural_object in &scene.procedural_objects {
+            let geometry = &scene.procedural_geometries[procedural_object.geometry_index];
+            let shape = intersection_shape(geometry.intersection_shader.file())?;
+            let transform = procedural_object.transform;
+            let instance = instances.len() as u32;
+            primitives.extend(geometry.aabbs.iter().enumerate().map(|(index, aabb)| {
+                Primitive::Procedural {
+                    bounds: transform_aabb(transform, aabb),
+                    shape,
+                    world_to_object: transform.inverse(),
+                    instance,
+                    index: index as u32,
+                }
+            }));
+
+            instances.push(Instance {
+                object_to_world: transform,
+                world_to_object: transform.inverse(),
+                first_vertex: 0,
+                material: Material::new(
+                    geometry.closest_hit_shader.file(),
+                    &[],
+                    procedural_object.custom_index,
+                    table,
+                )?,
+            });
+        }
+
+        let lights = scene
+            .lights
+            .iter()
+            .map(|light| {
+                let spectrum = match *light {
+                    Light::Point {
+                        color, spectra_i, ..
+                    }
+                    | Light::Directional {
+                        color, spectra_i, ..
+                    } => Spectrum::from_light(table, color, spectra_i),
+                    Light::Triangle {
+                        color, spectra_i, ..
+                    } => Spectrum::from_light(table, color, Some(spectra_i)),
+                };
+                (light.clone(), spectrum)
+            })
+            .collect();
+
+        Ok(Self {
+            bvh: Bvh::new(primitives),
+            instances,
+            vertices,
+            lights,
+            spectra: scene.spectra_data.clone(),
+        })
+    }
+
+    fn spectrum(&self, spectrum: Spectrum, wavelengths: Vec4) -> Vec4 {
+        match spectrum {
+            Spectrum::Rgb { coeffs, scale } => {
+                Vec4::from_array(
+                    wavelengths
+                        .to_array()
+                        .map(|w| eval_sigmoid_polynomial(coeffs, w as f64) as f32),
+                ) * scale
+            }
+            Spectrum::Tabulated { index, scale } => {
+                Vec4::from_array(
+                    wavelengths
+                        .to_array()
+                        .map(|w| sample_tabulated(&self.spectra[index], w)),
+                ) * scale
+            }
+        }
+    }
+
+    /// Whether anything lies along the ray, like the shadow rays of path.rgen
+    pub fn occluded(&self, ray: &Ray) -> bool {
+        self.bvh.occluded(ray)
+    }
+
+    /// Traces a ray and runs the closest hit shader, None on a miss
+    pub fn trace(&self, ray: &Ray, wavelengths: Vec4, rng: &mut Rng) -> Option<Payload> {
+        let hit = self.bvh.intersect(ray)?;
+        let instance = &self.instances[hit.instance];
+        let direction = ray.direction;
+        let mut payload = Payload {
+            instance: hit.instance as u32,
+            ..Default::default()
+        };
+
+        match instance.material {
+            Material::AreaEmitter { first_light } => {
+                let Some(&(
+                    Light::Triangle {
+                        vertices: [a, b, c],
+                        emit_type,
+                        ..
+                    },
+                    spectrum,
+                )) = self.lights.get(first_light + hit.primitive)
+                else {
+                    return Some(payload);
+                };
+                // emitter.rchit only reads the first light of the object, every triangle
+                // of an area light shares its color and emit type so only the position differs
+                let normal = (b - a).cross(c - a);
+                let area = normal.length() / 2.0;
+                let normal = normal.normalize();
+
+                payload.hit_pos = a * (1.0 - hit.barycentrics.x - hit.barycentrics.y)
+                    + b * hit.barycentrics.x
+                    + c * hit.barycentrics.y;
+                payload.emitter_pdf = 1.0 / self.lights.len() as f32 / area;
+                payload.emitter_type = emit_type;
+                payload.is_emitter = true;
+                if direction.dot(normal) >= 0.0 {
+                    payload.hit_normal = -normal;
+                } else {
+                    payload.rad = self.spectrum(spectrum, wavelengths);
+                    payload.hit_normal = normal;
+                }
+            }
+            Material::Diffuse { albedo } => {
+                let mut surface = self.triangle_surface(instance, &hit);
+                surface.flip_backface(direction);
+                let albedo = self.spectrum(albedo, wavelengths);
+                self.sample_emitter(&mut payload, surface.pos, wavelengths, rng, |_| albedo);
+                sample_diffuse(&mut payload, surface.normal, albedo, rng);
+                payload.set_surface(&surface, surface.face_normal);
+            }
+            Material::Mirror => {
+                let surface = self.triangle_surface(instance, &hit);
+                payload.brdf_val = Vec4::ONE;
+                payload.brdf_d = reflect(direction, surface.normal);
+                payload.emitter_pdf = 1.0;
+                payload.set_surface(&surface, surface.normal);
+                payload.is_specular = true;
+            }
+            Material::Dielectric { b, c } => {
+                let surface = self.triangle_surface(instance, &hit);
+                sample_dielectric(
+                    &mut payload,
+                    surface.normal,
+                    direction,
+                    b,
+                    c,
+                    wavelengths,
+                    rng,
+                );
+                payload.emitter_pdf = 1.0;
+                payload.set_surface(&surface, surface.normal);
+                payload.is_specular = true;
+            }
+            Material::Microfacet {
+                albedo,
+                ks,
+                ior,
+                roughness,
+            } => {
+                let mut surface = self.triangle_surface(instance, &hit);
+                surface.flip_backface(direction);
+                let microfacet = Microfacet {
+                    albedo: self.spectrum(albedo, wavelengths),
+                    ks,
+                    ior,
+                    roughness,
+                    wo: -direction,
+                    normal: surface.normal,
+                };
+                self.sample_emitter(&mut payload, surface.pos, wavelengths, rng, |wi| {
+                    microfacet.eval(wi).0
+                });
+                microfacet.sample(&mut payload, rng);
+                payload.set_surface(&surface, surface.face_normal);
+            }
+            Material::Grating {
+                period,
+                height,
+                towards,
+            } => {
+                let mut surface = self.triangle_surface(instance, &hit);
+                surface.flip_backface(direction);
+                let grating = Grating {
+                    period,
+                    height,
+                    towards,
+                };
+                grating.sample(&mut payload, surface.normal, direction, wavelengths.x, rng);
+                payload.emitter_pdf = 1.0;
+                payload.set_surface(&surface, surface.normal);
+                payload.is_specular = true;
+            }
+            // the flat shaders show a color, they're emitters that are always visible
+            // they leave the emitter type alone, so it's set to the always visible type here
+            Material::Flat { color } => {
+                let surface = self.triangle_surface(instance, &hit);
+                payload.set_flat(&surface, color);
+            }
+            Material::Normals => {
+                let surface = self.triangle_surface(instance, &hit);
+                payload.set_flat(&surface, surface.normal.abs());
+            }
+            Material::Checkerboard {
+                albedo_1,
+                albedo_2,
+                scale,
+            } => {
+                let surface = self.triangle_surface(instance, &hit);
+                // the shader transforms the position as a direction, so the pattern ignores translation
+                let object_pos = instance.world_to_object.transform_point3(surface.pos);
+                let pattern_pos = instance.object_to_world.transform_vector3(object_pos);
+                let period = scale + scale;
+                let rem = (pattern_pos.truncate()
+                    - period * (pattern_pos.truncate() / period).floor())
+                .floor();
+                let color = if rem.x == rem.y { albedo_1 } else { albedo_2 };
+                payload.set_flat(&surface, color);
+            }
+            Material::DirectionalEmitter { light } => {
+                let Some(&(
+                    Light::Directional {
+                        position: light_position,
+                        ..
+                    },
+                    spectrum,
+                )) = self.lights.get(light)
+                else {
+                    return Some(payload);
+                };
+                let normal = instance
+                    .object_to_world
+                    .transform_vector3(hit.normal)
+                    .normalize();
+                let radius = (instance.object_to_world.x_axis.truncate().length()
+                    + instance.object_to_world.y_axis.truncate().length())
+                    * 0.5;
+                let area = PI * radius * radius;
+
+                let to_center = light_position - ray.origin;
+                let dist = (to_center - to_center.dot(normal) * normal).length();
+
+                payload.hit_pos = ray.origin + hit.t * direction;
+                payload.emitter_pdf = 1.0 / self.lights.len() as f32 / area;
+                payload.is_emitter = true;
+                if direction.dot(normal) < 0.0 && dist < radius {
+                    payload.rad = self.spectrum(spectrum, wavelengths);
+                    payload.hit_normal = normal;
+                    payload.emitter_type = 1.0;
+                } else {
+                    payload.hit_normal = -normal;
+                    payload.emitter_type = -1.0;
+                }
+            }
+            Material::ProceduralDiffuse { albedo } => {
+                let mut surface = procedural_surface(instance, ray, &hit);
+                surface.flip_backface(direction);
+                let albedo = self.spectrum(albedo, wavelengths);
+                self.sample_emitter(&mut payload, surface.pos, wavelengths, rng, |_| albedo);
+                sample_diffuse(&mut payload, surface.normal, albedo, rng);
+                payload.set_surface(&surface, surface.normal);
+            }
+            Material::Dvd { rings, back } => {
+                let mut surface = procedural_surface(instance, ray, &hit);
+                let is_backface = direction.dot(surface.normal) > 0.0;
+                surface.flip_backface(direction);
+                let object_origin = instance.world_to_object.transform_point3(ray.origin);
+                let object_direction = instance.world_to_object.transform_vector3(direction);
+                let dist_from_center = (object_origin + hit.t * object_direction)
+                    .truncate()
+                    .length();
+
+                let diffuse = match dist_from_center {
+                    d if (0.35..0.4).contains(&d) && !is_backface => Some(rings[0]),
+                    d if (0.33..0.35).contains(&d) => Some(rings[1]),
+                    d if (0.22..0.33).contains(&d) => Some(rings[2]),
+                    d if d < 0.35 => None,
+                    _ if is_backface => Some(back),
+                    _ => None,
+                };
+                if let Some(albedo) = diffuse {
+                    // unlike the other shaders, the brdf is sampled before the emitter
+                    let albedo = self.spectrum(albedo, wavelengths);
+                    sample_diffuse(&mut payload, surface.normal, albedo, rng);
+                    self.sample_emitter(&mut payload, surface.pos, wavelengths, rng, |_| albedo);
+                } else if dist_from_center < 0.35 {
+                    let b = Vec3::new(1.4182, 0.0, 0.0);
+                    let c = Vec3::new(0.021304, 0.0, 0.0);
+                    sample_dielectric(
+                        &mut payload,
+                        surface.normal,
+                        direction,
+                        b,
+                        c,
+                        wavelengths,
+                        rng,
+                    );
+                    payload.emitter_pdf = 1.0;
+                } else {
+                    let grating = Grating {
+                        period: 740.0,
+                        height: 110.0,
+                        towards: instance.object_to_world.w_axis.truncate() - surface.pos,
+                    };
+                    grating.sample(&mut payload, surface.normal, direction, wavelengths.x, rng);
+                    payload.emitter_pdf = 1.0;
+                }
+                payload.set_surface(&surface, surface.normal);
+                payload.is_specular = diffuse.is_none();
+            }
+        }
+
+        Some(payload)
+    }
+
+    fn triangle_surface(&self, instance: &Instance, hit: &Hit) -> Surface {
+        let first = instance.first_vertex + 3 * hit.primitive;
+        let [(a, a_normal), (b, b_normal), (c, c_normal)] =
+            [0, 1, 2].map(|k| self.vertices[first + k]);
+        let weights = Vec3::new(
+            1.0 - hit.barycentrics.x - hit.barycentrics.y,
+            hit.barycentrics.x,
+            hit.barycentrics.y,
+        );
+
+        let transform = instance.object_to_world;
+        let pos = a * weights.x + b * weights.y + c * weights.z;
+        let normal = a_normal * weights.x + b_normal * weights.y + c_normal * weights.z;
+        let face_normal = (b - a).cross(c - a).normalize();
+        Surface {
+            pos: transform.transform_point3(pos),
+            normal: transform.transform_vector3(normal).normalize(),
+            face_normal: transform.transform_vector3(face_normal).normalize(),
+        }
+    }
+
+    fn sample_light(&self, hit_pos: Vec3, wavelengths: Vec4, rng: &mut Rng) -> EmitterSample {
+        let mut result = EmitterSample {
+            position: Vec3::X,
+            direction: Vec3::X,
+            normal: Vec3::X,
+            radiance: Vec4::ZERO,
+            pdf: 1.0,
+        };
+
+        let light_count = self.lights.len() as f32;
+        let light_i = (rng.next() * light_count) as usize;
+        let Some((light, spectrum)) = self.lights.get(light_i) else {
+            return result;
+        };
+
+        match *light {
+            Light::Point { position, .. } => {
+                result.position = position;
+                result.direction = (position - hit_pos).normalize();
+                result.normal = -result.direction;
+                result.radiance = self.spectrum(*spectrum, wavelengths);
+                result.pdf = 1.0 / light_count;
+            }
+            Light::Triangle {
+                vertices: [a, b, c],
+                emit_type,
+                ..
+            } => {
+                let s = rng.next();
+                let t = rng.next().sqrt();
+                let normal = (b - a).cross(c - a);
+                let area = normal.length() / 2.0;
+                let normal = normal.normalize();
+
+                result.position = (1.0 - t) * a + (1.0 - s) * t * b + s * t * c;
+                result.direction = (result.position - hit_pos).normalize();
+                let cos_angle_to_light = (-result.direction).dot(normal);
+                let visible = (emit_type == 0.0 && cos_angle_to_light >= 0.996)
+                    || (emit_type == 1.0 && cos_angle_to_light >= 0.0)
+                    || (emit_type == 2.0 && cos_angle_to_light >= 0.6);
+                if visible {
+                    result.pdf = 1.0 / light_count / area;
+                    result.normal = normal;
+                    result.radiance = self.spectrum(*spectrum, wavelengths);
+                }
+            }
+            Light::Directional {
+                position,
+                direction,
+                ..
+            } => {
+                // the beam test in emitter_sampling.glsl measures from the light to itself,
+                // so directional lights are never in their own beam and only hits count
+                let light_dir = direction.normalize();
+                result.position = position;
+                result.direction = -light_dir;
+                result.normal = light_dir;
+                result.pdf = 1.0 / light_count;
+            }
+        }
+
+        result
+    }
+
+    fn sample_emitter(
+        &self,
+        payload: &mut Payload,
+        hit_pos: Vec3,
+        wavelengths: Vec4,
+        rng: &mut Rng,
+        eval_brdf: impl Fn(Vec3) -> Vec4,
+    ) {
+        let light = self.sample_light(hit_pos, wavelengths, rng);
+        payload.emitter_o = light.position;
+        payload.emitter_pdf = light.pdf;
+        payload.emitter_brdf_val = eval_brdf(light.direction);
+        payload.emitter_normal = light.normal;
+        payload.rad = light.radiance;
+    }
+}
+
+impl Payload {
+    fn set_surface(&mut self, surface: &Surface, geo_normal: Vec3) {
+        self.hit_pos = surface.pos;
+        self.hit_normal = surface.normal;
+        self.hit_geo_normal = geo_normal;
+    }
+
+    fn set_flat(&mut self, surface: &Surface, color: Vec3) {
+        self.set_surface(surface, surface.normal);
+        self.rad = color.extend(0.0);
+        self.is_emitter = true;
+        self.emitter_type = 1.0;
+    }
+}
+
+// procedural hits report an object space normal, the position comes from the ray
+fn procedural_surface(instance: &Instance, ray: &Ray, hit: &Hit) -> Surface {
+    let normal = instance
+        .object_to_world
+        .transform_vector3(hit.normal)
+        .normalize();
+    Surface {
+        pos: ray.origin + hit.t * ray.direction,
+        normal,
+        face_normal: normal,
+    }
+}
+
+fn reflect(i: Vec3, n: Vec3) -> Vec3 {
+    i - 2.0 * n.dot(i) * n
+}
+
+// same as glsl, zero on total internal reflection
+fn refract(i: Vec3, n: Vec3, eta: f32) -> Vec3 {
+    let cos_i = n.dot(i);
+    let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+    if k < 0.0 {
+        Vec3::ZERO
+    } else {
+        eta * i - (eta * cos_i + k.sqrt()) * n
+    }
+}
+
+// the rest of this mirrors sampling.glsl and the sample_brdf functions of the hit shaders
+
+fn sample_cosine_hemisphere(u: f32, v: f32) -> Vec3 {
+    let theta = u.sqrt().acos();
+    let phi = 2.0 * PI * v;
+    Vec3::new(
+        theta.sin() * phi.cos(),
+        theta.sin() * phi.sin(),
+        theta.cos(),
+    )
+}
+
+fn sample_beckmann(a: f32, u: f32, v: f32) -> Vec3 {
+    let cos_t = (1.0 / (1.0 - a * a * u.ln())).sqrt();
+    let sin_t = (1.0 - cos_t * cos_t).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(sin_t * phi.cos(), sin_t * phi.sin(), cos_t)
+}
+
+fn pdf_beckmann(cos_t: f32, a: f32) -> f32 {
+    let tan_t_2 = 1.0 / (cos_t * cos_t) - 1.0;
+    (-tan_t_2 / (a * a)).exp() / (PI * a * a * cos_t * cos_t * cos_t)
+}
+
+fn fresnel(cos_i: f32, eta: f32) -> f32 {
+    let sin_t_sq = eta * eta * (1.0 - cos_i * cos_i);
+    if sin_t_sq > 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin_t_sq).max(0.0).sqrt();
+    let prl = (eta * cos_t - cos_i) / (eta * cos_t + cos_i);
+    let ppd = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (prl * prl + ppd * ppd)
+}
+
+// rotates a sample around +z to be around the normal
+fn frame_sample(wi: Vec3, normal: Vec3) -> Vec3 {
+    let axis = Vec3::new(normal.y, -normal.x, 0.0);
+    if axis.length() < 0.0001 {
+        if normal.z < 0.0 {
+            return Vec3::new(wi.x, -wi.y, -wi.z);
+        }
+        return wi;
+    }
+
+    let cos_t = normal.z;
+    let sin_t = axis.length();
+    let r = axis.normalize();
+    wi * cos_t + wi.cross(r) * sin_t + r * r.dot(wi) * (1.0 - cos_t)
+}
+
+fn sample_diffuse(payload: &mut Payload, normal: Vec3, albedo: Vec4, rng: &mut Rng) {
+    let (u, v) = (rng.next(), rng.next());
+    payload.brdf_val = albedo;
+    payload.brdf_d = frame_sample(sample_cosine_hemisphere(u, v), normal);
+}
+
+fn sample_dielectric(
+    payload: &mut Payload,
+    mut normal: Vec3,
+    direction: Vec3,
+    b: Vec3,
+    c: Vec3,
+    wavelengths: Vec4,
+    rng: &mut Rng,
+) {
+    payload.brdf_val = Vec4::ONE;
+
+    // the index of refraction depends on wavelength, so only the hero wavelength is followed
+    payload.is_dispersive = true;
+    let lambda_squared = (wavelengths.x / 1000.0).powi(2);
+    let eta_squared = 1.0
+        + (0..3)
+            .map(|i| b[i] * lambda_squared / (lambda_squared - c[i]))
+            .sum::<f32>();
+    let mut eta = 1.0 / eta_squared.sqrt();
+
+    if normal.dot(-direction) < 0.0 {
+        normal = -normal;
+        eta = 1.0 / eta;
+    }
+
+    let reflected = reflect(direction, normal);
+    let f = fresnel(reflected.dot(normal).abs(), eta);
+    payload.brdf_d = if rng.next() < f {
+        reflected.normalize()
+    } else {
+        refract(direction, normal, eta).normalize()
+    };
+}
+
+struct Microfacet {
+    albedo: Vec4,
+    ks: f32,
+    ior: f32,
+    roughness: f32,
+    wo: Vec3,
+    normal: Vec3,
+}
+
+impl Microfacet {
+    fn g1(&self, wv: Vec3, wh: Vec3) -> f32 {
+        let cos_t = wv.dot(self.normal);
+        let tan_t = (1.0 - cos_t * cos_t).sqrt() / cos_t;
+        let b = 1.0 / (self.roughness * tan_t);
+        let val = if b < 1.6 {
+            (3.535 * b + 2.181 * b * b) / (1.0 + 2.276 * b + 2.577 * b * b)
+        } else {
+            1.0
+        };
+        if wv.dot(wh) / cos_t > 0.0 {
+            val
+        } else {
+            0.0
+        }
+    }
+
+    // brdf value and pdf of sampling wi
+    fn eval(&self, wi: Vec3) -> (Vec4, f32) {
+        let wh = (wi + self.wo).normalize();
+        let g = self.g1(wi, wh) * self.g1(self.wo, wh);
+        let f = fresnel(wh.dot(self.wo), 1.0 / self.ior);
+
+        let cos_wh = wh.dot(self.normal);
+        let cos_wi = wi.dot(self.normal);
+        let cos_wo = self.wo.dot(self.normal);
+
+        let d = pdf_beckmann(cos_wh, self.roughness);
+        let jh = 1.0 / (4.0 * wh.dot(wi));
+        let pdf = self.ks * d * jh + (1.0 - self.ks) * cos_wi / PI;
+        let specular = self.ks * d * f * g / (4.0 * cos_wi * cos_wo * cos_wh);
+        (self.albedo / PI + specular, pdf)
+    }
+
+    fn sample(&self, payload: &mut Payload, rng: &mut Rng) {
+        let (u, v) = (rng.next(), rng.next());
+        payload.brdf_d = if rng.next() < self.ks {
+            let specular_normal = frame_sample(sample_beckmann(self.roughness, u, v), self.normal);
+            reflect(-self.wo, specular_normal)
+        } else {
+            frame_sample(sample_cosine_hemisphere(u, v), self.normal)
+        };
+
+        let (val, pdf) = self.eval(payload.brdf_d);
+        payload.brdf_val = val * payload.brdf_d.dot(self.normal) / pdf;
+    }
+}
+
+struct Grating {
+    period: f32,
+    height: f32,
+    towards: Vec3,
+}
+
+// bessel function of the first kind, first 5 terms of its series
+fn bessel(order: i32, x: f32) -> f32 {
+    let mut ans = 0.0;
+    let mut sgn = 1.0;
+    let mut denom = (2..=order).product::<i32>() as f32;
+    let mut numer = (x / 2.0).powi(order);
+    let x22 = x * x / 4.0;
+    for m in 0..5 {
+        ans += sgn * numer / denom;
+        numer *= x22;
+        denom *= ((m + 1) * (m + 1 + order)) as f32;
+        sgn = -sgn;
+    }
+    ans
+}
+
+impl Grating {
+    fn sample(
+        &self,
+        payload: &mut Payload,
+        normal: Vec3,
+        direction: Vec3,
+        wavelength: f32,
+        rng: &mut Rng,
+    ) {
+        payload.brdf_val = Vec4::ONE;
+
+        let towards = self.towards.normalize();
+        let towards_perp_normal = towards - towards.dot(normal) * normal;
+        if towards_perp_normal.length() == 0.0 {
+            payload.brdf_val = Vec4::ZERO;
+            return;
+        }
+        let across_grating = towards_perp_normal.normalize();
+        let along_grating = normal.cross(across_grating);
+
+        // the diffraction angle depends on wavelength, so only the hero wavelength is followed
+        payload.is_dispersive = true;
+        let x = 4.0 * PI * self.height / wavelength;
+        let intensities: [f32; 9] = std::array::from_fn(|i| bessel(i as i32 + 1, x).powi(2));
+
+        let r = rng.next() * 2.0 - 1.0;
+        let mut cdf = 0.0;
+        let mut lobe = 0;
+        for (i, p) in intensities.iter().enumerate() {
+            cdf += 2.0 * p;
+            if r.abs() < cdf {
+                lobe = i as i32 + 1;
+                break;
+            }
+        }
+        if r < 0.0 {
+            lobe = -lobe;
+        }
+
+        let wi_along = direction.dot(along_grating) * along_grating;
+        let wi_across = direction - wi_along;
+        if wi_across.length() > 0.0 {
+            let cos_i = (-wi_across.normalize()).dot(normal);
+            let sin_i = (1.0 - cos_i * cos_i).sqrt();
+            let sin_o = sin_i - lobe as f32 * wavelength / self.period;
+            let cos_o = (1.0 - sin_o * sin_o).sqrt();
+            if cos_o.is_nan() {
+                payload.brdf_val = Vec4::ZERO;
+                return;
+            }
+            payload.brdf_d = across_grating * sin_o + normal * cos_o + wi_along;
+        } else {
+            payload.brdf_d = reflect(direction, normal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame_sample, sample_tabulated, Rng};
+    use crate::scene::scenes::mesh::SPECTRUM_SAMPLES;
+    use glam::Vec3;
+
+    #[test]
+    fn random_numbers() {
+        // the same seed gives the same sequence, in [0, 1)
+        let mut a = Rng::tea(3, 7);
+        let mut b = Rng::tea(3, 7);
+        for _ in 0..1000 {
+            let x = a.next();
+            assert_eq!(x, b.next());
+            assert!((0.0..1.0).contains(&x));
+        }
+        assert_ne!(Rng::tea(3, 7).next(), Rng::tea(4, 7).next());
+    }
+
+    #[test]
+    fn frame_samples_follow_the_normal() {
+        for normal in [
+            Vec3::Z,
+            Vec3::NEG_Z,
+            Vec3::X,
+            Vec3::new(1.0, -2.0, 0.5).normalize(),
+        ] {
+            assert!(frame_sample(Vec3::Z, normal).abs_diff_eq(normal, 1e-5));
+        }
+    }
+
+    #[test]
+    fn tabulated_spectra() {
+        let samples: [f32; SPECTRUM_SAMPLES] = std::array::from_fn(|i| i as f32);
+        let texel_center = |i: f32| 380.0 + (i + 0.5) * 340.0 / SPECTRUM_SAMPLES as f32;
+        assert!((sample_tabulated(&samples, texel_center(40.0)) - 40.0).abs() < 1e-3);
+        assert!((sample_tabulated(&samples, texel_center(40.5)) - 40.5).abs() < 1e-3);
+        assert_eq!(sample_tabulated(&samples, 300.0), 0.0);
+        assert_eq!(
+            sample_tabulated(&samples, 800.0),
+            (SPECTRUM_SAMPLES - 1) as f32
+        );
+    }
+}
@@ -1,27 +1,69 @@
-use std::{cell::RefCell, ffi::c_char, path::Path, rc::Rc, sync::LazyLock};
+use std::{
+    cell::{Cell, RefCell},
+    ffi::c_char,
+    path::Path,
+    rc::Rc,
+    sync::LazyLock,
+    time::Duration,
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use ash::{khr, vk, Device, Entry, Instance};
 use gpu_allocator::{vulkan::*, MemoryLocation};
-use image::{ImageBuffer, Rgba};
 use tobj::Model;
 
 use crate::{
+    checkpoint::{hash_bytes, Checkpoint},
+    debug::DebugNames,
+    defer::Defer,
+    denoise::denoise,
     features::{vk_features, VkFeatureGuard, VkFeatures},
-    render::Renderer,
+    output::{
+        collect_aovs, write_aov_files, write_display_image, write_linear_image,
+        write_multispectral, AovImage, ImageFormat,
+    },
+    render::{AccelBuildTimes, Renderer},
+    sampling::{estimate_noise, frame_seed, visible_size},
     scene::{
         scenes::mesh::{
-            Light, MeshScene, MeshSceneUpdate, Object, ProceduralGeometry, ProceduralObject,
+            DenoiseSettings, Light, MeshScene, MeshSceneUpdate, Object, OutputSettings,
+            ProceduralGeometry, ProceduralObject, SamplingSettings, Shader, SpectralSettings,
+            SPECTRUM_SAMPLES,
         },
         Scene,
     },
-    utils::{align_up, AllocatedBuffer, AllocatedImage, QueueFamilyInfo},
-    window::WindowData,
+    spectral::{RGB_TO_SPECTRUM_RES, RGB_TO_SPECTRUM_TABLE},
+    tonemap::tonemap,
+    utils::{align_up, AllocatedBuffer, AllocatedImage, QueueFamily, QueueFamilyInfo},
+    window::{letterbox, WindowData},
 };
 
+use denoise::DenoisePass;
+use tonemap::TonemapPass;
+
+mod denoise;
+mod tonemap;
+
+// spectra index used by point and directional lights without a spectrum
+// these are uplifted from their rgb color in the shader instead
+// keep in sync with NO_SPECTRUM in hit_common.glsl
+const NO_SPECTRUM: u32 = u32::MAX;
+
+// indices into the aov images, keep in sync with raygen_common.glsl
+const AOV_ALBEDO: usize = 0;
+const AOV_NORMAL: usize = 1;
+const AOV_DEPTH_INSTANCE: usize = 2;
+const AOV_IMAGE_COUNT: usize = 3;
+
+// pairs of start and end timestamps
+const TIMESTAMP_TRACE: u32 = 0;
+const TIMESTAMP_BUILD: u32 = 2;
+const TIMESTAMP_COUNT: u32 = 4;
+
 pub struct RaytraceRenderer {
     allocator: Rc<RefCell<Allocator>>,
     device: Device,
+    debug_names: DebugNames,
     accel_struct_device: khr::acceleration_structure::Device,
     rt_pipeline_device: khr::ray_tracing_pipeline::Device,
     device_properties: vk::PhysicalDeviceProperties,
@@ -48,24 +90,66 @@ pub struct RaytraceRenderer {
     descriptor_set_layout: vk::DescriptorSetLayout,
     storage_image: Option<AllocatedImage>,
     accumulation_image: Option<AllocatedImage>,
+    // luminance moments that drive adaptive sampling
+    moment_image: Option<AllocatedImage>,
+    // tonemapped storage image, this is what gets displayed
+    display_image: Option<AllocatedImage>,
+    tonemap_pass: Option<TonemapPass>,
+    denoise_pass: Option<DenoisePass>,
+    // ping-pong images of the denoise pass, only created for the denoised preview
+    denoise_images: Vec<AllocatedImage>,
+    denoise_settings: DenoiseSettings,
+    sampling_settings: SamplingSettings,
+    output_settings: OutputSettings,
     vertex_normal_buffer: Option<AllocatedBuffer>,
     light_buffer: Option<AllocatedBuffer>,
     offset_buffer: Option<AllocatedBuffer>,
     brdf_param_buffer: Option<AllocatedBuffer>,
     spectra_texture: Option<AllocatedImage>,
+    rgb_to_spectrum_texture: Option<AllocatedImage>,
     spectra_sampler: vk::Sampler,
+    spectral_settings: SpectralSettings,
+    // per pixel wavelength bins, [y][x][bin]
+    spectral_bin_buffer: Option<AllocatedBuffer>,
+    // albedo, normal and depth with instance, indexed by the AOV_* constants
+    aov_images: Vec<AllocatedImage>,
+    // hit group of every tlas instance, turns the instance aov into the brdf aov
+    instance_hit_groups: Vec<u32>,
     command_buffers: Vec<vk::CommandBuffer>,
-    push_data: [u8; 128 + 8 + 4],
+    offscreen_command_buffer: vk::CommandBuffer,
+    timestamp_pool: vk::QueryPool,
+    // nanoseconds per tick, None if the compute queue doesn't write timestamps
+    timestamp_period: Option<f32>,
+    timestamp_mask: u64,
+    last_trace_time: Option<Duration>,
+    accel_build_times: Option<AccelBuildTimes>,
+    // pipelines of scenes without procedural geometry can skip aabbs during traversal
+    primitive_culling: bool,
+    // view and projection inverses, seed, frame, padding, tile offset and image size
+    push_data: [u8; 128 + 8 + 4 + 4 + 8 + 8],
     current_frame: u32,
+    // per frame seeds are derived from this and the frame
+    seed: u64,
+    scene_hash: u64,
+    // applied on the next render, once the view and size are known
+    pending_checkpoint: Option<Checkpoint>,
 }
 
 impl RaytraceRenderer {
+    // also returns the device time of the build
+    #[allow(clippy::type_complexity)]
     fn build_accel_structs(
         &self,
         ty: vk::AccelerationStructureTypeKHR,
         geometries: &[vk::AccelerationStructureGeometryKHR],
         primitive_counts: &[u32],
-    ) -> anyhow::Result<(Vec<vk::AccelerationStructureKHR>, Vec<AllocatedBuffer>)> {
+        // one per geometry, for debugging
+        names: &[String],
+    ) -> anyhow::Result<(
+        Vec<vk::AccelerationStructureKHR>,
+        Vec<AllocatedBuffer>,
+        Option<Duration>,
+    )> {
         let mut build_infos = Vec::new();
         let mut build_range_infos = Vec::new();
         let mut scratch_buffers = Vec::new();
@@ -73,7 +157,9 @@ impl RaytraceRenderer {
         let mut accel_structs = Vec::new();
         let mut buffers = Vec::new();
 
-        for (geometry, primitive_count) in geometries.iter().zip(primitive_counts) {
+        for ((geometry, primitive_count), name) in
+            geometries.iter().zip(primitive_counts).zip(names)
+        {
             let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR {
                 first_vertex: 0,
                 primitive_count: *primitive_count,
@@ -111,6 +197,7 @@ impl RaytraceRenderer {
                 MemoryLocation::GpuOnly,
                 self.device_properties.limits,
             )?;
+            buffer.set_name(&self.debug_names, &format!("{name} storage"));
 
             let create_info = vk::AccelerationStructureCreateInfoKHR {
                 ty: build_info.ty,
@@ -123,6 +210,7 @@ impl RaytraceRenderer {
                 self.accel_struct_device
                     .create_acceleration_structure(&create_info, None)
             }?;
+            self.debug_names.name(accel_struct, name);
             build_info.dst_acceleration_structure = accel_struct;
 
             let scratch_buffer = AllocatedBuffer::new_with_alignment(
@@ -135,6 +223,7 @@ impl RaytraceRenderer {
                 self.accel_properties
                     .min_acceleration_structure_scratch_offset_alignment,
             )?;
+            scratch_buffer.set_name(&self.debug_names, &format!("{name} scratch"));
 
             build_info.scratch_data = vk::DeviceOrHostAddressKHR {
                 device_address: unsafe { scratch_buffer.get_device_address(&self.device) },
@@ -172,11 +261,20 @@ impl RaytraceRenderer {
                 },
             )?;
 
+            let label = if ty == vk::AccelerationStructureTypeKHR::TOP_LEVEL {
+                c"build tlas"
+            } else {
+                c"build blas"
+            };
+            self.debug_names.begin_label(build_command_buffer, label);
+            self.cmd_timestamp(build_command_buffer, TIMESTAMP_BUILD, true);
             self.accel_struct_device.cmd_build_acceleration_structures(
                 build_command_buffer,
                 &build_infos,
                 &unsqueezed_build_range_infos,
             );
+            self.cmd_timestamp(build_command_buffer, TIMESTAMP_BUILD, false);
+            self.debug_names.end_label(build_command_buffer);
             self.device.end_command_buffer(build_command_buffer)?;
             self.device.queue_submit(
                 self.compute_queue,
@@ -197,9 +295,55 @@ impl RaytraceRenderer {
             }
         }
 
-        Ok((accel_structs, buffers))
+        let build_time = self.read_timestamps(TIMESTAMP_BUILD)?;
+        Ok((accel_structs, buffers, build_time))
+    }
+
+    // writes the start or end of the timestamp pair at `query`, nothing without timestamp support
+    unsafe fn cmd_timestamp(&self, command_buffer: vk::CommandBuffer, query: u32, start: bool) {
+        if self.timestamp_period.is_none() {
+            return;
+        }
+        if start {
+            self.device
+                .cmd_reset_query_pool(command_buffer, self.timestamp_pool, query, 2);
+            self.device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                self.timestamp_pool,
+                query,
+            );
+        } else {
+            self.device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                self.timestamp_pool,
+                query + 1,
+            );
+        }
+    }
+
+    // time between the timestamp pair at `query`, the command buffer writing it has to be submitted
+    fn read_timestamps(&self, query: u32) -> anyhow::Result<Option<Duration>> {
+        let Some(period) = self.timestamp_period else {
+            return Ok(None);
+        };
+        let mut timestamps = [0u64; 2];
+        unsafe {
+            self.device.get_query_pool_results(
+                self.timestamp_pool,
+                query,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+            )?;
+        }
+        let ticks = timestamps[1].wrapping_sub(timestamps[0]) & self.timestamp_mask;
+        Ok(Some(Duration::from_nanos(
+            (ticks as f64 * period as f64) as u64,
+        )))
     }
 
+    #[allow(clippy::type_complexity)]
     fn get_mesh_geometries(
         &self,
         meshes: &[Model],
@@ -226,6 +370,7 @@ impl RaytraceRenderer {
                 self.device_properties.limits,
             )?;
             vertex_buffer.store(&mesh.mesh.positions)?;
+            vertex_buffer.set_name(&self.debug_names, &format!("{} vertices", mesh.name));
 
             let index_count = mesh.mesh.indices.len();
             let index_stride = std::mem::size_of_val(&mesh.mesh.indices[0]);
@@ -241,6 +386,7 @@ impl RaytraceRenderer {
                 self.device_properties.limits,
             )?;
             index_buffer.store(&mesh.mesh.indices)?;
+            index_buffer.set_name(&self.debug_names, &format!("{} indices", mesh.name));
 
             let geometry = vk::AccelerationStructureGeometryKHR {
                 geometry_type: vk::GeometryTypeKHR::TRIANGLES,
@@ -307,6 +453,8 @@ impl RaytraceRenderer {
                 self.device_properties.limits,
             )?;
             aabb_buffer.store(&aabb_data)?;
+            let name = proc_geom.closest_hit_shader.name().to_string_lossy();
+            aabb_buffer.set_name(&self.debug_names, &format!("{name} aabbs"));
 
             let geometry = vk::AccelerationStructureGeometryKHR {
                 geometry_type: vk::GeometryTypeKHR::AABBS,
@@ -433,6 +581,7 @@ impl RaytraceRenderer {
             self.device_properties.limits,
         )?;
         instance_buffer.store(&instances)?;
+        instance_buffer.set_name(&self.debug_names, "tlas instances");
 
         let geometry = vk::AccelerationStructureGeometryKHR {
             geometry_type: vk::GeometryTypeKHR::INSTANCES,
@@ -518,6 +667,38 @@ impl RaytraceRenderer {
                 binding: 7,
                 ..Default::default()
             },
+            // rgb to spectrum coefficient table
+            vk::DescriptorSetLayoutBinding {
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                stage_flags: vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+                binding: 8,
+                ..Default::default()
+            },
+            // multispectral output bins
+            vk::DescriptorSetLayoutBinding {
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+                binding: 9,
+                ..Default::default()
+            },
+            // aov images
+            vk::DescriptorSetLayoutBinding {
+                descriptor_count: AOV_IMAGE_COUNT as u32,
+                descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+                binding: 10,
+                ..Default::default()
+            },
+            // luminance moments
+            vk::DescriptorSetLayoutBinding {
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+                binding: 11,
+                ..Default::default()
+            },
         ];
 
         let create_info = vk::DescriptorSetLayoutCreateInfo {
@@ -563,11 +744,21 @@ impl RaytraceRenderer {
             self.device
                 .create_pipeline_layout(&layout_create_info, None)?
         };
+        self.debug_names
+            .name(pipeline_layout, "path tracing pipeline layout");
 
         let mut shaders = Vec::new();
+        // hit shaders are named after their brdf
+        let compile = |shader: &Shader| -> anyhow::Result<vk::ShaderModule> {
+            let module = shader.compile(&self.device)?.module();
+            let name = shader.name().to_string_lossy();
+            self.debug_names
+                .name(module, &format!("{name} ({})", shader.file()));
+            Ok(module)
+        };
 
-        let raygen_module = scene.raygen_shader.compile(&self.device)?.module();
-        let miss_module = scene.miss_shader.compile(&self.device)?.module();
+        let raygen_module = compile(&scene.raygen_shader)?;
+        let miss_module = compile(&scene.miss_shader)?;
         let mut shader_stages = vec![
             vk::PipelineShaderStageCreateInfo {
                 stage: vk::ShaderStageFlags::RAYGEN_KHR,
@@ -604,7 +795,7 @@ impl RaytraceRenderer {
         ];
 
         for hit_shader in scene.hit_shaders.iter() {
-            let module = hit_shader.clone().compile(&self.device)?.module();
+            let module = compile(hit_shader)?;
             shader_stages.push(vk::PipelineShaderStageCreateInfo {
                 stage: vk::ShaderStageFlags::CLOSEST_HIT_KHR,
                 module,
@@ -625,11 +816,8 @@ impl RaytraceRenderer {
         let triangle_hit_group_count = scene.hit_shaders.len();
 
         for proc_geom in scene.procedural_geometries.iter() {
-            let int_module = proc_geom
-                .intersection_shader
-                .compile(&self.device)?
-                .module();
-            let hit_module = proc_geom.closest_hit_shader.compile(&self.device)?.module();
+            let int_module = compile(&proc_geom.intersection_shader)?;
+            let hit_module = compile(&proc_geom.closest_hit_shader)?;
 
             let int_stage_index = shader_stages.len() as u32;
             shader_stages.push(vk::PipelineShaderStageCreateInfo {
@@ -659,11 +847,60 @@ impl RaytraceRenderer {
             });
         }
 
+        // spectral and color settings are passed to every stage as specialization constants
+        // keep the constant ids in sync with color_spaces.glsl and path.rgen
+        let spectral = scene.spectral;
+        let output = &scene.output;
+        let white = output
+            .white_point
+            .unwrap_or(output.color_space.default_white());
+        let xyz_to_output = output.color_space.render_xyz_to_rgb(white);
+        let mut spec_data = vec![
+            spectral.min_wavelength.to_bits(),
+            spectral.max_wavelength.to_bits(),
+            spectral.sampling as u32,
+            spectral.bins,
+        ];
+        // row by row
+        spec_data.extend(
+            xyz_to_output
+                .transpose()
+                .to_cols_array()
+                .map(|x| (x as f32).to_bits()),
+        );
+        spec_data.push(self.aovs_enabled() as vk::Bool32);
+        spec_data.push(scene.sampling.frame_samples());
+        spec_data.push(scene.sampling.adaptive_threshold.unwrap_or(0.0).to_bits());
+        spec_data.push(scene.render.debug_printf as vk::Bool32);
+        let spec_entries: Vec<_> = (0..spec_data.len() as u32)
+            .map(|i| vk::SpecializationMapEntry {
+                constant_id: i,
+                offset: i * std::mem::size_of::<u32>() as u32,
+                size: std::mem::size_of::<u32>(),
+            })
+            .collect();
+        let spec_info = vk::SpecializationInfo {
+            map_entry_count: spec_entries.len() as u32,
+            p_map_entries: spec_entries.as_ptr(),
+            data_size: std::mem::size_of_val(spec_data.as_slice()),
+            p_data: spec_data.as_ptr() as *const std::ffi::c_void,
+            ..Default::default()
+        };
+        for stage in shader_stages.iter_mut() {
+            stage.p_specialization_info = &raw const spec_info;
+        }
+
+        let flags = if self.primitive_culling && scene.procedural_geometries.is_empty() {
+            vk::PipelineCreateFlags::RAY_TRACING_SKIP_AABBS_KHR
+        } else {
+            vk::PipelineCreateFlags::empty()
+        };
         let pipeline = unsafe {
             let out = self.rt_pipeline_device.create_ray_tracing_pipelines(
                 vk::DeferredOperationKHR::null(),
                 vk::PipelineCache::null(),
                 &[vk::RayTracingPipelineCreateInfoKHR {
+                    flags,
                     stage_count: shader_stages.len() as u32,
                     p_stages: shader_stages.as_ptr(),
                     group_count: shader_groups.len() as u32,
@@ -681,6 +918,7 @@ impl RaytraceRenderer {
                     .ok_or(anyhow!("failed to construct pipeline: {y}"))?,
             }
         };
+        self.debug_names.name(pipeline, "path tracing pipeline");
 
         for shader in shaders {
             unsafe {
@@ -754,6 +992,7 @@ impl RaytraceRenderer {
         &self,
         data: &[T],
         usage: vk::BufferUsageFlags,
+        name: &str,
     ) -> anyhow::Result<AllocatedBuffer> {
         let size = std::mem::size_of_val(data) as u64;
         let mut staging_buffer = AllocatedBuffer::new(
@@ -765,6 +1004,7 @@ impl RaytraceRenderer {
             self.device_properties.limits,
         )?;
         staging_buffer.store(data)?;
+        staging_buffer.set_name(&self.debug_names, &format!("{name} staging"));
 
         let buffer = AllocatedBuffer::new(
             &self.device,
@@ -774,6 +1014,7 @@ impl RaytraceRenderer {
             MemoryLocation::GpuOnly,
             self.device_properties.limits,
         )?;
+        buffer.set_name(&self.debug_names, name);
 
         self.copy_buffer(staging_buffer.buffer, buffer.buffer, size)?;
 
@@ -782,13 +1023,14 @@ impl RaytraceRenderer {
         Ok(buffer)
     }
 
-    fn create_spectra_texture(
+    fn create_float_texture(
         &self,
         width: u32,
         height: u32,
+        format: vk::Format,
         data: &[f32],
+        name: &str,
     ) -> anyhow::Result<AllocatedImage> {
-        let format = vk::Format::R32_SFLOAT;
         let mut image = AllocatedImage::new(
             &self.device,
             &mut self.allocator.borrow_mut(),
@@ -797,6 +1039,7 @@ impl RaytraceRenderer {
             vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
             MemoryLocation::GpuOnly,
         )?;
+        image.set_name(&self.debug_names, name);
 
         let data_bytes: &[u8] = bytemuck::cast_slice(data);
         let mut buffer = AllocatedBuffer::new(
@@ -807,6 +1050,7 @@ impl RaytraceRenderer {
             MemoryLocation::CpuToGpu,
             self.device_properties.limits,
         )?;
+        buffer.set_name(&self.debug_names, &format!("{name} staging"));
 
         buffer.store(data)?;
 
@@ -925,165 +1169,876 @@ impl RaytraceRenderer {
         Ok(image)
     }
 
-    fn create_sbt(
+    // records a copy into a host visible staging buffer of `size` bytes, waits for it and returns
+    // the copied floats. the command buffer and staging buffer are freed on every path
+    fn read_back(
         &self,
-        shader_group_count: usize,
-    ) -> anyhow::Result<(
-        AllocatedBuffer,
-        vk::StridedDeviceAddressRegionKHR,
-        vk::StridedDeviceAddressRegionKHR,
-        vk::StridedDeviceAddressRegionKHR,
-        vk::StridedDeviceAddressRegionKHR,
-    )> {
-        let unaligned_table_data = unsafe {
-            self.rt_pipeline_device
-                .get_ray_tracing_shader_group_handles(
-                    self.pipeline,
-                    0,
-                    shader_group_count as u32,
-                    shader_group_count
-                        * self.rt_pipeline_properties.shader_group_handle_size as usize,
-                )?
-        };
-
-        let handle_size = self.rt_pipeline_properties.shader_group_handle_size as usize;
-        let handle_stride = align_up(
-            handle_size as u32,
-            self.rt_pipeline_properties.shader_group_handle_alignment,
-        ) as usize;
-        let base_stride = align_up(
-            handle_size as u32,
-            self.rt_pipeline_properties.shader_group_base_alignment,
-        ) as usize;
-
-        let table_size = 2 * base_stride + (shader_group_count - 2) * handle_stride;
-        let mut table_data = vec![0u8; table_size];
-
-        // raygen
-        table_data[0..handle_size].copy_from_slice(&unaligned_table_data[0..handle_size]);
-        // miss
-        table_data[base_stride..base_stride + handle_size]
-            .copy_from_slice(&unaligned_table_data[handle_size..2 * handle_size]);
-        // closest hit
-        for i in 0..shader_group_count - 2 {
-            let aligned_base = 2 * base_stride + i * handle_stride;
-            table_data[aligned_base..aligned_base + handle_size].copy_from_slice(
-                &unaligned_table_data[(i + 2) * handle_size..(i + 3) * handle_size],
-            );
+        size: vk::DeviceSize,
+        name: &str,
+        record: impl FnOnce(vk::CommandBuffer, vk::Buffer),
+    ) -> anyhow::Result<Vec<f32>> {
+        unsafe {
+            self.device.device_wait_idle()?;
         }
 
-        let sbt_buffer = unsafe {
-            self.create_device_buffer(
-                &table_data,
-                vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR
-                    | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
-            )?
-        };
-
-        let sbt_address = unsafe { sbt_buffer.get_device_address(&self.device) };
-        let raygen_region = vk::StridedDeviceAddressRegionKHR {
-            device_address: sbt_address,
-            stride: handle_stride as u64,
-            size: handle_stride as u64,
-        };
-        let miss_region = vk::StridedDeviceAddressRegionKHR {
-            device_address: sbt_address + base_stride as u64,
-            stride: handle_stride as u64,
-            size: handle_stride as u64,
-        };
-        let hit_region = vk::StridedDeviceAddressRegionKHR {
-            device_address: sbt_address + 2 * base_stride as u64,
-            stride: handle_stride as u64,
-            size: (shader_group_count as u64 - 2) * handle_stride as u64,
-        };
-        let callable_region = vk::StridedDeviceAddressRegionKHR::default();
-
-        Ok((
-            sbt_buffer,
-            raygen_region,
-            miss_region,
-            hit_region,
-            callable_region,
-        ))
-    }
+        let staging_buffer = AllocatedBuffer::new(
+            &self.device,
+            &mut self.allocator.borrow_mut(),
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuToCpu,
+            self.device_properties.limits,
+        )?;
+        staging_buffer.set_name(&self.debug_names, name);
+        let (buffer, mapped_ptr) = (staging_buffer.buffer, staging_buffer.mapped_ptr());
+        // destroy takes the buffer by value, so the guard holds it in a cell
+        let _staging_buffer = Cell::new(Some(staging_buffer)).defer(|x| {
+            if let Some(buffer) = x.take() {
+                unsafe { buffer.destroy(&self.device, &mut self.allocator.borrow_mut()) }
+            }
+        });
+        let mapped_ptr = mapped_ptr.ok_or_else(|| anyhow!("staging buffer not mapped"))?;
 
-    fn create_descriptor_pool_and_set(
-        &self,
-        layout: vk::DescriptorSetLayout,
-        sizes: &[vk::DescriptorPoolSize],
-    ) -> anyhow::Result<(vk::DescriptorPool, vk::DescriptorSet)> {
-        let pool = {
-            let pool_info = vk::DescriptorPoolCreateInfo {
-                pool_size_count: sizes.len() as u32,
-                p_pool_sizes: sizes.as_ptr(),
-                max_sets: 1,
+        let command_buffer = {
+            let allocate_info = vk::CommandBufferAllocateInfo {
+                command_buffer_count: 1,
+                command_pool: self.command_pool,
+                level: vk::CommandBufferLevel::PRIMARY,
                 ..Default::default()
             };
+            unsafe { self.device.allocate_command_buffers(&allocate_info)?[0] }
+        }
+        .defer(|x| unsafe { self.device.free_command_buffers(self.command_pool, &[*x]) });
 
-            unsafe { self.device.create_descriptor_pool(&pool_info, None) }?
-        };
+        unsafe {
+            self.device.begin_command_buffer(
+                *command_buffer,
+                &vk::CommandBufferBeginInfo {
+                    flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+                    ..Default::default()
+                },
+            )?;
+            record(*command_buffer, buffer);
+            self.device.end_command_buffer(*command_buffer)?;
 
-        let set = unsafe {
-            let allocate_info = vk::DescriptorSetAllocateInfo {
-                descriptor_pool: pool,
-                p_set_layouts: &raw const layout,
-                descriptor_set_count: 1,
-                ..Default::default()
-            };
-            self.device.allocate_descriptor_sets(&allocate_info)?[0]
-        };
+            self.device.queue_submit(
+                self.compute_queue,
+                &[vk::SubmitInfo {
+                    command_buffer_count: 1,
+                    p_command_buffers: &*command_buffer,
+                    ..Default::default()
+                }],
+                vk::Fence::null(),
+            )?;
+            self.device.queue_wait_idle(self.compute_queue)?;
 
-        Ok((pool, set))
+            let len = size as usize / std::mem::size_of::<f32>();
+            Ok(std::slice::from_raw_parts(mapped_ptr as *const f32, len).to_vec())
+        }
     }
 
-    fn create_command_buffer(&self) -> anyhow::Result<vk::CommandBuffer> {
-        let allocate_info = vk::CommandBufferAllocateInfo {
-            command_buffer_count: 1,
-            command_pool: self.command_pool,
-            level: vk::CommandBufferLevel::PRIMARY,
-            ..Default::default()
+    // copies an RGBA32F image in the GENERAL layout back to the cpu
+    fn read_back_image(&self, image: &AllocatedImage) -> anyhow::Result<Vec<f32>> {
+        let size = (image.width * image.height) as usize * 4 * std::mem::size_of::<f32>();
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
         };
 
-        Ok(unsafe { self.device.allocate_command_buffers(&allocate_info)?[0] })
+        self.read_back(
+            size as vk::DeviceSize,
+            "image readback",
+            |command_buffer, buffer| unsafe {
+                self.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR
+                        | vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[vk::ImageMemoryBarrier {
+                        src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+                        old_layout: vk::ImageLayout::GENERAL,
+                        new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        image: image.image,
+                        subresource_range,
+                        ..Default::default()
+                    }],
+                );
+
+                self.device.cmd_copy_image_to_buffer(
+                    command_buffer,
+                    image.image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    buffer,
+                    &[vk::BufferImageCopy {
+                        buffer_offset: 0,
+                        buffer_row_length: 0,
+                        buffer_image_height: 0,
+                        image_subresource: vk::ImageSubresourceLayers {
+                            aspect_mask: vk::ImageAspectFlags::COLOR,
+                            mip_level: 0,
+                            base_array_layer: 0,
+                            layer_count: 1,
+                        },
+                        image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                        image_extent: vk::Extent3D {
+                            width: image.width,
+                            height: image.height,
+                            depth: 1,
+                        },
+                    }],
+                );
+
+                self.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[vk::ImageMemoryBarrier {
+                        src_access_mask: vk::AccessFlags::TRANSFER_READ,
+                        dst_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        old_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        new_layout: vk::ImageLayout::GENERAL,
+                        image: image.image,
+                        subresource_range,
+                        ..Default::default()
+                    }],
+                );
+            },
+        )
     }
 
-    fn record_command_buffer(
-        &self,
-        command_buffer: vk::CommandBuffer,
-        target_image: vk::Image,
-        (target_width, target_height): (u32, u32),
-    ) -> anyhow::Result<()> {
-        let command_buffer_begin_info = vk::CommandBufferBeginInfo::default();
+    /// Reads back the raw per pixel bin sums, [y][x][bin]
+    fn read_back_spectral_bins(&self) -> anyhow::Result<Vec<f32>> {
+        let bin_buffer = self
+            .spectral_bin_buffer
+            .as_ref()
+            .ok_or_else(|| anyhow!("no spectral bin buffer"))?;
+        let storage_image = self
+            .storage_image
+            .as_ref()
+            .ok_or_else(|| anyhow!("no storage image"))?;
 
-        unsafe {
-            self.device
-                .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+        let sample_count = storage_image.width * storage_image.height * self.spectral_settings.bins;
+        let size = (sample_count as usize * std::mem::size_of::<f32>()) as vk::DeviceSize;
 
-            self.device
-                .begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+        self.read_back(
+            size,
+            "spectral bin readback",
+            |command_buffer, buffer| unsafe {
+                self.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[vk::MemoryBarrier {
+                        src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+                        ..Default::default()
+                    }],
+                    &[],
+                    &[],
+                );
+
+                self.device.cmd_copy_buffer(
+                    command_buffer,
+                    bin_buffer.buffer,
+                    buffer,
+                    &[vk::BufferCopy {
+                        src_offset: 0,
+                        dst_offset: 0,
+                        size,
+                    }],
+                );
+            },
+        )
+    }
 
-            self.device.cmd_bind_pipeline(
-                command_buffer,
-                vk::PipelineBindPoint::RAY_TRACING_KHR,
-                self.pipeline,
-            );
-            self.device.cmd_bind_descriptor_sets(
-                command_buffer,
-                vk::PipelineBindPoint::RAY_TRACING_KHR,
-                self.pipeline_layout,
-                0,
-                &[self.descriptor_set],
-                &[],
-            );
+    /// Overwrites an RGBA32F image that is in the GENERAL layout
+    fn upload_image(&self, image: &AllocatedImage, data: &[f32]) -> anyhow::Result<()> {
+        if data.len() != (image.width * image.height * 4) as usize {
+            bail!("expected {}x{} RGBA pixels", image.width, image.height);
+        }
 
-            self.device.cmd_push_constants(
-                command_buffer,
-                self.pipeline_layout,
-                vk::ShaderStageFlags::RAYGEN_KHR,
-                0,
-                &self.push_data,
-            );
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
 
+        let mut staging_buffer = AllocatedBuffer::new(
+            &self.device,
+            &mut self.allocator.borrow_mut(),
+            std::mem::size_of_val(data) as vk::DeviceSize,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            self.device_properties.limits,
+        )?;
+        staging_buffer.store(data)?;
+        staging_buffer.set_name(&self.debug_names, "image upload");
+
+        let command_buffer = {
+            let allocate_info = vk::CommandBufferAllocateInfo {
+                command_buffer_count: 1,
+                command_pool: self.command_pool,
+                level: vk::CommandBufferLevel::PRIMARY,
+                ..Default::default()
+            };
+            unsafe { self.device.allocate_command_buffers(&allocate_info)?[0] }
+        };
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+
+        unsafe {
+            self.device.begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo {
+                    flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+                    ..Default::default()
+                },
+            )?;
+
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier {
+                    src_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                    dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    old_layout: vk::ImageLayout::GENERAL,
+                    new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    image: image.image,
+                    subresource_range,
+                    ..Default::default()
+                }],
+            );
+
+            self.device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.buffer,
+                image.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_row_length: 0,
+                    buffer_image_height: 0,
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    },
+                    image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                    image_extent: vk::Extent3D {
+                        width: image.width,
+                        height: image.height,
+                        depth: 1,
+                    },
+                }],
+            );
+
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier {
+                    src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                    old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    new_layout: vk::ImageLayout::GENERAL,
+                    image: image.image,
+                    subresource_range,
+                    ..Default::default()
+                }],
+            );
+
+            self.device.end_command_buffer(command_buffer)?;
+
+            self.device.queue_submit(
+                self.compute_queue,
+                &[vk::SubmitInfo {
+                    command_buffer_count: 1,
+                    p_command_buffers: &raw const command_buffer,
+                    ..Default::default()
+                }],
+                vk::Fence::null(),
+            )?;
+
+            self.device.queue_wait_idle(self.compute_queue)?;
+            self.device
+                .free_command_buffers(self.command_pool, &[command_buffer]);
+            staging_buffer.destroy(&self.device, &mut self.allocator.borrow_mut());
+        }
+
+        Ok(())
+    }
+
+    fn set_tile(&mut self, (x, y): (u32, u32), (width, height): (u32, u32)) {
+        self.push_data[144..160].copy_from_slice(bytemuck::cast_slice(&[x, y, width, height]));
+    }
+
+    /// Identifies the scene, camera and sampling a checkpoint was rendered with
+    fn checkpoint_hash(&self) -> u64 {
+        let mut bytes = self.scene_hash.to_le_bytes().to_vec();
+        // view and projection inverses, then the tile offset and image size
+        bytes.extend_from_slice(&self.push_data[..128]);
+        bytes.extend_from_slice(&self.push_data[144..]);
+        bytes.extend_from_slice(&self.sampling_settings.frame_samples().to_le_bytes());
+        hash_bytes(&bytes)
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: Checkpoint) -> anyhow::Result<()> {
+        let accum = self.accumulation_image.as_ref().unwrap();
+        let size = (accum.width, accum.height);
+        if checkpoint.size != size {
+            bail!(
+                "checkpoint is {}x{} but the render is {}x{}",
+                checkpoint.size.0,
+                checkpoint.size.1,
+                size.0,
+                size.1
+            );
+        }
+        if checkpoint.scene_hash != self.checkpoint_hash() {
+            bail!("checkpoint was rendered from a different scene, camera or sample count");
+        }
+        let aov_count = if self.aovs_enabled() {
+            AOV_IMAGE_COUNT
+        } else {
+            0
+        };
+        if checkpoint.aovs.len() != aov_count {
+            bail!(
+                "checkpoint has {} aovs, expected {aov_count}",
+                checkpoint.aovs.len()
+            );
+        }
+        let bins = if self.spectral_settings.bins > 0 {
+            (size.0 * size.1 * self.spectral_settings.bins) as usize
+        } else {
+            0
+        };
+        if checkpoint.spectral_bins.len() != bins {
+            bail!("checkpoint has a different number of spectral bins");
+        }
+
+        self.upload_image(accum, &checkpoint.accumulation)?;
+        self.upload_image(self.moment_image.as_ref().unwrap(), &checkpoint.moments)?;
+        for (image, data) in self.aov_images.iter().zip(&checkpoint.aovs) {
+            self.upload_image(image, data)?;
+        }
+        if bins > 0 {
+            unsafe {
+                let mut staging_buffer = AllocatedBuffer::new(
+                    &self.device,
+                    &mut self.allocator.borrow_mut(),
+                    (bins * std::mem::size_of::<f32>()) as vk::DeviceSize,
+                    vk::BufferUsageFlags::TRANSFER_SRC,
+                    MemoryLocation::CpuToGpu,
+                    self.device_properties.limits,
+                )?;
+                staging_buffer.store(&checkpoint.spectral_bins)?;
+                staging_buffer.set_name(&self.debug_names, "spectral bin upload");
+                self.copy_buffer(
+                    staging_buffer.buffer,
+                    self.spectral_bin_buffer.as_ref().unwrap().buffer,
+                    (bins * std::mem::size_of::<f32>()) as vk::DeviceSize,
+                )?;
+                staging_buffer.destroy(&self.device, &mut self.allocator.borrow_mut());
+            }
+        }
+
+        self.current_frame = checkpoint.frame;
+        self.seed = checkpoint.seed;
+        Ok(())
+    }
+
+    fn create_spectral_bin_buffer(
+        &self,
+        (width, height): (u32, u32),
+    ) -> anyhow::Result<AllocatedBuffer> {
+        // the shader never touches the buffer when binning is off, but binding 9 still needs something
+        let bins = self.spectral_settings.bins.max(1) as u64;
+        let size = if self.spectral_settings.bins == 0 {
+            std::mem::size_of::<f32>() as u64
+        } else {
+            width as u64 * height as u64 * bins * std::mem::size_of::<f32>() as u64
+        };
+
+        let buffer = AllocatedBuffer::new(
+            &self.device,
+            &mut self.allocator.borrow_mut(),
+            size,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_SRC
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuOnly,
+            self.device_properties.limits,
+        )?;
+        buffer.set_name(&self.debug_names, "spectral bins");
+        Ok(buffer)
+    }
+
+    fn create_aov_images(
+        &self,
+        (width, height): (u32, u32),
+    ) -> anyhow::Result<Vec<AllocatedImage>> {
+        // like the spectral bins, binding 10 needs images even when no aovs are written
+        let size = if !self.aovs_enabled() {
+            (1, 1)
+        } else {
+            (width, height)
+        };
+
+        const AOV_NAMES: [&str; AOV_IMAGE_COUNT] = ["albedo aov", "normal aov", "depth aov"];
+        AOV_NAMES
+            .iter()
+            .map(|name| {
+                let mut image = AllocatedImage::new(
+                    &self.device,
+                    &mut self.allocator.borrow_mut(),
+                    size,
+                    vk::Format::R32G32B32A32_SFLOAT,
+                    vk::ImageUsageFlags::STORAGE
+                        | vk::ImageUsageFlags::TRANSFER_SRC
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                    MemoryLocation::GpuOnly,
+                )?;
+                image.set_name(&self.debug_names, name);
+                image.transition(
+                    &self.device,
+                    self.compute_queue,
+                    self.command_pool,
+                    vk::ImageLayout::GENERAL,
+                )?;
+                Ok(image)
+            })
+            .collect()
+    }
+
+    /// Whether the raygen shader writes aovs, the denoiser needs them even if none are saved
+    fn aovs_enabled(&self) -> bool {
+        !self.output_settings.aovs.is_empty() || self.denoise_settings.enabled()
+    }
+
+    fn create_denoise_images(
+        &self,
+        (width, height): (u32, u32),
+    ) -> anyhow::Result<Vec<AllocatedImage>> {
+        ["denoise ping", "denoise pong"]
+            .iter()
+            .map(|name| {
+                let mut image = AllocatedImage::new(
+                    &self.device,
+                    &mut self.allocator.borrow_mut(),
+                    (width, height),
+                    vk::Format::R32G32B32A32_SFLOAT,
+                    vk::ImageUsageFlags::STORAGE,
+                    MemoryLocation::GpuOnly,
+                )?;
+                image.set_name(&self.debug_names, name);
+                image.transition(
+                    &self.device,
+                    self.compute_queue,
+                    self.command_pool,
+                    vk::ImageLayout::GENERAL,
+                )?;
+                Ok(image)
+            })
+            .collect()
+    }
+
+    // the tonemap pass reads the denoised image when the preview is denoised
+    fn update_post_pass_images(&self) {
+        let mut linear_view = self.storage_image.as_ref().unwrap().image_view;
+
+        if let Some(denoise_pass) = &self.denoise_pass {
+            let ping_pong_views = [0, 1].map(|i| self.denoise_images[i].image_view);
+            denoise_pass.update_images(
+                &self.device,
+                linear_view,
+                ping_pong_views,
+                self.aov_images[AOV_ALBEDO].image_view,
+                self.aov_images[AOV_NORMAL].image_view,
+            );
+            linear_view =
+                ping_pong_views[DenoisePass::output_index(self.denoise_settings.iterations)];
+        }
+
+        self.tonemap_pass.as_ref().unwrap().update_images(
+            &self.device,
+            linear_view,
+            self.display_image.as_ref().unwrap().image_view,
+        );
+    }
+
+    fn write_aov_descriptors(&self) {
+        let infos: Vec<_> = self
+            .aov_images
+            .iter()
+            .map(|image| vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::GENERAL,
+                image_view: image.image_view,
+                sampler: vk::Sampler::null(),
+            })
+            .collect();
+        let write = vk::WriteDescriptorSet {
+            dst_set: self.descriptor_set,
+            dst_binding: 10,
+            dst_array_element: 0,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: infos.len() as u32,
+            p_image_info: infos.as_ptr(),
+            ..Default::default()
+        };
+
+        unsafe {
+            self.device.update_descriptor_sets(&[write], &[]);
+        }
+    }
+
+    /// Reads back the RGB average of an aov that holds a sum of per frame averages
+    fn read_back_accumulated_aov(&self, index: usize) -> anyhow::Result<Vec<f32>> {
+        let frames = self.current_frame.max(1) as f32;
+        Ok(self
+            .read_back_image(&self.aov_images[index])?
+            .chunks(4)
+            .flat_map(|p| [p[0] / frames, p[1] / frames, p[2] / frames])
+            .collect())
+    }
+
+    /// Reads back the enabled aovs in the order the scene lists them
+    fn read_back_aovs(&self) -> anyhow::Result<Vec<AovImage>> {
+        let aovs = &self.output_settings.aovs;
+        if aovs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(collect_aovs(
+            aovs,
+            &self.read_back_accumulated_aov(AOV_ALBEDO)?,
+            &self.read_back_accumulated_aov(AOV_NORMAL)?,
+            &self.read_back_image(&self.aov_images[AOV_DEPTH_INSTANCE])?,
+            &self.instance_hit_groups,
+        ))
+    }
+
+    fn create_sbt(
+        &self,
+        shader_group_count: usize,
+    ) -> anyhow::Result<(
+        AllocatedBuffer,
+        vk::StridedDeviceAddressRegionKHR,
+        vk::StridedDeviceAddressRegionKHR,
+        vk::StridedDeviceAddressRegionKHR,
+        vk::StridedDeviceAddressRegionKHR,
+    )> {
+        let unaligned_table_data = unsafe {
+            self.rt_pipeline_device
+                .get_ray_tracing_shader_group_handles(
+                    self.pipeline,
+                    0,
+                    shader_group_count as u32,
+                    shader_group_count
+                        * self.rt_pipeline_properties.shader_group_handle_size as usize,
+                )?
+        };
+
+        let handle_size = self.rt_pipeline_properties.shader_group_handle_size as usize;
+        let handle_stride = align_up(
+            handle_size as u32,
+            self.rt_pipeline_properties.shader_group_handle_alignment,
+        ) as usize;
+        let base_stride = align_up(
+            handle_size as u32,
+            self.rt_pipeline_properties.shader_group_base_alignment,
+        ) as usize;
+
+        let table_size = 2 * base_stride + (shader_group_count - 2) * handle_stride;
+        let mut table_data = vec![0u8; table_size];
+
+        // raygen
+        table_data[0..handle_size].copy_from_slice(&unaligned_table_data[0..handle_size]);
+        // miss
+        table_data[base_stride..base_stride + handle_size]
+            .copy_from_slice(&unaligned_table_data[handle_size..2 * handle_size]);
+        // closest hit
+        for i in 0..shader_group_count - 2 {
+            let aligned_base = 2 * base_stride + i * handle_stride;
+            table_data[aligned_base..aligned_base + handle_size].copy_from_slice(
+                &unaligned_table_data[(i + 2) * handle_size..(i + 3) * handle_size],
+            );
+        }
+
+        let sbt_buffer = unsafe {
+            self.create_device_buffer(
+                &table_data,
+                vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR
+                    | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                "sbt",
+            )?
+        };
+
+        let sbt_address = unsafe { sbt_buffer.get_device_address(&self.device) };
+        let raygen_region = vk::StridedDeviceAddressRegionKHR {
+            device_address: sbt_address,
+            stride: handle_stride as u64,
+            size: handle_stride as u64,
+        };
+        let miss_region = vk::StridedDeviceAddressRegionKHR {
+            device_address: sbt_address + base_stride as u64,
+            stride: handle_stride as u64,
+            size: handle_stride as u64,
+        };
+        let hit_region = vk::StridedDeviceAddressRegionKHR {
+            device_address: sbt_address + 2 * base_stride as u64,
+            stride: handle_stride as u64,
+            size: (shader_group_count as u64 - 2) * handle_stride as u64,
+        };
+        let callable_region = vk::StridedDeviceAddressRegionKHR::default();
+
+        Ok((
+            sbt_buffer,
+            raygen_region,
+            miss_region,
+            hit_region,
+            callable_region,
+        ))
+    }
+
+    fn create_descriptor_pool_and_set(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        sizes: &[vk::DescriptorPoolSize],
+    ) -> anyhow::Result<(vk::DescriptorPool, vk::DescriptorSet)> {
+        let pool = {
+            let pool_info = vk::DescriptorPoolCreateInfo {
+                pool_size_count: sizes.len() as u32,
+                p_pool_sizes: sizes.as_ptr(),
+                max_sets: 1,
+                ..Default::default()
+            };
+
+            unsafe { self.device.create_descriptor_pool(&pool_info, None) }?
+        };
+
+        let set = unsafe {
+            let allocate_info = vk::DescriptorSetAllocateInfo {
+                descriptor_pool: pool,
+                p_set_layouts: &raw const layout,
+                descriptor_set_count: 1,
+                ..Default::default()
+            };
+            self.device.allocate_descriptor_sets(&allocate_info)?[0]
+        };
+
+        Ok((pool, set))
+    }
+
+    // applies the updates and fills in the push constants of the next frame
+    fn prepare_frame(&mut self, updates: &[MeshSceneUpdate]) -> anyhow::Result<()> {
+        for update in updates {
+            match update {
+                MeshSceneUpdate::NewView(view) => {
+                    let view_inverse_cols = view.inverse().to_cols_array();
+                    let view_bytes: &[u8] = bytemuck::cast_slice(&view_inverse_cols);
+                    self.push_data[0..64].copy_from_slice(view_bytes);
+
+                    self.current_frame = 0;
+                }
+                MeshSceneUpdate::NewSize((width, height, projection)) => unsafe {
+                    self.device.device_wait_idle()?;
+
+                    for (image, name) in [
+                        (&mut self.storage_image, "storage image"),
+                        (&mut self.accumulation_image, "accumulation image"),
+                        (&mut self.moment_image, "moment image"),
+                        (&mut self.display_image, "display image"),
+                    ] {
+                        let old_image = image.take().unwrap();
+
+                        *image = Some(AllocatedImage::new(
+                            &self.device,
+                            &mut self.allocator.borrow_mut(),
+                            (*width, *height),
+                            old_image.format,
+                            old_image.usage,
+                            MemoryLocation::GpuOnly,
+                        )?);
+                        image.as_ref().unwrap().set_name(&self.debug_names, name);
+                        image.as_mut().unwrap().transition(
+                            &self.device,
+                            self.compute_queue,
+                            self.command_pool,
+                            vk::ImageLayout::GENERAL,
+                        )?;
+
+                        old_image.destroy(&self.device, &mut self.allocator.borrow_mut());
+                    }
+
+                    let bound_images = [
+                        (0, &self.storage_image),
+                        (1, &self.accumulation_image),
+                        (11, &self.moment_image),
+                    ];
+                    let infos = bound_images.map(|(_, image)| vk::DescriptorImageInfo {
+                        image_layout: vk::ImageLayout::GENERAL,
+                        image_view: image.as_ref().unwrap().image_view,
+                        sampler: vk::Sampler::null(),
+                    });
+                    let mut writes: Vec<_> = bound_images
+                        .iter()
+                        .zip(&infos)
+                        .map(|((binding, _), info)| vk::WriteDescriptorSet {
+                            dst_set: self.descriptor_set,
+                            dst_binding: *binding,
+                            dst_array_element: 0,
+                            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                            descriptor_count: 1,
+                            p_image_info: info,
+                            ..Default::default()
+                        })
+                        .collect();
+
+                    let spectral_bin_info;
+                    if self.spectral_settings.bins > 0 {
+                        let old_buffer = self.spectral_bin_buffer.take().unwrap();
+                        old_buffer.destroy(&self.device, &mut self.allocator.borrow_mut());
+                        self.spectral_bin_buffer =
+                            Some(self.create_spectral_bin_buffer((*width, *height))?);
+
+                        spectral_bin_info = vk::DescriptorBufferInfo {
+                            buffer: self.spectral_bin_buffer.as_ref().unwrap().buffer,
+                            range: vk::WHOLE_SIZE,
+                            offset: 0,
+                        };
+                        writes.push(vk::WriteDescriptorSet {
+                            dst_set: self.descriptor_set,
+                            dst_binding: 9,
+                            dst_array_element: 0,
+                            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                            descriptor_count: 1,
+                            p_buffer_info: &raw const spectral_bin_info,
+                            ..Default::default()
+                        });
+                    }
+
+                    self.device.update_descriptor_sets(&writes, &[]);
+                    if self.aovs_enabled() {
+                        for image in self.aov_images.drain(..) {
+                            image.destroy(&self.device, &mut self.allocator.borrow_mut());
+                        }
+                        self.aov_images = self.create_aov_images((*width, *height))?;
+                        self.write_aov_descriptors();
+                    }
+                    if self.denoise_pass.is_some() {
+                        for image in self.denoise_images.drain(..) {
+                            image.destroy(&self.device, &mut self.allocator.borrow_mut());
+                        }
+                        self.denoise_images = self.create_denoise_images((*width, *height))?;
+                    }
+                    self.update_post_pass_images();
+
+                    let projection_inverse_cols = projection.inverse().to_cols_array();
+                    let projection_bytes: &[u8] = bytemuck::cast_slice(&projection_inverse_cols);
+                    self.push_data[64..128].copy_from_slice(projection_bytes);
+                    self.set_tile((0, 0), (*width, *height));
+
+                    self.current_frame = 0;
+                },
+                MeshSceneUpdate::Tile { offset, image_size } => {
+                    self.set_tile(*offset, *image_size);
+                    self.current_frame = 0;
+                }
+            }
+        }
+
+        if let Some(checkpoint) = self.pending_checkpoint.take() {
+            self.restore_checkpoint(checkpoint)?;
+        }
+
+        let r = frame_seed(self.seed, self.current_frame);
+        self.push_data[128..128 + 8].copy_from_slice(bytemuck::cast_slice(&[r.0, r.1]));
+
+        self.push_data[128 + 8..128 + 8 + 4]
+            .copy_from_slice(bytemuck::cast_slice(&[self.current_frame]));
+
+        Ok(())
+    }
+
+    fn create_command_buffer(&self, name: &str) -> anyhow::Result<vk::CommandBuffer> {
+        let allocate_info = vk::CommandBufferAllocateInfo {
+            command_buffer_count: 1,
+            command_pool: self.command_pool,
+            level: vk::CommandBufferLevel::PRIMARY,
+            ..Default::default()
+        };
+
+        let command_buffer = unsafe { self.device.allocate_command_buffers(&allocate_info)?[0] };
+        self.debug_names.name(command_buffer, name);
+        Ok(command_buffer)
+    }
+
+    // frames with a target are blitted to it, offscreen frames time the trace instead
+    fn record_command_buffer(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        target: Option<(vk::Image, (u32, u32))>,
+    ) -> anyhow::Result<()> {
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::default();
+
+        unsafe {
+            self.device
+                .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+
+            self.device
+                .begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                self.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+
+            self.device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::RAYGEN_KHR,
+                0,
+                &self.push_data,
+            );
+
+            self.debug_names.begin_label(command_buffer, c"trace");
+            if target.is_none() {
+                self.cmd_timestamp(command_buffer, TIMESTAMP_TRACE, true);
+            }
             self.rt_pipeline_device.cmd_trace_rays(
                 command_buffer,
                 &self.raygen_region,
@@ -1094,103 +2049,217 @@ impl RaytraceRenderer {
                 self.storage_image.as_ref().unwrap().height,
                 1,
             );
+            if target.is_none() {
+                self.cmd_timestamp(command_buffer, TIMESTAMP_TRACE, false);
+            }
+            self.debug_names.end_label(command_buffer);
 
             self.device.cmd_pipeline_barrier(
                 command_buffer,
-                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR | vk::PipelineStageFlags::TRANSFER,
-                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
                 vk::DependencyFlags::empty(),
                 &[vk::MemoryBarrier {
                     src_access_mask: vk::AccessFlags::SHADER_WRITE,
-                    dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+                    dst_access_mask: vk::AccessFlags::SHADER_READ,
                     ..Default::default()
                 }],
                 &[],
-                &[vk::ImageMemoryBarrier {
-                    src_access_mask: vk::AccessFlags::NONE,
-                    dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                    old_layout: vk::ImageLayout::UNDEFINED,
-                    new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    image: target_image,
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    ..Default::default()
-                }],
+                &[],
             );
 
-            self.device.cmd_blit_image(
-                command_buffer,
-                self.storage_image.as_ref().unwrap().image,
-                vk::ImageLayout::GENERAL,
-                target_image,
-                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                &[vk::ImageBlit {
-                    src_subresource: vk::ImageSubresourceLayers {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        mip_level: 0,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    src_offsets: [
-                        vk::Offset3D { x: 0, y: 0, z: 0 },
-                        vk::Offset3D {
-                            x: self.storage_image.as_ref().unwrap().width as i32,
-                            y: self.storage_image.as_ref().unwrap().height as i32,
-                            z: 1,
-                        },
-                    ],
-                    dst_subresource: vk::ImageSubresourceLayers {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        mip_level: 0,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    dst_offsets: [
-                        vk::Offset3D { x: 0, y: 0, z: 0 },
-                        vk::Offset3D {
-                            x: target_width as i32,
-                            y: target_height as i32,
-                            z: 1,
-                        },
-                    ],
-                }],
-                vk::Filter::LINEAR,
-            );
+            if let Some(denoise_pass) = &self.denoise_pass {
+                self.debug_names.begin_label(command_buffer, c"denoise");
+                denoise_pass.record(
+                    &self.device,
+                    command_buffer,
+                    (
+                        self.storage_image.as_ref().unwrap().width,
+                        self.storage_image.as_ref().unwrap().height,
+                    ),
+                    &self.denoise_settings,
+                    self.current_frame + 1,
+                );
+                self.debug_names.end_label(command_buffer);
+                self.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[vk::MemoryBarrier {
+                        src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        dst_access_mask: vk::AccessFlags::SHADER_READ,
+                        ..Default::default()
+                    }],
+                    &[],
+                    &[],
+                );
+            }
 
-            self.device.cmd_pipeline_barrier(
+            self.debug_names.begin_label(command_buffer, c"tonemap");
+            self.tonemap_pass.as_ref().unwrap().record(
+                &self.device,
                 command_buffer,
-                vk::PipelineStageFlags::TRANSFER,
-                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[vk::ImageMemoryBarrier {
-                    src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                    dst_access_mask: vk::AccessFlags::NONE,
-                    old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
-                    image: target_image,
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    ..Default::default()
-                }],
+                (
+                    self.display_image.as_ref().unwrap().width,
+                    self.display_image.as_ref().unwrap().height,
+                ),
+                &self.output_settings,
             );
+            self.debug_names.end_label(command_buffer);
+
+            if let Some((target_image, target_size)) = target {
+                self.debug_names.begin_label(command_buffer, c"blit");
+                self.record_blit(command_buffer, target_image, target_size);
+                self.debug_names.end_label(command_buffer);
+            }
 
             self.device.end_command_buffer(command_buffer)?;
         }
 
         Ok(())
     }
+
+    // letterboxes the display image into the target and hands it over for presenting
+    unsafe fn record_blit(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        target_image: vk::Image,
+        (target_width, target_height): (u32, u32),
+    ) {
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[vk::MemoryBarrier {
+                src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+                ..Default::default()
+            }],
+            &[],
+            &[vk::ImageMemoryBarrier {
+                src_access_mask: vk::AccessFlags::NONE,
+                dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                old_layout: vk::ImageLayout::UNDEFINED,
+                new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                image: target_image,
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                ..Default::default()
+            }],
+        );
+
+        // the render keeps its aspect ratio, the rest of the window is cleared to black
+        let color_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        self.device.cmd_clear_color_image(
+            command_buffer,
+            target_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &vk::ClearColorValue::default(),
+            &[color_range],
+        );
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier {
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                image: target_image,
+                subresource_range: color_range,
+                ..Default::default()
+            }],
+        );
+
+        let display_image = self.display_image.as_ref().unwrap();
+        let ((x, y), (width, height)) = letterbox(
+            (display_image.width, display_image.height),
+            (target_width, target_height),
+        );
+        self.device.cmd_blit_image(
+            command_buffer,
+            self.display_image.as_ref().unwrap().image,
+            vk::ImageLayout::GENERAL,
+            target_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[vk::ImageBlit {
+                src_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                src_offsets: [
+                    vk::Offset3D { x: 0, y: 0, z: 0 },
+                    vk::Offset3D {
+                        x: self.display_image.as_ref().unwrap().width as i32,
+                        y: self.display_image.as_ref().unwrap().height as i32,
+                        z: 1,
+                    },
+                ],
+                dst_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                dst_offsets: [
+                    vk::Offset3D {
+                        x: x as i32,
+                        y: y as i32,
+                        z: 0,
+                    },
+                    vk::Offset3D {
+                        x: (x + width) as i32,
+                        y: (y + height) as i32,
+                        z: 1,
+                    },
+                ],
+            }],
+            vk::Filter::LINEAR,
+        );
+
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier {
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask: vk::AccessFlags::NONE,
+                old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                image: target_image,
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                ..Default::default()
+            }],
+        );
+    }
 }
 
 impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
@@ -1201,6 +2270,7 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         physical_device: vk::PhysicalDevice,
         queue_family_info: &QueueFamilyInfo,
         allocator: Rc<RefCell<Allocator>>,
+        debug_names: DebugNames,
     ) -> anyhow::Result<Self> {
         let accel_struct_device = khr::acceleration_structure::Device::new(instance, device);
         let rt_pipeline_device = khr::ray_tracing_pipeline::Device::new(instance, device);
@@ -1230,6 +2300,37 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         };
         let compute_queue = unsafe { device.get_device_queue(compute_queue_index, 0) };
 
+        let offscreen_command_buffer = {
+            let allocate_info = vk::CommandBufferAllocateInfo {
+                command_buffer_count: 1,
+                command_pool,
+                level: vk::CommandBufferLevel::PRIMARY,
+                ..Default::default()
+            };
+            unsafe { device.allocate_command_buffers(&allocate_info)?[0] }
+        };
+        debug_names.name(command_pool, "raytrace command pool");
+        debug_names.name(offscreen_command_buffer, "offscreen");
+
+        let enabled_features = Self::required_features().for_device(instance, physical_device);
+        let primitive_culling = enabled_features
+            .enabled::<vk::PhysicalDeviceRayTracingPipelineFeaturesKHR>()
+            .is_some_and(|features| features.ray_traversal_primitive_culling == vk::TRUE);
+
+        let queue_families =
+            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let timestamp_bits = queue_families[compute_queue_index as usize].timestamp_valid_bits;
+        let limits = physical_device_properties2.properties.limits;
+        let timestamp_period = (timestamp_bits > 0).then_some(limits.timestamp_period);
+        let timestamp_pool = {
+            let create_info = vk::QueryPoolCreateInfo {
+                query_type: vk::QueryType::TIMESTAMP,
+                query_count: TIMESTAMP_COUNT,
+                ..Default::default()
+            };
+            unsafe { device.create_query_pool(&create_info, None) }?
+        };
+
         let sampler_info = vk::SamplerCreateInfo {
             mag_filter: vk::Filter::LINEAR,
             min_filter: vk::Filter::LINEAR,
@@ -1240,10 +2341,13 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         };
 
         let spectra_sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+        debug_names.name(spectra_sampler, "spectra sampler");
+        debug_names.name(timestamp_pool, "timestamps");
 
-        Ok(RaytraceRenderer {
+        let mut renderer = RaytraceRenderer {
             allocator,
             device: device.clone(),
+            debug_names,
             accel_struct_device,
             rt_pipeline_device,
             device_properties: physical_device_properties2.properties,
@@ -1270,27 +2374,65 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
             descriptor_set_layout: Default::default(),
             storage_image: Default::default(),
             accumulation_image: Default::default(),
+            moment_image: Default::default(),
+            display_image: Default::default(),
+            tonemap_pass: Default::default(),
+            denoise_pass: Default::default(),
+            denoise_images: Default::default(),
+            denoise_settings: Default::default(),
+            sampling_settings: Default::default(),
+            output_settings: Default::default(),
             vertex_normal_buffer: Default::default(),
             light_buffer: Default::default(),
             offset_buffer: Default::default(),
             brdf_param_buffer: Default::default(),
             spectra_texture: Default::default(),
+            rgb_to_spectrum_texture: Default::default(),
             spectra_sampler,
+            spectral_settings: Default::default(),
+            spectral_bin_buffer: Default::default(),
+            aov_images: Default::default(),
+            instance_hit_groups: Default::default(),
             command_buffers: Default::default(),
-            push_data: [0; 128 + 8 + 4],
+            offscreen_command_buffer,
+            timestamp_pool,
+            timestamp_period,
+            timestamp_mask: u64::MAX >> (64 - timestamp_bits.clamp(1, 64)),
+            last_trace_time: None,
+            accel_build_times: None,
+            primitive_culling,
+            push_data: [0; 128 + 8 + 4 + 4 + 8 + 8],
             current_frame: 0,
-        })
+            seed: 0,
+            scene_hash: 0,
+            pending_checkpoint: None,
+        };
+
+        // the table is the same for every scene, so it is uploaded once
+        renderer.rgb_to_spectrum_texture = Some(renderer.create_float_texture(
+            RGB_TO_SPECTRUM_RES as u32,
+            (3 * RGB_TO_SPECTRUM_RES * RGB_TO_SPECTRUM_RES) as u32,
+            vk::Format::R32G32B32A32_SFLOAT,
+            &RGB_TO_SPECTRUM_TABLE.texture_data(),
+            "rgb to spectrum table",
+        )?);
+
+        Ok(renderer)
     }
 
-    fn ingest_scene(&mut self, scene: &MeshScene) -> anyhow::Result<()> {
+    fn ingest_scene(&mut self, scene: &MeshScene, size: (u32, u32)) -> anyhow::Result<()> {
         self.storage_image = Some(AllocatedImage::new(
             &self.device,
             &mut self.allocator.borrow_mut(),
-            (WindowData::DEFAULT_WIDTH, WindowData::DEFAULT_HEIGHT),
+            size,
             vk::Format::R32G32B32A32_SFLOAT,
             vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
             MemoryLocation::GpuOnly,
         )?);
+        self.storage_image
+            .as_ref()
+            .unwrap()
+            .set_name(&self.debug_names, "storage image");
         self.storage_image.as_mut().unwrap().transition(
             &self.device,
             self.compute_queue,
@@ -1301,11 +2443,17 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         self.accumulation_image = Some(AllocatedImage::new(
             &self.device,
             &mut self.allocator.borrow_mut(),
-            (WindowData::DEFAULT_WIDTH, WindowData::DEFAULT_HEIGHT),
+            size,
             vk::Format::R32G32B32A32_SFLOAT,
-            vk::ImageUsageFlags::STORAGE,
+            vk::ImageUsageFlags::STORAGE
+                | vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::TRANSFER_DST,
             MemoryLocation::GpuOnly,
         )?);
+        self.accumulation_image
+            .as_ref()
+            .unwrap()
+            .set_name(&self.debug_names, "accumulation image");
         self.accumulation_image.as_mut().unwrap().transition(
             &self.device,
             self.compute_queue,
@@ -1313,13 +2461,85 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
             vk::ImageLayout::GENERAL,
         )?;
 
+        self.moment_image = Some(AllocatedImage::new(
+            &self.device,
+            &mut self.allocator.borrow_mut(),
+            size,
+            vk::Format::R32G32B32A32_SFLOAT,
+            vk::ImageUsageFlags::STORAGE
+                | vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuOnly,
+        )?);
+        self.moment_image
+            .as_ref()
+            .unwrap()
+            .set_name(&self.debug_names, "moment image");
+        self.moment_image.as_mut().unwrap().transition(
+            &self.device,
+            self.compute_queue,
+            self.command_pool,
+            vk::ImageLayout::GENERAL,
+        )?;
+
+        self.display_image = Some(AllocatedImage::new(
+            &self.device,
+            &mut self.allocator.borrow_mut(),
+            size,
+            vk::Format::R32G32B32A32_SFLOAT,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+            MemoryLocation::GpuOnly,
+        )?);
+        self.display_image
+            .as_ref()
+            .unwrap()
+            .set_name(&self.debug_names, "display image");
+        self.display_image.as_mut().unwrap().transition(
+            &self.device,
+            self.compute_queue,
+            self.command_pool,
+            vk::ImageLayout::GENERAL,
+        )?;
+
+        self.output_settings = scene.output.clone();
+        self.denoise_settings = scene.denoise;
+        self.sampling_settings = scene.sampling;
+        self.seed = scene.render.seed.unwrap_or_else(rand::random);
+        self.scene_hash = scene.source_hash;
+
+        self.spectral_settings = scene.spectral;
+        self.spectral_bin_buffer = Some(self.create_spectral_bin_buffer(size)?);
+
+        self.aov_images = self.create_aov_images(size)?;
+
+        let tonemap_pass = TonemapPass::new(&self.device, &scene.tonemap_shader)?;
+        tonemap_pass.set_names(&self.debug_names);
+        self.tonemap_pass = Some(tonemap_pass);
+        if self.denoise_settings.preview {
+            let denoise_pass = DenoisePass::new(&self.device, &scene.denoise_shader)?;
+            denoise_pass.set_names(&self.debug_names);
+            self.denoise_pass = Some(denoise_pass);
+            self.denoise_images = self.create_denoise_images(size)?;
+        }
+        self.update_post_pass_images();
+
         let (mesh_geometries, mesh_buffers, mesh_primitive_counts) =
             self.get_mesh_geometries(&scene.meshes)?;
 
-        (self.triangle_blas, self.triangle_blas_buffers) = self.build_accel_structs(
+        let triangle_build_time;
+        (
+            self.triangle_blas,
+            self.triangle_blas_buffers,
+            triangle_build_time,
+        ) = self.build_accel_structs(
             vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
             &mesh_geometries,
             &mesh_primitive_counts,
+            &scene
+                .meshes
+                .iter()
+                .map(|mesh| format!("{} blas", mesh.name))
+                .collect::<Vec<_>>(),
         )?;
         for (vbuf, ibuf) in mesh_buffers {
             unsafe {
@@ -1328,14 +2548,27 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
             }
         }
 
+        let mut procedural_build_time = Some(Duration::ZERO);
         if !scene.procedural_geometries.is_empty() {
             let (proc_geometries, proc_buffers, proc_primitive_counts) =
                 self.get_procedural_geometries(&scene.procedural_geometries)?;
 
-            (self.procedural_blas, self.procedural_blas_buffers) = self.build_accel_structs(
+            (
+                self.procedural_blas,
+                self.procedural_blas_buffers,
+                procedural_build_time,
+            ) = self.build_accel_structs(
                 vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
                 &proc_geometries,
                 &proc_primitive_counts,
+                &scene
+                    .procedural_geometries
+                    .iter()
+                    .map(|proc_geom| {
+                        let name = proc_geom.closest_hit_shader.name().to_string_lossy();
+                        format!("{name} blas")
+                    })
+                    .collect::<Vec<_>>(),
             )?;
             for buf in proc_buffers {
                 unsafe {
@@ -1346,6 +2579,8 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
 
         let descriptor_sizes: Vec<vk::DescriptorPoolSize>;
         (self.descriptor_set_layout, descriptor_sizes) = self.get_descriptor_set_layout()?;
+        self.debug_names
+            .name(self.descriptor_set_layout, "path tracing set layout");
 
         let shader_group_count: usize;
         (
@@ -1363,14 +2598,38 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
                 &self.procedural_blas,
                 self.triangle_hit_group_count,
             )?;
+        // same order as the instances in get_full_instance_geometry
+        let procedural_hit_groups = scene
+            .procedural_objects
+            .iter()
+            .map(|proc_obj| (self.triangle_hit_group_count + proc_obj.geometry_index) as u32);
+        self.instance_hit_groups = scene
+            .objects
+            .iter()
+            .map(|object| object.brdf_i as u32)
+            .chain(procedural_hit_groups)
+            .collect();
 
-        (self.top_as, self.top_as_buffer) = {
-            let (top_as, mut top_as_buffer) = self.build_accel_structs(
+        let top_level_build_time;
+        (self.top_as, self.top_as_buffer, top_level_build_time) = {
+            let (top_as, mut top_as_buffer, build_time) = self.build_accel_structs(
                 vk::AccelerationStructureTypeKHR::TOP_LEVEL,
                 &[instance_geometry],
                 &[instance_count],
+                &["tlas".to_string()],
             )?;
-            (top_as[0], Some(top_as_buffer.remove(0)))
+            (top_as[0], Some(top_as_buffer.remove(0)), build_time)
+        };
+        self.accel_build_times = match (
+            triangle_build_time,
+            procedural_build_time,
+            top_level_build_time,
+        ) {
+            (Some(triangles), Some(procedural), Some(top_level)) => Some(AccelBuildTimes {
+                bottom_level: triangles + procedural,
+                top_level,
+            }),
+            _ => None,
         };
         unsafe {
             instance_buffer.destroy(&self.device, &mut self.allocator.borrow_mut());
@@ -1388,6 +2647,10 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
 
         (self.descriptor_pool, self.descriptor_set) =
             self.create_descriptor_pool_and_set(self.descriptor_set_layout, &descriptor_sizes)?;
+        self.debug_names
+            .name(self.descriptor_pool, "path tracing descriptor pool");
+        self.debug_names
+            .name(self.descriptor_set, "path tracing set");
 
         let vertex_normal_data: Vec<f32> = scene
             .meshes
@@ -1406,69 +2669,104 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
             .collect();
 
         self.vertex_normal_buffer = Some(unsafe {
-            self.create_device_buffer(&vertex_normal_data, vk::BufferUsageFlags::STORAGE_BUFFER)?
+            self.create_device_buffer(
+                &vertex_normal_data,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                "vertices and normals",
+            )?
         });
 
         let mut light_data = Vec::<u8>::new();
         light_data.extend_from_slice(bytemuck::cast_slice(&[scene.lights.len() as u32]));
         for light in scene.lights.iter() {
-            if let Light::Point { color, position } = light {
-                light_data.extend_from_slice(bytemuck::cast_slice(&[0u32]));
-                light_data.extend_from_slice(bytemuck::cast_slice(&color.to_array()));
-                light_data.extend_from_slice(bytemuck::cast_slice(&position.to_array()));
-                light_data.extend_from_slice(bytemuck::cast_slice(&[0f32; 10]));
-                light_data.extend_from_slice(bytemuck::cast_slice(&[0u32]));
-            } else if let Light::Triangle {
-                color,
-                vertices,
-                emit_type,
-                spectra_i,
-            } = light
-            {
-                light_data.extend_from_slice(bytemuck::cast_slice(&[1u32]));
-                light_data.extend_from_slice(bytemuck::cast_slice(&color.to_array()));
-                light_data.extend_from_slice(bytemuck::cast_slice(&[0f32, 0f32, 0f32]));
-                for vertex in vertices {
-                    light_data.extend_from_slice(bytemuck::cast_slice(&vertex.to_array()));
+            match light {
+                Light::Point {
+                    color,
+                    position,
+                    spectra_i,
+                } => {
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[0u32]));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&color.to_array()));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&position.to_array()));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[0f32; 10]));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[
+                        spectra_i.unwrap_or(NO_SPECTRUM)
+                    ]));
+                }
+                Light::Triangle {
+                    color,
+                    vertices,
+                    emit_type,
+                    spectra_i,
+                } => {
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[1u32]));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&color.to_array()));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[0f32, 0f32, 0f32]));
+                    for vertex in vertices {
+                        light_data.extend_from_slice(bytemuck::cast_slice(&vertex.to_array()));
+                    }
+                    light_data.extend_from_slice(&emit_type.to_ne_bytes());
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[*spectra_i]));
+                }
+                Light::Directional {
+                    color,
+                    position,
+                    direction,
+                    radius,
+                    spectra_i,
+                } => {
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[2u32]));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&color.to_array()));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&position.to_array()));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&direction.to_array()));
+                    light_data.extend_from_slice(&radius.to_ne_bytes());
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[0f32; 6]));
+                    light_data.extend_from_slice(bytemuck::cast_slice(&[
+                        spectra_i.unwrap_or(NO_SPECTRUM)
+                    ]));
                 }
-                light_data.extend_from_slice(&emit_type.to_ne_bytes());
-                light_data.extend_from_slice(bytemuck::cast_slice(&[*spectra_i]));
-            } else if let Light::Directional {
-                color,
-                position,
-                direction,
-                radius,
-                ..
-            } = light
-            {
-                light_data.extend_from_slice(bytemuck::cast_slice(&[2u32]));
-                light_data.extend_from_slice(bytemuck::cast_slice(&color.to_array()));
-                light_data.extend_from_slice(bytemuck::cast_slice(&position.to_array()));
-                light_data.extend_from_slice(bytemuck::cast_slice(&direction.to_array()));
-                light_data.extend_from_slice(&radius.to_ne_bytes());
-                light_data.extend_from_slice(bytemuck::cast_slice(&[0f32; 6]));
-                light_data.extend_from_slice(bytemuck::cast_slice(&[0u32]));
             }
         }
 
         self.light_buffer = Some(unsafe {
-            self.create_device_buffer(&light_data, vk::BufferUsageFlags::STORAGE_BUFFER)?
+            self.create_device_buffer(&light_data, vk::BufferUsageFlags::STORAGE_BUFFER, "lights")?
         });
 
         if scene.spectra_data.is_empty() {
-            self.spectra_texture = Some(self.create_spectra_texture(1, 1, &[1f32])?);
+            self.spectra_texture = Some(self.create_float_texture(
+                1,
+                1,
+                vk::Format::R32_SFLOAT,
+                &[1f32],
+                "spectra",
+            )?);
         } else {
-            let flattened_spectra_data: Vec<f32> = scene.spectra_data.iter().flatten().copied().collect();
-            self.spectra_texture = Some(self.create_spectra_texture(681, scene.spectra_data.len() as u32, &flattened_spectra_data)?);
+            let flattened_spectra_data: Vec<f32> =
+                scene.spectra_data.iter().flatten().copied().collect();
+            self.spectra_texture = Some(self.create_float_texture(
+                SPECTRUM_SAMPLES as u32,
+                scene.spectra_data.len() as u32,
+                vk::Format::R32_SFLOAT,
+                &flattened_spectra_data,
+                "spectra",
+            )?);
         }
 
         self.offset_buffer = Some(unsafe {
-            self.create_device_buffer(&scene.offset_buf, vk::BufferUsageFlags::STORAGE_BUFFER)?
+            self.create_device_buffer(
+                &scene.offset_buf,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                "brdf param offsets",
+            )?
         });
 
         if !scene.brdf_buf.is_empty() {
             self.brdf_param_buffer = Some(unsafe {
-                self.create_device_buffer(&scene.brdf_buf, vk::BufferUsageFlags::STORAGE_BUFFER)?
+                self.create_device_buffer(
+                    &scene.brdf_buf,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    "brdf params",
+                )?
             });
         }
 
@@ -1478,6 +2776,7 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         let proj_bytes: &[u8] = bytemuck::cast_slice(&proj_inverse_cols);
         self.push_data[0..64].copy_from_slice(view_bytes);
         self.push_data[64..128].copy_from_slice(proj_bytes);
+        self.set_tile((0, 0), size);
 
         let mut writes = Vec::new();
 
@@ -1507,7 +2806,22 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
             dst_array_element: 0,
             descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
             descriptor_count: 1,
-            p_image_info: &raw const accumulation_info,
+            p_image_info: &raw const accumulation_info,
+            ..Default::default()
+        });
+
+        let moment_info = vk::DescriptorImageInfo {
+            image_layout: vk::ImageLayout::GENERAL,
+            image_view: self.moment_image.as_ref().unwrap().image_view,
+            sampler: vk::Sampler::null(),
+        };
+        writes.push(vk::WriteDescriptorSet {
+            dst_set: self.descriptor_set,
+            dst_binding: 11,
+            dst_array_element: 0,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 1,
+            p_image_info: &raw const moment_info,
             ..Default::default()
         });
 
@@ -1571,9 +2885,40 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
             ..Default::default()
         });
 
+        let rgb_to_spectrum_info = vk::DescriptorImageInfo {
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            image_view: self.rgb_to_spectrum_texture.as_ref().unwrap().image_view,
+            sampler: self.spectra_sampler,
+        };
+        writes.push(vk::WriteDescriptorSet {
+            dst_set: self.descriptor_set,
+            dst_binding: 8,
+            dst_array_element: 0,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: 1,
+            p_image_info: &raw const rgb_to_spectrum_info,
+            ..Default::default()
+        });
+
+        let spectral_bin_info = vk::DescriptorBufferInfo {
+            buffer: self.spectral_bin_buffer.as_ref().unwrap().buffer,
+            range: vk::WHOLE_SIZE,
+            offset: 0,
+        };
+        writes.push(vk::WriteDescriptorSet {
+            dst_set: self.descriptor_set,
+            dst_binding: 9,
+            dst_array_element: 0,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            p_buffer_info: &raw const spectral_bin_info,
+            ..Default::default()
+        });
+
         unsafe {
             self.device.update_descriptor_sets(&writes, &[]);
         }
+        self.write_aov_descriptors();
 
         Ok(())
     }
@@ -1583,83 +2928,19 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         updates: &[<MeshScene as Scene>::Update],
         target: &mut WindowData,
     ) -> anyhow::Result<()> {
-        for update in updates {
-            match update {
-                MeshSceneUpdate::NewView(view) => {
-                    let view_inverse_cols = view.inverse().to_cols_array();
-                    let view_bytes: &[u8] = bytemuck::cast_slice(&view_inverse_cols);
-                    self.push_data[0..64].copy_from_slice(view_bytes);
-
-                    self.current_frame = 0;
-                }
-                MeshSceneUpdate::NewSize((width, height, projection)) => unsafe {
-                    self.device.device_wait_idle()?;
-
-                    let mut infos = Vec::new();
-                    let mut writes = Vec::new();
-                    for image in [&mut self.storage_image, &mut self.accumulation_image] {
-                        let old_image = image.take().unwrap();
-
-                        *image = Some(AllocatedImage::new(
-                            &self.device,
-                            &mut self.allocator.borrow_mut(),
-                            (*width, *height),
-                            old_image.format,
-                            old_image.usage,
-                            MemoryLocation::GpuOnly,
-                        )?);
-                        image.as_mut().unwrap().transition(
-                            &self.device,
-                            self.compute_queue,
-                            self.command_pool,
-                            vk::ImageLayout::GENERAL,
-                        )?;
-
-                        old_image.destroy(&self.device, &mut self.allocator.borrow_mut());
-
-                        infos.push(vk::DescriptorImageInfo {
-                            image_layout: vk::ImageLayout::GENERAL,
-                            image_view: image.as_ref().unwrap().image_view,
-                            sampler: vk::Sampler::null(),
-                        });
-                        writes.push(vk::WriteDescriptorSet {
-                            dst_set: self.descriptor_set,
-                            dst_binding: writes.len() as u32,
-                            dst_array_element: 0,
-                            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
-                            descriptor_count: 1,
-                            p_image_info: infos.as_ptr().add(writes.len()),
-                            ..Default::default()
-                        });
-                    }
-
-                    self.device.update_descriptor_sets(&writes, &[]);
-
-                    let projection_inverse_cols = projection.inverse().to_cols_array();
-                    let projection_bytes: &[u8] = bytemuck::cast_slice(&projection_inverse_cols);
-                    self.push_data[64..128].copy_from_slice(projection_bytes);
-
-                    self.current_frame = 0;
-                },
-            }
-        }
-
-        let r: (u32, u32) = rand::random();
-        self.push_data[128..128 + 8].copy_from_slice(bytemuck::cast_slice(&[r.0, r.1]));
-
-        self.push_data[128 + 8..128 + 8 + 4]
-            .copy_from_slice(bytemuck::cast_slice(&[self.current_frame]));
+        self.prepare_frame(updates)?;
 
         let (image, image_index) = target.acquire_next_image()?;
 
         if image_index as usize >= self.command_buffers.len() {
-            self.command_buffers.push(self.create_command_buffer()?);
+            let name = format!("swapchain image {image_index}");
+            self.command_buffers
+                .push(self.create_command_buffer(&name)?);
         }
 
         self.record_command_buffer(
             self.command_buffers[image_index as usize],
-            image,
-            target.get_size(),
+            Some((image, target.get_size())),
         )?;
 
         let (image_semaphore, render_semaphore) = target.get_current_semaphores();
@@ -1689,179 +2970,180 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         Ok(())
     }
 
-    fn save_image<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
-        let storage_image = self
-            .storage_image
-            .as_ref()
-            .ok_or_else(|| anyhow!("no storage image"))?;
-
-        let width = storage_image.width;
-        let height = storage_image.height;
-        let pixel_size = 4 * std::mem::size_of::<f32>(); // RGBA f32
-        let buffer_size = (width * height) as usize * pixel_size;
+    fn render_offscreen(&mut self, updates: &[MeshSceneUpdate]) -> anyhow::Result<()> {
+        self.prepare_frame(updates)?;
+        self.record_command_buffer(self.offscreen_command_buffer, None)?;
 
+        let submit_info = vk::SubmitInfo {
+            command_buffer_count: 1,
+            p_command_buffers: &raw const self.offscreen_command_buffer,
+            ..Default::default()
+        };
         unsafe {
-            self.device.device_wait_idle()?;
+            self.device
+                .queue_submit(self.compute_queue, &[submit_info], vk::Fence::null())?;
+            self.device.queue_wait_idle(self.compute_queue)?;
         }
+        self.last_trace_time = self.read_timestamps(TIMESTAMP_TRACE)?;
 
-        let staging_buffer = AllocatedBuffer::new(
-            &self.device,
-            &mut self.allocator.borrow_mut(),
-            buffer_size as vk::DeviceSize,
-            vk::BufferUsageFlags::TRANSFER_DST,
-            MemoryLocation::GpuToCpu,
-            self.device_properties.limits,
-        )?;
-
-        let command_buffer = {
-            let allocate_info = vk::CommandBufferAllocateInfo {
-                command_buffer_count: 1,
-                command_pool: self.command_pool,
-                level: vk::CommandBufferLevel::PRIMARY,
-                ..Default::default()
-            };
-            unsafe { self.device.allocate_command_buffers(&allocate_info)?[0] }
-        };
+        self.current_frame += 1;
 
-        unsafe {
-            self.device.begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo {
-                    flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
-                    ..Default::default()
-                },
-            )?;
+        Ok(())
+    }
 
-            self.device.cmd_pipeline_barrier(
-                command_buffer,
-                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
-                vk::PipelineStageFlags::TRANSFER,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[vk::ImageMemoryBarrier {
-                    src_access_mask: vk::AccessFlags::SHADER_WRITE,
-                    dst_access_mask: vk::AccessFlags::TRANSFER_READ,
-                    old_layout: vk::ImageLayout::GENERAL,
-                    new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                    image: storage_image.image,
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    ..Default::default()
-                }],
+    fn save_image<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> anyhow::Result<()> {
+        // linear formats get the untouched render, everything else gets what is displayed
+        // unless it gets denoised here, which has to happen before tonemapping
+        let denoise_on_cpu = self.denoise_settings.save;
+        let image = if format.is_linear() || denoise_on_cpu {
+            self.storage_image.as_ref()
+        } else {
+            self.display_image.as_ref()
+        }
+        .ok_or_else(|| anyhow!("no image to save"))?;
+
+        let width = image.width;
+        let height = image.height;
+        let mut pixel_data = self.read_back_image(image)?;
+        let aovs = self.read_back_aovs()?;
+
+        if denoise_on_cpu {
+            pixel_data = denoise(
+                (width, height),
+                &pixel_data,
+                &self.read_back_accumulated_aov(AOV_ALBEDO)?,
+                &self.read_back_accumulated_aov(AOV_NORMAL)?,
+                &self.denoise_settings,
             );
+            if !format.is_linear() {
+                pixel_data = tonemap(&pixel_data, &self.output_settings);
+            }
+        }
 
-            self.device.cmd_copy_image_to_buffer(
-                command_buffer,
-                storage_image.image,
-                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                staging_buffer.buffer,
-                &[vk::BufferImageCopy {
-                    buffer_offset: 0,
-                    buffer_row_length: 0,
-                    buffer_image_height: 0,
-                    image_subresource: vk::ImageSubresourceLayers {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        mip_level: 0,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
-                    image_extent: vk::Extent3D {
-                        width,
-                        height,
-                        depth: 1,
-                    },
-                }],
-            );
+        let color_space = self.output_settings.color_space;
+        if format.is_linear() {
+            write_linear_image(
+                path.as_ref(),
+                (width, height),
+                format,
+                color_space,
+                &pixel_data,
+                &aovs,
+            )
+        } else {
+            write_display_image(path.as_ref(), (width, height), color_space, &pixel_data)?;
+            write_aov_files(path.as_ref(), (width, height), &aovs)
+        }
+    }
 
-            self.device.cmd_pipeline_barrier(
-                command_buffer,
-                vk::PipelineStageFlags::TRANSFER,
-                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[vk::ImageMemoryBarrier {
-                    src_access_mask: vk::AccessFlags::TRANSFER_READ,
-                    dst_access_mask: vk::AccessFlags::SHADER_WRITE,
-                    old_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                    new_layout: vk::ImageLayout::GENERAL,
-                    image: storage_image.image,
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    ..Default::default()
-                }],
-            );
+    fn save_spectral_image<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let bins = self.spectral_settings.bins;
+        if bins == 0 {
+            bail!("multispectral binning is not enabled for this scene");
+        }
+        let storage_image = self
+            .storage_image
+            .as_ref()
+            .ok_or_else(|| anyhow!("no storage image"))?;
+        let width = storage_image.width;
+        let height = storage_image.height;
 
-            self.device.end_command_buffer(command_buffer)?;
+        // the bins hold a sum of per frame averages
+        let frames = self.current_frame.max(1) as f32;
+        let data: Vec<f32> = self
+            .read_back_spectral_bins()?
+            .iter()
+            .map(|x| x / frames)
+            .collect();
 
-            self.device.queue_submit(
-                self.compute_queue,
-                &[vk::SubmitInfo {
-                    command_buffer_count: 1,
-                    p_command_buffers: &raw const command_buffer,
-                    ..Default::default()
-                }],
-                vk::Fence::null(),
-            )?;
+        let SpectralSettings {
+            min_wavelength,
+            max_wavelength,
+            ..
+        } = self.spectral_settings;
+        let bin_width = (max_wavelength - min_wavelength) / bins as f32;
+        let wavelengths: Vec<f32> = (0..bins)
+            .map(|i| min_wavelength + (i as f32 + 0.5) * bin_width)
+            .collect();
 
-            self.device.queue_wait_idle(self.compute_queue)?;
-            self.device
-                .free_command_buffers(self.command_pool, &[command_buffer]);
-        }
+        write_multispectral(path.as_ref(), (width, height), &wavelengths, &data)
+    }
 
-        let pixel_data: &[f32] = unsafe {
-            let ptr = staging_buffer
-                .mapped_ptr()
-                .ok_or_else(|| anyhow!("staging buffer not mapped"))?
-                as *const f32;
-            std::slice::from_raw_parts(ptr, width as usize * height as usize * 4)
+    fn save_checkpoint<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let accum = self
+            .accumulation_image
+            .as_ref()
+            .ok_or_else(|| anyhow!("no accumulation to checkpoint"))?;
+        let aovs = if self.aovs_enabled() {
+            self.aov_images
+                .iter()
+                .map(|image| self.read_back_image(image))
+                .collect::<anyhow::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+        let spectral_bins = if self.spectral_settings.bins > 0 {
+            self.read_back_spectral_bins()?
+        } else {
+            Vec::new()
         };
 
-        let mut img_data = Vec::with_capacity(width as usize * height as usize * 4);
-        for pixel in pixel_data.chunks(4) {
-            let r = pixel[0];
-            let g = pixel[1];
-            let b = pixel[2];
-            let a = pixel[3];
-
-            let to_srgb = |v: f32| -> u8 {
-                let c = v.clamp(0.0, 1.0);
-                let srgb = if c <= 0.0031308 {
-                    12.92 * c
-                } else {
-                    1.055 * c.powf(1.0 / 2.4) - 0.055
-                };
-                (srgb * 255.0) as u8
-            };
-
-            img_data.push(to_srgb(r));
-            img_data.push(to_srgb(g));
-            img_data.push(to_srgb(b));
-            img_data.push((a.clamp(0.0, 1.0) * 255.0) as u8);
+        Checkpoint {
+            scene_hash: self.checkpoint_hash(),
+            size: (accum.width, accum.height),
+            frame: self.current_frame,
+            seed: self.seed,
+            accumulation: self.read_back_image(accum)?,
+            moments: self.read_back_image(self.moment_image.as_ref().unwrap())?,
+            aovs,
+            spectral_bins,
         }
+        .write(path.as_ref())
+    }
 
-        let img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, img_data)
-            .ok_or_else(|| anyhow!("failed to create image buffer"))?;
+    fn resume_from<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.pending_checkpoint = Some(Checkpoint::read(path.as_ref())?);
+        Ok(())
+    }
 
-        img.save(path)?;
+    fn read_back_linear(&self) -> anyhow::Result<Vec<f32>> {
+        self.read_back_image(
+            self.storage_image
+                .as_ref()
+                .ok_or_else(|| anyhow!("no image to read back"))?,
+        )
+    }
 
-        unsafe {
-            staging_buffer.destroy(&self.device, &mut self.allocator.borrow_mut());
-        }
+    fn max_image_size(&self) -> u32 {
+        self.device_properties.limits.max_image_dimension2_d
+    }
 
-        Ok(())
+    fn samples_per_pixel(&self) -> u32 {
+        self.current_frame * self.sampling_settings.frame_samples()
+    }
+
+    fn estimate_noise(&self) -> anyhow::Result<Option<f32>> {
+        let accum = self
+            .accumulation_image
+            .as_ref()
+            .ok_or_else(|| anyhow!("no accumulation to estimate noise from"))?;
+        let tile: [u32; 4] = bytemuck::pod_read_unaligned(&self.push_data[144..160]);
+        let size = (accum.width, accum.height);
+        let visible = visible_size(size, (tile[0], tile[1]), (tile[2], tile[3]));
+        Ok(estimate_noise(
+            &self.read_back_image(accum)?,
+            accum.width,
+            visible,
+            self.current_frame,
+        ))
+    }
+
+    fn last_trace_time(&self) -> anyhow::Result<Option<Duration>> {
+        Ok(self.last_trace_time)
+    }
+
+    fn accel_build_times(&self) -> Option<AccelBuildTimes> {
+        self.accel_build_times
     }
 
     fn required_instance_extensions() -> &'static [*const c_char] {
@@ -1891,6 +3173,7 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
                 },
                 vk::PhysicalDeviceRayTracingPipelineFeaturesKHR {
                     ray_tracing_pipeline,
+                    #[optional] ray_traversal_primitive_culling,
                 },
             }
         });
@@ -1900,8 +3183,8 @@ impl Renderer<MeshScene, WindowData> for RaytraceRenderer {
         FEATURES.get_list()
     }
 
-    fn has_required_queue_families(queue_family_info: &QueueFamilyInfo) -> bool {
-        queue_family_info.compute_index.is_some() && queue_family_info.present_index.is_some()
+    fn required_queue_families() -> &'static [QueueFamily] {
+        &[QueueFamily::Compute]
     }
 
     fn get_queue_info(queue_family_info: &QueueFamilyInfo) -> Vec<vk::DeviceQueueCreateInfo<'_>> {
@@ -1924,6 +3207,7 @@ impl Drop for RaytraceRenderer {
                 .expect("failed to wait for device idle");
 
             self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_query_pool(self.timestamp_pool, None);
 
             self.device
                 .destroy_descriptor_pool(self.descriptor_pool, None);
@@ -1965,10 +3249,30 @@ impl Drop for RaytraceRenderer {
                 x.destroy(&self.device, &mut self.allocator.borrow_mut());
             }
 
+            if let Some(x) = self.moment_image.take() {
+                x.destroy(&self.device, &mut self.allocator.borrow_mut());
+            }
+
             if let Some(x) = self.accumulation_image.take() {
                 x.destroy(&self.device, &mut self.allocator.borrow_mut());
             }
 
+            if let Some(x) = self.display_image.take() {
+                x.destroy(&self.device, &mut self.allocator.borrow_mut());
+            }
+
+            if let Some(x) = self.tonemap_pass.take() {
+                x.destroy(&self.device);
+            }
+
+            if let Some(x) = self.denoise_pass.take() {
+                x.destroy(&self.device);
+            }
+
+            for x in self.denoise_images.drain(..) {
+                x.destroy(&self.device, &mut self.allocator.borrow_mut());
+            }
+
             if let Some(x) = self.vertex_normal_buffer.take() {
                 x.destroy(&self.device, &mut self.allocator.borrow_mut());
             }
@@ -1989,6 +3293,18 @@ impl Drop for RaytraceRenderer {
                 x.destroy(&self.device, &mut self.allocator.borrow_mut());
             }
 
+            if let Some(x) = self.rgb_to_spectrum_texture.take() {
+                x.destroy(&self.device, &mut self.allocator.borrow_mut());
+            }
+
+            if let Some(x) = self.spectral_bin_buffer.take() {
+                x.destroy(&self.device, &mut self.allocator.borrow_mut());
+            }
+
+            for x in self.aov_images.drain(..) {
+                x.destroy(&self.device, &mut self.allocator.borrow_mut());
+            }
+
             self.device.destroy_sampler(self.spectra_sampler, None);
         }
     }
@@ -0,0 +1,275 @@
+use anyhow::bail;
+use ash::{vk, Device};
+
+use crate::{
+    debug::DebugNames,
+    scene::scenes::mesh::{DenoiseSettings, Shader},
+};
+
+// keep in sync with local_size in denoise.comp
+const WORKGROUP_SIZE: u32 = 16;
+
+// keep in sync with the FLAG_* constants in denoise.comp
+const FLAG_DEMODULATE: u32 = 1;
+const FLAG_REMODULATE: u32 = 2;
+
+// linear to ping, ping to pong and pong to ping
+const SET_COUNT: usize = 3;
+
+/// Compute pass that runs the à-trous denoiser on the linear render for the interactive view
+///
+/// Iterations ping-pong between two images, see [DenoisePass::output_index] for which one ends up
+/// holding the result.
+pub struct DenoisePass {
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl DenoisePass {
+    pub fn new(device: &Device, shader: &Shader) -> anyhow::Result<Self> {
+        // input, output, albedo and normal
+        let bindings = [0, 1, 2, 3].map(|binding| vk::DescriptorSetLayoutBinding {
+            descriptor_count: 1,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            binding,
+            ..Default::default()
+        });
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo {
+                    p_bindings: bindings.as_ptr(),
+                    binding_count: bindings.len() as u32,
+                    ..Default::default()
+                },
+                None,
+            )?
+        };
+
+        let pool_size = vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: (bindings.len() * SET_COUNT) as u32,
+        };
+        let descriptor_pool = unsafe {
+            device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo {
+                    pool_size_count: 1,
+                    p_pool_sizes: &raw const pool_size,
+                    max_sets: SET_COUNT as u32,
+                    ..Default::default()
+                },
+                None,
+            )?
+        };
+        let set_layouts = [descriptor_set_layout; SET_COUNT];
+        let descriptor_sets = unsafe {
+            device.allocate_descriptor_sets(&vk::DescriptorSetAllocateInfo {
+                descriptor_pool,
+                p_set_layouts: set_layouts.as_ptr(),
+                descriptor_set_count: SET_COUNT as u32,
+                ..Default::default()
+            })?
+        };
+
+        // step, the three phis, frame count and flags
+        let push_constant_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 6 * std::mem::size_of::<u32>() as u32,
+        };
+        let pipeline_layout = unsafe {
+            device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo {
+                    p_set_layouts: &raw const descriptor_set_layout,
+                    set_layout_count: 1,
+                    push_constant_range_count: 1,
+                    p_push_constant_ranges: &raw const push_constant_range,
+                    ..Default::default()
+                },
+                None,
+            )?
+        };
+
+        let module = shader.compile(device)?.module();
+        let pipeline = unsafe {
+            let out = device.create_compute_pipelines(
+                vk::PipelineCache::null(),
+                &[vk::ComputePipelineCreateInfo {
+                    stage: vk::PipelineShaderStageCreateInfo {
+                        stage: vk::ShaderStageFlags::COMPUTE,
+                        module,
+                        p_name: c"main".as_ptr(),
+                        ..Default::default()
+                    },
+                    layout: pipeline_layout,
+                    ..Default::default()
+                }],
+                None,
+            );
+            device.destroy_shader_module(module, None);
+            match out {
+                Ok(x) => x[0],
+                Err((_, e)) => bail!("failed to construct denoise pipeline: {e}"),
+            }
+        };
+
+        Ok(Self {
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_sets,
+            pipeline_layout,
+            pipeline,
+        })
+    }
+
+    /// Index of the ping-pong image holding the result after `iterations`
+    pub fn output_index(iterations: u32) -> usize {
+        (iterations as usize + 1) % 2
+    }
+
+    pub fn set_names(&self, names: &DebugNames) {
+        names.name(self.descriptor_set_layout, "denoise set layout");
+        names.name(self.descriptor_pool, "denoise descriptor pool");
+        for (i, set) in self.descriptor_sets.iter().enumerate() {
+            names.name(*set, &format!("denoise set {i}"));
+        }
+        names.name(self.pipeline_layout, "denoise pipeline layout");
+        names.name(self.pipeline, "denoise pipeline");
+    }
+
+    /// Points the pass at new images, all must be RGBA32F and in the GENERAL layout
+    pub fn update_images(
+        &self,
+        device: &Device,
+        linear_view: vk::ImageView,
+        ping_pong_views: [vk::ImageView; 2],
+        albedo_view: vk::ImageView,
+        normal_view: vk::ImageView,
+    ) {
+        let [ping, pong] = ping_pong_views;
+        let set_views = [
+            [linear_view, ping, albedo_view, normal_view],
+            [ping, pong, albedo_view, normal_view],
+            [pong, ping, albedo_view, normal_view],
+        ];
+        let infos = set_views.map(|views| {
+            views.map(|image_view| vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::GENERAL,
+                image_view,
+                sampler: vk::Sampler::null(),
+            })
+        });
+        let writes: Vec<_> =
+            self.descriptor_sets
+                .iter()
+                .zip(&infos)
+                .flat_map(|(&dst_set, set_infos)| {
+                    set_infos.iter().enumerate().map(move |(binding, info)| {
+                        vk::WriteDescriptorSet {
+                            dst_set,
+                            dst_binding: binding as u32,
+                            dst_array_element: 0,
+                            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                            descriptor_count: 1,
+                            p_image_info: info,
+                            ..Default::default()
+                        }
+                    })
+                })
+                .collect();
+
+        unsafe {
+            device.update_descriptor_sets(&writes, &[]);
+        }
+    }
+
+    /// Records every iteration, `frames` is the number of frames summed in the aov images
+    pub unsafe fn record(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        (width, height): (u32, u32),
+        settings: &DenoiseSettings,
+        frames: u32,
+    ) {
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline,
+        );
+
+        for iteration in 0..settings.iterations {
+            if iteration > 0 {
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[vk::MemoryBarrier {
+                        src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        dst_access_mask: vk::AccessFlags::SHADER_READ,
+                        ..Default::default()
+                    }],
+                    &[],
+                    &[],
+                );
+            }
+
+            let set = if iteration == 0 {
+                0
+            } else {
+                2 - iteration as usize % 2
+            };
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_sets[set]],
+                &[],
+            );
+
+            let step = 1u32 << iteration;
+            let mut flags = 0;
+            if iteration == 0 {
+                flags |= FLAG_DEMODULATE;
+            }
+            if iteration + 1 == settings.iterations {
+                flags |= FLAG_REMODULATE;
+            }
+            // later iterations see a smoother image, so the color falloff tightens
+            let push_data = [
+                step,
+                (settings.color_phi / step as f32).to_bits(),
+                settings.normal_phi.to_bits(),
+                settings.albedo_phi.to_bits(),
+                (frames as f32).to_bits(),
+                flags,
+            ];
+            device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                bytemuck::cast_slice(&push_data),
+            );
+
+            device.cmd_dispatch(
+                command_buffer,
+                width.div_ceil(WORKGROUP_SIZE),
+                height.div_ceil(WORKGROUP_SIZE),
+                1,
+            );
+        }
+    }
+
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::bail;
+use ash::{vk, Device};
+
+use crate::{
+    debug::DebugNames,
+    scene::scenes::mesh::{OutputSettings, Shader},
+    tonemap::rec709_matrices,
+};
+
+// keep in sync with local_size in tonemap.comp
+const WORKGROUP_SIZE: u32 = 16;
+// two words, padding to 16 bytes and two mat3 whose columns are padded to vec4
+const PUSH_CONSTANT_WORDS: usize = 4 + 2 * 12;
+
+/// Compute pass that applies exposure and a tonemapping operator to the linear render
+///
+/// The result is what gets blitted to the window and saved to 8 bit images.
+pub struct TonemapPass {
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl TonemapPass {
+    pub fn new(device: &Device, shader: &Shader) -> anyhow::Result<Self> {
+        // linear input and display output
+        let bindings = [0, 1].map(|binding| vk::DescriptorSetLayoutBinding {
+            descriptor_count: 1,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            binding,
+            ..Default::default()
+        });
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo {
+                    p_bindings: bindings.as_ptr(),
+                    binding_count: bindings.len() as u32,
+                    ..Default::default()
+                },
+                None,
+            )?
+        };
+
+        let pool_size = vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: bindings.len() as u32,
+        };
+        let descriptor_pool = unsafe {
+            device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo {
+                    pool_size_count: 1,
+                    p_pool_sizes: &raw const pool_size,
+                    max_sets: 1,
+                    ..Default::default()
+                },
+                None,
+            )?
+        };
+        let descriptor_set = unsafe {
+            device.allocate_descriptor_sets(&vk::DescriptorSetAllocateInfo {
+                descriptor_pool,
+                p_set_layouts: &raw const descriptor_set_layout,
+                descriptor_set_count: 1,
+                ..Default::default()
+            })?[0]
+        };
+
+        // exposure, operator and the two Rec.709 matrices
+        let push_constant_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: PUSH_CONSTANT_WORDS as u32 * std::mem::size_of::<u32>() as u32,
+        };
+        let pipeline_layout = unsafe {
+            device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo {
+                    p_set_layouts: &raw const descriptor_set_layout,
+                    set_layout_count: 1,
+                    push_constant_range_count: 1,
+                    p_push_constant_ranges: &raw const push_constant_range,
+                    ..Default::default()
+                },
+                None,
+            )?
+        };
+
+        let module = shader.compile(device)?.module();
+        let pipeline = unsafe {
+            let out = device.create_compute_pipelines(
+                vk::PipelineCache::null(),
+                &[vk::ComputePipelineCreateInfo {
+                    stage: vk::PipelineShaderStageCreateInfo {
+                        stage: vk::ShaderStageFlags::COMPUTE,
+                        module,
+                        p_name: c"main".as_ptr(),
+                        ..Default::default()
+                    },
+                    layout: pipeline_layout,
+                    ..Default::default()
+                }],
+                None,
+            );
+            device.destroy_shader_module(module, None);
+            match out {
+                Ok(x) => x[0],
+                Err((_, e)) => bail!("failed to construct tonemap pipeline: {e}"),
+            }
+        };
+
+        Ok(Self {
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_set,
+            pipeline_layout,
+            pipeline,
+        })
+    }
+
+    pub fn set_names(&self, names: &DebugNames) {
+        names.name(self.descriptor_set_layout, "tonemap set layout");
+        names.name(self.descriptor_pool, "tonemap descriptor pool");
+        names.name(self.descriptor_set, "tonemap set");
+        names.name(self.pipeline_layout, "tonemap pipeline layout");
+        names.name(self.pipeline, "tonemap pipeline");
+    }
+
+    /// Points the pass at new images, both must be RGBA32F and in the GENERAL layout
+    pub fn update_images(
+        &self,
+        device: &Device,
+        linear_view: vk::ImageView,
+        display_view: vk::ImageView,
+    ) {
+        let infos = [linear_view, display_view].map(|image_view| vk::DescriptorImageInfo {
+            image_layout: vk::ImageLayout::GENERAL,
+            image_view,
+            sampler: vk::Sampler::null(),
+        });
+        let writes: Vec<_> = infos
+            .iter()
+            .enumerate()
+            .map(|(binding, info)| vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: binding as u32,
+                dst_array_element: 0,
+                descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: 1,
+                p_image_info: info,
+                ..Default::default()
+            })
+            .collect();
+
+        unsafe {
+            device.update_descriptor_sets(&writes, &[]);
+        }
+    }
+
+    pub unsafe fn record(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        (width, height): (u32, u32),
+        settings: &OutputSettings,
+    ) {
+        let mut push_data = Vec::with_capacity(PUSH_CONSTANT_WORDS);
+        push_data.extend([settings.exposure.to_bits(), settings.tonemap as u32, 0, 0]);
+        let (to_rec709, from_rec709) = rec709_matrices(settings);
+        for matrix in [to_rec709, from_rec709] {
+            for column in matrix.to_cols_array_2d() {
+                push_data.extend(column.map(f32::to_bits));
+                push_data.push(0);
+            }
+        }
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline,
+        );
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout,
+            0,
+            &[self.descriptor_set],
+            &[],
+        );
+        device.cmd_push_constants(
+            command_buffer,
+            self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE,
+            0,
+            bytemuck::cast_slice(&push_data),
+        );
+        device.cmd_dispatch(
+            command_buffer,
+            width.div_ceil(WORKGROUP_SIZE),
+            height.div_ceil(WORKGROUP_SIZE),
+            1,
+        );
+    }
+
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+    }
+}
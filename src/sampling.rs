@@ -0,0 +1,99 @@
+// keeps dark pixels from dominating the relative error
+const NOISE_EPSILON: f32 = 1e-2;
+
+/// Estimates the relative error of an accumulation image
+///
+/// Each pixel holds the XYZ sum of per frame averages, with the Y sum of the odd frames in alpha.
+/// Half the difference between the even and odd frame means is compared to the overall mean and
+/// averaged over the `visible` top left part of the `width` wide accumulation, so tile overhang
+/// is left out. Needs at least two frames.
+pub fn estimate_noise(
+    accum: &[f32],
+    width: u32,
+    (visible_width, visible_height): (u32, u32),
+    frames: u32,
+) -> Option<f32> {
+    let pixels = visible_width as usize * visible_height as usize;
+    if frames < 2 || pixels == 0 {
+        return None;
+    }
+
+    let odd_frames = (frames / 2) as f32;
+    let even_frames = frames as f32 - odd_frames;
+    let error = accum
+        .chunks(width as usize * 4)
+        .take(visible_height as usize)
+        .flat_map(|row| row[..visible_width as usize * 4].chunks(4))
+        .map(|p| {
+            let (sum, odd_sum) = (p[1], p[3]);
+            let even = (sum - odd_sum) / even_frames;
+            let odd = odd_sum / odd_frames;
+            let mean = sum / frames as f32;
+            0.5 * (even - odd).abs() / (mean + NOISE_EPSILON)
+        })
+        .sum::<f32>();
+    Some(error / pixels as f32)
+}
+
+/// Part of a `size` tile at `offset` that lies inside the image
+pub fn visible_size(
+    (width, height): (u32, u32),
+    (x, y): (u32, u32),
+    (image_width, image_height): (u32, u32),
+) -> (u32, u32) {
+    (
+        width.min(image_width.saturating_sub(x)),
+        height.min(image_height.saturating_sub(y)),
+    )
+}
+
+/// Push constant seed for a frame, derived from the base seed so a resumed render continues the
+/// same sequence
+pub fn frame_seed(seed: u64, frame: u32) -> (u32, u32) {
+    // splitmix64
+    let mut z = seed.wrapping_add((frame as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z as u32, (z >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_noise, frame_seed, visible_size};
+
+    // accumulates per frame luminances the way path.rgen does
+    fn accumulate(frames: &[f32]) -> Vec<f32> {
+        let sum = frames.iter().sum();
+        let odd_sum = frames.iter().skip(1).step_by(2).sum();
+        vec![0.0, sum, 0.0, odd_sum]
+    }
+
+    #[test]
+    fn noise_estimate() {
+        assert_eq!(estimate_noise(&accumulate(&[1.0]), 1, (1, 1), 1), None);
+
+        let converged = accumulate(&[1.0; 8]);
+        assert_eq!(estimate_noise(&converged, 1, (1, 1), 8), Some(0.0));
+
+        let noisy = accumulate(&[1.2, 0.8, 1.2, 0.8]);
+        let noisier = accumulate(&[1.5, 0.5, 1.5, 0.5]);
+        let noisy_estimate = estimate_noise(&noisy, 1, (1, 1), 4).unwrap();
+        assert!(noisy_estimate > 0.0);
+        assert!(estimate_noise(&noisier, 1, (1, 1), 4).unwrap() > noisy_estimate);
+
+        // a 2x2 tile hanging one column and one row over the image only counts the top left
+        let tile = [noisy, noisier.clone(), noisier.clone(), noisier].concat();
+        let size = visible_size((2, 2), (4, 4), (5, 5));
+        assert_eq!(size, (1, 1));
+        assert_eq!(estimate_noise(&tile, 2, size, 4), Some(noisy_estimate));
+        assert!(estimate_noise(&tile, 2, (2, 2), 4).unwrap() > noisy_estimate);
+    }
+
+    #[test]
+    fn frame_seeds() {
+        assert_eq!(frame_seed(1, 3), frame_seed(1, 3));
+        assert_ne!(frame_seed(1, 3), frame_seed(1, 4));
+        assert_ne!(frame_seed(1, 3), frame_seed(2, 3));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    alloc::{self, Layout}, collections::HashMap, f32::consts::PI, ffi::{CStr, CString}, fs::File, io::{BufRead, BufReader, Read}, iter::{self, Peekable}, path::Path, ptr::NonNull
+    alloc::{self, Layout}, collections::HashMap, f32::consts::PI, ffi::{CStr, CString}, fs::File, io::{BufRead, BufReader, Read}, iter::{self, Peekable}, path::Path, ptr::NonNull, str::FromStr
 };
 
 use anyhow::{anyhow, bail, Result};
@@ -12,10 +12,13 @@ use toml::{map::Map, Table, Value};
 
 use crate::{
     camera::Camera,
+    checkpoint::hash_bytes,
+    color::{parse_white_point, Chromaticity, ColorSpace},
     scene::{
         type_lexer::{Token, TokenIter},
         Scene,
     },
+    window::WindowData,
 };
 
 const MESHES_DIR: &str = "resources/meshes";
@@ -24,6 +27,11 @@ const SPECTRA_DIR: &str = "resources/spectra";
 const SPIRV_EXTENSION: &str = ".spv";
 const SPIRV_MAGIC: u32 = 0x07230203;
 
+// spectra are tabulated at 0.5nm steps from 380nm to 720nm
+pub const SPECTRUM_SAMPLES: usize = 681;
+pub const SPECTRUM_MIN_WAVELENGTH: f32 = 380.0;
+pub const SPECTRUM_MAX_WAVELENGTH: f32 = 720.0;
+
 #[derive(Debug)]
 pub struct MeshScene {
     pub camera: Camera,
@@ -34,6 +42,8 @@ pub struct MeshScene {
     pub raygen_shader: Shader,
     pub miss_shader: Shader,
     pub hit_shaders: Vec<Shader>,
+    pub tonemap_shader: Shader,
+    pub denoise_shader: Shader,
 
     pub procedural_geometries: Vec<ProceduralGeometry>,
     pub procedural_objects: Vec<ProceduralObject>,
@@ -41,7 +51,194 @@ pub struct MeshScene {
     pub brdf_buf: Vec<u8>,
     pub offset_buf: Vec<u32>,
 
-    pub spectra_data: Vec<[f32; 681]>,
+    pub spectra_data: Vec<[f32; SPECTRUM_SAMPLES]>,
+    pub spectral: SpectralSettings,
+    pub output: OutputSettings,
+    pub denoise: DenoiseSettings,
+    pub sampling: SamplingSettings,
+    pub render: RenderSettings,
+    // hash of the scene file, checkpoints refuse to resume on a different scene
+    pub source_hash: u64,
+}
+
+// keep in sync with the WAVELENGTH_SAMPLING_* constants in path.rgen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum WavelengthSampling {
+    Uniform = 0,
+    Stratified = 1,
+    // importance sampled against the CIE Y curve
+    CieY = 2,
+    // hero wavelength with 4 wavelengths per path
+    Hero = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectralSettings {
+    pub min_wavelength: f32,
+    pub max_wavelength: f32,
+    pub sampling: WavelengthSampling,
+    // number of wavelength bins accumulated for multispectral output, 0 disables it
+    pub bins: u32,
+}
+
+impl Default for SpectralSettings {
+    fn default() -> Self {
+        Self {
+            min_wavelength: SPECTRUM_MIN_WAVELENGTH,
+            max_wavelength: SPECTRUM_MAX_WAVELENGTH,
+            sampling: WavelengthSampling::Stratified,
+            bins: 0,
+        }
+    }
+}
+
+// keep in sync with the TONEMAP_* constants in tonemap.comp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Tonemap {
+    Clamp = 0,
+    Reinhard = 1,
+    // Stephen Hill's fit of the ACES filmic curve
+    Aces = 2,
+    Agx = 3,
+}
+
+impl FromStr for Tonemap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "clamp" => Tonemap::Clamp,
+            "reinhard" => Tonemap::Reinhard,
+            "aces" | "filmic" => Tonemap::Aces,
+            "agx" => Tonemap::Agx,
+            _ => bail!("tonemap must be one of \"clamp\", \"reinhard\", \"aces\" or \"agx\""),
+        })
+    }
+}
+
+/// Arbitrary output variable written alongside the beauty image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    // first hit albedo in the output color space
+    Albedo,
+    // world space shading normal of the first hit
+    Normal,
+    // distance along the camera ray to the first hit
+    Depth,
+    // index of the instance in the tlas, objects come first in scene order
+    Instance,
+    // hit group of the instance, brdf_i for objects
+    Brdf,
+}
+
+impl FromStr for Aov {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "albedo" => Aov::Albedo,
+            "normal" => Aov::Normal,
+            "depth" => Aov::Depth,
+            "instance" | "object" => Aov::Instance,
+            "brdf" | "material" => Aov::Brdf,
+            _ => bail!(
+                "aov must be one of \"albedo\", \"normal\", \"depth\", \"instance\" or \"brdf\""
+            ),
+        })
+    }
+}
+
+impl Aov {
+    /// Layer name in EXR files, also used as the suffix of separate files
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Instance => "instance",
+            Aov::Brdf => "brdf",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputSettings {
+    // in stops, applied to the linear image before tonemapping
+    pub exposure: f32,
+    pub tonemap: Tonemap,
+    pub color_space: ColorSpace,
+    // white point the render is adapted to, defaults to the one of the color space
+    pub white_point: Option<Chromaticity>,
+    pub aovs: Vec<Aov>,
+    // fixed render size, None follows the window
+    pub resolution: Option<(u32, u32)>,
+    // scales the interactive view, captures always render at full size
+    pub render_scale: f32,
+    // captures larger than this are traced one square tile at a time, None only tiles what
+    // exceeds the device limit, in tiles of 2048
+    pub tile_size: Option<u32>,
+}
+
+impl OutputSettings {
+    /// Aspect ratio the camera starts with, before the first render size is known
+    pub fn aspect_ratio(&self) -> f32 {
+        let (width, height) = self
+            .resolution
+            .unwrap_or((WindowData::DEFAULT_WIDTH, WindowData::DEFAULT_HEIGHT));
+        width as f32 / height as f32
+    }
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            tonemap: Tonemap::Clamp,
+            color_space: ColorSpace::Srgb,
+            white_point: None,
+            aovs: Vec::new(),
+            resolution: None,
+            render_scale: 1.0,
+            tile_size: None,
+        }
+    }
+}
+
+/// Edge avoiding à-trous filter guided by the albedo and normal aovs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DenoiseSettings {
+    // gpu pass on the interactive view
+    pub preview: bool,
+    // cpu pass on saved images
+    pub save: bool,
+    // each iteration doubles the filter footprint
+    pub iterations: u32,
+    // edge stopping falloffs, larger values blur more across differences
+    pub color_phi: f32,
+    pub normal_phi: f32,
+    pub albedo_phi: f32,
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> Self {
+        Self {
+            preview: false,
+            save: false,
+            iterations: 5,
+            color_phi: 1.0,
+            normal_phi: 0.3,
+            albedo_phi: 0.1,
+        }
+    }
+}
+
+impl DenoiseSettings {
+    /// Whether any pass needs the albedo and normal aovs
+    pub fn enabled(&self) -> bool {
+        self.preview || self.save
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -49,6 +246,7 @@ pub enum Light {
     Point {
         color: Vec3,
         position: Vec3,
+        spectra_i: Option<u32>,
     },
     Triangle {
         color: Vec3,
@@ -61,13 +259,16 @@ pub enum Light {
         position: Vec3,
         direction: Vec3,
         radius: f32,
+        spectra_i: Option<u32>,
     },
 }
 
+// the file is the shader source in resources/shaders, its SPIR-V is only read when compiling
+// so renderers that don't run the shaders can load scenes without them
 #[derive(Debug, Clone)]
 pub enum Shader {
-    Uncompiled(CString, Box<[u32]>),
-    Compiled(CString, vk::ShaderModule),
+    Uncompiled(CString, String),
+    Compiled(CString, String, vk::ShaderModule),
 }
 
 #[derive(Debug, Clone)]
@@ -110,9 +311,18 @@ enum ShaderType {
     Vec2,
     UInt,
     Int,
+    // index into the spectra texture, stored as a uint
+    Spectrum,
     Array(Box<ShaderType>, u64),
 }
 
+// all spectra loaded by the scene, in the order they appear in the spectra texture
+#[derive(Debug, Default)]
+struct Spectra {
+    data: Vec<[f32; SPECTRUM_SAMPLES]>,
+    file_map: HashMap<String, u32>,
+}
+
 #[derive(Debug)]
 struct Shaders {
     raygen: Shader,
@@ -124,6 +334,11 @@ struct Shaders {
 pub enum MeshSceneUpdate {
     NewView(Mat4),
     NewSize((u32, u32, Mat4)),
+    // renders the tile at offset out of a larger image, the render size is the tile size
+    Tile {
+        offset: (u32, u32),
+        image_size: (u32, u32),
+    },
 }
 
 impl Scene for MeshScene {
@@ -132,23 +347,33 @@ impl Scene for MeshScene {
 
 impl Shader {
     pub fn module(&self) -> vk::ShaderModule {
-        let Shader::Compiled(_, module) = self else {
+        let Shader::Compiled(_, _, module) = self else {
             panic!("shader is not compiled")
         };
 
         *module
     }
 
-    fn name(&self) -> &CStr {
+    /// The brdf name for hit shaders
+    pub fn name(&self) -> &CStr {
         match self {
             Shader::Uncompiled(name, _) => name,
-            Shader::Compiled(name, _) => name,
+            Shader::Compiled(name, _, _) => name,
+        }
+    }
+
+    /// Source file of the shader, e.g. `diffuse.rchit`
+    pub fn file(&self) -> &str {
+        match self {
+            Shader::Uncompiled(_, file) => file,
+            Shader::Compiled(_, file, _) => file,
         }
     }
 
     pub fn compile(&self, device: &Device) -> Result<Self> {
         match self {
-            Shader::Uncompiled(name, code) => {
+            Shader::Uncompiled(name, file) => {
+                let code = MeshScene::read_spirv(file)?;
                 let create_info = vk::ShaderModuleCreateInfo {
                     code_size: code.len() * size_of::<u32>(),
                     p_code: code.as_ptr(),
@@ -156,13 +381,127 @@ impl Shader {
                 };
 
                 let module = unsafe { device.create_shader_module(&create_info, None) }?;
-                Ok(Shader::Compiled(name.clone(), module))
+                Ok(Shader::Compiled(name.clone(), file.clone(), module))
             }
             x @ Shader::Compiled(..) => Ok(x.clone()),
         }
     }
 }
 
+impl Spectra {
+    fn load_file(&mut self, spectra_filename: &str) -> Result<u32> {
+        if let Some(&idx) = self.file_map.get(spectra_filename) {
+            return Ok(idx);
+        }
+
+        let spectra_path = Path::new(SPECTRA_DIR).join(spectra_filename);
+        let spectra_file = File::open(spectra_path)?;
+        let reader = BufReader::new(spectra_file);
+
+        let spectra: Vec<f32> = reader
+            .lines()
+            .filter_map(|line| line.ok().and_then(|s| s.trim().parse::<f32>().ok()))
+            .collect();
+        let num_samples = spectra.len();
+        let spectra = spectra.try_into().map_err(|_| {
+            anyhow!(
+                "spectra file {spectra_filename} has {num_samples} samples, expected {SPECTRUM_SAMPLES}"
+            )
+        })?;
+
+        let idx = self.push(spectra);
+        self.file_map.insert(spectra_filename.to_string(), idx);
+        Ok(idx)
+    }
+
+    fn push(&mut self, spectra: [f32; SPECTRUM_SAMPLES]) -> u32 {
+        let idx = self.data.len() as u32;
+        self.data.push(spectra);
+        idx
+    }
+
+    /// Linearly resamples (wavelength, value) pairs sorted by wavelength onto the spectra texture grid
+    fn resample(points: &[(f32, f32)]) -> [f32; SPECTRUM_SAMPLES] {
+        let step =
+            (SPECTRUM_MAX_WAVELENGTH - SPECTRUM_MIN_WAVELENGTH) / (SPECTRUM_SAMPLES - 1) as f32;
+
+        let mut samples = [0f32; SPECTRUM_SAMPLES];
+        let mut segment = 0;
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let wavelength = SPECTRUM_MIN_WAVELENGTH + i as f32 * step;
+            while segment + 2 < points.len() && points[segment + 1].0 < wavelength {
+                segment += 1;
+            }
+
+            let (w0, v0) = points[segment];
+            let (w1, v1) = points[(segment + 1).min(points.len() - 1)];
+            *sample = if w1 <= w0 {
+                v0
+            } else {
+                let t = ((wavelength - w0) / (w1 - w0)).clamp(0.0, 1.0);
+                v0 + t * (v1 - v0)
+            };
+        }
+
+        samples
+    }
+}
+
+/// How many samples are traced per frame and when a capture stops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingSettings {
+    // samples per pixel traced every frame
+    pub samples_per_frame: u32,
+    // a capture stops at whichever of these is reached first
+    pub spp: Option<u32>,
+    // in seconds
+    pub time_limit: Option<f32>,
+    // relative error estimated from the difference between even and odd frames
+    pub noise_threshold: Option<f32>,
+    // pixels stop being sampled once their 95% confidence interval is within this fraction of
+    // their mean, None samples every pixel every frame
+    pub adaptive_threshold: Option<f32>,
+}
+
+impl Default for SamplingSettings {
+    fn default() -> Self {
+        Self {
+            samples_per_frame: 128,
+            spp: None,
+            time_limit: None,
+            noise_threshold: None,
+            adaptive_threshold: None,
+        }
+    }
+}
+
+impl SamplingSettings {
+    /// Whether any stopping condition is set
+    pub fn has_limit(&self) -> bool {
+        self.spp.is_some() || self.time_limit.is_some() || self.noise_threshold.is_some()
+    }
+
+    /// Samples traced every frame, lowered so a target spp is hit in as few frames as possible
+    pub fn frame_samples(&self) -> u32 {
+        match self.spp {
+            Some(spp) => {
+                let frames = spp.div_ceil(self.samples_per_frame);
+                spp.div_ceil(frames)
+            }
+            None => self.samples_per_frame,
+        }
+    }
+}
+
+/// How frames are traced, without changing what the image converges to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RenderSettings {
+    // base seed the per frame seeds are derived from, None picks a random one
+    pub seed: Option<u64>,
+    // shaders print what they find suspicious, set by --debug-printf rather than the scene file
+    pub debug_printf: bool,
+}
+
 impl MeshScene {
     pub fn load_from(mut reader: impl Read) -> Result<Self> {
         let mut toml_conf = String::new();
@@ -170,7 +509,12 @@ impl MeshScene {
 
         let conf: Table = toml_conf.parse()?;
 
-        let camera = Self::parse_toml_camera(&conf)?;
+        let spectral = Self::parse_toml_spectral(&conf)?;
+        let output = Self::parse_toml_output(&conf)?;
+        let camera = Self::parse_toml_camera(&conf, output.aspect_ratio())?;
+        let denoise = Self::parse_toml_denoise(&conf)?;
+        let sampling = Self::parse_toml_sampling(&conf)?;
+        let render = Self::parse_toml_render(&conf)?;
 
         // load the global shaders
         let (shaders, shader_type_map) = Self::parse_toml_shaders(&conf)?;
@@ -178,10 +522,17 @@ impl MeshScene {
 
         // load objects before lights
         // this is to give them the correct brdf_params_index
-        let mut objects =
-            Self::parse_toml_objects(&conf, &mesh_map, &meshes, &shaders.rchit, &shader_type_map)?;
-        let mut spectra_data = Vec::new();
-        let lights = Self::parse_toml_lights(&conf, &mesh_map, &meshes, &mut objects, &mut spectra_data)?;
+        let mut spectra = Spectra::default();
+        let mut objects = Self::parse_toml_objects(
+            &conf,
+            &mesh_map,
+            &meshes,
+            &shaders.rchit,
+            &shader_type_map,
+            &mut spectra,
+        )?;
+        let lights =
+            Self::parse_toml_lights(&conf, &mesh_map, &meshes, &mut objects, &mut spectra)?;
 
         let (procedural_geometries, procedural_objects) =
             Self::parse_procedural_geometries(&conf, &lights)?;
@@ -189,6 +540,18 @@ impl MeshScene {
         let (brdf_buf, offset_buf) =
             Self::get_brdf_params_buffer_and_indices(&objects, &shaders.rchit);
 
+        // checkpoints must notice edits to the mesh and spectrum files too, not just the toml
+        let mut source = toml_conf.into_bytes();
+        for model in &meshes {
+            source.extend_from_slice(bytemuck::cast_slice(&model.mesh.positions));
+            source.extend_from_slice(bytemuck::cast_slice(&model.mesh.normals));
+            source.extend_from_slice(bytemuck::cast_slice(&model.mesh.texcoords));
+            source.extend_from_slice(bytemuck::cast_slice(&model.mesh.indices));
+        }
+        for spectrum in &spectra.data {
+            source.extend_from_slice(bytemuck::cast_slice(spectrum));
+        }
+
         Ok(Self {
             camera,
             lights,
@@ -197,11 +560,19 @@ impl MeshScene {
             raygen_shader: shaders.raygen,
             miss_shader: shaders.miss,
             hit_shaders: shaders.rchit,
+            tonemap_shader: Self::load_shader("tonemap.comp", "tonemap")?,
+            denoise_shader: Self::load_shader("denoise.comp", "denoise")?,
             procedural_geometries,
             procedural_objects,
             brdf_buf,
             offset_buf,
-            spectra_data,
+            spectra_data: spectra.data,
+            spectral,
+            output,
+            denoise,
+            sampling,
+            render,
+            source_hash: hash_bytes(&source),
         })
     }
 
@@ -286,6 +657,7 @@ impl MeshScene {
         meshes: &[Model],
         shaders: &[Shader],
         type_map: &HashMap<String, Vec<ShaderType>>,
+        spectra: &mut Spectra,
     ) -> Result<Vec<Object>> {
         // get primitive start offsets of meshes
         let mut offset = 0;
@@ -330,7 +702,7 @@ impl MeshScene {
             for (field, type_info) in brdf_fields.iter().zip(field_types) {
                 // similar to array comment in parse_toml_field - technically there can be padding between fields
                 // but like there will not be :)
-                let data = Self::parse_toml_field(field, type_info)?;
+                let data = Self::parse_toml_field(field, type_info, spectra)?;
                 datas.extend_from_slice(&data);
             }
 
@@ -354,7 +726,11 @@ impl MeshScene {
         Ok(objects)
     }
 
-    fn parse_toml_field(field: &Value, type_info: &ShaderType) -> Result<Vec<u8>> {
+    fn parse_toml_field(
+        field: &Value,
+        type_info: &ShaderType,
+        spectra: &mut Spectra,
+    ) -> Result<Vec<u8>> {
         match type_info {
             ShaderType::Float => {
                 let float = Self::parse_toml_f32(field)?;
@@ -407,13 +783,19 @@ impl MeshScene {
                 let num: i32 = num.try_into()?;
                 Ok(num.to_le_bytes().to_vec())
             }
+            ShaderType::Spectrum => {
+                let spectra_i = Self::parse_toml_spectrum(field, spectra)?;
+                Ok(spectra_i.to_le_bytes().to_vec())
+            }
             ShaderType::Array(shader_type, _) => {
                 let Value::Array(array) = field else {
                     bail!("array type requires toml array");
                 };
 
                 let mut full_data = Vec::new();
-                let datas = array.iter().map(|f| Self::parse_toml_field(f, shader_type));
+                let datas = array
+                    .iter()
+                    .map(|f| Self::parse_toml_field(f, shader_type, spectra));
                 for data in datas {
                     // technically there should be padding for alignment
                     // but with the types we are using w/ layout scalar everything has same alignment (4)
@@ -426,6 +808,52 @@ impl MeshScene {
         }
     }
 
+    fn parse_toml_spectrum(field: &Value, spectra: &mut Spectra) -> Result<u32> {
+        const SPECTRUM_ERR: &str = "spectrum type requires a spectra file name, an array of samples, or an array of [wavelength, value] pairs";
+
+        let array = match field {
+            Value::String(spectra_filename) => return spectra.load_file(spectra_filename),
+            Value::Array(array) => array,
+            _ => bail!(SPECTRUM_ERR),
+        };
+
+        if array.len() < 2 {
+            bail!("inline spectrum requires at least 2 samples");
+        }
+
+        let mut points = Vec::new();
+        // the first entry decides between pairs and samples, mixing them is an error
+        if let Value::Array(_) = array[0] {
+            // explicit (wavelength, value) pairs
+            for pair in array {
+                let Value::Array(pair) = pair else {
+                    bail!(SPECTRUM_ERR);
+                };
+                let [wavelength, value] = &pair[..] else {
+                    bail!(SPECTRUM_ERR);
+                };
+                points.push((
+                    Self::parse_toml_f32(wavelength)?,
+                    Self::parse_toml_f32(value)?,
+                ));
+            }
+
+            if points.windows(2).any(|w| w[0].0 >= w[1].0) {
+                bail!("inline spectrum wavelengths must be strictly increasing");
+            }
+        } else {
+            // samples evenly spaced over the full spectral range
+            let step =
+                (SPECTRUM_MAX_WAVELENGTH - SPECTRUM_MIN_WAVELENGTH) / (array.len() - 1) as f32;
+            for (i, value) in array.iter().enumerate() {
+                let wavelength = SPECTRUM_MIN_WAVELENGTH + i as f32 * step;
+                points.push((wavelength, Self::parse_toml_f32(value)?));
+            }
+        }
+
+        Ok(spectra.push(Spectra::resample(&points)))
+    }
+
     fn parse_toml_shaders(conf: &Table) -> Result<(Shaders, HashMap<String, Vec<ShaderType>>)> {
         let Value::Table(_global_shaders) = Self::get_field(conf, "global_shaders")? else {
             bail!("global_shaders must be a table");
@@ -490,11 +918,23 @@ impl MeshScene {
             bail!("shader path must be a string");
         };
 
-        let mut spv_name = name.clone();
+        Self::load_shader(name, shader_name)
+    }
+
+    fn load_shader(name: &str, shader_name: &str) -> Result<Shader> {
+        Ok(Shader::Uncompiled(
+            CString::new(shader_name)?,
+            name.to_string(),
+        ))
+    }
+
+    fn read_spirv(name: &str) -> Result<Box<[u32]>> {
+        let mut spv_name = name.to_string();
         spv_name.push_str(SPIRV_EXTENSION);
 
         let spv_path = Path::new(SPIRV_DIR).join(spv_name);
-        let mut spv_file = File::open(spv_path)?;
+        let mut spv_file = File::open(&spv_path)
+            .map_err(|e| anyhow!("couldn't open {}: {e}", spv_path.display()))?;
         let file_info = spv_file.metadata()?;
 
         let shader_size = file_info.len();
@@ -530,7 +970,7 @@ impl MeshScene {
             bail!("invalid SPIR-V magic number");
         }
 
-        Ok(Shader::Uncompiled(CString::new(shader_name)?, code))
+        Ok(code)
     }
 
     fn parse_toml_meshes(conf: &Table) -> Result<(Vec<Model>, HashMap<String, u32>)> {
@@ -597,7 +1037,7 @@ impl MeshScene {
         mesh_map: &HashMap<String, u32>,
         meshes: &[Model],
         objects: &mut Vec<Object>,
-        spectra_data: &mut Vec<[f32; 681]>,
+        spectra: &mut Spectra,
     ) -> Result<Vec<Light>> {
         let Value::Array(light_confs) = conf
             .get("light")
@@ -608,8 +1048,6 @@ impl MeshScene {
 
         let mut lights = Vec::new();
 
-        let mut spectra_map: HashMap<String, u32> = HashMap::new();
-
         for light_conf in light_confs {
             let Value::Table(light_conf) = light_conf else {
                 bail!("light must be a table");
@@ -627,6 +1065,14 @@ impl MeshScene {
                     .ok_or(anyhow!("no color field found for light"))?,
             )?;
 
+            // only area lights fall back to d65 - point and directional lights
+            // without a spectrum get their color uplifted from rgb instead
+            let spectra_i = match light_conf.get("spectra") {
+                Some(Value::String(spectra_filename)) => Some(spectra.load_file(spectra_filename)?),
+                Some(_) => bail!("light spectra must be a spectra file name"),
+                None => None,
+            };
+
             match light_type.as_str() {
                 "point" => {
                     let position = Self::parse_toml_vec3(
@@ -634,7 +1080,11 @@ impl MeshScene {
                             .get("position")
                             .ok_or(anyhow!("no top_left field found for light"))?,
                     )?;
-                    lights.push(Light::Point { color, position });
+                    lights.push(Light::Point {
+                        color,
+                        position,
+                        spectra_i,
+                    });
                 }
                 "area" => {
                     let transform = Self::parse_toml_transform(
@@ -656,29 +1106,9 @@ impl MeshScene {
                     };
                     let emit_type = Self::parse_toml_f32(value)?;
 
-                    let spectra_filename = match light_conf.get("spectra") {
-                        Some(Value::String(spectra_filename)) => &spectra_filename,
-                        _ => "d65",
-                    };
-
-                    let spectra_i = if let Some(&idx) = spectra_map.get(spectra_filename) {
-                        idx
-                    } else {
-                        let spectra_path = Path::new(SPECTRA_DIR).join(spectra_filename);
-                        let spectra_file = File::open(spectra_path)?;
-                        let reader = BufReader::new(spectra_file);
-    
-                        let spectra = reader
-                            .lines()
-                            .filter_map(|line| line.ok().and_then(|s| s.trim().parse::<f32>().ok()))
-                            .collect::<Vec<f32>>()
-                            .try_into()
-                            .unwrap();
-
-                        let idx = spectra_data.len() as u32;
-                        spectra_data.push(spectra);
-                        spectra_map.insert(spectra_filename.to_string(), idx);
-                        idx
+                    let spectra_i = match spectra_i {
+                        Some(idx) => idx,
+                        None => spectra.load_file("d65")?,
                     };
 
                     let start_idx = lights.len();
@@ -741,6 +1171,7 @@ impl MeshScene {
                         position,
                         direction,
                         radius,
+                        spectra_i,
                     });
                 }
                 _ => bail!("unknown light type"),
@@ -963,6 +1394,7 @@ impl MeshScene {
             "uint" => ShaderType::UInt,
             "vec3" => ShaderType::Vec3,
             "vec2" => ShaderType::Vec2,
+            "spectrum" => ShaderType::Spectrum,
             s => bail!("invalid typename: {s}"),
         })
     }
@@ -1145,7 +1577,7 @@ impl MeshScene {
         translation * rotation_mat * scale
     }
 
-    fn parse_toml_camera(conf: &Table) -> Result<Camera> {
+    fn parse_toml_camera(conf: &Table, aspect_ratio: f32) -> Result<Camera> {
         let Some(Value::Table(camera_table)) = conf.get("camera") else {
             bail!("camera must be a table")
         };
@@ -1167,6 +1599,446 @@ impl MeshScene {
         };
         let view = Self::parse_transform(view_str)?;
 
-        Ok(Camera::new(view, fov))
+        Ok(Camera::new(view, fov, aspect_ratio))
+    }
+
+    fn parse_toml_spectral(conf: &Table) -> Result<SpectralSettings> {
+        let mut settings = SpectralSettings::default();
+        let Some(spectral) = conf.get("spectral") else {
+            return Ok(settings);
+        };
+        let Value::Table(spectral_table) = spectral else {
+            bail!("spectral must be a table")
+        };
+
+        if let Some(range) = spectral_table.get("range") {
+            let range = match range {
+                Value::Array(range) if range.len() == 2 => range
+                    .iter()
+                    .map(|x| match x {
+                        Value::Integer(x) => Ok(*x as f32),
+                        Value::Float(x) => Ok(*x as f32),
+                        _ => bail!("spectral.range must contain integers or floats"),
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+                _ => bail!("spectral.range must be an array of 2 wavelengths"),
+            };
+            if range[0] < SPECTRUM_MIN_WAVELENGTH
+                || range[1] > SPECTRUM_MAX_WAVELENGTH
+                || range[0] >= range[1]
+            {
+                bail!(
+                    "spectral.range must be an increasing range within {SPECTRUM_MIN_WAVELENGTH}nm to {SPECTRUM_MAX_WAVELENGTH}nm"
+                );
+            }
+            settings.min_wavelength = range[0];
+            settings.max_wavelength = range[1];
+        }
+
+        if let Some(sampling) = spectral_table.get("sampling") {
+            settings.sampling = match sampling.as_str() {
+                Some("uniform") => WavelengthSampling::Uniform,
+                Some("stratified") => WavelengthSampling::Stratified,
+                Some("cie_y") => WavelengthSampling::CieY,
+                Some("hero") => WavelengthSampling::Hero,
+                _ => bail!(
+                    "spectral.sampling must be one of \"uniform\", \"stratified\", \"cie_y\" or \"hero\""
+                ),
+            };
+        }
+
+        Ok(settings)
+    }
+
+    fn parse_toml_denoise(conf: &Table) -> Result<DenoiseSettings> {
+        let mut settings = DenoiseSettings::default();
+        let Some(denoise) = conf.get("denoise") else {
+            return Ok(settings);
+        };
+        let Value::Table(denoise_table) = denoise else {
+            bail!("denoise must be a table")
+        };
+
+        for (key, flag) in [
+            ("preview", &mut settings.preview),
+            ("save", &mut settings.save),
+        ] {
+            if let Some(value) = denoise_table.get(key) {
+                let Value::Boolean(value) = value else {
+                    bail!("denoise.{key} must be a boolean");
+                };
+                *flag = *value;
+            }
+        }
+
+        if let Some(iterations) = denoise_table.get("iterations") {
+            settings.iterations = match iterations {
+                Value::Integer(x) if (1..=10).contains(x) => *x as u32,
+                _ => bail!("denoise.iterations must be an integer from 1 to 10"),
+            };
+        }
+
+        for (key, phi) in [
+            ("color_phi", &mut settings.color_phi),
+            ("normal_phi", &mut settings.normal_phi),
+            ("albedo_phi", &mut settings.albedo_phi),
+        ] {
+            if let Some(value) = denoise_table.get(key) {
+                *phi = Self::parse_toml_f32(value)?;
+                if *phi <= 0.0 {
+                    bail!("denoise.{key} must be positive");
+                }
+            }
+        }
+
+        Ok(settings)
+    }
+
+    fn parse_toml_sampling(conf: &Table) -> Result<SamplingSettings> {
+        let mut settings = SamplingSettings::default();
+        let Some(sampling) = conf.get("sampling") else {
+            return Ok(settings);
+        };
+        let Value::Table(sampling_table) = sampling else {
+            bail!("sampling must be a table")
+        };
+
+        let positive_integer = |key: &str| -> Result<Option<u32>> {
+            match sampling_table.get(key) {
+                None => Ok(None),
+                Some(Value::Integer(x)) if *x > 0 && *x <= u32::MAX as i64 => Ok(Some(*x as u32)),
+                Some(_) => bail!("sampling.{key} must be a positive integer"),
+            }
+        };
+        if let Some(samples_per_frame) = positive_integer("samples_per_frame")? {
+            settings.samples_per_frame = samples_per_frame;
+        }
+        settings.spp = positive_integer("spp")?;
+
+        for (key, limit) in [
+            ("time_limit", &mut settings.time_limit),
+            ("noise_threshold", &mut settings.noise_threshold),
+            ("adaptive_threshold", &mut settings.adaptive_threshold),
+        ] {
+            if let Some(value) = sampling_table.get(key) {
+                let value = Self::parse_toml_f32(value)?;
+                if value <= 0.0 {
+                    bail!("sampling.{key} must be positive");
+                }
+                *limit = Some(value);
+            }
+        }
+
+        Ok(settings)
+    }
+
+    fn parse_toml_render(conf: &Table) -> Result<RenderSettings> {
+        let mut settings = RenderSettings::default();
+        let Some(render) = conf.get("render") else {
+            return Ok(settings);
+        };
+        let Value::Table(render_table) = render else {
+            bail!("render must be a table")
+        };
+
+        if let Some(seed) = render_table.get("seed") {
+            settings.seed = match seed {
+                Value::Integer(x) if *x >= 0 => Some(*x as u64),
+                _ => bail!("render.seed must be a non-negative integer"),
+            };
+        }
+
+        Ok(settings)
+    }
+
+    fn parse_toml_output(conf: &Table) -> Result<OutputSettings> {
+        let mut settings = OutputSettings::default();
+        let Some(output) = conf.get("output") else {
+            return Ok(settings);
+        };
+        let Value::Table(output_table) = output else {
+            bail!("output must be a table")
+        };
+
+        if let Some(exposure) = output_table.get("exposure") {
+            settings.exposure = Self::parse_toml_f32(exposure)?;
+        }
+
+        if let Some(tonemap) = output_table.get("tonemap") {
+            let Value::String(tonemap) = tonemap else {
+                bail!("output.tonemap must be a string");
+            };
+            settings.tonemap = tonemap.parse()?;
+        }
+
+        if let Some(color_space) = output_table.get("color_space") {
+            let Value::String(color_space) = color_space else {
+                bail!("output.color_space must be a string");
+            };
+            settings.color_space = color_space.parse()?;
+        }
+
+        if let Some(white_point) = output_table.get("white_point") {
+            settings.white_point = Some(match white_point {
+                Value::String(name) => parse_white_point(name)?,
+                Value::Array(xy) if xy.len() == 2 => {
+                    let x = Self::parse_toml_f32(&xy[0])? as f64;
+                    let y = Self::parse_toml_f32(&xy[1])? as f64;
+                    if x <= 0.0 || y <= 0.0 || x + y > 1.0 {
+                        bail!("output.white_point [{x}, {y}] is not a valid chromaticity, expected 0 < x, 0 < y and x + y <= 1");
+                    }
+                    [x, y]
+                }
+                _ => bail!("output.white_point must be a name or an [x, y] chromaticity"),
+            });
+        }
+
+        if let Some(aovs) = output_table.get("aovs") {
+            let Value::Array(aovs) = aovs else {
+                bail!("output.aovs must be an array");
+            };
+            for aov in aovs {
+                let Value::String(aov) = aov else {
+                    bail!("output.aovs must only contain strings");
+                };
+                let aov = aov.parse()?;
+                if !settings.aovs.contains(&aov) {
+                    settings.aovs.push(aov);
+                }
+            }
+        }
+
+        if let Some(resolution) = output_table.get("resolution") {
+            settings.resolution = Some(match resolution {
+                Value::Array(size) if size.len() == 2 => match (&size[0], &size[1]) {
+                    (Value::Integer(w), Value::Integer(h))
+                        if (1..=u32::MAX as i64).contains(w)
+                            && (1..=u32::MAX as i64).contains(h) =>
+                    {
+                        (*w as u32, *h as u32)
+                    }
+                    _ => bail!("output.resolution must contain positive integers"),
+                },
+                _ => bail!("output.resolution must be an array of a width and a height"),
+            });
+        }
+
+        if let Some(render_scale) = output_table.get("render_scale") {
+            settings.render_scale = Self::parse_toml_f32(render_scale)?;
+            if settings.render_scale <= 0.0 || settings.render_scale > 4.0 {
+                bail!("output.render_scale must be greater than 0 and at most 4");
+            }
+        }
+
+        if let Some(tile_size) = output_table.get("tile_size") {
+            settings.tile_size = Some(match tile_size {
+                Value::Integer(x) if (1..=u32::MAX as i64).contains(x) => *x as u32,
+                _ => bail!("output.tile_size must be a positive integer"),
+            });
+        }
+
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml::Value;
+
+    use crate::color::{ColorSpace, D50};
+
+    use super::{
+        Aov, MeshScene, OutputSettings, ShaderType, Spectra, SpectralSettings, Tonemap,
+        WavelengthSampling, SPECTRUM_SAMPLES,
+    };
+
+    #[test]
+    fn parse_spectrum_type() {
+        assert_eq!(
+            MeshScene::parse_type_str("[spectrum; 2]").unwrap(),
+            ShaderType::Array(Box::new(ShaderType::Spectrum), 2)
+        );
+    }
+
+    #[test]
+    fn inline_spectra() {
+        let mut spectra = Spectra::default();
+
+        // evenly spaced samples over the whole range
+        let field: Value = "x = [0.0, 1.0]".parse::<toml::Table>().unwrap()["x"].clone();
+        let data =
+            MeshScene::parse_toml_field(&field, &ShaderType::Spectrum, &mut spectra).unwrap();
+        assert_eq!(data, 0u32.to_le_bytes());
+        assert_eq!(spectra.data[0][0], 0.0);
+        assert_eq!(spectra.data[0][SPECTRUM_SAMPLES - 1], 1.0);
+        assert!((spectra.data[0][SPECTRUM_SAMPLES / 2] - 0.5).abs() < 1e-5);
+
+        // wavelength/value pairs are clamped outside of the provided range
+        let field: Value = "x = [[500, 0.25], [600, 0.75]]"
+            .parse::<toml::Table>()
+            .unwrap()["x"]
+            .clone();
+        let data =
+            MeshScene::parse_toml_field(&field, &ShaderType::Spectrum, &mut spectra).unwrap();
+        assert_eq!(data, 1u32.to_le_bytes());
+        assert_eq!(spectra.data[1][0], 0.25);
+        assert_eq!(spectra.data[1][SPECTRUM_SAMPLES - 1], 0.75);
+        // 550nm is sample 340
+        assert!((spectra.data[1][340] - 0.5).abs() < 1e-5);
+
+        let field: Value = "x = [[600, 0.25], [500, 0.75]]"
+            .parse::<toml::Table>()
+            .unwrap()["x"]
+            .clone();
+        assert!(MeshScene::parse_toml_field(&field, &ShaderType::Spectrum, &mut spectra).is_err());
+
+        // pairs and samples can't be mixed
+        let field: Value = "x = [[500, 0.25], 0.75]".parse::<toml::Table>().unwrap()["x"].clone();
+        assert!(MeshScene::parse_toml_field(&field, &ShaderType::Spectrum, &mut spectra).is_err());
+    }
+
+    #[test]
+    fn spectral_settings() {
+        let conf: toml::Table = "".parse().unwrap();
+        assert_eq!(
+            MeshScene::parse_toml_spectral(&conf).unwrap(),
+            SpectralSettings::default()
+        );
+
+        let conf: toml::Table = "[spectral]\nrange = [400, 700.5]\nsampling = \"hero\""
+            .parse()
+            .unwrap();
+        let settings = MeshScene::parse_toml_spectral(&conf).unwrap();
+        assert_eq!(settings.min_wavelength, 400.0);
+        assert_eq!(settings.max_wavelength, 700.5);
+        assert_eq!(settings.sampling, WavelengthSampling::Hero);
+
+        for bad in [
+            "[spectral]\nrange = [300, 700]",
+            "[spectral]\nrange = [700, 400]",
+            "[spectral]\nsampling = \"random\"",
+        ] {
+            assert!(MeshScene::parse_toml_spectral(&bad.parse().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn denoise_settings() {
+        let conf: toml::Table = "[denoise]\npreview = true\niterations = 3\ncolor_phi = 2"
+            .parse()
+            .unwrap();
+        let settings = MeshScene::parse_toml_denoise(&conf).unwrap();
+        assert!(settings.preview && !settings.save && settings.enabled());
+        assert_eq!(settings.iterations, 3);
+        assert_eq!(settings.color_phi, 2.0);
+
+        for bad in ["iterations = 0", "normal_phi = -1", "save = 1"] {
+            let conf: toml::Table = format!("[denoise]\n{bad}").parse().unwrap();
+            assert!(MeshScene::parse_toml_denoise(&conf).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn sampling_settings() {
+        let conf: toml::Table = "".parse().unwrap();
+        let settings = MeshScene::parse_toml_sampling(&conf).unwrap();
+        assert!(!settings.has_limit());
+        assert_eq!(settings.frame_samples(), 128);
+
+        let conf: toml::Table = "[sampling]\nspp = 200\ntime_limit = 60".parse().unwrap();
+        let settings = MeshScene::parse_toml_sampling(&conf).unwrap();
+        assert!(settings.has_limit());
+        assert_eq!(settings.time_limit, Some(60.0));
+        // two frames of 100 instead of two of 128
+        assert_eq!(settings.frame_samples(), 100);
+
+        let conf: toml::Table = "[sampling]\nspp = 16\nadaptive_threshold = 0.05"
+            .parse()
+            .unwrap();
+        let settings = MeshScene::parse_toml_sampling(&conf).unwrap();
+        assert_eq!(settings.frame_samples(), 16);
+        assert_eq!(settings.adaptive_threshold, Some(0.05));
+
+        for bad in [
+            "spp = 0",
+            "samples_per_frame = 1.5",
+            "noise_threshold = -0.1",
+            "adaptive_threshold = 0",
+        ] {
+            let conf: toml::Table = format!("[sampling]\n{bad}").parse().unwrap();
+            assert!(MeshScene::parse_toml_sampling(&conf).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn render_settings() {
+        let conf: toml::Table = "".parse().unwrap();
+        assert_eq!(MeshScene::parse_toml_render(&conf).unwrap().seed, None);
+
+        let conf: toml::Table = "[render]\nseed = 1234".parse().unwrap();
+        assert_eq!(
+            MeshScene::parse_toml_render(&conf).unwrap().seed,
+            Some(1234)
+        );
+
+        for bad in ["seed = -1", "seed = \"1234\""] {
+            let conf: toml::Table = format!("[render]\n{bad}").parse().unwrap();
+            assert!(MeshScene::parse_toml_render(&conf).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn output_settings() {
+        let conf: toml::Table = "".parse().unwrap();
+        assert_eq!(
+            MeshScene::parse_toml_output(&conf).unwrap(),
+            OutputSettings::default()
+        );
+        // scenes without a tonemap keep the plain clamp they always had
+        assert_eq!(OutputSettings::default().tonemap, Tonemap::Clamp);
+
+        let conf: toml::Table =
+            "[output]\nexposure = -1\ntonemap = \"agx\"\ncolor_space = \"acescg\"\nwhite_point = \"d50\""
+                .parse()
+                .unwrap();
+        let settings = MeshScene::parse_toml_output(&conf).unwrap();
+        assert_eq!(settings.exposure, -1.0);
+        assert_eq!(settings.tonemap, Tonemap::Agx);
+        assert_eq!(settings.color_space, ColorSpace::AcesCg);
+        assert_eq!(settings.white_point, Some(D50));
+
+        for white_point in ["[0.3, 0]", "[0, 0.3]", "[0.6, 0.5]"] {
+            let conf: toml::Table = format!("[output]\nwhite_point = {white_point}")
+                .parse()
+                .unwrap();
+            assert!(MeshScene::parse_toml_output(&conf).is_err());
+        }
+
+        let conf: toml::Table = "[output]\naovs = [\"normal\", \"object\", \"normal\"]"
+            .parse()
+            .unwrap();
+        let settings = MeshScene::parse_toml_output(&conf).unwrap();
+        assert_eq!(settings.aovs, [Aov::Normal, Aov::Instance]);
+
+        let conf: toml::Table =
+            "[output]\nresolution = [3840, 2160]\nrender_scale = 0.5\ntile_size = 1024"
+                .parse()
+                .unwrap();
+        let settings = MeshScene::parse_toml_output(&conf).unwrap();
+        assert_eq!(settings.resolution, Some((3840, 2160)));
+        assert_eq!(settings.render_scale, 0.5);
+        assert_eq!(settings.tile_size, Some(1024));
+        assert_eq!(settings.aspect_ratio(), 16.0 / 9.0);
+
+        for bad in [
+            "tonemap = \"hable\"",
+            "resolution = [0, 100]",
+            "resolution = [1920]",
+            "render_scale = 0",
+            "tile_size = 0",
+        ] {
+            let conf: toml::Table = format!("[output]\n{bad}").parse().unwrap();
+            assert!(MeshScene::parse_toml_output(&conf).is_err(), "{bad}");
+        }
     }
 }
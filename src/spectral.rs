@@ -0,0 +1,426 @@
+use std::{sync::LazyLock, thread};
+
+// wavelength range covered by the renderer - keep in sync with color_spaces.glsl
+pub const MIN_WAVELENGTH: f64 = 380.0;
+pub const MAX_WAVELENGTH: f64 = 720.0;
+
+// CIE D65 illuminant at 5nm steps from 380nm to 720nm
+const D65_5NM: [f64; 69] = [
+    49.9755, 52.3118, 54.6482, 68.7015, 82.7549, 87.1204, 91.486, 92.4589, 93.4318, 90.057,
+    86.6823, 95.7736, 104.865, 110.936, 117.008, 117.41, 117.812, 116.336, 114.861, 115.392,
+    115.923, 112.367, 108.811, 109.082, 109.354, 108.578, 107.802, 106.296, 104.79, 106.239,
+    107.689, 106.047, 104.405, 104.225, 104.046, 102.023, 100., 98.1671, 96.3342, 96.0611, 95.788,
+    92.2368, 88.6856, 89.3459, 90.0062, 89.8026, 89.5991, 88.6489, 87.6987, 85.4936, 83.2886,
+    83.4939, 83.6992, 81.863, 80.0268, 80.1207, 80.2146, 81.2462, 82.2778, 80.281, 78.2842,
+    74.0027, 69.7213, 70.6652, 71.6091, 72.979, 74.349, 67.9765, 61.604,
+];
+
+#[cfg(test)]
+const XYZ_TO_SRGB: [[f64; 3]; 3] = [
+    [3.240969941904523, -1.537383177570094, -0.498610760293003],
+    [-0.969243636280880, 1.875967501507721, 0.041555057407176],
+    [0.055630079696994, -0.203976958888977, 1.056971514242879],
+];
+
+const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+    [0.412390799265959, 0.357584339383878, 0.180480788401834],
+    [0.212639005871510, 0.715168678767756, 0.072192315360734],
+    [0.019330818715592, 0.119194779794626, 0.950532152249661],
+];
+
+// resolution of each axis of the rgb to spectrum coefficient table
+pub const RGB_TO_SPECTRUM_RES: usize = 32;
+
+// wavelength step used when fitting the coefficient table
+const FIT_STEP: f64 = 5.0;
+
+// piecewise gaussian
+fn g(x: f64, mu: f64, t1: f64, t2: f64) -> f64 {
+    let t = if x < mu { t1 } else { t2 } * (x - mu);
+    (-t * t / 2.0).exp()
+}
+
+/// Analytic fit of the CIE 1931 colour matching functions, same as `wavelengthToXYZ` in color_spaces.glsl
+pub fn wavelength_to_xyz(lambda: f64) -> [f64; 3] {
+    let x = 0.362 * g(lambda, 442.0, 0.0624, 0.0374) + 1.056 * g(lambda, 599.8, 0.0264, 0.0323)
+        - 0.065 * g(lambda, 501.1, 0.0490, 0.0382);
+    let y = 0.821 * g(lambda, 568.8, 0.0213, 0.0247) + 0.286 * g(lambda, 530.9, 0.0613, 0.0322);
+    let z = 1.217 * g(lambda, 437.0, 0.0845, 0.0278) + 0.681 * g(lambda, 459.0, 0.0385, 0.0725);
+    [x, y, z]
+}
+
+/// D65 spectral power distribution, linearly interpolated from the 5nm table
+pub fn wavelength_to_d65(lambda: f64) -> f64 {
+    let t = ((lambda - MIN_WAVELENGTH) / 5.0).clamp(0.0, (D65_5NM.len() - 1) as f64);
+    let i = (t as usize).min(D65_5NM.len() - 2);
+    let w = t - i as f64;
+    D65_5NM[i] * (1.0 - w) + D65_5NM[i + 1] * w
+}
+
+#[cfg(test)]
+pub fn xyz_to_srgb(xyz: [f64; 3]) -> [f64; 3] {
+    mat_mul(&XYZ_TO_SRGB, xyz)
+}
+
+pub fn srgb_to_xyz(rgb: [f64; 3]) -> [f64; 3] {
+    mat_mul(&SRGB_TO_XYZ, rgb)
+}
+
+fn mat_mul(m: &[[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+/// Sigmoid used to keep uplifted spectra in [0, 1]
+pub fn sigmoid(x: f64) -> f64 {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+}
+
+/// Evaluates the sigmoid polynomial described by `coeffs` at a wavelength
+///
+/// The polynomial is in terms of the wavelength normalized to [0, 1] over the visible range
+pub fn eval_sigmoid_polynomial(coeffs: [f32; 3], lambda: f64) -> f64 {
+    let t = (lambda - MIN_WAVELENGTH) / (MAX_WAVELENGTH - MIN_WAVELENGTH);
+    let [a, b, c] = coeffs.map(f64::from);
+    sigmoid((a * t + b) * t + c)
+}
+
+// maps a z index of the coefficient table to the max rgb component it was fitted for
+// this spends more of the table on dark and bright colours
+fn z_scale(k: usize) -> f64 {
+    fn smoothstep(x: f64) -> f64 {
+        x * x * (3.0 - 2.0 * x)
+    }
+    smoothstep(smoothstep(k as f64 / (RGB_TO_SPECTRUM_RES - 1) as f64))
+}
+
+// inverse of z_scale, returns the continuous z index
+fn inverse_z_scale(z: f64) -> f64 {
+    fn inverse_smoothstep(x: f64) -> f64 {
+        0.5 - ((1.0 - 2.0 * x).asin() / 3.0).sin()
+    }
+    inverse_smoothstep(inverse_smoothstep(z)) * (RGB_TO_SPECTRUM_RES - 1) as f64
+}
+
+// tabulated xyz * d65 weights used when fitting, normalized so a constant 1 spectrum has Y = 1
+struct FitTables {
+    t: Vec<f64>,
+    xyz: Vec<[f64; 3]>,
+    white: [f64; 3],
+}
+
+impl FitTables {
+    fn new() -> Self {
+        let samples = ((MAX_WAVELENGTH - MIN_WAVELENGTH) / FIT_STEP) as usize + 1;
+        let mut t = Vec::with_capacity(samples);
+        let mut xyz = Vec::with_capacity(samples);
+        for i in 0..samples {
+            let lambda = MIN_WAVELENGTH + i as f64 * FIT_STEP;
+            // trapezoid rule
+            let weight = if i == 0 || i == samples - 1 { 0.5 } else { 1.0 };
+            let d65 = wavelength_to_d65(lambda);
+            t.push((lambda - MIN_WAVELENGTH) / (MAX_WAVELENGTH - MIN_WAVELENGTH));
+            xyz.push(wavelength_to_xyz(lambda).map(|c| c * d65 * weight));
+        }
+
+        let y_sum: f64 = xyz.iter().map(|c| c[1]).sum();
+        for c in &mut xyz {
+            *c = c.map(|v| v / y_sum);
+        }
+        let white = xyz.iter().fold([0.0; 3], |acc, c| {
+            [acc[0] + c[0], acc[1] + c[1], acc[2] + c[2]]
+        });
+
+        Self { t, xyz, white }
+    }
+
+    fn lab(&self, xyz: [f64; 3]) -> [f64; 3] {
+        fn f(t: f64) -> f64 {
+            const DELTA: f64 = 6.0 / 29.0;
+            if t > DELTA * DELTA * DELTA {
+                t.cbrt()
+            } else {
+                t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+            }
+        }
+        let [fx, fy, fz] = [0, 1, 2].map(|i| f(xyz[i] / self.white[i]));
+        [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+    }
+
+    // difference in CIELAB between the target colour and the colour of the sigmoid polynomial
+    fn residual(&self, coeffs: [f64; 3], target_lab: [f64; 3]) -> [f64; 3] {
+        let mut xyz = [0.0; 3];
+        for (&t, w) in self.t.iter().zip(&self.xyz) {
+            let s = sigmoid((coeffs[0] * t + coeffs[1]) * t + coeffs[2]);
+            for j in 0..3 {
+                xyz[j] += w[j] * s;
+            }
+        }
+        let lab = self.lab(xyz);
+        [0, 1, 2].map(|i| target_lab[i] - lab[i])
+    }
+
+    // gauss-newton fit of the sigmoid polynomial coefficients, starting from the given guess
+    fn fit(&self, rgb: [f64; 3], mut coeffs: [f64; 3]) -> [f64; 3] {
+        const EPS: f64 = 1e-6;
+        let target_lab = self.lab(srgb_to_xyz(rgb));
+
+        for _ in 0..15 {
+            let r = self.residual(coeffs, target_lab);
+
+            // forward differences
+            let mut jacobian = [[0.0; 3]; 3];
+            for j in 0..3 {
+                let mut shifted = coeffs;
+                shifted[j] += EPS;
+                let r_shifted = self.residual(shifted, target_lab);
+                for i in 0..3 {
+                    jacobian[i][j] = (r_shifted[i] - r[i]) / EPS;
+                }
+            }
+
+            let Some(step) = solve3(jacobian, r) else {
+                break;
+            };
+            for j in 0..3 {
+                coeffs[j] -= step[j];
+            }
+
+            // keep the sigmoid from getting too steep
+            let max = coeffs.iter().fold(0f64, |m, c| m.max(c.abs()));
+            if max > 200.0 {
+                coeffs = coeffs.map(|c| c * 200.0 / max);
+            }
+
+            if r.iter().map(|v| v * v).sum::<f64>() < 1e-6 {
+                break;
+            }
+        }
+
+        coeffs
+    }
+}
+
+// solves a * x = b with cramer's rule
+fn solve3(a: [[f64; 3]; 3], b: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(a);
+    if d.abs() < 1e-15 {
+        return None;
+    }
+    Some([0, 1, 2].map(|j| {
+        let mut m = a;
+        for i in 0..3 {
+            m[i][j] = b[i];
+        }
+        det(m) / d
+    }))
+}
+
+// fitting the table takes a while, it's the same for every scene and renderer
+pub static RGB_TO_SPECTRUM_TABLE: LazyLock<RgbToSpectrumTable> =
+    LazyLock::new(RgbToSpectrumTable::new);
+
+/// Table of sigmoid polynomial coefficients for uplifting sRGB colours to smooth reflectance spectra
+///
+/// Based on "A Low-Dimensional Function Space for Efficient Spectral Upsampling" (Jakob and Hanika 2019).
+/// Colours are indexed by their largest component, the largest component's value (z), and the other
+/// two components divided by it (x and y). Entries are stored as
+/// `[max component][z][y][x]`, which is also the layout of the texture uploaded to the GPU.
+#[derive(Debug)]
+pub struct RgbToSpectrumTable {
+    pub coeffs: Vec<[f32; 3]>,
+}
+
+impl RgbToSpectrumTable {
+    pub fn new() -> Self {
+        const RES: usize = RGB_TO_SPECTRUM_RES;
+        let tables = FitTables::new();
+
+        // each (max component, y) column is fitted on its own, since every z starts from its neighbour's fit
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let columns: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|thread_i| {
+                    let tables = &tables;
+                    s.spawn(move || {
+                        (thread_i..3 * RES)
+                            .step_by(threads)
+                            .map(|column| {
+                                let (l, j) = (column / RES, column % RES);
+                                (l, j, Self::fit_column(tables, l, j))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        let mut coeffs = vec![[0f32; 3]; 3 * RES * RES * RES];
+        for (l, j, column) in columns {
+            for (k, row) in column.chunks(RES).enumerate() {
+                let offset = ((l * RES + k) * RES + j) * RES;
+                coeffs[offset..offset + RES].copy_from_slice(row);
+            }
+        }
+
+        Self { coeffs }
+    }
+
+    // fits every z and x for the given max component and y, returned as [z][x]
+    fn fit_column(tables: &FitTables, l: usize, j: usize) -> Vec<[f32; 3]> {
+        const RES: usize = RGB_TO_SPECTRUM_RES;
+        let y = j as f64 / (RES - 1) as f64;
+        let mut column = vec![[0f32; 3]; RES * RES];
+
+        let mut fit = |k: usize, i: usize, guess: [f64; 3]| {
+            let x = i as f64 / (RES - 1) as f64;
+            let z = z_scale(k);
+            let mut rgb = [0.0; 3];
+            rgb[l] = z;
+            rgb[(l + 1) % 3] = x * z;
+            rgb[(l + 2) % 3] = y * z;
+            let coeffs = tables.fit(rgb, guess);
+            column[k * RES + i] = coeffs.map(|c| c as f32);
+            coeffs
+        };
+
+        // fit outward from a mid-brightness colour, where the fit is easiest
+        let start = RES / 5;
+        for i in 0..RES {
+            let mut guess = [0.0; 3];
+            for k in start..RES {
+                guess = fit(k, i, guess);
+            }
+            let mut guess = [0.0; 3];
+            for k in (0..start).rev() {
+                guess = fit(k, i, guess);
+            }
+        }
+
+        column
+    }
+
+    /// Looks up the sigmoid polynomial coefficients for an sRGB colour in [0, 1]
+    ///
+    /// Mirrors `rgb_to_spectrum_coeffs` in spectra.glsl
+    pub fn coeffs(&self, rgb: [f64; 3]) -> [f32; 3] {
+        const RES: usize = RGB_TO_SPECTRUM_RES;
+        let rgb = rgb.map(|c| c.clamp(0.0, 1.0));
+
+        // grey spectra are constant, so invert the sigmoid directly
+        if rgb[0] == rgb[1] && rgb[1] == rgb[2] {
+            let v = rgb[0];
+            return [0.0, 0.0, ((v - 0.5) / (v * (1.0 - v)).sqrt()) as f32];
+        }
+
+        let l = if rgb[0] >= rgb[1] {
+            if rgb[0] >= rgb[2] {
+                0
+            } else {
+                2
+            }
+        } else if rgb[1] >= rgb[2] {
+            1
+        } else {
+            2
+        };
+        let z = rgb[l];
+        let x = rgb[(l + 1) % 3] / z * (RES - 1) as f64;
+        let y = rgb[(l + 2) % 3] / z * (RES - 1) as f64;
+        let zc = inverse_z_scale(z);
+
+        let (xi, yi, zi) = (
+            (x as usize).min(RES - 2),
+            (y as usize).min(RES - 2),
+            (zc as usize).min(RES - 2),
+        );
+        let (dx, dy, dz) = (x - xi as f64, y - yi as f64, zc - zi as f64);
+
+        let at = |dk: usize, dj: usize, di: usize| {
+            self.coeffs[((l * RES + zi + dk) * RES + yi + dj) * RES + xi + di].map(f64::from)
+        };
+        let lerp = |a: [f64; 3], b: [f64; 3], t: f64| [0, 1, 2].map(|c| a[c] + (b[c] - a[c]) * t);
+
+        let lerp_xy = |dk: usize| {
+            lerp(
+                lerp(at(dk, 0, 0), at(dk, 0, 1), dx),
+                lerp(at(dk, 1, 0), at(dk, 1, 1), dx),
+                dy,
+            )
+        };
+        lerp(lerp_xy(0), lerp_xy(1), dz).map(|c| c as f32)
+    }
+
+    /// Texture data, as rows of RGBA texels with `RGB_TO_SPECTRUM_RES` texels per row
+    pub fn texture_data(&self) -> Vec<f32> {
+        self.coeffs
+            .iter()
+            .flat_map(|&[a, b, c]| [a, b, c, 0.0])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // integrates a spectrum against the CIE curves under D65 and converts to sRGB
+    fn spectrum_to_srgb(spectrum: impl Fn(f64) -> f64) -> [f64; 3] {
+        let mut xyz = [0.0; 3];
+        let mut y_sum = 0.0;
+        let mut lambda = MIN_WAVELENGTH;
+        while lambda <= MAX_WAVELENGTH {
+            let cmf = wavelength_to_xyz(lambda);
+            let d65 = wavelength_to_d65(lambda);
+            let s = spectrum(lambda);
+            for i in 0..3 {
+                xyz[i] += cmf[i] * d65 * s;
+            }
+            y_sum += cmf[1] * d65;
+            lambda += 1.0;
+        }
+        xyz_to_srgb(xyz.map(|c| c / y_sum))
+    }
+
+    #[test]
+    fn uplift_round_trip() {
+        let table = RgbToSpectrumTable::new();
+        let colours = [
+            [0.0, 0.0, 0.0],
+            [0.5, 0.5, 0.5],
+            [0.8, 0.2, 0.1],
+            [0.1, 0.7, 0.3],
+            [0.2, 0.3, 0.9],
+            [0.9, 0.9, 0.1],
+            [0.05, 0.02, 0.01],
+            [0.6, 0.4, 0.5],
+            [0.95, 0.6, 0.3],
+        ];
+
+        for rgb in colours {
+            let coeffs = table.coeffs(rgb);
+            let uplifted = spectrum_to_srgb(|lambda| {
+                let s = eval_sigmoid_polynomial(coeffs, lambda);
+                assert!((0.0..=1.0).contains(&s));
+                s
+            });
+            for i in 0..3 {
+                assert!(
+                    (uplifted[i] - rgb[i]).abs() < 0.01,
+                    "{rgb:?} round-tripped to {uplifted:?}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    output::{write_display_image, write_linear_image, ImageFormat},
+    scene::scenes::mesh::OutputSettings,
+    tonemap::tonemap,
+};
+
+// the most pixels stitched in memory, 1 GiB of RGBA floats, larger images have to stream a .pfm
+const MAX_BUFFERED_PIXELS: u64 = 8192 * 8192;
+
+/// Offsets of the tiles covering an image, row by row from the top left
+///
+/// Every tile is `tile_size` square, the ones on the right and bottom edges hang over the image.
+pub fn tile_grid((width, height): (u32, u32), tile_size: u32) -> Vec<(u32, u32)> {
+    (0..height.div_ceil(tile_size))
+        .flat_map(|ty| {
+            (0..width.div_ceil(tile_size)).map(move |tx| (tx * tile_size, ty * tile_size))
+        })
+        .collect()
+}
+
+/// Receives the linear RGBA pixels of finished tiles and assembles the final image
+pub trait TileWriter {
+    /// `pixels` covers the whole tile, the part hanging over the image is skipped
+    fn write_tile(&mut self, offset: (u32, u32), tile_size: u32, pixels: &[f32]) -> Result<()>;
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Streams tiles straight into a .pfm, so the full image never has to fit in memory
+///
+/// Rows in a pfm have a fixed size, so each tile row is written in place.
+pub struct PfmTileWriter {
+    file: BufWriter<File>,
+    size: (u32, u32),
+    header_len: u64,
+}
+
+impl PfmTileWriter {
+    pub fn new(path: &Path, (width, height): (u32, u32)) -> Result<Self> {
+        let header = format!("PF\n{width} {height}\n-1.0\n");
+        let file = File::create(path)?;
+        file.set_len(header.len() as u64 + width as u64 * height as u64 * 12)?;
+        let mut file = BufWriter::new(file);
+        file.write_all(header.as_bytes())?;
+        Ok(Self {
+            file,
+            size: (width, height),
+            header_len: header.len() as u64,
+        })
+    }
+}
+
+impl TileWriter for PfmTileWriter {
+    fn write_tile(&mut self, (x, y): (u32, u32), tile_size: u32, pixels: &[f32]) -> Result<()> {
+        let (width, height) = self.size;
+        let columns = tile_size.min(width - x) as usize;
+        for row in 0..tile_size.min(height - y) {
+            // pfm rows go from the bottom of the image to the top
+            let image_row = (height - 1 - (y + row)) as u64;
+            let position = self.header_len + (image_row * width as u64 + x as u64) * 12;
+            self.file.seek(SeekFrom::Start(position))?;
+
+            let start = (row * tile_size) as usize * 4;
+            for pixel in pixels[start..start + columns * 4].chunks(4) {
+                for c in &pixel[..3] {
+                    self.file.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Stitches tiles into one image on the host and writes it in any format at the end
+pub struct BufferedTileWriter {
+    path: PathBuf,
+    format: ImageFormat,
+    settings: OutputSettings,
+    size: (u32, u32),
+    pixels: Vec<f32>,
+}
+
+impl BufferedTileWriter {
+    pub fn new(
+        path: &Path,
+        format: ImageFormat,
+        settings: &OutputSettings,
+        (width, height): (u32, u32),
+    ) -> Self {
+        Self {
+            path: path.to_owned(),
+            format,
+            settings: settings.clone(),
+            size: (width, height),
+            pixels: vec![0.0; width as usize * height as usize * 4],
+        }
+    }
+}
+
+impl TileWriter for BufferedTileWriter {
+    fn write_tile(&mut self, (x, y): (u32, u32), tile_size: u32, pixels: &[f32]) -> Result<()> {
+        let (width, height) = self.size;
+        let columns = tile_size.min(width - x) as usize;
+        for row in 0..tile_size.min(height - y) {
+            let src = (row * tile_size) as usize * 4;
+            let dst = ((y + row) as usize * width as usize + x as usize) * 4;
+            self.pixels[dst..dst + columns * 4].copy_from_slice(&pixels[src..src + columns * 4]);
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let color_space = self.settings.color_space;
+        if self.format.is_linear() {
+            write_linear_image(
+                &self.path,
+                self.size,
+                self.format,
+                color_space,
+                &self.pixels,
+                &[],
+            )
+        } else {
+            let pixels = tonemap(&self.pixels, &self.settings);
+            write_display_image(&self.path, self.size, color_space, &pixels)
+        }
+    }
+}
+
+/// Streams .pfm outputs and stitches everything else in memory, up to a size limit
+pub fn tile_writer(
+    path: &Path,
+    format: ImageFormat,
+    settings: &OutputSettings,
+    size: (u32, u32),
+) -> Result<Box<dyn TileWriter>> {
+    if format == ImageFormat::Pfm {
+        return Ok(Box::new(PfmTileWriter::new(path, size)?));
+    }
+    if size.0 as u64 * size.1 as u64 > MAX_BUFFERED_PIXELS {
+        bail!(
+            "{}x{} is too large to stitch in memory, write a .pfm to stream the tiles to disk",
+            size.0,
+            size.1
+        );
+    }
+    Ok(Box::new(BufferedTileWriter::new(
+        path, format, settings, size,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{output::ImageFormat, scene::scenes::mesh::OutputSettings};
+
+    use super::{tile_grid, tile_writer, BufferedTileWriter, PfmTileWriter, TileWriter};
+
+    const SIZE: (u32, u32) = (5, 3);
+    const TILE_SIZE: u32 = 2;
+
+    // red is the x coordinate and green the y coordinate
+    fn render_tile((x, y): (u32, u32)) -> Vec<f32> {
+        (0..TILE_SIZE * TILE_SIZE)
+            .flat_map(|i| {
+                [
+                    (x + i % TILE_SIZE) as f32,
+                    (y + i / TILE_SIZE) as f32,
+                    0.0,
+                    1.0,
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tiles_cover_the_image() {
+        assert_eq!(
+            tile_grid(SIZE, TILE_SIZE),
+            [(0, 0), (2, 0), (4, 0), (0, 2), (2, 2), (4, 2)]
+        );
+        assert_eq!(tile_grid((4, 4), 4), [(0, 0)]);
+    }
+
+    #[test]
+    fn stitched_tiles_match_a_streamed_pfm() {
+        let dir = std::env::temp_dir().join(format!("kg-tiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let streamed_path = dir.join("streamed.pfm");
+        let stitched_path = dir.join("stitched.pfm");
+
+        let mut streamed = Box::new(PfmTileWriter::new(&streamed_path, SIZE).unwrap());
+        let mut stitched = Box::new(BufferedTileWriter::new(
+            &stitched_path,
+            ImageFormat::Pfm,
+            &OutputSettings::default(),
+            SIZE,
+        ));
+        for offset in tile_grid(SIZE, TILE_SIZE) {
+            streamed
+                .write_tile(offset, TILE_SIZE, &render_tile(offset))
+                .unwrap();
+            stitched
+                .write_tile(offset, TILE_SIZE, &render_tile(offset))
+                .unwrap();
+        }
+
+        let expected: Vec<f32> = (0..SIZE.1)
+            .flat_map(|y| (0..SIZE.0).flat_map(move |x| [x as f32, y as f32, 0.0, 1.0]))
+            .collect();
+        assert_eq!(stitched.pixels, expected);
+
+        streamed.finish().unwrap();
+        stitched.finish().unwrap();
+        let streamed = std::fs::read(&streamed_path).unwrap();
+        assert_eq!(streamed, std::fs::read(&stitched_path).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn huge_images_only_stream() {
+        let path = std::env::temp_dir().join(format!("kg-tiles-{}.exr", std::process::id()));
+        let size = (100_000, 100_000);
+        let format = ImageFormat::Exr { half: false };
+        let writer = tile_writer(&path, format, &OutputSettings::default(), size);
+        assert!(writer.is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,89 @@
+use glam::{Mat3, Vec3};
+
+use crate::scene::scenes::mesh::{OutputSettings, Tonemap};
+
+/// Applies exposure and the tonemapping operator to linear RGBA pixels
+///
+/// This is the CPU counterpart of tonemap.comp, used when a saved image is post-processed on the
+/// CPU and can't come from the display image.
+pub fn tonemap(pixels: &[f32], settings: &OutputSettings) -> Vec<f32> {
+    let scale = settings.exposure.exp2();
+    let (to_rec709, from_rec709) = rec709_matrices(settings);
+    pixels
+        .chunks(4)
+        .flat_map(|pixel| {
+            let color = Vec3::from_slice(pixel).max(Vec3::ZERO) * scale;
+            let color = match settings.tonemap {
+                Tonemap::Clamp => color.clamp(Vec3::ZERO, Vec3::ONE),
+                Tonemap::Reinhard => color / (1.0 + color),
+                Tonemap::Aces => from_rec709 * aces(to_rec709 * color),
+                Tonemap::Agx => from_rec709 * agx(to_rec709 * color),
+            };
+            [color.x, color.y, color.z, pixel[3]]
+        })
+        .collect()
+}
+
+/// Matrices into and out of the Rec.709 input that ACES and AgX are fitted to
+pub fn rec709_matrices(settings: &OutputSettings) -> (Mat3, Mat3) {
+    let white = settings
+        .white_point
+        .unwrap_or(settings.color_space.default_white());
+    let to_rec709 = settings.color_space.to_rec709(white);
+    (to_rec709.as_mat3(), to_rec709.inverse().as_mat3())
+}
+
+// Stephen Hill's fit of the ACES RRT and ODT
+// the constants are copied digit for digit from tonemap.comp
+#[allow(clippy::excessive_precision)]
+fn aces(color: Vec3) -> Vec3 {
+    // written row by row like the shader, hence the transpose
+    let input = Mat3::from_cols(
+        Vec3::new(0.59719, 0.35458, 0.04823),
+        Vec3::new(0.07600, 0.90834, 0.01566),
+        Vec3::new(0.02840, 0.13383, 0.83777),
+    )
+    .transpose();
+    let output = Mat3::from_cols(
+        Vec3::new(1.60475, -0.53108, -0.07367),
+        Vec3::new(-0.10208, 1.10813, -0.00605),
+        Vec3::new(-0.00327, -0.07276, 1.07602),
+    )
+    .transpose();
+
+    let color = input * color;
+    let a = color * (color + 0.0245786) - 0.000090537;
+    let b = color * (0.983729 * color + 0.4329510) + 0.238081;
+    (output * (a / b)).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+// minimal AgX with the default look
+#[allow(clippy::excessive_precision)]
+fn agx(color: Vec3) -> Vec3 {
+    let inset = Mat3::from_cols(
+        Vec3::new(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        Vec3::new(0.0784335999999992, 0.878468636469772, 0.0784336),
+        Vec3::new(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+    );
+    let outset = Mat3::from_cols(
+        Vec3::new(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        Vec3::new(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        Vec3::new(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+    );
+    let (min_ev, max_ev) = (-12.47393, 4.026069);
+
+    let color = inset * color;
+    let color = Vec3::from(color.max(Vec3::splat(1e-10)).to_array().map(f32::log2));
+    let color =
+        (color.clamp(Vec3::splat(min_ev), Vec3::splat(max_ev)) - min_ev) / (max_ev - min_ev);
+
+    // polynomial fit of the default contrast curve
+    let x2 = color * color;
+    let x4 = x2 * x2;
+    let color = 15.5 * x4 * x2 - 40.14 * x4 * color + 31.96 * x4 - 6.868 * x2 * color
+        + 0.4298 * x2
+        + 0.1191 * color
+        - 0.00232;
+
+    (outset * color).clamp(Vec3::ZERO, Vec3::ONE).powf(2.2)
+}
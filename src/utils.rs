@@ -1,7 +1,15 @@
-use anyhow::Result;
+use std::convert::Infallible;
+use std::ffi::{c_char, CStr};
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
 use ash::{khr, vk, Device, Entry, Instance};
 use gpu_allocator::vulkan::*;
 use gpu_allocator::MemoryLocation;
+use log::{info, warn};
+
+use crate::debug::DebugNames;
 
 #[derive(Default, Clone)]
 pub struct QueueFamilyInfo {
@@ -11,11 +19,45 @@ pub struct QueueFamilyInfo {
     pub transfer_index: Option<u32>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFamily {
+    Present,
+    Compute,
+}
+
+impl fmt::Display for QueueFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QueueFamily::Present => "present",
+            QueueFamily::Compute => "compute",
+        })
+    }
+}
+
+impl QueueFamilyInfo {
+    pub fn index(&self, family: QueueFamily) -> Option<u32> {
+        match family {
+            QueueFamily::Present => self.present_index,
+            QueueFamily::Compute => self.compute_index,
+        }
+    }
+
+    /// The families in `families` the device has no queue for
+    pub fn missing(&self, families: &[QueueFamily]) -> Vec<QueueFamily> {
+        families
+            .iter()
+            .copied()
+            .filter(|&family| self.index(family).is_none())
+            .collect()
+    }
+}
+
 pub fn query_queue_families(
     vk_lib: &Entry,
     instance: &Instance,
     device: vk::PhysicalDevice,
-    surface: vk::SurfaceKHR,
+    // without a surface nothing is looked up for presenting
+    surface: Option<vk::SurfaceKHR>,
 ) -> Result<QueueFamilyInfo> {
     let queue_families = unsafe { instance.get_physical_device_queue_family_properties(device) };
     let mut info = QueueFamilyInfo::default();
@@ -35,6 +77,9 @@ pub fn query_queue_families(
             info.transfer_index = Some(i as u32);
         }
 
+        let Some(surface) = surface else {
+            continue;
+        };
         let present_support = unsafe {
             surface_loader.get_physical_device_surface_support(device, i as u32, surface)
         }?;
@@ -46,6 +91,225 @@ pub fn query_queue_families(
     Ok(info)
 }
 
+/// Names of the extensions in `extensions` the Vulkan implementation doesn't support
+pub fn missing_instance_extensions(
+    vk_lib: &Entry,
+    extensions: &[*const c_char],
+) -> Result<Vec<String>> {
+    let supported_extensions = unsafe { vk_lib.enumerate_instance_extension_properties(None)? };
+    Ok(extensions
+        .iter()
+        .map(|&ext| unsafe { CStr::from_ptr(ext) })
+        .filter(|&ext_name| {
+            !supported_extensions
+                .iter()
+                .any(|x| x.extension_name_as_c_str().unwrap() == ext_name)
+        })
+        .map(|ext_name| ext_name.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Names of the extensions in `extensions` the device doesn't support
+pub fn missing_device_extensions(
+    instance: &Instance,
+    device: vk::PhysicalDevice,
+    extensions: &[*const c_char],
+) -> Result<Vec<String>> {
+    let supported_extensions = unsafe { instance.enumerate_device_extension_properties(device)? };
+    Ok(extensions
+        .iter()
+        .map(|&ext| unsafe { CStr::from_ptr(ext) })
+        .filter(|&ext_name| {
+            !supported_extensions
+                .iter()
+                .any(|x| x.extension_name_as_c_str().unwrap() == ext_name)
+        })
+        .map(|ext_name| ext_name.to_string_lossy().into_owned())
+        .collect())
+}
+
+pub fn device_name(instance: &Instance, device: vk::PhysicalDevice) -> String {
+    let properties = unsafe { instance.get_physical_device_properties(device) };
+    properties
+        .device_name_as_c_str()
+        .map_or("unknown".to_string(), |name| {
+            name.to_string_lossy().into_owned()
+        })
+}
+
+/// Everything that keeps a device from being used, empty if nothing does
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SuitabilityReport {
+    pub missing_instance_extensions: Vec<String>,
+    pub missing_device_extensions: Vec<String>,
+    // as `Struct::field`
+    pub missing_features: Vec<String>,
+    pub missing_queue_families: Vec<QueueFamily>,
+    // the window surface has no formats or present modes on the device
+    pub unsupported_surface: bool,
+}
+
+impl SuitabilityReport {
+    pub fn is_suitable(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for SuitabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut problems = Vec::new();
+        if !self.missing_instance_extensions.is_empty() {
+            problems.push(format!(
+                "missing instance extensions {}",
+                self.missing_instance_extensions.join(", ")
+            ));
+        }
+        if !self.missing_device_extensions.is_empty() {
+            problems.push(format!(
+                "missing device extensions {}",
+                self.missing_device_extensions.join(", ")
+            ));
+        }
+        if !self.missing_features.is_empty() {
+            problems.push(format!(
+                "missing features {}",
+                self.missing_features.join(", ")
+            ));
+        }
+        if !self.missing_queue_families.is_empty() {
+            let families: Vec<_> = self
+                .missing_queue_families
+                .iter()
+                .map(|family| family.to_string())
+                .collect();
+            problems.push(format!("missing queue families {}", families.join(", ")));
+        }
+        if self.unsupported_surface {
+            problems.push("can't present to the window surface".to_string());
+        }
+
+        if problems.is_empty() {
+            f.write_str("suitable")
+        } else {
+            f.write_str(&problems.join("; "))
+        }
+    }
+}
+
+/// A device chosen on the command line, by its index in `kg devices` or part of its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Index(index) => write!(f, "device {index}"),
+            DeviceSelector::Name(name) => write!(f, "\"{name}\""),
+        }
+    }
+}
+
+impl DeviceSelector {
+    /// Names match case insensitively anywhere in the device name
+    pub fn matches(&self, index: usize, name: &str) -> bool {
+        match self {
+            DeviceSelector::Index(i) => *i == index,
+            DeviceSelector::Name(part) => name.to_lowercase().contains(&part.to_lowercase()),
+        }
+    }
+}
+
+/// The device with the highest score, the first one on ties
+pub fn pick_physical_device(
+    devices: impl Iterator<Item = vk::PhysicalDevice>,
+    score: impl Fn(vk::PhysicalDevice) -> u32,
+) -> Option<vk::PhysicalDevice> {
+    let mut best: Option<(vk::PhysicalDevice, u32)> = None;
+    for device in devices {
+        let device_score = score(device);
+        if best.is_none_or(|(_, best_score)| device_score > best_score) {
+            best = Some((device, device_score));
+        }
+    }
+    best.map(|(device, _)| device)
+}
+
+/// The device the selector names, otherwise the suitable device with the highest score
+///
+/// `check` reports what keeps a device from being used, every device's report is logged.
+pub fn choose_physical_device(
+    instance: &Instance,
+    selector: Option<&DeviceSelector>,
+    check: impl Fn(vk::PhysicalDevice) -> Result<SuitabilityReport>,
+    score: impl Fn(vk::PhysicalDevice) -> u32,
+) -> Result<vk::PhysicalDevice> {
+    let devices = unsafe { instance.enumerate_physical_devices()? };
+    if devices.is_empty() {
+        bail!("no Vulkan devices found");
+    }
+
+    if let Some(selector) = selector {
+        let (device, name) = devices
+            .iter()
+            .enumerate()
+            .map(|(i, &device)| (i, device, device_name(instance, device)))
+            .find(|(i, _, name)| selector.matches(*i, name))
+            .map(|(_, device, name)| (device, name))
+            .ok_or_else(|| anyhow!("no device matches {selector}, see `kg devices`"))?;
+        let report = check(device)?;
+        if !report.is_suitable() {
+            bail!("{name} can't be used: {report}");
+        }
+        return Ok(device);
+    }
+
+    let mut suitable = Vec::new();
+    let mut problems = Vec::new();
+    for (i, &device) in devices.iter().enumerate() {
+        let name = device_name(instance, device);
+        // skip and log if the check fails
+        let report = match check(device) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("failed to check if device was suitable: {e}");
+                problems.push(format!("{i}: {name}: {e}"));
+                continue;
+            }
+        };
+        info!("Device {i}: {name}: {report}");
+        if report.is_suitable() {
+            suitable.push(device);
+        } else {
+            problems.push(format!("{i}: {name}: {report}"));
+        }
+    }
+
+    pick_physical_device(suitable.into_iter(), score).ok_or_else(|| {
+        anyhow!(
+            "no suitable device found\n{}",
+            problems
+                .iter()
+                .map(|problem| format!("  {problem}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    })
+}
+
 pub fn align_up(value: u32, alignment: u32) -> u32 {
     (value + alignment - 1) & !(alignment - 1)
 }
@@ -141,6 +405,10 @@ impl AllocatedBuffer {
             .map(|p| p.as_ptr() as *const u8)
     }
 
+    pub fn set_name(&self, names: &DebugNames, name: &str) {
+        names.name(self.buffer, name);
+    }
+
     pub unsafe fn destroy(self, device: &Device, allocator: &mut Allocator) {
         device.destroy_buffer(self.buffer, None);
         allocator.free(self.allocation).unwrap();
@@ -301,9 +569,65 @@ impl AllocatedImage {
         Ok(())
     }
 
+    // the view gets the same name with a suffix
+    pub fn set_name(&self, names: &DebugNames, name: &str) {
+        names.name(self.image, name);
+        names.name(self.image_view, &format!("{name} view"));
+    }
+
     pub unsafe fn destroy(self, device: &Device, allocator: &mut Allocator) {
         device.destroy_image_view(self.image_view, None);
         device.destroy_image(self.image, None);
         allocator.free(self.allocation).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::Handle;
+
+    use super::*;
+
+    #[test]
+    fn device_selectors() {
+        assert_eq!("1".parse(), Ok(DeviceSelector::Index(1)));
+        let selector: DeviceSelector = "rtx".parse().unwrap();
+        assert_eq!(selector, DeviceSelector::Name("rtx".to_string()));
+        assert!(selector.matches(0, "NVIDIA GeForce RTX 3080"));
+        assert!(!selector.matches(0, "AMD Radeon Graphics"));
+        assert!(DeviceSelector::Index(1).matches(1, "llvmpipe"));
+        assert!(!DeviceSelector::Index(1).matches(0, "llvmpipe"));
+    }
+
+    #[test]
+    fn suitability_reports() {
+        let mut report = SuitabilityReport::default();
+        assert!(report.is_suitable());
+        assert_eq!(report.to_string(), "suitable");
+
+        report.missing_device_extensions = vec![
+            "VK_KHR_acceleration_structure".to_string(),
+            "VK_KHR_ray_tracing_pipeline".to_string(),
+        ];
+        report.missing_features =
+            vec!["PhysicalDeviceVulkan12Features::buffer_device_address".to_string()];
+        report.missing_queue_families = vec![QueueFamily::Compute];
+        assert!(!report.is_suitable());
+        assert_eq!(
+            report.to_string(),
+            "missing device extensions VK_KHR_acceleration_structure, VK_KHR_ray_tracing_pipeline; \
+             missing features PhysicalDeviceVulkan12Features::buffer_device_address; \
+             missing queue families compute"
+        );
+    }
+
+    #[test]
+    fn highest_score_wins() {
+        let devices = (1..=4).map(vk::PhysicalDevice::from_raw);
+        let scores = [1, 3, 3, 2];
+        let score = |device: vk::PhysicalDevice| scores[device.as_raw() as usize - 1];
+        let picked = pick_physical_device(devices, score);
+        assert_eq!(picked, Some(vk::PhysicalDevice::from_raw(2)));
+        assert_eq!(pick_physical_device(std::iter::empty(), score), None);
+    }
+}
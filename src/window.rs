@@ -1,10 +1,14 @@
-use std::{ffi::c_char, ptr};
+use std::{ffi::c_char, ptr, sync::LazyLock};
 
 use anyhow::{anyhow, Result};
 use ash::{khr, vk, Device, Entry, Instance};
 use winit::window::Window;
 
-use crate::{defer::Defer, utils};
+use crate::{
+    defer::Defer,
+    features::{vk_features, VkFeatureGuard, VkFeatures},
+    utils,
+};
 
 const MAX_FRAMES_IN_FLIGHT: usize = 2;
 
@@ -252,7 +256,8 @@ impl WindowData {
         let present_mode = Self::choose_present_mode(&support_details.present_modes);
         let image_extent = Self::choose_extent(window, &support_details.capabilities);
 
-        let queue_info = utils::query_queue_families(vk_lib, instance, physical_device, surface)?;
+        let queue_info =
+            utils::query_queue_families(vk_lib, instance, physical_device, Some(surface))?;
         let queue_indices = [
             queue_info
                 .compute_index
@@ -372,6 +377,17 @@ impl WindowData {
         const EXTENSIONS: &[*const c_char] = &[khr::swapchain::NAME.as_ptr()];
         EXTENSIONS
     }
+
+    // merged with the renderer's features when the device is created
+    pub fn required_features() -> VkFeatureGuard<'static> {
+        static FEATURES: LazyLock<VkFeatures> = LazyLock::new(|| {
+            vk_features! {
+                vk::PhysicalDeviceFeatures {},
+            }
+        });
+
+        FEATURES.get_list()
+    }
 }
 
 impl Drop for WindowData {
@@ -397,3 +413,40 @@ impl Drop for WindowData {
         }
     }
 }
+
+/// Offset and size of the largest centered rectangle in `target` with the aspect ratio of `size`
+pub fn letterbox(
+    (width, height): (u32, u32),
+    (target_width, target_height): (u32, u32),
+) -> ((u32, u32), (u32, u32)) {
+    let scale = (target_width as f32 / width as f32).min(target_height as f32 / height as f32);
+    let fit_width = ((width as f32 * scale).round() as u32).clamp(1, target_width);
+    let fit_height = ((height as f32 * scale).round() as u32).clamp(1, target_height);
+    (
+        (
+            (target_width - fit_width) / 2,
+            (target_height - fit_height) / 2,
+        ),
+        (fit_width, fit_height),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::letterbox;
+
+    #[test]
+    fn letterboxing() {
+        assert_eq!(letterbox((100, 100), (100, 100)), ((0, 0), (100, 100)));
+        // wide image in a square window gets bars above and below
+        assert_eq!(
+            letterbox((2000, 1000), (1000, 1000)),
+            ((0, 250), (1000, 500))
+        );
+        // tall image gets bars on the sides
+        assert_eq!(
+            letterbox((500, 1000), (1000, 1000)),
+            ((250, 0), (500, 1000))
+        );
+    }
+}